@@ -0,0 +1,110 @@
+/*!
+Command line tools for WinDivert filters.
+*/
+
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+use windivert::{
+    capture::CaptureReader,
+    filter::{coverage::Coverage, fields::FIELDS, Filter},
+    prelude::WinDivertLayer,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Runs a filter over a pcap or pcapng file and reports per-clause hit counts.
+    Coverage {
+        /// Network layer filter.
+        filter: String,
+        /// Capture file.
+        capture: PathBuf,
+        /// Read the filter as a tcpdump/pcap-filter expression.
+        #[arg(long)]
+        pcap: bool,
+        /// List the packets that only matched through each alternative of an `or` expression.
+        #[arg(short, long)]
+        exclusive: bool,
+    },
+    /// Prints the catalog of filter fields as JSON.
+    Fields,
+}
+
+fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        Command::Coverage {
+            filter,
+            capture,
+            pcap,
+            exclusive,
+        } => coverage(&filter, &capture, pcap, exclusive),
+        Command::Fields => fields(),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn coverage(
+    filter: &str,
+    capture: &PathBuf,
+    pcap: bool,
+    exclusive: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = match pcap {
+        true => Filter::from_pcap(filter)?,
+        false => Filter::parse(filter)?,
+    };
+    filter.validate(WinDivertLayer::Network)?;
+
+    let mut reader = CaptureReader::open(capture)?;
+    let mut coverage = Coverage::new(&filter);
+    let mut frames = Vec::new();
+    for packet in &mut reader {
+        let packet = packet?;
+        frames.push(packet.frame);
+        coverage.record(&packet.into_packet());
+    }
+
+    if pcap {
+        println!("Filter: {filter}");
+    }
+    if reader.skipped() > 0 {
+        println!("Skipped {} non IP frames", reader.skipped());
+    }
+    print!("{coverage}");
+
+    if exclusive {
+        for clause in coverage.clauses().iter().filter(|clause| clause.branch) {
+            // Frame numbers start at 1, like in Wireshark
+            let packets: Vec<_> = clause
+                .exclusive
+                .iter()
+                .map(|index| (frames[*index] + 1).to_string())
+                .collect();
+            println!();
+            println!("Only matched by {}:", clause.expr);
+            match packets.is_empty() {
+                true => println!("  none"),
+                false => println!("  {}", packets.join(", ")),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn fields() -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", serde_json::to_string_pretty(FIELDS)?);
+    Ok(())
+}
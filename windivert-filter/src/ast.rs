@@ -0,0 +1,370 @@
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use crate::Field;
+
+/// Byte range of a filter string that produced a token or AST node.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Span {
+    /// Offset of the first byte.
+    pub start: usize,
+    /// Offset one past the last byte.
+    pub end: usize,
+}
+
+impl Span {
+    /// Creates a new span covering `start..end`.
+    pub const fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// Smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+/**
+Filter expression node.
+
+Spans are informative only and are ignored when comparing expressions, so two filters that only
+differ in whitespace or token spelling compare equal.
+*/
+#[derive(Debug, Clone)]
+pub struct Expr {
+    /// Expression type.
+    pub kind: ExprKind,
+    /// Source location of the expression.
+    pub span: Span,
+}
+
+impl Expr {
+    /// Creates a new expression with an empty span.
+    pub fn new(kind: ExprKind) -> Self {
+        Self {
+            kind,
+            span: Span::default(),
+        }
+    }
+
+    /// Creates a new expression with the provided span.
+    pub fn with_span(kind: ExprKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl Eq for Expr {}
+
+/// Filter expression types.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExprKind {
+    /// `true` or `false` constant.
+    Bool(bool),
+    /// Field used without comparison, equivalent to `field != 0`.
+    Field(FieldRef),
+    /// Field compared against a constant value.
+    Compare {
+        /// Left hand side field.
+        field: FieldRef,
+        /// Comparison operator.
+        op: CmpOp,
+        /// Right hand side constant.
+        value: Value,
+    },
+    /// Logical negation: `not expr` or `!expr`.
+    Not(Box<Expr>),
+    /// Logical conjunction: `lhs and rhs` or `lhs && rhs`.
+    And(Box<Expr>, Box<Expr>),
+    /// Logical disjunction: `lhs or rhs` or `lhs || rhs`.
+    Or(Box<Expr>, Box<Expr>),
+    /// Conditional expression: `cond ? then : otherwise`.
+    Ternary {
+        /// Condition.
+        cond: Box<Expr>,
+        /// Expression evaluated when the condition holds.
+        then: Box<Expr>,
+        /// Expression evaluated when the condition doesn't hold.
+        otherwise: Box<Expr>,
+    },
+}
+
+/// Reference to a filter field, including the index for `packet[i]` style fields.
+#[derive(Debug, Clone)]
+pub struct FieldRef {
+    /// Referenced field.
+    pub field: Field,
+    /**
+    Byte offset for indexed fields, `packet32[1]` has index `4`. Negative values index from the end
+    of the packet or payload.
+    */
+    pub index: Option<i32>,
+    /// Source location of the field.
+    pub span: Span,
+}
+
+impl FieldRef {
+    /// Creates a new reference to a non indexed field.
+    pub fn new(field: Field) -> Self {
+        Self {
+            field,
+            index: None,
+            span: Span::default(),
+        }
+    }
+
+    /// Creates a new reference to an indexed field.
+    pub fn indexed(field: Field, index: i32) -> Self {
+        Self {
+            field,
+            index: Some(index),
+            span: Span::default(),
+        }
+    }
+}
+
+impl PartialEq for FieldRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.field == other.field && self.index == other.index
+    }
+}
+
+impl Eq for FieldRef {}
+
+impl fmt::Display for FieldRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.field.index_width();
+        match self.index {
+            Some(index) if index % width == 0 => {
+                write!(f, "{}[{}]", self.field.name(), index / width)
+            }
+            Some(index) => write!(f, "{}[{}b]", self.field.name(), index),
+            None => f.write_str(self.field.name()),
+        }
+    }
+}
+
+/// Comparison operators.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CmpOp {
+    /// `==`
+    Eq,
+    /// `!=`
+    Ne,
+    /// `<`
+    Lt,
+    /// `<=`
+    Le,
+    /// `>`
+    Gt,
+    /// `>=`
+    Ge,
+}
+
+impl CmpOp {
+    /// Operator symbol.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CmpOp::Eq => "==",
+            CmpOp::Ne => "!=",
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+        }
+    }
+
+    /// Operator producing the opposite result, i.e. `!(a op b) == (a op.negate() b)`.
+    pub fn negate(&self) -> CmpOp {
+        match self {
+            CmpOp::Eq => CmpOp::Ne,
+            CmpOp::Ne => CmpOp::Eq,
+            CmpOp::Lt => CmpOp::Ge,
+            CmpOp::Le => CmpOp::Gt,
+            CmpOp::Gt => CmpOp::Le,
+            CmpOp::Ge => CmpOp::Lt,
+        }
+    }
+}
+
+impl fmt::Display for CmpOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Constant values used on the right hand side of comparisons.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Value {
+    /// Decimal or hexadecimal integer, optionally negative.
+    Int(i128),
+    /// Dotted IPv4 address.
+    Ipv4(Ipv4Addr),
+    /// IPv6 address.
+    Ipv6(Ipv6Addr),
+    /// Symbolic constant such as `TCP` or `CONNECT`.
+    Symbol(Symbol),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{value}"),
+            Value::Ipv4(addr) => write!(f, "{addr}"),
+            Value::Ipv6(addr) => write!(f, "{addr}"),
+            Value::Symbol(symbol) => f.write_str(symbol.name()),
+        }
+    }
+}
+
+/**
+Symbolic constants accepted by the filter language.
+
+`CLOSE` is shared by socket and reflect events, so its numeric value depends on the layer the
+filter is evaluated on.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Symbol {
+    /// `TRUE` (1).
+    True,
+    /// `FALSE` (0).
+    False,
+    /// `ICMP` protocol number (1).
+    Icmp,
+    /// `TCP` protocol number (6).
+    Tcp,
+    /// `UDP` protocol number (17).
+    Udp,
+    /// `ICMPV6` protocol number (58).
+    Icmpv6,
+    /// `PACKET` network event.
+    Packet,
+    /// `ESTABLISHED` flow event.
+    Established,
+    /// `DELETED` flow event.
+    Deleted,
+    /// `BIND` socket event.
+    Bind,
+    /// `CONNECT` socket event.
+    Connect,
+    /// `LISTEN` socket event.
+    Listen,
+    /// `ACCEPT` socket event.
+    Accept,
+    /// `CLOSE` socket or reflect event.
+    Close,
+    /// `OPEN` reflect event.
+    Open,
+    /// `NETWORK` layer.
+    Network,
+    /// `NETWORK_FORWARD` layer.
+    NetworkForward,
+    /// `FLOW` layer.
+    Flow,
+    /// `SOCKET` layer.
+    Socket,
+    /// `REFLECT` layer.
+    Reflect,
+}
+
+impl Symbol {
+    /// Every supported symbol.
+    pub const ALL: [Symbol; 20] = [
+        Symbol::True,
+        Symbol::False,
+        Symbol::Icmp,
+        Symbol::Tcp,
+        Symbol::Udp,
+        Symbol::Icmpv6,
+        Symbol::Packet,
+        Symbol::Established,
+        Symbol::Deleted,
+        Symbol::Bind,
+        Symbol::Connect,
+        Symbol::Listen,
+        Symbol::Accept,
+        Symbol::Close,
+        Symbol::Open,
+        Symbol::Network,
+        Symbol::NetworkForward,
+        Symbol::Flow,
+        Symbol::Socket,
+        Symbol::Reflect,
+    ];
+
+    /// Symbol name as written in filters.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Symbol::True => "TRUE",
+            Symbol::False => "FALSE",
+            Symbol::Icmp => "ICMP",
+            Symbol::Tcp => "TCP",
+            Symbol::Udp => "UDP",
+            Symbol::Icmpv6 => "ICMPV6",
+            Symbol::Packet => "PACKET",
+            Symbol::Established => "ESTABLISHED",
+            Symbol::Deleted => "DELETED",
+            Symbol::Bind => "BIND",
+            Symbol::Connect => "CONNECT",
+            Symbol::Listen => "LISTEN",
+            Symbol::Accept => "ACCEPT",
+            Symbol::Close => "CLOSE",
+            Symbol::Open => "OPEN",
+            Symbol::Network => "NETWORK",
+            Symbol::NetworkForward => "NETWORK_FORWARD",
+            Symbol::Flow => "FLOW",
+            Symbol::Socket => "SOCKET",
+            Symbol::Reflect => "REFLECT",
+        }
+    }
+
+    /// Case insensitive symbol lookup.
+    pub fn from_name(name: &str) -> Option<Symbol> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|symbol| symbol.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Renders the expression fully parenthesized, which is always accepted by `WinDivertOpen()`.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Bool(true) => f.write_str("true"),
+            ExprKind::Bool(false) => f.write_str("false"),
+            ExprKind::Field(field) => write!(f, "{field}"),
+            ExprKind::Compare { field, op, value } => write!(f, "{field} {op} {value}"),
+            ExprKind::Not(expr) => write!(f, "not ({expr})"),
+            ExprKind::And(lhs, rhs) => write!(f, "({lhs}) and ({rhs})"),
+            ExprKind::Or(lhs, rhs) => write!(f, "({lhs}) or ({rhs})"),
+            ExprKind::Ternary {
+                cond,
+                then,
+                otherwise,
+            } => write!(f, "({cond}) ? ({then}) : ({otherwise})"),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use thiserror::Error;
+
+use crate::Span;
+
+/**
+Error produced when a filter string can't be parsed.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{message} (at {span})")]
+pub struct FilterParseError {
+    /// Description of the error.
+    pub message: String,
+    /// Location of the offending token in the filter string.
+    pub span: Span,
+}
+
+impl FilterParseError {
+    /// Creates a new error pointing at `span`.
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        Self {
+            message: message.into(),
+            span,
+        }
+    }
+}
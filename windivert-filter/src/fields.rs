@@ -0,0 +1,488 @@
+/*!
+Catalog of the filter fields.
+
+[`FIELDS`] lists every field of the filter language with the type of its values, the layers it can
+be used on and its documentation. The parser resolves field names with [`lookup()`] and
+[`validate()`](crate::validate) checks fields against the same table, so tools built on it, such
+as editors and configuration validators, accept exactly the filters `windivert` accepts. With the
+`serde` feature the table can be serialized, the `windivert fields` command of `windivert-cli`
+prints it as JSON.
+*/
+
+use std::fmt;
+
+use crate::FilterLayer;
+
+/// Description of a filter field, see the [module documentation](self).
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FieldInfo {
+    /// Field described.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub field: Field,
+    /// Field name as written in filters.
+    pub name: &'static str,
+    /// Type of the values held by the field.
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
+    pub ty: FieldType,
+    /// Layers the field can be used on.
+    pub layers: &'static [FilterLayer],
+    /// Set for fields that require an index, e.g. `packet[0]`.
+    pub indexed: bool,
+    /// Short description of the field.
+    pub doc: &'static str,
+}
+
+/// Type of the values a field can hold.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum FieldType {
+    /// Boolean flag, compared as `0` or `1`.
+    Bool,
+    /// 8 bit unsigned integer.
+    U8,
+    /// 16 bit unsigned integer.
+    U16,
+    /// 32 bit unsigned integer.
+    U32,
+    /// 64 bit unsigned integer.
+    U64,
+    /// 16 bit signed integer.
+    I16,
+    /// 64 bit signed integer.
+    I64,
+    /// IPv4 address.
+    Ipv4,
+    /// IPv6 address.
+    Ipv6,
+    /// IPv4 or IPv6 address.
+    IpAddr,
+    /// ICMP or ICMPv6 message type, an 8 bit unsigned integer.
+    IcmpType,
+    /// Event identifier, see `WinDivertEvent`.
+    Event,
+    /// Layer identifier, see [`FilterLayer`].
+    Layer,
+}
+
+impl FieldType {
+    /**
+    Inclusive range of integer values the type can hold.
+
+    Addresses can be compared with integers too, IPv4 addresses as 32 bit integers and IPv6
+    addresses as 128 bit integers.
+    */
+    pub fn int_range(&self) -> IntRange {
+        let (min, max) = match self {
+            FieldType::Bool => (0, 1),
+            FieldType::U8 | FieldType::IcmpType | FieldType::Event | FieldType::Layer => {
+                (0, u8::MAX.into())
+            }
+            FieldType::U16 => (0, u16::MAX.into()),
+            FieldType::U32 | FieldType::Ipv4 => (0, u32::MAX.into()),
+            FieldType::U64 => (0, u64::MAX.into()),
+            FieldType::Ipv6 | FieldType::IpAddr => (0, u128::MAX),
+            FieldType::I16 => (i16::MIN.into(), i16::MAX as u128),
+            FieldType::I64 => (i64::MIN.into(), i64::MAX as u128),
+        };
+        IntRange { min, max }
+    }
+}
+
+/// Inclusive range of integers, returned by [`FieldType::int_range()`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct IntRange {
+    /// Smallest value.
+    pub min: i128,
+    /// Largest value, unsigned so that it can hold the largest IPv6 address.
+    pub max: u128,
+}
+
+impl IntRange {
+    /// Returns `true` if `value` is in the range.
+    pub fn contains(&self, value: i128) -> bool {
+        value >= self.min && u128::try_from(value).map_or(true, |value| value <= self.max)
+    }
+}
+
+impl FieldInfo {
+    /// Returns `true` if the field can be used in filters for the provided layer.
+    pub fn available_on(&self, layer: FilterLayer) -> bool {
+        self.layers.contains(&layer)
+    }
+}
+
+/// Case insensitive field lookup.
+pub fn lookup(name: &str) -> Option<&'static FieldInfo> {
+    FIELDS
+        .iter()
+        .find(|info| info.name.eq_ignore_ascii_case(name))
+}
+
+const ALL: &[FilterLayer] = &[
+    FilterLayer::Network,
+    FilterLayer::Forward,
+    FilterLayer::Flow,
+    FilterLayer::Socket,
+    FilterLayer::Reflect,
+];
+const NOT_REFLECT: &[FilterLayer] = &[
+    FilterLayer::Network,
+    FilterLayer::Forward,
+    FilterLayer::Flow,
+    FilterLayer::Socket,
+];
+const PACKET: &[FilterLayer] = &[FilterLayer::Network, FilterLayer::Forward];
+const DIRECTION: &[FilterLayer] = &[FilterLayer::Network, FilterLayer::Flow, FilterLayer::Socket];
+const PROCESS: &[FilterLayer] = &[FilterLayer::Flow, FilterLayer::Socket, FilterLayer::Reflect];
+const ENDPOINT: &[FilterLayer] = &[FilterLayer::Flow, FilterLayer::Socket];
+const REFLECT: &[FilterLayer] = &[FilterLayer::Reflect];
+
+/// Doc comment text without the space following `///`.
+const fn doc(text: &'static str) -> &'static str {
+    match text.as_bytes() {
+        [b' ', rest @ ..] => match std::str::from_utf8(rest) {
+            Ok(text) => text,
+            Err(_) => text,
+        },
+        _ => text,
+    }
+}
+
+macro_rules! fields {
+    (@indexed) => { false };
+    (@indexed indexed) => { true };
+    ($(
+        #[doc = $doc:literal]
+        $variant:ident => $name:literal, $ty:ident, $layers:ident $(, $indexed:ident)?;
+    )*) => {
+        /**
+        Filter fields.
+
+        Field names are matched case insensitively, [`Field::name()`] returns the spelling used by
+        the WinDivert documentation.
+        */
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub enum Field {
+            $(#[doc = $doc] $variant,)*
+        }
+
+        impl Field {
+            /// Every supported field.
+            pub const ALL: &'static [Field] = &[$(Field::$variant,)*];
+        }
+
+        /// Every supported field, in the same order as [`Field::ALL`].
+        pub static FIELDS: &[FieldInfo] = &[$(
+            FieldInfo {
+                field: Field::$variant,
+                name: $name,
+                ty: FieldType::$ty,
+                layers: $layers,
+                indexed: fields!(@indexed $($indexed)?),
+                doc: doc($doc),
+            },
+        )*];
+    };
+}
+
+/**
+Invokes the macro `$callback` with the field table, one `Variant => "name", Type, LAYERS` entry per
+field, followed by `indexed` for indexed fields. The typed fields of the builder of `windivert` are
+generated from the same entries.
+*/
+#[doc(hidden)]
+#[macro_export]
+macro_rules! field_table {
+    ($callback:ident) => {
+        $callback! {
+            /// The value zero.
+            Zero => "zero", U32, ALL;
+            /// Event timestamp.
+            Timestamp => "timestamp", I64, ALL;
+            /// Event type.
+            Event => "event", Event, ALL;
+            /// 8 bit random number.
+            Random8 => "random8", U8, PACKET;
+            /// 16 bit random number.
+            Random16 => "random16", U16, PACKET;
+            /// 32 bit random number.
+            Random32 => "random32", U32, PACKET;
+            /// Packet length.
+            Length => "length", U32, PACKET;
+            /// Is outbound?
+            Outbound => "outbound", Bool, DIRECTION;
+            /// Is inbound?
+            Inbound => "inbound", Bool, DIRECTION;
+            /// Is IP fragment?
+            Fragment => "fragment", Bool, PACKET;
+            /// Interface index.
+            IfIdx => "ifIdx", U32, PACKET;
+            /// Sub-interface index.
+            SubIfIdx => "subIfIdx", U32, PACKET;
+            /// Is loopback packet?
+            Loopback => "loopback", Bool, DIRECTION;
+            /// Is impostor packet?
+            Impostor => "impostor", Bool, PACKET;
+            /// Process identifier.
+            ProcessId => "processId", U32, PROCESS;
+            /// Local address.
+            LocalAddr => "localAddr", IpAddr, ENDPOINT;
+            /// Local port.
+            LocalPort => "localPort", U16, ENDPOINT;
+            /// Remote address.
+            RemoteAddr => "remoteAddr", IpAddr, ENDPOINT;
+            /// Remote port.
+            RemotePort => "remotePort", U16, ENDPOINT;
+            /// Protocol.
+            Protocol => "protocol", U8, ENDPOINT;
+            /// Endpoint identifier.
+            EndpointId => "endpointId", U64, ENDPOINT;
+            /// Parent endpoint identifier.
+            ParentEndpointId => "parentEndpointId", U64, ENDPOINT;
+            /// Handle layer.
+            Layer => "layer", Layer, REFLECT;
+            /// Handle priority.
+            Priority => "priority", I16, REFLECT;
+            /// Is IPv4?
+            Ip => "ip", Bool, NOT_REFLECT;
+            /// Is IPv6?
+            Ipv6 => "ipv6", Bool, NOT_REFLECT;
+            /// Is ICMP?
+            Icmp => "icmp", Bool, NOT_REFLECT;
+            /// Is ICMPv6?
+            Icmpv6 => "icmpv6", Bool, NOT_REFLECT;
+            /// Is TCP?
+            Tcp => "tcp", Bool, NOT_REFLECT;
+            /// Is UDP?
+            Udp => "udp", Bool, NOT_REFLECT;
+            /// IPv4 header length.
+            IpHdrLength => "ip.HdrLength", U8, PACKET;
+            /// IPv4 type of service.
+            IpTos => "ip.TOS", U8, PACKET;
+            /// IPv4 total length.
+            IpLength => "ip.Length", U16, PACKET;
+            /// IPv4 identification.
+            IpId => "ip.Id", U16, PACKET;
+            /// IPv4 don't fragment flag.
+            IpDf => "ip.DF", Bool, PACKET;
+            /// IPv4 more fragments flag.
+            IpMf => "ip.MF", Bool, PACKET;
+            /// IPv4 fragment offset.
+            IpFragOff => "ip.FragOff", U16, PACKET;
+            /// IPv4 time to live.
+            IpTtl => "ip.TTL", U8, PACKET;
+            /// IPv4 protocol.
+            IpProtocol => "ip.Protocol", U8, PACKET;
+            /// IPv4 header checksum.
+            IpChecksum => "ip.Checksum", U16, PACKET;
+            /// IPv4 source address.
+            IpSrcAddr => "ip.SrcAddr", Ipv4, PACKET;
+            /// IPv4 destination address.
+            IpDstAddr => "ip.DstAddr", Ipv4, PACKET;
+            /// IPv6 traffic class.
+            Ipv6TrafficClass => "ipv6.TrafficClass", U8, PACKET;
+            /// IPv6 flow label.
+            Ipv6FlowLabel => "ipv6.FlowLabel", U32, PACKET;
+            /// IPv6 payload length.
+            Ipv6Length => "ipv6.Length", U16, PACKET;
+            /// IPv6 next header.
+            Ipv6NextHdr => "ipv6.NextHdr", U8, PACKET;
+            /// IPv6 hop limit.
+            Ipv6HopLimit => "ipv6.HopLimit", U8, PACKET;
+            /// IPv6 source address.
+            Ipv6SrcAddr => "ipv6.SrcAddr", Ipv6, PACKET;
+            /// IPv6 destination address.
+            Ipv6DstAddr => "ipv6.DstAddr", Ipv6, PACKET;
+            /// ICMP type.
+            IcmpType => "icmp.Type", IcmpType, PACKET;
+            /// ICMP code.
+            IcmpCode => "icmp.Code", U8, PACKET;
+            /// ICMP checksum.
+            IcmpChecksum => "icmp.Checksum", U16, PACKET;
+            /// ICMP rest of header.
+            IcmpBody => "icmp.Body", U32, PACKET;
+            /// ICMPv6 type.
+            Icmpv6Type => "icmpv6.Type", IcmpType, PACKET;
+            /// ICMPv6 code.
+            Icmpv6Code => "icmpv6.Code", U8, PACKET;
+            /// ICMPv6 checksum.
+            Icmpv6Checksum => "icmpv6.Checksum", U16, PACKET;
+            /// ICMPv6 rest of header.
+            Icmpv6Body => "icmpv6.Body", U32, PACKET;
+            /// TCP source port.
+            TcpSrcPort => "tcp.SrcPort", U16, PACKET;
+            /// TCP destination port.
+            TcpDstPort => "tcp.DstPort", U16, PACKET;
+            /// TCP sequence number.
+            TcpSeqNum => "tcp.SeqNum", U32, PACKET;
+            /// TCP acknowledgement number.
+            TcpAckNum => "tcp.AckNum", U32, PACKET;
+            /// TCP header length.
+            TcpHdrLength => "tcp.HdrLength", U8, PACKET;
+            /// TCP URG flag.
+            TcpUrg => "tcp.Urg", Bool, PACKET;
+            /// TCP ACK flag.
+            TcpAck => "tcp.Ack", Bool, PACKET;
+            /// TCP PSH flag.
+            TcpPsh => "tcp.Psh", Bool, PACKET;
+            /// TCP RST flag.
+            TcpRst => "tcp.Rst", Bool, PACKET;
+            /// TCP SYN flag.
+            TcpSyn => "tcp.Syn", Bool, PACKET;
+            /// TCP FIN flag.
+            TcpFin => "tcp.Fin", Bool, PACKET;
+            /// TCP window size.
+            TcpWindow => "tcp.Window", U16, PACKET;
+            /// TCP checksum.
+            TcpChecksum => "tcp.Checksum", U16, PACKET;
+            /// TCP urgent pointer.
+            TcpUrgPtr => "tcp.UrgPtr", U16, PACKET;
+            /// TCP payload length.
+            TcpPayloadLength => "tcp.PayloadLength", U16, PACKET;
+            /// UDP source port.
+            UdpSrcPort => "udp.SrcPort", U16, PACKET;
+            /// UDP destination port.
+            UdpDstPort => "udp.DstPort", U16, PACKET;
+            /// UDP length.
+            UdpLength => "udp.Length", U16, PACKET;
+            /// UDP checksum.
+            UdpChecksum => "udp.Checksum", U16, PACKET;
+            /// UDP payload length.
+            UdpPayloadLength => "udp.PayloadLength", U16, PACKET;
+            /// Packet byte at the given index.
+            Packet => "packet", U8, PACKET, indexed;
+            /// Packet 16 bit word at the given index.
+            Packet16 => "packet16", U16, PACKET, indexed;
+            /// Packet 32 bit word at the given index.
+            Packet32 => "packet32", U32, PACKET, indexed;
+            /// TCP payload byte at the given index.
+            TcpPayload => "tcp.Payload", U8, PACKET, indexed;
+            /// TCP payload 16 bit word at the given index.
+            TcpPayload16 => "tcp.Payload16", U16, PACKET, indexed;
+            /// TCP payload 32 bit word at the given index.
+            TcpPayload32 => "tcp.Payload32", U32, PACKET, indexed;
+            /// UDP payload byte at the given index.
+            UdpPayload => "udp.Payload", U8, PACKET, indexed;
+            /// UDP payload 16 bit word at the given index.
+            UdpPayload16 => "udp.Payload16", U16, PACKET, indexed;
+            /// UDP payload 32 bit word at the given index.
+            UdpPayload32 => "udp.Payload32", U32, PACKET, indexed;
+        }
+    };
+}
+
+crate::field_table!(fields);
+
+impl Field {
+    /// Catalog entry of the field.
+    pub fn info(&self) -> &'static FieldInfo {
+        &FIELDS[*self as usize]
+    }
+
+    /// Field name as written in filters.
+    pub fn name(&self) -> &'static str {
+        self.info().name
+    }
+
+    /// Case insensitive field lookup.
+    pub fn from_name(name: &str) -> Option<Field> {
+        lookup(name).map(|info| info.field)
+    }
+
+    /// Type of the values held by the field.
+    pub fn field_type(&self) -> FieldType {
+        self.info().ty
+    }
+
+    /// Returns `true` if the field can be used in filters for the provided layer.
+    pub fn available_on(&self, layer: FilterLayer) -> bool {
+        self.info().available_on(layer)
+    }
+
+    /// Returns `true` for fields that require an index, e.g. `packet[0]`.
+    pub fn is_indexed(&self) -> bool {
+        self.info().indexed
+    }
+
+    /// Size in bytes of the value read by an indexed field, e.g. `2` for `packet16[i]`.
+    #[doc(hidden)]
+    pub fn index_width(&self) -> i32 {
+        match self {
+            Field::Packet | Field::TcpPayload | Field::UdpPayload => 1,
+            Field::Packet16 | Field::TcpPayload16 | Field::UdpPayload16 => 2,
+            _ => 4,
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalog() {
+        assert_eq!(FIELDS.len(), Field::ALL.len());
+        for (index, (info, field)) in FIELDS.iter().zip(Field::ALL).enumerate() {
+            assert_eq!(info.field, *field);
+            assert_eq!(lookup(info.name).unwrap().field, *field);
+            assert_eq!(lookup(&info.name.to_uppercase()).unwrap().field, *field);
+            assert!(!info.doc.is_empty() && !info.doc.starts_with(' '));
+            assert!(!info.layers.is_empty());
+            assert!(FIELDS[..index].iter().all(|other| other.name != info.name));
+        }
+        assert_eq!(Field::from_name("TCP.dstport"), Some(Field::TcpDstPort));
+        assert_eq!(Field::from_name("tcp.Dst"), None);
+        assert_eq!(Field::TcpDstPort.to_string(), "tcp.DstPort");
+        assert_eq!(Field::Outbound.info().doc, "Is outbound?");
+    }
+
+    #[test]
+    fn types_and_layers() {
+        assert_eq!(Field::IcmpType.field_type(), FieldType::IcmpType);
+        assert_eq!(Field::Icmpv6Type.field_type(), FieldType::IcmpType);
+        assert_eq!(Field::IcmpCode.field_type(), FieldType::U8);
+        assert_eq!(Field::IpSrcAddr.field_type(), FieldType::Ipv4);
+        assert_eq!(Field::Ipv6DstAddr.field_type(), FieldType::Ipv6);
+        assert_eq!(Field::RemoteAddr.field_type(), FieldType::IpAddr);
+        assert_eq!(Field::Event.field_type(), FieldType::Event);
+
+        assert!(Field::TcpDstPort.available_on(FilterLayer::Forward));
+        assert!(!Field::TcpDstPort.available_on(FilterLayer::Flow));
+        assert!(Field::ProcessId.available_on(FilterLayer::Reflect));
+        assert!(!Field::Outbound.available_on(FilterLayer::Forward));
+        assert!(!Field::Tcp.available_on(FilterLayer::Reflect));
+
+        let indexed: Vec<_> = FIELDS.iter().filter(|info| info.indexed).collect();
+        assert_eq!(indexed.len(), 9);
+        assert!(indexed.iter().all(|info| info.field.is_indexed()));
+        assert_eq!(Field::UdpPayload16.index_width(), 2);
+    }
+
+    #[test]
+    fn int_ranges() {
+        let range = FieldType::IcmpType.int_range();
+        assert_eq!(range, IntRange { min: 0, max: 255 });
+        assert!(range.contains(255) && !range.contains(256) && !range.contains(-1));
+
+        let range = FieldType::I16.int_range();
+        assert!(range.contains(-32768) && !range.contains(-32769) && !range.contains(32768));
+
+        // IPv6 addresses span the whole 128 bit range
+        let range = FieldType::Ipv6.int_range();
+        assert_eq!(range.max, u128::MAX);
+        assert!(range.contains(i128::MAX) && !range.contains(-1));
+        assert_eq!(FieldType::IpAddr.int_range(), range);
+        assert_eq!(FieldType::Ipv4.int_range().max, u32::MAX.into());
+    }
+}
@@ -0,0 +1,286 @@
+use crate::{Expr, ExprKind};
+
+/// Indentation used by the pretty printer.
+const INDENT: &str = "    ";
+
+/// Binding strength of an expression, higher binds tighter.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    Ternary,
+    Or,
+    And,
+    Unary,
+}
+
+fn precedence(expr: &Expr) -> Precedence {
+    match expr.kind {
+        ExprKind::Ternary { .. } => Precedence::Ternary,
+        ExprKind::Or(..) => Precedence::Or,
+        ExprKind::And(..) => Precedence::And,
+        _ => Precedence::Unary,
+    }
+}
+
+/// Operands of a chain of `and` or `or` expressions, regardless of how they are grouped.
+pub fn chain<'a>(expr: &'a Expr, operands: &mut Vec<&'a Expr>) {
+    match &expr.kind {
+        ExprKind::And(lhs, rhs) | ExprKind::Or(lhs, rhs) => {
+            for operand in [lhs, rhs] {
+                if std::mem::discriminant(&operand.kind) == std::mem::discriminant(&expr.kind) {
+                    chain(operand, operands);
+                } else {
+                    operands.push(operand);
+                }
+            }
+        }
+        _ => operands.push(expr),
+    }
+}
+
+fn operator(expr: &Expr) -> &'static str {
+    match expr.kind {
+        ExprKind::And(..) => "and",
+        _ => "or",
+    }
+}
+
+impl Expr {
+    /**
+    Renders the expression in canonical form.
+
+    Operators are written as `not`, `and`, `or` and `==`, fields use their documented casing,
+    numbers are written in decimal and only the parentheses required by precedence are kept.
+    Chains of `and`/`or` are written without grouping, so `a and (b and c)` and `(a and b) and c`
+    share the same canonical form. Indexes aligned to the width of the field are written in units
+    of the field, so `packet32[4b]` becomes the equivalent `packet32[1]`.
+    */
+    pub fn to_canonical_string(&self) -> String {
+        let mut out = String::new();
+        canonical(self, &mut out);
+        out
+    }
+
+    /**
+    Renders the expression in canonical form over multiple lines.
+
+    Subexpressions that don't fit in `width` columns are split, placing each operand of `and`/`or`
+    chains on its own line and indenting parenthesized groups.
+    */
+    pub fn to_pretty_string(&self, width: usize) -> String {
+        pretty(self, width).join("\n")
+    }
+}
+
+fn canonical(expr: &Expr, out: &mut String) {
+    match &expr.kind {
+        ExprKind::Bool(true) => out.push_str("true"),
+        ExprKind::Bool(false) => out.push_str("false"),
+        ExprKind::Field(field) => out.push_str(&field.to_string()),
+        ExprKind::Compare { field, op, value } => {
+            out.push_str(&format!("{field} {op} {value}"));
+        }
+        ExprKind::Not(operand) => {
+            out.push_str("not ");
+            operand_canonical(operand, Precedence::Unary, out);
+        }
+        ExprKind::And(..) | ExprKind::Or(..) => {
+            let mut operands = Vec::new();
+            chain(expr, &mut operands);
+            for (i, operand) in operands.into_iter().enumerate() {
+                if i != 0 {
+                    out.push(' ');
+                    out.push_str(operator(expr));
+                    out.push(' ');
+                }
+                operand_canonical(operand, precedence(expr), out);
+            }
+        }
+        ExprKind::Ternary {
+            cond,
+            then,
+            otherwise,
+        } => {
+            operand_canonical(cond, Precedence::Or, out);
+            out.push_str(" ? ");
+            operand_canonical(then, Precedence::Or, out);
+            out.push_str(" : ");
+            canonical(otherwise, out);
+        }
+    }
+}
+
+/// Writes `expr`, wrapping it in parentheses if it binds looser than `min`.
+fn operand_canonical(expr: &Expr, min: Precedence, out: &mut String) {
+    if precedence(expr) < min {
+        out.push('(');
+        canonical(expr, out);
+        out.push(')');
+    } else {
+        canonical(expr, out);
+    }
+}
+
+fn pretty(expr: &Expr, width: usize) -> Vec<String> {
+    let flat = expr.to_canonical_string();
+    if flat.len() <= width {
+        return vec![flat];
+    }
+    match &expr.kind {
+        ExprKind::Not(operand) if precedence(operand) < Precedence::Unary => {
+            let mut lines = vec!["not (".to_owned()];
+            lines.extend(indent(pretty(operand, width.saturating_sub(INDENT.len()))));
+            lines.push(")".to_owned());
+            lines
+        }
+        ExprKind::And(..) | ExprKind::Or(..) => {
+            let mut operands = Vec::new();
+            chain(expr, &mut operands);
+            let op = operator(expr);
+            let mut lines = Vec::new();
+            for (i, operand) in operands.into_iter().enumerate() {
+                let prefix = if i == 0 {
+                    String::new()
+                } else {
+                    format!("{op} ")
+                };
+                let mut operand = pretty_operand(operand, width.saturating_sub(prefix.len()));
+                operand[0].insert_str(0, &prefix);
+                lines.extend(operand);
+            }
+            lines
+        }
+        ExprKind::Ternary {
+            cond,
+            then,
+            otherwise,
+        } => {
+            let branch_width = width.saturating_sub(INDENT.len() + 2);
+            let mut lines = pretty_operand(cond, width);
+            for (symbol, branch) in [("? ", then), (": ", otherwise)] {
+                let branch = pretty_operand(branch, branch_width);
+                let mut branch = indent(branch);
+                branch[0].insert_str(INDENT.len(), symbol);
+                lines.extend(branch);
+            }
+            lines
+        }
+        _ => vec![flat],
+    }
+}
+
+/// Operands of `and`/`or` chains and ternaries are parenthesized unless they are unary.
+fn pretty_operand(expr: &Expr, width: usize) -> Vec<String> {
+    if precedence(expr) == Precedence::Unary {
+        return pretty(expr, width);
+    }
+    let flat = format!("({})", expr.to_canonical_string());
+    if flat.len() <= width {
+        return vec![flat];
+    }
+    let mut lines = vec!["(".to_owned()];
+    lines.extend(indent(pretty(expr, width.saturating_sub(INDENT.len()))));
+    lines.push(")".to_owned());
+    lines
+}
+
+fn indent(lines: Vec<String>) -> Vec<String> {
+    lines
+        .into_iter()
+        .map(|line| format!("{INDENT}{line}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse;
+
+    /// Filters and their canonical form.
+    const CANONICAL: &[(&str, &str)] = &[
+        ("tcp && udp || !icmp", "tcp and udp or not icmp"),
+        ("(tcp or udp) and icmp", "(tcp or udp) and icmp"),
+        ("tcp or (udp and icmp)", "tcp or udp and icmp"),
+        ("not (tcp and udp)", "not (tcp and udp)"),
+        ("not (not tcp)", "not not tcp"),
+        (
+            "tcp ? udp : icmp ? ip : ipv6",
+            "tcp ? udp : icmp ? ip : ipv6",
+        ),
+        (
+            "(tcp ? udp : icmp) ? ip : ipv6",
+            "(tcp ? udp : icmp) ? ip : ipv6",
+        ),
+        (
+            "tcp ? (udp ? ip : ipv6) : icmp",
+            "tcp ? (udp ? ip : ipv6) : icmp",
+        ),
+        ("tcp or udp ? icmp : ip", "tcp or udp ? icmp : ip"),
+        ("TCP.dstport = 0x50", "tcp.DstPort == 80"),
+        (
+            "ipv6.DstAddr == 2001:DB8:0:0::1",
+            "ipv6.DstAddr == 2001:db8::1",
+        ),
+        (
+            "ipv6.SrcAddr != ::ffff:10.0.0.1",
+            "ipv6.SrcAddr != ::ffff:10.0.0.1",
+        ),
+        ("ip.DstAddr >= 10.0.0.0", "ip.DstAddr >= 10.0.0.0"),
+        ("packet32[1] == 1", "packet32[1] == 1"),
+        ("packet32[4b] == 1", "packet32[1] == 1"),
+        ("packet32[3b] == 1", "packet32[3b] == 1"),
+        ("packet16[-1] == 1", "packet16[-1] == 1"),
+        ("packet16[-3b] == 1", "packet16[-3b] == 1"),
+        ("tcp.Payload[0] == 0x16", "tcp.Payload[0] == 22"),
+        ("event == connect", "event == CONNECT"),
+        ("timestamp > -5", "timestamp > -5"),
+    ];
+
+    #[test]
+    fn canonical() {
+        for (filter, expected) in CANONICAL {
+            let parsed = parse(filter).unwrap();
+            let canonical = parsed.to_canonical_string();
+            assert_eq!(canonical, *expected, "{filter}");
+            // Formatting keeps the tree, and the canonical form is stable
+            let reparsed = parse(&canonical).unwrap();
+            assert_eq!(reparsed, parsed, "{filter}");
+            assert_eq!(reparsed.to_canonical_string(), canonical, "{filter}");
+        }
+
+        // Chains are written without grouping, and parsed back grouped to the left
+        let filter = parse("tcp and (udp and icmp)").unwrap();
+        assert_eq!(filter.to_canonical_string(), "tcp and udp and icmp");
+        let grouped = parse("(tcp and udp) and icmp").unwrap();
+        assert_eq!(grouped.to_canonical_string(), "tcp and udp and icmp");
+    }
+
+    #[test]
+    fn pretty() {
+        let filter = parse(
+            "tcp.DstPort == 80 and (ip.SrcAddr == 10.0.0.1 or ip.SrcAddr == 10.0.0.2) \
+             and not (udp or icmp) and (ipv6 ? ipv6.HopLimit > 1 : ip.TTL > 1)",
+        )
+        .unwrap();
+        let expected = "\
+tcp.DstPort == 80
+and (ip.SrcAddr == 10.0.0.1 or ip.SrcAddr == 10.0.0.2)
+and not (udp or icmp)
+and (ipv6 ? ipv6.HopLimit > 1 : ip.TTL > 1)";
+        assert_eq!(filter.to_pretty_string(60), expected);
+        let expected = "\
+tcp.DstPort == 80
+and (
+    ip.SrcAddr == 10.0.0.1
+    or ip.SrcAddr == 10.0.0.2
+)
+and not (udp or icmp)
+and (
+    ipv6
+        ? ipv6.HopLimit > 1
+        : ip.TTL > 1
+)";
+        assert_eq!(filter.to_pretty_string(30), expected);
+        let pretty = parse(&filter.to_pretty_string(30)).unwrap();
+        assert_eq!(pretty, filter);
+        assert_eq!(filter.to_pretty_string(200), filter.to_canonical_string());
+    }
+}
@@ -0,0 +1,82 @@
+use std::{convert::TryFrom, fmt};
+
+/**
+WinDivert layer a filter is checked against.
+
+Mirrors `WinDivertLayer` of `windivert-sys`, with the same numeric values, so this crate doesn't
+depend on the WinDivert library. `FilterLayer::try_from(u32::from(layer))` converts a
+`WinDivertLayer`.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "lowercase")
+)]
+#[repr(u32)]
+pub enum FilterLayer {
+    /// Network layer.
+    Network = 0,
+    /// Network forward layer.
+    Forward = 1,
+    /// Flow layer.
+    Flow = 2,
+    /// Socket layer.
+    Socket = 3,
+    /// Reflect layer.
+    Reflect = 4,
+}
+
+impl FilterLayer {
+    /// Every layer.
+    pub const ALL: [FilterLayer; 5] = [
+        FilterLayer::Network,
+        FilterLayer::Forward,
+        FilterLayer::Flow,
+        FilterLayer::Socket,
+        FilterLayer::Reflect,
+    ];
+
+    /// Lowercase layer name, as used in error messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterLayer::Network => "network",
+            FilterLayer::Forward => "forward",
+            FilterLayer::Flow => "flow",
+            FilterLayer::Socket => "socket",
+            FilterLayer::Reflect => "reflect",
+        }
+    }
+
+    /// Case sensitive lookup by [`name()`](Self::name).
+    pub fn from_name(name: &str) -> Option<FilterLayer> {
+        Self::ALL.iter().copied().find(|layer| layer.name() == name)
+    }
+}
+
+impl TryFrom<u32> for FilterLayer {
+    type Error = u32;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(FilterLayer::Network),
+            1 => Ok(FilterLayer::Forward),
+            2 => Ok(FilterLayer::Flow),
+            3 => Ok(FilterLayer::Socket),
+            4 => Ok(FilterLayer::Reflect),
+            _ => Err(value),
+        }
+    }
+}
+
+impl From<FilterLayer> for u32 {
+    fn from(layer: FilterLayer) -> Self {
+        layer as u32
+    }
+}
+
+impl fmt::Display for FilterLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
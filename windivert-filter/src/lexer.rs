@@ -0,0 +1,347 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::{FilterParseError, Span};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum TokenKind {
+    Word(String),
+    Int(i128),
+    /// Byte offset used as index, e.g. `2b` in `packet16[2b]`.
+    Bytes(i128),
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Not,
+    And,
+    Or,
+    Question,
+    Colon,
+    Minus,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eof,
+}
+
+impl TokenKind {
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            TokenKind::Word(word) => format!("`{word}`"),
+            TokenKind::Int(value) => format!("number `{value}`"),
+            TokenKind::Bytes(value) => format!("byte offset `{value}b`"),
+            TokenKind::Ipv4(addr) => format!("address `{addr}`"),
+            TokenKind::Ipv6(addr) => format!("address `{addr}`"),
+            TokenKind::LParen => "`(`".into(),
+            TokenKind::RParen => "`)`".into(),
+            TokenKind::LBracket => "`[`".into(),
+            TokenKind::RBracket => "`]`".into(),
+            TokenKind::Not => "`not`".into(),
+            TokenKind::And => "`and`".into(),
+            TokenKind::Or => "`or`".into(),
+            TokenKind::Question => "`?`".into(),
+            TokenKind::Colon => "`:`".into(),
+            TokenKind::Minus => "`-`".into(),
+            TokenKind::Eq => "`==`".into(),
+            TokenKind::Ne => "`!=`".into(),
+            TokenKind::Lt => "`<`".into(),
+            TokenKind::Le => "`<=`".into(),
+            TokenKind::Gt => "`>`".into(),
+            TokenKind::Ge => "`>=`".into(),
+            TokenKind::Eof => "end of filter".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+fn is_word_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'.' || byte == b':'
+}
+
+/// Splits `source` into tokens. The returned vector always ends with [`TokenKind::Eof`].
+pub(crate) fn tokenize(source: &str) -> Result<Vec<Token>, FilterParseError> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let start = pos;
+        let next = bytes.get(pos + 1).copied();
+        let (kind, len) = match bytes[pos] {
+            byte if byte.is_ascii_whitespace() => {
+                pos += 1;
+                continue;
+            }
+            b'(' => (TokenKind::LParen, 1),
+            b')' => (TokenKind::RParen, 1),
+            b'[' => (TokenKind::LBracket, 1),
+            b']' => (TokenKind::RBracket, 1),
+            b'?' => (TokenKind::Question, 1),
+            b'-' => (TokenKind::Minus, 1),
+            b'&' if next == Some(b'&') => (TokenKind::And, 2),
+            b'|' if next == Some(b'|') => (TokenKind::Or, 2),
+            b'!' if next == Some(b'=') => (TokenKind::Ne, 2),
+            b'!' => (TokenKind::Not, 1),
+            b'=' if next == Some(b'=') => (TokenKind::Eq, 2),
+            // WinDivert also accepts a single `=` for equality
+            b'=' => (TokenKind::Eq, 1),
+            b'<' if next == Some(b'=') => (TokenKind::Le, 2),
+            b'<' => (TokenKind::Lt, 1),
+            b'>' if next == Some(b'=') => (TokenKind::Ge, 2),
+            b'>' => (TokenKind::Gt, 1),
+            byte if is_word_byte(byte) => {
+                let len = bytes[pos..]
+                    .iter()
+                    .position(|&byte| !is_word_byte(byte))
+                    .unwrap_or(bytes.len() - pos);
+                word(&source[pos..pos + len], start)?
+            }
+            _ => {
+                let c = source[pos..].chars().next().unwrap_or_default();
+                return Err(FilterParseError::new(
+                    format!("unexpected character `{c}`"),
+                    Span::new(start, start + c.len_utf8()),
+                ));
+            }
+        };
+        pos += len;
+        tokens.push(Token {
+            kind,
+            span: Span::new(start, pos),
+        });
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        span: Span::new(bytes.len(), bytes.len()),
+    });
+    Ok(tokens)
+}
+
+/// Classifies a run of word characters, returning the token and the number of bytes consumed.
+///
+/// Words may contain `:` since it is part of IPv6 literals. If the run isn't an IPv6 address the
+/// word is cut at the first colon so that `cond ? a:b` still lexes the ternary separator.
+fn word(text: &str, start: usize) -> Result<(TokenKind, usize), FilterParseError> {
+    if text.contains(':') {
+        if let Ok(addr) = text.parse::<Ipv6Addr>() {
+            return Ok((TokenKind::Ipv6(addr), text.len()));
+        }
+        return match text.find(':') {
+            Some(0) => Ok((TokenKind::Colon, 1)),
+            Some(colon) => word(&text[..colon], start),
+            None => unreachable!(),
+        };
+    }
+
+    let span = Span::new(start, start + text.len());
+    let kind = if text.as_bytes()[0].is_ascii_digit() {
+        number(text, span)?
+    } else {
+        match text.to_ascii_lowercase().as_str() {
+            "and" => TokenKind::And,
+            "or" => TokenKind::Or,
+            "not" => TokenKind::Not,
+            _ => TokenKind::Word(text.to_owned()),
+        }
+    };
+    Ok((kind, text.len()))
+}
+
+fn number(text: &str, span: Span) -> Result<TokenKind, FilterParseError> {
+    if text.contains('.') {
+        return text
+            .parse::<Ipv4Addr>()
+            .map(TokenKind::Ipv4)
+            .map_err(|_| FilterParseError::new(format!("invalid IPv4 address `{text}`"), span));
+    }
+
+    let bytes = text
+        .strip_suffix('b')
+        .filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()));
+    let value = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => bytes.unwrap_or(text).parse::<u64>(),
+    };
+    value
+        .map(|value| match bytes {
+            Some(_) => TokenKind::Bytes(value as i128),
+            None => TokenKind::Int(value as i128),
+        })
+        .map_err(|err| {
+            let message = match err.kind() {
+                std::num::IntErrorKind::PosOverflow => format!("number `{text}` is too large"),
+                _ => format!("invalid number `{text}`"),
+            };
+            FilterParseError::new(message, span)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        let mut kinds: Vec<_> = tokenize(source)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect();
+        assert_eq!(kinds.pop(), Some(TokenKind::Eof));
+        kinds
+    }
+
+    fn error(source: &str) -> (String, Span) {
+        let error = tokenize(source).unwrap_err();
+        (error.message, error.span)
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(
+            kinds("10.0.0.1 255.255.255.255"),
+            [
+                TokenKind::Ipv4(Ipv4Addr::new(10, 0, 0, 1)),
+                TokenKind::Ipv4(Ipv4Addr::BROADCAST),
+            ]
+        );
+        assert_eq!(
+            kinds("::1 fe80::1:2 ::ffff:1.2.3.4"),
+            [
+                TokenKind::Ipv6(Ipv6Addr::LOCALHOST),
+                TokenKind::Ipv6("fe80::1:2".parse().unwrap()),
+                TokenKind::Ipv6("::ffff:1.2.3.4".parse().unwrap()),
+            ]
+        );
+        assert_eq!(
+            kinds("0 42 0x1F 0XfF 18446744073709551615"),
+            [
+                TokenKind::Int(0),
+                TokenKind::Int(42),
+                TokenKind::Int(31),
+                TokenKind::Int(255),
+                TokenKind::Int(u64::MAX as i128),
+            ]
+        );
+        assert_eq!(kinds("-5"), [TokenKind::Minus, TokenKind::Int(5)]);
+        assert_eq!(kinds("-0x10"), [TokenKind::Minus, TokenKind::Int(16)]);
+        assert_eq!(kinds("12b"), [TokenKind::Bytes(12)]);
+        assert_eq!(kinds("0x12b"), [TokenKind::Int(0x12b)]);
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(
+            kinds("&& || ! and OR Not"),
+            [
+                TokenKind::And,
+                TokenKind::Or,
+                TokenKind::Not,
+                TokenKind::And,
+                TokenKind::Or,
+                TokenKind::Not,
+            ]
+        );
+        assert_eq!(
+            kinds("= == != < <= > >= !tcp"),
+            [
+                TokenKind::Eq,
+                TokenKind::Eq,
+                TokenKind::Ne,
+                TokenKind::Lt,
+                TokenKind::Le,
+                TokenKind::Gt,
+                TokenKind::Ge,
+                TokenKind::Not,
+                TokenKind::Word("tcp".into()),
+            ]
+        );
+        assert_eq!(
+            kinds("packet[-1]"),
+            [
+                TokenKind::Word("packet".into()),
+                TokenKind::LBracket,
+                TokenKind::Minus,
+                TokenKind::Int(1),
+                TokenKind::RBracket,
+            ]
+        );
+    }
+
+    #[test]
+    fn ternary_colon() {
+        let word = |word: &str| TokenKind::Word(word.into());
+        assert_eq!(
+            kinds("tcp?udp:icmp"),
+            [
+                word("tcp"),
+                TokenKind::Question,
+                word("udp"),
+                TokenKind::Colon,
+                word("icmp"),
+            ]
+        );
+        assert_eq!(
+            kinds("ip ? 1:2"),
+            [
+                word("ip"),
+                TokenKind::Question,
+                TokenKind::Int(1),
+                TokenKind::Colon,
+                TokenKind::Int(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans() {
+        let tokens = tokenize(" tcp.DstPort  ==0x50").unwrap();
+        let spans: Vec<_> = tokens.iter().map(|token| token.span).collect();
+        assert_eq!(
+            spans,
+            [
+                Span::new(1, 12),
+                Span::new(14, 16),
+                Span::new(16, 20),
+                Span::new(20, 20),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error("tcp and é"),
+            ("unexpected character `é`".into(), Span::new(8, 10))
+        );
+        assert_eq!(
+            error("x == 18446744073709551616"),
+            (
+                "number `18446744073709551616` is too large".into(),
+                Span::new(5, 25)
+            )
+        );
+        assert_eq!(
+            error("x == 0x"),
+            ("invalid number `0x`".into(), Span::new(5, 7))
+        );
+        assert_eq!(
+            error("x == 1.2.3"),
+            ("invalid IPv4 address `1.2.3`".into(), Span::new(5, 10))
+        );
+        assert_eq!(
+            error("x == 12ab"),
+            ("invalid number `12ab`".into(), Span::new(5, 9))
+        );
+    }
+}
@@ -0,0 +1,33 @@
+#![deny(missing_docs)]
+/*!
+Front end of the WinDivert filter language, shared by the [`windivert`] and [`windivert-macros`]
+crates.
+
+This crate holds the parts of the filter support that don't depend on the WinDivert library: the
+syntax tree, the parser, the field catalog and the layer checks. `windivert` re-exports them from
+its `filter` module, and `windivert-macros` uses them to check filters at compile time without
+depending on `windivert` and `windivert-sys`. It is an implementation detail of those crates, use
+`windivert::filter` instead.
+
+[`windivert`]: https://docs.rs/windivert
+[`windivert-macros`]: https://docs.rs/windivert-macros
+*/
+
+mod ast;
+mod error;
+pub mod fields;
+mod format;
+mod layer;
+mod lexer;
+mod parser;
+mod placeholders;
+mod validate;
+
+pub use ast::*;
+pub use error::FilterParseError;
+pub use fields::{Field, FieldType, IntRange};
+pub use format::chain;
+pub use layer::FilterLayer;
+pub use parser::{parse, MAX_HEIGHT, MAX_TESTS};
+pub use placeholders::{Placeholder, Placeholders};
+pub use validate::{validate, validate_field, validate_value};
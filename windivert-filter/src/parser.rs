@@ -0,0 +1,599 @@
+use crate::{
+    ast::{CmpOp, Expr, ExprKind, FieldRef, Symbol, Value},
+    fields::{lookup, FIELDS},
+    lexer::{tokenize, Token, TokenKind},
+    FilterParseError, Span,
+};
+
+/// Maximum nesting depth accepted by the parser.
+const MAX_DEPTH: usize = 128;
+
+/// Maximum number of tests of a compiled filter object.
+pub const MAX_TESTS: usize = 256;
+
+/**
+Maximum height of the expression tree, bounding the recursion of the code walking it. `and` and
+`or` chains build left deep trees, so long chains are limited along with nesting. Filters that fit
+in a filter object, of at most [`MAX_TESTS`] tests, are always accepted.
+*/
+pub const MAX_HEIGHT: usize = MAX_TESTS + MAX_DEPTH;
+
+/// Expression along with the height of its tree.
+type Node = (Expr, usize);
+
+/// Parses a filter string into an expression tree.
+pub fn parse(source: &str) -> Result<Expr, FilterParseError> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        depth: 0,
+    };
+    if parser.peek().kind == TokenKind::Eof {
+        return Err(FilterParseError::new("empty filter", parser.peek().span));
+    }
+    let (expr, _) = parser.expr()?;
+    match parser.peek() {
+        Token {
+            kind: TokenKind::Eof,
+            ..
+        } => Ok(expr),
+        token => Err(FilterParseError::new(
+            format!("unexpected {}", token.kind.describe()),
+            token.span,
+        )),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+
+    fn bump(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> Option<Token> {
+        if &self.peek().kind == kind {
+            Some(self.bump())
+        } else {
+            None
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<Token, FilterParseError> {
+        self.eat(&kind).ok_or_else(|| {
+            let token = self.peek();
+            FilterParseError::new(
+                format!(
+                    "expected {}, found {}",
+                    kind.describe(),
+                    token.kind.describe()
+                ),
+                token.span,
+            )
+        })
+    }
+
+    fn enter(&mut self) -> Result<(), FilterParseError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(FilterParseError::new(
+                "filter is nested too deeply",
+                self.peek().span,
+            ));
+        }
+        Ok(())
+    }
+
+    /// `expr := or ['?' expr ':' expr]`
+    fn expr(&mut self) -> Result<Node, FilterParseError> {
+        self.enter()?;
+        let cond = self.or()?;
+        let node = if let Some(question) = self.eat(&TokenKind::Question) {
+            let then = self.expr()?;
+            self.expect(TokenKind::Colon)?;
+            let otherwise = self.expr()?;
+            let height = cond.1.max(then.1).max(otherwise.1) + 1;
+            check_height(height, question.span)?;
+            let span = cond.0.span.to(otherwise.0.span);
+            let kind = ExprKind::Ternary {
+                cond: Box::new(cond.0),
+                then: Box::new(then.0),
+                otherwise: Box::new(otherwise.0),
+            };
+            (Expr::with_span(kind, span), height)
+        } else {
+            cond
+        };
+        self.depth -= 1;
+        Ok(node)
+    }
+
+    /// `or := and (('or' | '||') and)*`
+    fn or(&mut self) -> Result<Node, FilterParseError> {
+        let mut lhs = self.and()?;
+        while let Some(or) = self.eat(&TokenKind::Or) {
+            let rhs = self.and()?;
+            lhs = binary(ExprKind::Or, lhs, rhs, or.span)?;
+        }
+        Ok(lhs)
+    }
+
+    /// `and := unary (('and' | '&&') unary)*`
+    fn and(&mut self) -> Result<Node, FilterParseError> {
+        let mut lhs = self.unary()?;
+        while let Some(and) = self.eat(&TokenKind::And) {
+            let rhs = self.unary()?;
+            lhs = binary(ExprKind::And, lhs, rhs, and.span)?;
+        }
+        Ok(lhs)
+    }
+
+    /// `unary := ('not' | '!') unary | '(' expr ')' | test`
+    fn unary(&mut self) -> Result<Node, FilterParseError> {
+        if let Some(not) = self.eat(&TokenKind::Not) {
+            self.enter()?;
+            let (expr, height) = self.unary()?;
+            self.depth -= 1;
+            check_height(height + 1, not.span)?;
+            let span = not.span.to(expr.span);
+            let expr = Expr::with_span(ExprKind::Not(Box::new(expr)), span);
+            return Ok((expr, height + 1));
+        }
+        if let Some(open) = self.eat(&TokenKind::LParen) {
+            let (mut expr, height) = self.expr()?;
+            let close = self.expect(TokenKind::RParen)?;
+            expr.span = open.span.to(close.span);
+            return Ok((expr, height));
+        }
+        Ok((self.test()?, 1))
+    }
+
+    /// `test := 'true' | 'false' | field [op value]`
+    fn test(&mut self) -> Result<Expr, FilterParseError> {
+        let token = self.bump();
+        let name = match token.kind {
+            TokenKind::Word(name) => name,
+            kind => {
+                return Err(FilterParseError::new(
+                    format!("expected field, found {}", kind.describe()),
+                    token.span,
+                ))
+            }
+        };
+
+        if name.eq_ignore_ascii_case("true") || name.eq_ignore_ascii_case("false") {
+            let value = name.eq_ignore_ascii_case("true");
+            return Ok(Expr::with_span(ExprKind::Bool(value), token.span));
+        }
+
+        let field = self.field(&name, token.span)?;
+        let op = match self.peek().kind {
+            TokenKind::Eq => CmpOp::Eq,
+            TokenKind::Ne => CmpOp::Ne,
+            TokenKind::Lt => CmpOp::Lt,
+            TokenKind::Le => CmpOp::Le,
+            TokenKind::Gt => CmpOp::Gt,
+            TokenKind::Ge => CmpOp::Ge,
+            _ => {
+                let span = field.span;
+                return Ok(Expr::with_span(ExprKind::Field(field), span));
+            }
+        };
+        self.bump();
+        let (value, value_span) = self.value()?;
+        let span = field.span.to(value_span);
+        Ok(Expr::with_span(
+            ExprKind::Compare { field, op, value },
+            span,
+        ))
+    }
+
+    fn field(&mut self, name: &str, span: Span) -> Result<FieldRef, FilterParseError> {
+        let info = lookup(name).ok_or_else(|| unknown_field(name, span))?;
+        let field = info.field;
+        if !info.indexed {
+            if self.peek().kind == TokenKind::LBracket {
+                return Err(FilterParseError::new(
+                    format!("field `{}` can't be indexed", field.name()),
+                    self.peek().span,
+                ));
+            }
+            return Ok(FieldRef {
+                field,
+                index: None,
+                span,
+            });
+        }
+
+        if self.peek().kind != TokenKind::LBracket {
+            return Err(FilterParseError::new(
+                format!("field `{}` requires an index", field.name()),
+                span,
+            ));
+        }
+        self.bump();
+        let negative = self.eat(&TokenKind::Minus).is_some();
+        let token = self.bump();
+        // Indexes count values of the field width, unless given in bytes with a `b` suffix
+        let width = field.index_width() as i128;
+        let offset = match token.kind {
+            TokenKind::Int(value) => value.saturating_mul(width),
+            TokenKind::Bytes(value) => value,
+            kind => {
+                return Err(FilterParseError::new(
+                    format!("expected index, found {}", kind.describe()),
+                    token.span,
+                ))
+            }
+        };
+        // Same bounds as WinDivert, the value has to be within the first or last 64 KiB
+        let max = u16::MAX as i128;
+        let valid = match negative {
+            true => (width..=max).contains(&offset),
+            false => offset <= max - width,
+        };
+        let index = if negative { -offset } else { offset };
+        if !valid {
+            return Err(FilterParseError::new(
+                format!("index `{index}` is out of range"),
+                token.span,
+            ));
+        }
+        let close = self.expect(TokenKind::RBracket)?;
+        Ok(FieldRef {
+            field,
+            index: Some(index as i32),
+            span: span.to(close.span),
+        })
+    }
+
+    fn value(&mut self) -> Result<(Value, Span), FilterParseError> {
+        let minus = self.eat(&TokenKind::Minus);
+        let token = self.bump();
+        let span = minus
+            .as_ref()
+            .map_or(token.span, |minus| minus.span.to(token.span));
+        let value = match token.kind {
+            TokenKind::Int(value) if minus.is_some() => Value::Int(-value),
+            TokenKind::Int(value) => Value::Int(value),
+            TokenKind::Ipv4(addr) if minus.is_none() => Value::Ipv4(addr),
+            TokenKind::Ipv6(addr) if minus.is_none() => Value::Ipv6(addr),
+            TokenKind::Word(ref word) if minus.is_none() => {
+                Value::Symbol(Symbol::from_name(word).ok_or_else(|| {
+                    FilterParseError::new(format!("unknown value `{word}`"), token.span)
+                })?)
+            }
+            kind => {
+                return Err(FilterParseError::new(
+                    format!("expected value, found {}", kind.describe()),
+                    token.span,
+                ))
+            }
+        };
+        Ok((value, span))
+    }
+}
+
+/// Joins two operands with a binary operator at `span`.
+fn binary(
+    kind: fn(Box<Expr>, Box<Expr>) -> ExprKind,
+    (lhs, lhs_height): Node,
+    (rhs, rhs_height): Node,
+    span: Span,
+) -> Result<Node, FilterParseError> {
+    let height = lhs_height.max(rhs_height) + 1;
+    check_height(height, span)?;
+    let span = lhs.span.to(rhs.span);
+    let expr = Expr::with_span(kind(Box::new(lhs), Box::new(rhs)), span);
+    Ok((expr, height))
+}
+
+fn check_height(height: usize, span: Span) -> Result<(), FilterParseError> {
+    match height > MAX_HEIGHT {
+        true => Err(FilterParseError::new(
+            "filter has too many chained tests",
+            span,
+        )),
+        false => Ok(()),
+    }
+}
+
+fn unknown_field(name: &str, span: Span) -> FilterParseError {
+    let closest = FIELDS
+        .iter()
+        .map(|info| (edit_distance(name, info.name), info.name))
+        .min_by_key(|(distance, _)| *distance)
+        .filter(|(distance, _)| *distance <= 2);
+    let message = match closest {
+        Some((_, field)) => format!("unknown field `{name}`, did you mean `{field}`?"),
+        None => format!("unknown field `{name}`"),
+    };
+    FilterParseError::new(message, span)
+}
+
+/// Case insensitive Levenshtein distance, used to suggest field names.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.to_ascii_lowercase().into_bytes();
+    let b = b.to_ascii_lowercase().into_bytes();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == cb { diagonal } else { diagonal + 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::Field;
+
+    fn compare(source: &str) -> (FieldRef, CmpOp, Value) {
+        match parse(source).unwrap().kind {
+            ExprKind::Compare { field, op, value } => (field, op, value),
+            kind => panic!("expected a comparison, found {kind:?}"),
+        }
+    }
+
+    fn error(source: &str) -> (String, Span) {
+        let error = parse(source).unwrap_err();
+        (error.message, error.span)
+    }
+
+    fn same(source: &str, expected: &str) {
+        assert_eq!(parse(source).unwrap(), parse(expected).unwrap(), "{source}");
+    }
+
+    #[test]
+    fn values() {
+        let port = FieldRef::new(Field::TcpDstPort);
+        assert_eq!(
+            compare("tcp.DstPort == 80"),
+            (port.clone(), CmpOp::Eq, Value::Int(80))
+        );
+        assert_eq!(
+            compare("tcp.DstPort != 0x50"),
+            (port.clone(), CmpOp::Ne, Value::Int(80))
+        );
+        assert_eq!(
+            compare("tcp.DstPort >= -1"),
+            (port, CmpOp::Ge, Value::Int(-1))
+        );
+        assert_eq!(
+            compare("ip.DstAddr < 10.0.0.1"),
+            (
+                FieldRef::new(Field::IpDstAddr),
+                CmpOp::Lt,
+                Value::Ipv4(Ipv4Addr::new(10, 0, 0, 1))
+            )
+        );
+        assert_eq!(
+            compare("ipv6.SrcAddr <= ::1"),
+            (
+                FieldRef::new(Field::Ipv6SrcAddr),
+                CmpOp::Le,
+                Value::Ipv6(Ipv6Addr::LOCALHOST)
+            )
+        );
+        assert_eq!(
+            compare("layer > network"),
+            (
+                FieldRef::new(Field::Layer),
+                CmpOp::Gt,
+                Value::Symbol(Symbol::from_name("NETWORK").unwrap())
+            )
+        );
+        assert_eq!(parse("TRUE").unwrap().kind, ExprKind::Bool(true));
+        assert_eq!(
+            parse("tcp").unwrap().kind,
+            ExprKind::Field(FieldRef::new(Field::Tcp))
+        );
+    }
+
+    #[test]
+    fn indexes() {
+        assert_eq!(
+            compare("packet[-4] == 1").0,
+            FieldRef::indexed(Field::Packet, -4)
+        );
+        assert_eq!(
+            compare("packet32[0x10] == 1").0,
+            FieldRef::indexed(Field::Packet32, 64)
+        );
+        assert_eq!(
+            compare("packet16[ - 2 ] == 1").0,
+            FieldRef::indexed(Field::Packet16, -4)
+        );
+        assert_eq!(
+            compare("tcp.Payload32[6b] == 1").0,
+            FieldRef::indexed(Field::TcpPayload32, 6)
+        );
+        assert_eq!(
+            compare("udp.Payload16[-3b] == 1").0,
+            FieldRef::indexed(Field::UdpPayload16, -3)
+        );
+        assert_eq!(
+            compare("packet[65534b] == 1").0,
+            FieldRef::indexed(Field::Packet, 65534)
+        );
+
+        same("packet32[1] == 1", "packet32[4b] == 1");
+        assert_eq!(
+            parse("packet16[3b] == 1 and packet32[-2] == 1")
+                .unwrap()
+                .to_canonical_string(),
+            "packet16[3b] == 1 and packet32[-2] == 1"
+        );
+    }
+
+    #[test]
+    fn operators() {
+        same("tcp && udp || !icmp", "tcp and udp or not icmp");
+        same("tcp.DstPort = 80", "tcp.DstPort == 80");
+        same("tcp or udp and icmp", "tcp or (udp and icmp)");
+        same("tcp and udp or icmp", "(tcp and udp) or icmp");
+        same("not tcp and udp", "(not tcp) and udp");
+        same("!!tcp", "not (not tcp)");
+        same("tcp and udp and icmp", "(tcp and udp) and icmp");
+    }
+
+    #[test]
+    fn ternary_precedence() {
+        same("tcp or udp ? icmp : ip", "(tcp or udp) ? icmp : ip");
+        same(
+            "tcp ? udp : icmp ? ip : ipv6",
+            "tcp ? udp : (icmp ? ip : ipv6)",
+        );
+        same(
+            "tcp ? udp ? icmp : ip : ipv6",
+            "tcp ? (udp ? icmp : ip) : ipv6",
+        );
+        same(
+            "tcp ? udp or icmp : ip and ipv6",
+            "tcp ? (udp or icmp) : (ip and ipv6)",
+        );
+        same("tcp?udp:icmp", "tcp ? udp : icmp");
+    }
+
+    #[test]
+    fn spans() {
+        let expr = parse(" (tcp and udp) ").unwrap();
+        assert_eq!(expr.span, Span::new(1, 14));
+        let ExprKind::And(lhs, rhs) = expr.kind else {
+            panic!("expected a conjunction");
+        };
+        assert_eq!((lhs.span, rhs.span), (Span::new(2, 5), Span::new(10, 13)));
+        assert_eq!(parse("packet[-1] == 2").unwrap().span, Span::new(0, 15));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error(""), ("empty filter".into(), Span::new(0, 0)));
+        assert_eq!(
+            error("tcp.DstPort =="),
+            (
+                "expected value, found end of filter".into(),
+                Span::new(14, 14)
+            )
+        );
+        assert_eq!(
+            error("tcp.DstPrt == 80"),
+            (
+                "unknown field `tcp.DstPrt`, did you mean `tcp.DstPort`?".into(),
+                Span::new(0, 10)
+            )
+        );
+        assert_eq!(
+            error("tcp and (udp"),
+            (
+                "expected `)`, found end of filter".into(),
+                Span::new(12, 12)
+            )
+        );
+        assert_eq!(
+            error("tcp udp"),
+            ("unexpected `udp`".into(), Span::new(4, 7))
+        );
+        assert_eq!(
+            error("packet == 1"),
+            ("field `packet` requires an index".into(), Span::new(0, 6))
+        );
+        assert_eq!(
+            error("tcp[1]"),
+            ("field `tcp` can't be indexed".into(), Span::new(3, 4))
+        );
+        assert_eq!(
+            error("packet[4294967296] == 1"),
+            (
+                "index `4294967296` is out of range".into(),
+                Span::new(7, 17)
+            )
+        );
+        assert_eq!(
+            error("packet32[16384] == 1"),
+            ("index `65536` is out of range".into(), Span::new(9, 14))
+        );
+        assert_eq!(
+            error("packet16[65534b] == 1"),
+            ("index `65534` is out of range".into(), Span::new(9, 15))
+        );
+        assert_eq!(
+            error("packet16[2 b] == 1"),
+            ("expected `]`, found `b`".into(), Span::new(11, 12))
+        );
+        assert_eq!(
+            error("packet16[-1b] == 1"),
+            ("index `-1` is out of range".into(), Span::new(10, 12))
+        );
+        assert_eq!(
+            error("packet[2x] == 1"),
+            ("invalid number `2x`".into(), Span::new(7, 9))
+        );
+        assert_eq!(
+            error("tcp ? udp"),
+            ("expected `:`, found end of filter".into(), Span::new(9, 9))
+        );
+        assert_eq!(
+            error("layer == nowhere"),
+            ("unknown value `nowhere`".into(), Span::new(9, 16))
+        );
+        assert_eq!(
+            error("ip.DstAddr == -1.2.3.4"),
+            (
+                "expected value, found address `1.2.3.4`".into(),
+                Span::new(15, 22)
+            )
+        );
+    }
+
+    #[test]
+    fn nesting_limit() {
+        // The whole filter is the first level
+        let depth = MAX_DEPTH - 1;
+        let nested = format!("{}tcp{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse(&nested).is_ok());
+        let nested = format!("{}tcp{}", "(".repeat(depth + 1), ")".repeat(depth + 1));
+        assert_eq!(
+            parse(&nested).unwrap_err().message,
+            "filter is nested too deeply"
+        );
+        assert!(parse(&"not ".repeat(MAX_DEPTH + 1)).is_err());
+    }
+
+    #[test]
+    fn chain_limit() {
+        // Long chains used to build trees deep enough to overflow the stack of their consumers
+        for operator in [" and ", " or ", " && "] {
+            let chain = vec!["tcp"; 10_000].join(operator);
+            let error = parse(&chain).unwrap_err();
+            assert_eq!(error.message, "filter has too many chained tests");
+
+            let chain = vec!["tcp.DstPort == 1"; MAX_TESTS].join(operator);
+            assert!(parse(&chain).is_ok());
+        }
+        let error = parse(&vec!["tcp"; MAX_HEIGHT + 1].join(" or ")).unwrap_err();
+        assert_eq!(error.message, "filter has too many chained tests");
+        let offset = (MAX_HEIGHT - 1) * "tcp or ".len() + "tcp ".len();
+        assert_eq!(error.span, Span::new(offset, offset + 2));
+    }
+}
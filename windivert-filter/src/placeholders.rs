@@ -0,0 +1,155 @@
+use crate::{Expr, ExprKind, Span};
+
+/// Text parsed in place of a value placeholder, valid for every field that accepts placeholders.
+const VALUE: &str = "0";
+/// Text parsed in place of an expression placeholder.
+const BOOL: &str = "true";
+
+/**
+Filter string with its placeholders replaced by literals the parser accepts in the same position.
+
+Placeholders following a comparison operator are parsed as a value and the others as a boolean
+expression. Shared by the `$name` parameters of `FilterTemplate` and the `{}` placeholders of the
+`filter!` macro of `windivert-macros`.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placeholders {
+    parsed: String,
+    placeholders: Vec<Placeholder>,
+}
+
+/// Placeholder of a filter string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placeholder {
+    /// Span of the placeholder in the filter string.
+    pub span: Span,
+    /// Offset of the replacement in the parsed filter.
+    offset: usize,
+    /// Text parsed in place of the placeholder.
+    replacement: &'static str,
+}
+
+impl Placeholders {
+    /// Replaces the placeholders of `source` found at `spans`, in order.
+    pub fn new(source: &str, spans: impl IntoIterator<Item = Span>) -> Self {
+        let mut parsed = String::with_capacity(source.len());
+        let mut placeholders = Vec::new();
+        let mut end = 0;
+        for span in spans {
+            parsed.push_str(&source[end..span.start]);
+            let replacement = match parsed.trim_end().chars().last() {
+                Some('=' | '<' | '>') => VALUE,
+                _ => BOOL,
+            };
+            placeholders.push(Placeholder {
+                span,
+                offset: parsed.len(),
+                replacement,
+            });
+            parsed.push_str(replacement);
+            end = span.end;
+        }
+        parsed.push_str(&source[end..]);
+        Self {
+            parsed,
+            placeholders,
+        }
+    }
+
+    /// Filter string to parse.
+    pub fn parsed(&self) -> &str {
+        &self.parsed
+    }
+
+    /// Placeholders in order of appearance.
+    pub fn iter(&self) -> std::slice::Iter<'_, Placeholder> {
+        self.placeholders.iter()
+    }
+
+    /// Maps an offset of the parsed filter back to the filter string.
+    pub fn original_position(&self, offset: usize) -> usize {
+        let mut position = offset;
+        for placeholder in &self.placeholders {
+            let len = placeholder.span.end - placeholder.span.start;
+            if offset >= placeholder.offset + placeholder.replacement.len() {
+                position = position + len - placeholder.replacement.len();
+            } else if offset >= placeholder.offset {
+                return placeholder.span.start;
+            }
+        }
+        position
+    }
+
+    /// Maps a span of the parsed filter back to the filter string.
+    pub fn original_span(&self, span: Span) -> Span {
+        Span::new(
+            self.original_position(span.start),
+            self.original_position(span.end),
+        )
+    }
+}
+
+impl Placeholder {
+    /**
+    Comparison or constant holding the placeholder in `expr`, the parsed filter.
+
+    Returns `None` if the placeholder is neither the value of a comparison nor a whole expression.
+    */
+    pub fn leaf<'a>(&self, expr: &'a Expr) -> Option<&'a Expr> {
+        let leaf = leaf(expr, self.offset)?;
+        match (&leaf.kind, self.replacement) {
+            (ExprKind::Bool(_), BOOL) | (ExprKind::Compare { .. }, VALUE) => Some(leaf),
+            _ => None,
+        }
+    }
+}
+
+/// Comparison or constant of `expr` located at `offset`.
+fn leaf(expr: &Expr, offset: usize) -> Option<&Expr> {
+    let contains = |span: Span| span.start <= offset && offset < span.end;
+    match &expr.kind {
+        ExprKind::Bool(_) | ExprKind::Field(_) | ExprKind::Compare { .. } => {
+            contains(expr.span).then_some(expr)
+        }
+        ExprKind::Not(expr) => leaf(expr, offset),
+        ExprKind::And(lhs, rhs) | ExprKind::Or(lhs, rhs) => {
+            leaf(lhs, offset).or_else(|| leaf(rhs, offset))
+        }
+        ExprKind::Ternary {
+            cond,
+            then,
+            otherwise,
+        } => leaf(cond, offset)
+            .or_else(|| leaf(then, offset))
+            .or_else(|| leaf(otherwise, offset)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn placeholders() {
+        let source = "tcp.DstPort == {} and {} or udp";
+        let spans = source
+            .match_indices("{}")
+            .map(|(index, _)| Span::new(index, index + 2));
+        let placeholders = Placeholders::new(source, spans);
+        assert_eq!(placeholders.parsed(), "tcp.DstPort == 0 and true or udp");
+        assert_eq!(placeholders.original_position(15), 15);
+        // Offsets inside a replacement map to the start of the placeholder
+        assert_eq!(placeholders.original_position(22), 22);
+        assert_eq!(placeholders.original_position(23), 22);
+        // `udp`
+        assert_eq!(placeholders.original_position(29), 28);
+
+        let expr = parse(placeholders.parsed()).unwrap();
+        let leaves: Vec<_> = placeholders
+            .iter()
+            .map(|placeholder| placeholder.leaf(&expr).map(|leaf| leaf.span))
+            .collect();
+        assert_eq!(leaves, [Some(Span::new(0, 16)), Some(Span::new(21, 25))]);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::{
+    CmpOp, Expr, ExprKind, FieldRef, FieldType, FilterLayer, FilterParseError, IntRange, Span,
+    Symbol, Value,
+};
+
+impl Symbol {
+    /// Numeric value of the symbol when used on the given layer.
+    pub fn value(&self, layer: FilterLayer) -> i128 {
+        match self {
+            Symbol::False | Symbol::Packet | Symbol::Network => 0,
+            Symbol::True | Symbol::Icmp | Symbol::Established | Symbol::NetworkForward => 1,
+            Symbol::Deleted | Symbol::Flow => 2,
+            Symbol::Bind | Symbol::Socket => 3,
+            Symbol::Connect | Symbol::Reflect => 4,
+            Symbol::Listen => 5,
+            Symbol::Accept => 6,
+            Symbol::Tcp => 6,
+            Symbol::Close if matches!(layer, FilterLayer::Reflect) => 9,
+            Symbol::Close => 7,
+            Symbol::Open => 8,
+            Symbol::Udp => 17,
+            Symbol::Icmpv6 => 58,
+        }
+    }
+
+    /// Returns `true` if the symbol is an event that can happen on the provided layer.
+    fn is_event_of(&self, layer: FilterLayer) -> bool {
+        use FilterLayer as L;
+        use Symbol::*;
+        match layer {
+            L::Network | L::Forward => matches!(self, Packet),
+            L::Flow => matches!(self, Established | Deleted),
+            L::Socket => matches!(self, Bind | Connect | Listen | Accept | Close),
+            L::Reflect => matches!(self, Open | Close),
+        }
+    }
+
+    fn is_event(&self) -> bool {
+        use Symbol::*;
+        matches!(
+            self,
+            Packet | Established | Deleted | Bind | Connect | Listen | Accept | Close | Open
+        )
+    }
+
+    fn is_layer(&self) -> bool {
+        use Symbol::*;
+        matches!(self, Network | NetworkForward | Flow | Socket | Reflect)
+    }
+}
+
+/**
+Checks that every field and value of `expr`, parsed from `source`, is valid for `layer`.
+
+The error points to the offending token of `source`.
+*/
+pub fn validate(source: &str, expr: &Expr, layer: FilterLayer) -> Result<(), FilterParseError> {
+    Validator { source, layer }.expr(expr)
+}
+
+struct Validator<'a> {
+    source: &'a str,
+    layer: FilterLayer,
+}
+
+impl Validator<'_> {
+    fn expr(&self, expr: &Expr) -> Result<(), FilterParseError> {
+        match &expr.kind {
+            ExprKind::Bool(_) => Ok(()),
+            ExprKind::Field(field) => validate_field(field, self.layer),
+            ExprKind::Compare { field, op, value } => {
+                validate_field(field, self.layer)?;
+                validate_value(field, *op, value, self.value_span(field, expr), self.layer)
+            }
+            ExprKind::Not(expr) => self.expr(expr),
+            ExprKind::And(lhs, rhs) | ExprKind::Or(lhs, rhs) => {
+                self.expr(lhs)?;
+                self.expr(rhs)
+            }
+            ExprKind::Ternary {
+                cond,
+                then,
+                otherwise,
+            } => {
+                self.expr(cond)?;
+                self.expr(then)?;
+                self.expr(otherwise)
+            }
+        }
+    }
+
+    /// Location of the value of a comparison, which follows the field and the operator.
+    fn value_span(&self, field: &FieldRef, compare: &Expr) -> Span {
+        let end = compare.span.end;
+        let start = self
+            .source
+            .get(field.span.end..end)
+            .and_then(|text| {
+                text.find(|c: char| !c.is_whitespace() && !"=!<>".contains(c))
+                    .map(|offset| field.span.end + offset)
+            })
+            .unwrap_or(compare.span.start);
+        Span::new(start, end)
+    }
+}
+
+/// Checks that `field` is available on `layer`.
+pub fn validate_field(field: &FieldRef, layer: FilterLayer) -> Result<(), FilterParseError> {
+    if field.field.info().available_on(layer) {
+        Ok(())
+    } else {
+        Err(error(
+            format!(
+                "field `{}` is not available on the {} layer",
+                field.field,
+                layer.name()
+            ),
+            field.span,
+        ))
+    }
+}
+
+/// Checks that `value` can be compared with `field` on `layer`, `value_span` locates the value.
+pub fn validate_value(
+    field: &FieldRef,
+    op: CmpOp,
+    value: &Value,
+    value_span: Span,
+    layer: FilterLayer,
+) -> Result<(), FilterParseError> {
+    let ty = field.field.field_type();
+    let mismatch = || {
+        error(
+            format!("`{value}` is not a valid value for field `{}`", field.field),
+            value_span,
+        )
+    };
+
+    let int = match (value, ty) {
+        (Value::Ipv4(_), FieldType::Ipv4 | FieldType::IpAddr) => return Ok(()),
+        (Value::Ipv6(_), FieldType::Ipv6 | FieldType::IpAddr) => return Ok(()),
+        (Value::Ipv4(_) | Value::Ipv6(_), _) => return Err(mismatch()),
+        (Value::Symbol(symbol), FieldType::Event) if symbol.is_event() => {
+            return if symbol.is_event_of(layer) {
+                Ok(())
+            } else {
+                Err(error(
+                    format!(
+                        "event `{symbol}` is not available on the {} layer",
+                        layer.name()
+                    ),
+                    value_span,
+                ))
+            };
+        }
+        (Value::Symbol(symbol), FieldType::Layer) if symbol.is_layer() => return Ok(()),
+        (Value::Symbol(symbol), _) if symbol.is_event() || symbol.is_layer() => {
+            return Err(mismatch())
+        }
+        (Value::Symbol(symbol), _) => symbol.value(layer),
+        (Value::Int(value), _) => *value,
+    };
+
+    let range = ty.int_range();
+    // Ordering comparisons against values just outside of the range are still meaningful
+    let range = match op {
+        CmpOp::Eq | CmpOp::Ne => range,
+        _ => IntRange {
+            min: range.min - 1,
+            max: range.max.saturating_add(1),
+        },
+    };
+    if !range.contains(int) {
+        return Err(error(
+            format!("`{value}` is out of range for field `{}`", field.field),
+            value_span,
+        ));
+    }
+    Ok(())
+}
+
+fn error(message: String, span: Span) -> FilterParseError {
+    FilterParseError::new(message, span)
+}
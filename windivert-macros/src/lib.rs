@@ -0,0 +1,339 @@
+#![deny(missing_docs)]
+/*!
+Compile time checked filters for the [`windivert`] crate.
+
+The [`filter!`] macro parses and validates a filter while the crate is being built, so typos in
+static filters fail `cargo build` instead of failing when opening a handle.
+
+[`windivert`]: https://docs.rs/windivert
+*/
+
+use std::{fmt, ptr};
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, Ident, LitStr, Token,
+};
+use windivert_filter::{
+    parse, validate, Expr, ExprKind, Field, FieldRef, FieldType, FilterLayer, FilterParseError,
+    Placeholders, Span, Value,
+};
+
+/**
+Checks a filter at compile time and evaluates to a [`Filter`].
+
+The first argument is the layer the filter is validated against: `network`, `forward`, `flow`,
+`socket` or `reflect`. Invalid filters, fields not available on the layer and out of range values
+are reported as compile errors.
+
+`{}` placeholders are replaced with the following arguments, in order. A placeholder compared
+against a field must have the type of the field, like `u16` for `tcp.DstPort`, `Ipv4Addr` for
+`ip.SrcAddr` or `bool` for flags such as `tcp.Syn`. A placeholder used as an expression on its own
+must be a `bool`. For instance `filter!(network, "tcp.DstPort == {} and {}", port, outbound_only)`
+requires `port: u16` and `outbound_only: bool`.
+
+The macro expands to the construction of the checked expression tree, with the arguments inserted
+in place of the placeholders, so the filter is never parsed at runtime. The [`Filter`] is built
+from the tree with `Filter::from()`, so its string is the canonical form of the filter, e.g.
+`tcp.DstPort == 443` for `"TCP.DstPort = 0x1bb"`.
+
+[`Filter`]: https://docs.rs/windivert/latest/windivert/filter/struct.Filter.html
+*/
+#[proc_macro]
+pub fn filter(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as Input);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+struct Input {
+    layer: Ident,
+    filter: LitStr,
+    args: Vec<syn::Expr>,
+}
+
+impl Parse for Input {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let layer = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let filter = input.parse()?;
+        let mut args = Vec::new();
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            args.push(input.parse()?);
+        }
+        Ok(Self {
+            layer,
+            filter,
+            args,
+        })
+    }
+}
+
+fn expand(input: Input) -> syn::Result<TokenStream2> {
+    let layer = layer(&input.layer)?;
+    let source = input.filter.value();
+    let spans = source
+        .match_indices("{}")
+        .map(|(index, _)| Span::new(index, index + 2));
+    let placeholders = Placeholders::new(&source, spans);
+    if placeholders.iter().len() != input.args.len() {
+        return Err(syn::Error::new_spanned(
+            &input.filter,
+            format!(
+                "expected {} arguments for the placeholders of the filter, found {}",
+                placeholders.iter().len(),
+                input.args.len()
+            ),
+        ));
+    }
+
+    let error = |error: FilterParseError| {
+        syn::Error::new_spanned(
+            &input.filter,
+            format!(
+                "invalid filter: {} (at position {})",
+                error.message,
+                placeholders.original_position(error.span.start)
+            ),
+        )
+    };
+    let expr = parse(placeholders.parsed()).map_err(error)?;
+    validate(placeholders.parsed(), &expr, layer).map_err(error)?;
+
+    if placeholders.iter().len() == 0 {
+        // Spans point into the canonical form, which is the string of the built filter
+        let canonical = parse(&expr.to_canonical_string()).map_err(error)?;
+        let expr = Tree::default().expr(&canonical);
+        return Ok(quote! { ::windivert::filter::Filter::from(#expr) });
+    }
+
+    let mut tree = Tree {
+        args: Vec::new(),
+        spans: false,
+    };
+    for (placeholder, arg) in placeholders.iter().zip(&input.args) {
+        let invalid = |message: String| {
+            syn::Error::new_spanned(
+                &input.filter,
+                format!(
+                    "placeholder at position {}: {message}",
+                    placeholder.span.start
+                ),
+            )
+        };
+        let leaf = placeholder.leaf(&expr).ok_or_else(|| {
+            invalid("placeholder must be a comparison value or a whole expression".to_owned())
+        })?;
+        let kind = match &leaf.kind {
+            ExprKind::Compare { field, op, .. } => {
+                let value = value(field.field, arg).map_err(invalid)?;
+                let field = field_ref(field, false);
+                let op = variant(op);
+                quote! {
+                    ::windivert::filter::ExprKind::Compare {
+                        field: #field,
+                        op: ::windivert::filter::CmpOp::#op,
+                        value: #value,
+                    }
+                }
+            }
+            _ => quote! { ::windivert::filter::ExprKind::Bool({ let value: bool = #arg; value }) },
+        };
+        tree.args.push((leaf, kind));
+    }
+    let expr = tree.expr(&expr);
+    Ok(quote! { ::windivert::filter::Filter::from(#expr) })
+}
+
+fn layer(layer: &Ident) -> syn::Result<FilterLayer> {
+    let name = layer.to_string();
+    FilterLayer::from_name(&name).ok_or_else(|| {
+        syn::Error::new_spanned(
+            layer,
+            format!("unknown layer `{name}`, expected network, forward, flow, socket or reflect"),
+        )
+    })
+}
+
+/// Code building an expression tree, with the placeholders replaced by their arguments.
+struct Tree<'a> {
+    /// Placeholder leaves of the tree and the code building their replacement.
+    args: Vec<(&'a Expr, TokenStream2)>,
+    /// Keeps the spans of the tree, which are only meaningful for the canonical form.
+    spans: bool,
+}
+
+impl Default for Tree<'_> {
+    fn default() -> Self {
+        Self {
+            args: Vec::new(),
+            spans: true,
+        }
+    }
+}
+
+impl Tree<'_> {
+    fn expr(&self, expr: &Expr) -> TokenStream2 {
+        let kind = match self.args.iter().find(|(leaf, _)| ptr::eq(*leaf, expr)) {
+            Some((_, kind)) => kind.clone(),
+            None => self.kind(&expr.kind),
+        };
+        let span = span(expr.span, self.spans);
+        quote! { ::windivert::filter::Expr::with_span(#kind, #span) }
+    }
+
+    fn kind(&self, kind: &ExprKind) -> TokenStream2 {
+        match kind {
+            ExprKind::Bool(value) => quote! { ::windivert::filter::ExprKind::Bool(#value) },
+            ExprKind::Field(field) => {
+                let field = field_ref(field, self.spans);
+                quote! { ::windivert::filter::ExprKind::Field(#field) }
+            }
+            ExprKind::Compare { field, op, value } => {
+                let field = field_ref(field, self.spans);
+                let op = variant(op);
+                let value = literal(value);
+                quote! {
+                    ::windivert::filter::ExprKind::Compare {
+                        field: #field,
+                        op: ::windivert::filter::CmpOp::#op,
+                        value: #value,
+                    }
+                }
+            }
+            ExprKind::Not(expr) => {
+                let expr = self.expr(expr);
+                quote! { ::windivert::filter::ExprKind::Not(::std::boxed::Box::new(#expr)) }
+            }
+            ExprKind::And(lhs, rhs) | ExprKind::Or(lhs, rhs) => {
+                let name = match kind {
+                    ExprKind::And(..) => format_ident!("And"),
+                    _ => format_ident!("Or"),
+                };
+                let (lhs, rhs) = (self.expr(lhs), self.expr(rhs));
+                quote! {
+                    ::windivert::filter::ExprKind::#name(
+                        ::std::boxed::Box::new(#lhs),
+                        ::std::boxed::Box::new(#rhs),
+                    )
+                }
+            }
+            ExprKind::Ternary {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let (cond, then, otherwise) =
+                    (self.expr(cond), self.expr(then), self.expr(otherwise));
+                quote! {
+                    ::windivert::filter::ExprKind::Ternary {
+                        cond: ::std::boxed::Box::new(#cond),
+                        then: ::std::boxed::Box::new(#then),
+                        otherwise: ::std::boxed::Box::new(#otherwise),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn span(span: Span, keep: bool) -> TokenStream2 {
+    if keep {
+        let (start, end) = (span.start, span.end);
+        quote! { ::windivert::filter::Span::new(#start, #end) }
+    } else {
+        quote! { ::windivert::filter::Span::new(0, 0) }
+    }
+}
+
+fn field_ref(field: &FieldRef, spans: bool) -> TokenStream2 {
+    let name = variant(&field.field);
+    let index = match field.index {
+        Some(index) => quote! { ::std::option::Option::Some(#index) },
+        None => quote! { ::std::option::Option::None },
+    };
+    let span = span(field.span, spans);
+    quote! {
+        ::windivert::filter::FieldRef {
+            field: ::windivert::filter::Field::#name,
+            index: #index,
+            span: #span,
+        }
+    }
+}
+
+/// Identifier of the variant `value`.
+fn variant(value: &impl fmt::Debug) -> Ident {
+    format_ident!("{}", format!("{value:?}"))
+}
+
+/// Constant value of the filter string.
+fn literal(value: &Value) -> TokenStream2 {
+    match value {
+        Value::Int(value) => quote! { ::windivert::filter::Value::Int(#value) },
+        Value::Ipv4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            quote! {
+                ::windivert::filter::Value::Ipv4(::std::net::Ipv4Addr::new(#a, #b, #c, #d))
+            }
+        }
+        Value::Ipv6(addr) => {
+            let bits = u128::from(*addr);
+            quote! { ::windivert::filter::Value::Ipv6(::std::net::Ipv6Addr::from(#bits)) }
+        }
+        Value::Symbol(symbol) => {
+            let name = variant(symbol);
+            quote! { ::windivert::filter::Value::Symbol(::windivert::filter::Symbol::#name) }
+        }
+    }
+}
+
+/// Argument converted to a value of `field`, checking that it has the type of the field.
+fn value(field: Field, arg: &syn::Expr) -> Result<TokenStream2, String> {
+    let ty = match field.field_type() {
+        FieldType::Bool => quote! { bool },
+        FieldType::U8 | FieldType::IcmpType => quote! { u8 },
+        FieldType::U16 => quote! { u16 },
+        FieldType::U32 => quote! { u32 },
+        FieldType::U64 => quote! { u64 },
+        FieldType::I16 => quote! { i16 },
+        FieldType::I64 => quote! { i64 },
+        FieldType::Ipv4 => {
+            return Ok(quote! {
+                ::windivert::filter::Value::Ipv4({ let value: ::std::net::Ipv4Addr = #arg; value })
+            })
+        }
+        FieldType::Ipv6 => {
+            return Ok(quote! {
+                ::windivert::filter::Value::Ipv6({ let value: ::std::net::Ipv6Addr = #arg; value })
+            })
+        }
+        FieldType::IpAddr => {
+            return Ok(quote! {
+                match ::std::convert::Into::<::std::net::IpAddr>::into(#arg) {
+                    ::std::net::IpAddr::V4(addr) => ::windivert::filter::Value::Ipv4(addr),
+                    ::std::net::IpAddr::V6(addr) => ::windivert::filter::Value::Ipv6(addr),
+                }
+            })
+        }
+        FieldType::Event | FieldType::Layer => {
+            return Err(format!(
+                "placeholders are not supported for field `{field}`"
+            ))
+        }
+    };
+    Ok(quote! {
+        ::windivert::filter::Value::Int(::std::convert::From::from({
+            let value: #ty = #arg;
+            value
+        }))
+    })
+}
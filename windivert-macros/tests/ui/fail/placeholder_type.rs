@@ -0,0 +1,6 @@
+use windivert_macros::filter;
+
+fn main() {
+    let port: u32 = 443;
+    filter!(network, "tcp.DstPort == {}", port);
+}
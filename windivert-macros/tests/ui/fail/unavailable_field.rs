@@ -0,0 +1,5 @@
+use windivert_macros::filter;
+
+fn main() {
+    filter!(network, "tcp and processId == 4");
+}
@@ -0,0 +1,24 @@
+use windivert::{filter::Filter, prelude::WinDivertLayer};
+use windivert_macros::filter;
+
+fn main() {
+    // Filters are built in canonical form
+    let filter = filter!(network, "TCP.dstport = 0x1bb && (ip.DstAddr >= 10.0.0.0 || !udp)");
+    assert_eq!(
+        filter.as_str(),
+        "tcp.DstPort == 443 and (ip.DstAddr >= 10.0.0.0 or not udp)"
+    );
+    assert_eq!(filter, Filter::parse(filter.as_str()).unwrap());
+
+    // Spans point into the canonical form
+    let filter = filter!(socket, "event == connect and remoteAddr == 2001:DB8::1");
+    assert_eq!(
+        filter.as_str(),
+        "event == CONNECT and remoteAddr == 2001:db8::1"
+    );
+    let error = filter.validate(WinDivertLayer::Flow).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Invalid filter: event `CONNECT` is not available on the flow layer (at position 9)"
+    );
+}
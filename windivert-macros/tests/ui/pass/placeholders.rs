@@ -0,0 +1,38 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use windivert::filter::Filter;
+use windivert_macros::filter;
+
+fn main() {
+    let port: u16 = 443;
+    let source = Ipv4Addr::new(10, 0, 0, 1);
+    let filter = filter!(
+        network,
+        "tcp.DstPort == {} and ip.SrcAddr == {} and {}",
+        port,
+        source,
+        true
+    );
+    assert_eq!(
+        filter.as_str(),
+        "tcp.DstPort == 443 and ip.SrcAddr == 10.0.0.1 and true"
+    );
+    let filter = filter!(flow, "processId == 4 and not loopback");
+    assert_eq!(filter.as_str(), "processId == 4 and not loopback");
+
+    // Arguments are inserted in the tree, whatever the type of the field
+    let remote: IpAddr = Ipv6Addr::LOCALHOST.into();
+    let filter = filter!(
+        socket,
+        "remoteAddr == {} ? localPort < {} : loopback == {}",
+        remote,
+        1024u16,
+        false
+    );
+    assert_eq!(
+        filter,
+        Filter::parse("remoteAddr == ::1 ? localPort < 1024 : loopback == 0").unwrap()
+    );
+    let filter = filter!(network, "tcp.Payload[-2b] == {} or {}", 0x16u8, false);
+    assert_eq!(filter.as_str(), "tcp.Payload[-2] == 22 or false");
+}
@@ -52,7 +52,7 @@ pub fn dll() {
     let mut strip = Command::new(strip);
     strip.stdout(Stdio::inherit()).stderr(Stdio::inherit());
 
-    strip.arg(&format!("{out_dir}/WinDivert.dll"));
+    strip.arg(format!("{out_dir}/WinDivert.dll"));
     let _ = strip.output().expect("Error striping windivert dll");
 
     let dlltool = Build::new()
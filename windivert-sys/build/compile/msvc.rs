@@ -32,11 +32,11 @@ pub fn dll() {
         compiler.arg(flag);
     }
 
-    compiler.arg(&format!("/MACHINE:{arch}"));
+    compiler.arg(format!("/MACHINE:{arch}"));
 
-    compiler.arg(&format!(r#"/PDB:{out_dir}\WinDivertDll.pdb"#));
-    compiler.arg(&format!(r#"/OUT:{out_dir}\WinDivert.dll"#));
-    compiler.arg(&format!(r#"/IMPLIB:{out_dir}\WinDivert.lib"#));
+    compiler.arg(format!(r#"/PDB:{out_dir}\WinDivertDll.pdb"#));
+    compiler.arg(format!(r#"/OUT:{out_dir}\WinDivert.dll"#));
+    compiler.arg(format!(r#"/IMPLIB:{out_dir}\WinDivert.lib"#));
 
     if let Ok(out) = compiler.output() {
         if !out.status.success() {
@@ -12,6 +12,11 @@ fn main() {
         return;
     }
 
+    // Only windows targets link against the WinDivert library
+    if env::var("CARGO_CFG_TARGET_OS").unwrap() != "windows" {
+        return;
+    }
+
     let out_dir = env::var("OUT_DIR").unwrap();
     println!("cargo:rerun-if-env-changed={LIB_PATH_ARG}");
     println!("cargo:rerun-if-env-changed={DLL_OUTPUT_PATH_ARG}");
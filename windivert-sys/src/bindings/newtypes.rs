@@ -1,4 +1,4 @@
-use std::{convert::TryFrom, u32};
+use std::convert::TryFrom;
 
 use super::WinDivertValueError;
 
@@ -262,6 +262,7 @@ Different flags affect how the opened handle behaves. The following flags are su
  * `send_only`: This flags forces the handle into send only mode which effectively disables [`recv()`](fn@super::WinDivertRecv) (and any of it's variants). This means that it is possible to inject packets or events, but not block/capture them.
  * `no_installs`: This flags causes [`WinDivertOpen`](fn@super::WinDivertOpen) to fail with ERROR_SERVICE_DOES_NOT_EXIST (1060) if the WinDivert driver is not already installed. This flag is useful for querying the WinDivert driver state using [`Reflect`](super::WinDivertLayer::Reflect) layer.
  * `fragments`: If set, the handle will capture inbound IP fragments, but not inbound reassembled IP packets. Otherwise, if not set (the default), the handle will capture inbound reassembled IP packets, but not inbound IP fragments. This flag only affects inbound packets at the [`Network`](super::WinDivertLayer::Network) layer, else the flag is ignored.
+
 Note that any combination of (`snif` | `drop`) or (`recv_only` | `send_only`) are considered invalid.
 
 Some layers have mandatory flags:
@@ -423,6 +424,11 @@ impl ChecksumFlags {
         self
     }
 
+    /// Returns `true` if the `no_ip` flag is set.
+    pub const fn no_ip(&self) -> bool {
+        self.0 & 0x0001 != 0
+    }
+
     /// Sets `no_ip` flag to `value`.
     pub fn set_no_ip_value(&mut self, value: bool) {
         self.0 = (self.0 & !0x0001) | (value as u64);
@@ -440,6 +446,11 @@ impl ChecksumFlags {
         self
     }
 
+    /// Returns `true` if the `no_icmp` flag is set.
+    pub const fn no_icmp(&self) -> bool {
+        self.0 & 0x0002 != 0
+    }
+
     /// Sets `no_icmp` flag to `value`.
     pub fn set_no_icmp_value(&mut self, value: bool) {
         self.0 = (self.0 & !0x0002) | ((value as u64) << 1);
@@ -457,6 +468,11 @@ impl ChecksumFlags {
         self
     }
 
+    /// Returns `true` if the `no_icmpv6` flag is set.
+    pub const fn no_icmpv6(&self) -> bool {
+        self.0 & 0x0004 != 0
+    }
+
     /// Sets `no_icmpv6` flag to `value`.
     pub fn set_no_icmpv6_value(&mut self, value: bool) {
         self.0 = (self.0 & !0x0004) | ((value as u64) << 2);
@@ -474,6 +490,11 @@ impl ChecksumFlags {
         self
     }
 
+    /// Returns `true` if the `no_tcp` flag is set.
+    pub const fn no_tcp(&self) -> bool {
+        self.0 & 0x0008 != 0
+    }
+
     /// Sets `no_tcp` flag to `value`.
     pub fn set_no_tcp_value(&mut self, value: bool) {
         self.0 = (self.0 & !0x0008) | ((value as u64) << 3);
@@ -491,6 +512,11 @@ impl ChecksumFlags {
         self
     }
 
+    /// Returns `true` if the `no_udp` flag is set.
+    pub const fn no_udp(&self) -> bool {
+        self.0 & 0x0010 != 0
+    }
+
     /// Sets `no_udp` flag to `value`.
     pub fn set_no_udp_value(&mut self, value: bool) {
         self.0 = (self.0 & !0x0010) | ((value as u64) << 4);
@@ -7,8 +7,6 @@ For more information, refer to [WinDivert's documentation].
 [WinDivert's documentation]: https://www.reqrypt.org/windivert-doc.html
 */
 #[warn(missing_docs)]
-#[cfg(target_os = "windows")]
 mod bindings;
 
-#[cfg(target_os = "windows")]
 pub use bindings::*;
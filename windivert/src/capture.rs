@@ -0,0 +1,690 @@
+/*!
+Readers for [pcap] and [pcapng] capture files.
+
+Captured frames are stripped of their link layer header so they can be handled like packets
+received on the network layer. Frames that don't carry an IPv4 or IPv6 packet are skipped.
+
+[pcap]: https://www.tcpdump.org/manpages/pcap-savefile.5.html
+[pcapng]: https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-00.html
+*/
+
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+    time::Duration,
+};
+
+use windivert_sys::{address::WINDIVERT_ADDRESS, WinDivertEvent, WinDivertLayer};
+
+use crate::{
+    address::WinDivertAddress, error::CaptureError, layer::NetworkLayer, packet::WinDivertPacket,
+};
+
+const PCAP_MICROS: u32 = 0xA1B2_C3D4;
+const PCAP_NANOS: u32 = 0xA1B2_3C4D;
+const PCAPNG_SECTION: u32 = 0x0A0D_0D0A;
+const PCAPNG_BYTE_ORDER: u32 = 0x1A2B_3C4D;
+
+const PCAPNG_INTERFACE: u32 = 1;
+const PCAPNG_PACKET: u32 = 2;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+
+/// `if_tsresol` interface option.
+const PCAPNG_TSRESOL: u16 = 9;
+/// `epb_flags` packet option.
+const PCAPNG_FLAGS: u16 = 2;
+
+/// Largest block or record accepted, to avoid huge allocations on corrupted files.
+const MAX_BLOCK_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Packet read from a capture file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedPacket {
+    /// Position of the frame in the file, starting at 0 and counting skipped frames.
+    pub frame: usize,
+    /// Capture time, relative to the unix epoch.
+    pub timestamp: Duration,
+    /// Length of the frame on the wire, including the link layer header.
+    pub original_len: usize,
+    /// IPv4 or IPv6 packet, truncated if the capture snapshot length was shorter than the frame.
+    pub data: Vec<u8>,
+    /// Direction of the packet, `None` if the capture doesn't record it.
+    pub outbound: Option<bool>,
+}
+
+impl CapturedPacket {
+    /// Returns `true` if the packet is an IPv6 packet.
+    pub fn ipv6(&self) -> bool {
+        self.data.first().map_or(false, |byte| byte >> 4 == 6)
+    }
+
+    /**
+    Converts the packet into a network layer packet.
+
+    Captures don't record the fields of the WinDivert address, so the address only sets the
+    layer, the event, the IP version and the direction, leaving other fields zeroed. Packets whose
+    direction isn't recorded are marked inbound.
+    */
+    pub fn into_packet(self) -> WinDivertPacket<'static, NetworkLayer> {
+        let mut address = WINDIVERT_ADDRESS::default();
+        address.set_layer(WinDivertLayer::Network);
+        address.set_event(WinDivertEvent::NetworkPacket);
+        address.set_ipv6(self.ipv6());
+        address.set_outbound(self.outbound.unwrap_or(false));
+        WinDivertPacket {
+            address: WinDivertAddress::from_raw(address),
+            data: Cow::Owned(self.data),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Pcap { link_type: u32, nanos: bool },
+    Pcapng,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+    link_type: u32,
+    snap_len: usize,
+    resolution: Resolution,
+}
+
+/// Units of the pcapng timestamps of an interface.
+#[derive(Debug, Clone, Copy)]
+enum Resolution {
+    Decimal(u32),
+    Binary(u32),
+}
+
+impl Resolution {
+    fn duration(self, ticks: u64) -> Duration {
+        match self {
+            Resolution::Decimal(exponent) => {
+                let units = 10u64.pow(exponent);
+                let nanos = u128::from(ticks % units) * 1_000_000_000 / u128::from(units);
+                Duration::new(ticks / units, nanos as u32)
+            }
+            Resolution::Binary(exponent) => {
+                let fraction = ticks & ((1 << exponent) - 1);
+                let nanos = (u128::from(fraction) * 1_000_000_000) >> exponent;
+                Duration::new(ticks >> exponent, nanos as u32)
+            }
+        }
+    }
+}
+
+/**
+Reader of pcap and pcapng capture files.
+
+The format is detected from the first bytes of the file. Packets are read with the [`Iterator`]
+implementation, which stops after the first error.
+*/
+#[derive(Debug)]
+pub struct CaptureReader<R: Read> {
+    reader: R,
+    format: Format,
+    big_endian: bool,
+    interfaces: Vec<Interface>,
+    frames: usize,
+    skipped: usize,
+    done: bool,
+}
+
+impl CaptureReader<BufReader<File>> {
+    /// Opens a capture file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, CaptureError> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Creates a reader, reading the file header from `reader`.
+    pub fn new(mut reader: R) -> Result<Self, CaptureError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        let mut capture = Self {
+            reader,
+            format: Format::Pcapng,
+            big_endian: false,
+            interfaces: Vec::new(),
+            frames: 0,
+            skipped: 0,
+            done: false,
+        };
+        let (big, little) = (u32::from_be_bytes(magic), u32::from_le_bytes(magic));
+        if big == PCAPNG_SECTION {
+            capture.section()?;
+            return Ok(capture);
+        }
+        let (magic, big_endian) = match [big, little] {
+            [PCAP_MICROS | PCAP_NANOS, _] => (big, true),
+            [_, PCAP_MICROS | PCAP_NANOS] => (little, false),
+            _ => return Err(CaptureError::UnknownFormat),
+        };
+        capture.big_endian = big_endian;
+        let mut header = [0; 20];
+        capture.reader.read_exact(&mut header)?;
+        let link_type = capture.u32(&header[16..]) & 0xFFFF;
+        if !supported(link_type) {
+            return Err(CaptureError::UnsupportedLinkType(link_type));
+        }
+        capture.format = Format::Pcap {
+            link_type,
+            nanos: magic == PCAP_NANOS,
+        };
+        Ok(capture)
+    }
+
+    /// Number of frames skipped so far because they didn't carry an IP packet.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Reads the next packet, returns `None` at the end of the file.
+    fn read(&mut self) -> Result<Option<CapturedPacket>, CaptureError> {
+        loop {
+            let frame = match self.format {
+                Format::Pcap { link_type, nanos } => self.record(link_type, nanos)?,
+                Format::Pcapng => self.block()?,
+            };
+            let Some((link_type, mut packet)) = frame else {
+                return Ok(None);
+            };
+            packet.frame = self.frames;
+            self.frames += 1;
+            match network(link_type, &packet.data) {
+                Some(range) => {
+                    packet.outbound = packet
+                        .outbound
+                        .or_else(|| direction(link_type, &packet.data));
+                    packet.data.truncate(range.end);
+                    packet.data.drain(..range.start);
+                    return Ok(Some(packet));
+                }
+                None => self.skipped += 1,
+            }
+        }
+    }
+
+    /// Reads a pcap record.
+    fn record(
+        &mut self,
+        link_type: u32,
+        nanos: bool,
+    ) -> Result<Option<(u32, CapturedPacket)>, CaptureError> {
+        let mut header = [0; 16];
+        if !self.fill(&mut header)? {
+            return Ok(None);
+        }
+        let fraction = self.u32(&header[4..]);
+        let nanos = match nanos {
+            true => fraction,
+            false => fraction.saturating_mul(1000),
+        };
+        if nanos >= 1_000_000_000 {
+            return Err(CaptureError::Malformed("invalid record timestamp"));
+        }
+        let timestamp = Duration::new(self.u32(&header[..]).into(), nanos);
+        let data = self.bytes(self.u32(&header[8..]) as usize)?;
+        let packet = CapturedPacket {
+            frame: 0,
+            timestamp,
+            original_len: self.u32(&header[12..]) as usize,
+            data,
+            outbound: None,
+        };
+        Ok(Some((link_type, packet)))
+    }
+
+    /// Reads pcapng blocks until a packet block is found.
+    fn block(&mut self) -> Result<Option<(u32, CapturedPacket)>, CaptureError> {
+        loop {
+            let mut header = [0; 8];
+            if !self.fill(&mut header)? {
+                return Ok(None);
+            }
+            // The byte order of a section header is only known after reading its body
+            if u32::from_be_bytes([header[0], header[1], header[2], header[3]]) == PCAPNG_SECTION {
+                self.section_body(&header[4..])?;
+                continue;
+            }
+            let length = self.u32(&header[4..]) as usize;
+            if length < 12 || length % 4 != 0 {
+                return Err(CaptureError::Malformed("invalid block length"));
+            }
+            let body = self.bytes(length - 8)?;
+            let body = &body[..body.len() - 4];
+            let packet = match self.u32(&header[..]) {
+                PCAPNG_INTERFACE => {
+                    self.interface(body)?;
+                    continue;
+                }
+                PCAPNG_ENHANCED_PACKET => self.packet(body, self.u32(body) as usize, 4)?,
+                PCAPNG_PACKET => self.packet(body, self.u16(body).into(), 4)?,
+                PCAPNG_SIMPLE_PACKET => {
+                    let interface = *self
+                        .interfaces
+                        .first()
+                        .ok_or(CaptureError::Malformed("packet of an undefined interface"))?;
+                    let original_len = self.u32(field(body, 0, 4)?) as usize;
+                    let captured = original_len.min(interface.snap_len).min(body.len() - 4);
+                    let packet = CapturedPacket {
+                        frame: 0,
+                        timestamp: Duration::ZERO,
+                        original_len,
+                        data: body[4..4 + captured].to_vec(),
+                        outbound: None,
+                    };
+                    (interface.link_type, packet)
+                }
+                _ => continue,
+            };
+            return Ok(Some(packet));
+        }
+    }
+
+    /// Reads a section header whose block type was read as the file magic.
+    fn section(&mut self) -> Result<(), CaptureError> {
+        let mut length = [0; 4];
+        self.reader.read_exact(&mut length)?;
+        self.section_body(&length)
+    }
+
+    fn section_body(&mut self, length: &[u8]) -> Result<(), CaptureError> {
+        let mut magic = [0; 4];
+        self.reader.read_exact(&mut magic)?;
+        self.big_endian = if u32::from_be_bytes(magic) == PCAPNG_BYTE_ORDER {
+            true
+        } else if u32::from_le_bytes(magic) == PCAPNG_BYTE_ORDER {
+            false
+        } else {
+            return Err(CaptureError::Malformed("invalid section byte order"));
+        };
+        let length = self.u32(length) as usize;
+        if length < 28 || length % 4 != 0 {
+            return Err(CaptureError::Malformed("invalid block length"));
+        }
+        self.bytes(length - 12)?;
+        self.interfaces.clear();
+        Ok(())
+    }
+
+    /// Reads the body of an interface description block.
+    fn interface(&mut self, body: &[u8]) -> Result<(), CaptureError> {
+        let link_type = self.u16(field(body, 0, 2)?).into();
+        let snap_len = match self.u32(field(body, 4, 4)?) {
+            0 => usize::MAX,
+            snap_len => snap_len as usize,
+        };
+        let resolution = match self.option(&body[8..], PCAPNG_TSRESOL)? {
+            Some(&[value]) => match value & 0x80 {
+                0 if value <= 19 => Resolution::Decimal(value.into()),
+                0x80 if value & 0x7F <= 63 => Resolution::Binary((value & 0x7F).into()),
+                _ => return Err(CaptureError::Malformed("invalid timestamp resolution")),
+            },
+            _ => Resolution::Decimal(6),
+        };
+        self.interfaces.push(Interface {
+            link_type,
+            snap_len,
+            resolution,
+        });
+        Ok(())
+    }
+
+    /// Reads the body of an enhanced or obsolete packet block, starting at the interface id.
+    fn packet(
+        &self,
+        body: &[u8],
+        interface: usize,
+        offset: usize,
+    ) -> Result<(u32, CapturedPacket), CaptureError> {
+        let interface = self
+            .interfaces
+            .get(interface)
+            .ok_or(CaptureError::Malformed("packet of an undefined interface"))?;
+        let header = field(body, offset, 16)?;
+        let ticks = u64::from(self.u32(header)) << 32 | u64::from(self.u32(&header[4..]));
+        let captured = self.u32(&header[8..]) as usize;
+        let data = field(body, offset + 16, captured)?.to_vec();
+        let options = body
+            .get(offset + 16 + (captured + 3) / 4 * 4..)
+            .unwrap_or_default();
+        // The two low bits of the flags hold the direction, 0 if it is unknown
+        let outbound = match self.option(options, PCAPNG_FLAGS)? {
+            Some(flags) if flags.len() == 4 => match self.u32(flags) & 3 {
+                1 => Some(false),
+                2 => Some(true),
+                _ => None,
+            },
+            _ => None,
+        };
+        let packet = CapturedPacket {
+            frame: 0,
+            timestamp: interface.resolution.duration(ticks),
+            original_len: self.u32(&header[12..]) as usize,
+            data,
+            outbound,
+        };
+        Ok((interface.link_type, packet))
+    }
+
+    /// Finds the value of the option `code` in the options of a pcapng block.
+    fn option<'a>(
+        &self,
+        mut options: &'a [u8],
+        code: u16,
+    ) -> Result<Option<&'a [u8]>, CaptureError> {
+        while options.len() >= 4 {
+            let length = self.u16(&options[2..]) as usize;
+            let value = field(options, 4, length)?;
+            match self.u16(options) {
+                0 => break,
+                option if option == code => return Ok(Some(value)),
+                _ => {}
+            }
+            options = options.get(4 + (length + 3) / 4 * 4..).unwrap_or_default();
+        }
+        Ok(None)
+    }
+
+    /// Fills `buffer`, returns `false` if the file ended before its first byte.
+    fn fill(&mut self, buffer: &mut [u8]) -> Result<bool, CaptureError> {
+        let mut read = 0;
+        while read < buffer.len() {
+            match self.reader.read(&mut buffer[read..]) {
+                Ok(0) if read == 0 => return Ok(false),
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(count) => read += count,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<Vec<u8>, CaptureError> {
+        if length > MAX_BLOCK_LENGTH {
+            return Err(CaptureError::Malformed("block too large"));
+        }
+        let mut buffer = vec![0; length];
+        self.reader.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn u16(&self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        }
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CapturedPacket, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.read().transpose();
+        self.done = !matches!(result, Some(Ok(_)));
+        result
+    }
+}
+
+/// `length` bytes of a block body starting at `offset`.
+fn field(body: &[u8], offset: usize, length: usize) -> Result<&[u8], CaptureError> {
+    body.get(offset..offset + length)
+        .ok_or(CaptureError::Malformed("truncated block"))
+}
+
+fn supported(link_type: u32) -> bool {
+    matches!(
+        link_type,
+        0 | 1 | 12 | 14 | 101 | 108 | 113 | 228 | 229 | 276
+    )
+}
+
+/// Direction recorded by the link layer header of a frame, `Some(true)` for outbound frames.
+fn direction(link_type: u32, frame: &[u8]) -> Option<bool> {
+    // Packet type of Linux cooked captures, 4 for packets sent by the host
+    let packet_type = match link_type {
+        113 => u16::from_be_bytes([*frame.first()?, *frame.get(1)?]),
+        276 => (*frame.get(10)?).into(),
+        _ => return None,
+    };
+    Some(packet_type == 4)
+}
+
+/// Range of the IP packet carried by a frame, `None` if it doesn't carry one.
+fn network(link_type: u32, frame: &[u8]) -> Option<std::ops::Range<usize>> {
+    let start = match link_type {
+        // BSD loopback, with the address family of the packet
+        0 | 108 => 4,
+        // Ethernet
+        1 => {
+            let mut offset = 12;
+            let mut ether_type = u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]);
+            // 802.1Q and 802.1ad tags
+            while matches!(ether_type, 0x8100 | 0x88A8 | 0x9100) {
+                offset += 4;
+                ether_type = u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]);
+            }
+            if !matches!(ether_type, 0x0800 | 0x86DD) {
+                return None;
+            }
+            offset + 2
+        }
+        // Raw IP
+        12 | 14 | 101 | 228 | 229 => 0,
+        // Linux cooked captures
+        113 => 16,
+        276 => 20,
+        _ => return None,
+    };
+    let packet = frame.get(start..)?;
+    // Drop link layer padding and trailers
+    let length = match packet.first()? >> 4 {
+        4 => u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]) as usize,
+        6 => match u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]) {
+            // Jumbogram
+            0 => packet.len(),
+            length => 40 + length as usize,
+        },
+        _ => return None,
+    };
+    Some(start..start + length.clamp(1, packet.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 20 byte IPv4 header of an empty packet.
+    const IPV4: [u8; 20] = [
+        0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+    ];
+
+    fn pcap(link_type: u32, records: &[&[u8]]) -> Vec<u8> {
+        let mut file = Vec::new();
+        for value in [PCAP_MICROS, 0x0004_0002, 0, 0, 65535, link_type] {
+            file.extend(value.to_le_bytes());
+        }
+        for (second, data) in records.iter().enumerate() {
+            let length = data.len() as u32;
+            for value in [second as u32, 500, length, length] {
+                file.extend(value.to_le_bytes());
+            }
+            file.extend(*data);
+        }
+        file
+    }
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let length = (12 + body.len() as u32).to_le_bytes();
+        let mut block = block_type.to_le_bytes().to_vec();
+        block.extend(length);
+        block.extend(body);
+        block.extend(length);
+        block
+    }
+
+    fn pcapng(blocks: &[Vec<u8>]) -> Vec<u8> {
+        let mut section = PCAPNG_BYTE_ORDER.to_le_bytes().to_vec();
+        section.extend([1, 0, 0, 0]);
+        section.extend(u64::MAX.to_le_bytes());
+        let mut file = block(PCAPNG_SECTION, &section);
+        // Raw IP interface
+        file.extend(block(PCAPNG_INTERFACE, &[101, 0, 0, 0, 0, 0, 0, 0]));
+        file.extend(blocks.concat());
+        file
+    }
+
+    /// Enhanced packet block of the first interface, with `epb_flags` if `flags` is set.
+    fn enhanced(data: &[u8], flags: Option<u32>) -> Vec<u8> {
+        let length = data.len() as u32;
+        let mut body = Vec::new();
+        for value in [0, 0, 1_000_000, length, length] {
+            body.extend(value.to_le_bytes());
+        }
+        body.extend(data);
+        body.resize((body.len() + 3) / 4 * 4, 0);
+        if let Some(flags) = flags {
+            body.extend(PCAPNG_FLAGS.to_le_bytes());
+            body.extend(4u16.to_le_bytes());
+            body.extend(flags.to_le_bytes());
+            body.extend([0; 4]);
+        }
+        block(PCAPNG_ENHANCED_PACKET, &body)
+    }
+
+    fn read(file: &[u8]) -> Result<Vec<CapturedPacket>, CaptureError> {
+        CaptureReader::new(file)?.collect()
+    }
+
+    #[test]
+    fn pcap_records() {
+        let packets = read(&pcap(101, &[&IPV4, &[], &IPV4])).unwrap();
+        let frames: Vec<_> = packets.iter().map(|packet| packet.frame).collect();
+        assert_eq!(frames, [0, 2]);
+        assert_eq!(packets[1].timestamp, Duration::new(2, 500_000));
+        assert_eq!(packets[0].data, IPV4);
+        assert_eq!(packets[0].outbound, None);
+
+        let file = pcap(101, &[&[]]);
+        let mut reader = CaptureReader::new(&file[..]).unwrap();
+        assert!(reader.next().is_none());
+        assert_eq!(reader.skipped(), 1);
+        assert!(read(&pcap(101, &[])).unwrap().is_empty());
+        assert!(matches!(read(&[]), Err(CaptureError::IOError(_))));
+        assert!(matches!(
+            read(&pcap(147, &[])),
+            Err(CaptureError::UnsupportedLinkType(147))
+        ));
+    }
+
+    #[test]
+    fn pcap_truncated() {
+        let file = pcap(101, &[&IPV4, &IPV4]);
+        // Record header, then record data cut short
+        for length in [file.len() - 30, file.len() - 1] {
+            let mut reader = CaptureReader::new(&file[..length]).unwrap();
+            assert_eq!(reader.next().unwrap().unwrap().data, IPV4);
+            let error = reader.next().unwrap().unwrap_err();
+            assert!(matches!(
+                error,
+                CaptureError::IOError(error) if error.kind() == io::ErrorKind::UnexpectedEof
+            ));
+            assert!(reader.next().is_none());
+        }
+    }
+
+    #[test]
+    fn pcapng_blocks() {
+        let file = pcapng(&[
+            enhanced(&IPV4, None),
+            enhanced(&[], Some(2)),
+            enhanced(&IPV4, Some(2)),
+            enhanced(&IPV4, Some(1)),
+        ]);
+        let packets = read(&file).unwrap();
+        let frames: Vec<_> = packets.iter().map(|packet| packet.frame).collect();
+        assert_eq!(frames, [0, 2, 3]);
+        assert_eq!(packets[0].timestamp, Duration::from_secs(1));
+        let directions: Vec<_> = packets.iter().map(|packet| packet.outbound).collect();
+        assert_eq!(directions, [None, Some(true), Some(false)]);
+        assert!(read(&pcapng(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pcapng_truncated() {
+        let file = pcapng(&[enhanced(&IPV4, None)]);
+        let error = read(&file[..file.len() - 4]).unwrap_err();
+        assert!(matches!(
+            error,
+            CaptureError::IOError(error) if error.kind() == io::ErrorKind::UnexpectedEof
+        ));
+
+        // Captured length past the end of the block
+        let mut block = enhanced(&IPV4, None);
+        block[20] = 24;
+        let error = read(&pcapng(&[block])).unwrap_err();
+        assert!(matches!(error, CaptureError::Malformed("truncated block")));
+
+        let mut block = enhanced(&IPV4, None);
+        block[4] = 13;
+        let error = read(&pcapng(&[block])).unwrap_err();
+        assert!(matches!(
+            error,
+            CaptureError::Malformed("invalid block length")
+        ));
+    }
+
+    #[test]
+    fn direction() {
+        // Linux cooked capture, packet type 4 is outgoing
+        let mut frame = vec![0, 4, 0, 1, 0, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0x08, 0x00];
+        frame.extend(IPV4);
+        let mut received = frame.clone();
+        received[1] = 0;
+        let packets = read(&pcap(113, &[&frame, &received])).unwrap();
+        assert_eq!(packets[0].data, IPV4);
+        assert_eq!(packets[0].outbound, Some(true));
+        assert_eq!(packets[1].outbound, Some(false));
+
+        let packet = packets[0].clone().into_packet();
+        assert!(packet.address.outbound());
+        assert!(!packet.address.ipv6());
+        assert!(!packets[1].clone().into_packet().address.outbound());
+    }
+
+    #[test]
+    fn ipv6() {
+        let mut packet = CapturedPacket {
+            frame: 0,
+            timestamp: Duration::ZERO,
+            original_len: 0,
+            data: Vec::new(),
+            outbound: None,
+        };
+        assert!(!packet.ipv6());
+        packet.data = vec![0x60; 40];
+        assert!(packet.ipv6());
+        assert!(packet.into_packet().address.ipv6());
+    }
+}
@@ -4,7 +4,6 @@ use std::{ffi::c_void, mem::MaybeUninit};
 use crate::address::WinDivertAddress;
 use crate::layer;
 use crate::prelude::*;
-use etherparse::{InternetSlice, SlicedPacket};
 use sys::address::WINDIVERT_ADDRESS;
 use windivert_sys as sys;
 
@@ -166,14 +165,11 @@ impl WinDivert<layer::NetworkLayer> {
                 address: WinDivertAddress::<layer::NetworkLayer>::from_raw(addr),
                 data: buffer
                     .map(|inner_buffer| {
-                        let headers = SlicedPacket::from_ip(inner_buffer)
-                            .expect("WinDivert can't capture anything below ip");
-                        let offset = match headers.ip.unwrap() {
-                            InternetSlice::Ipv4(ip_header, _) => ip_header.total_len() as usize,
-                            InternetSlice::Ipv6(ip6header, _) => {
-                                ip6header.payload_length() as usize + 40
-                            }
-                        };
+                        // Packets are stored back to back, each one as long as its IP header says
+                        let offset = ParsedPacket::parse(inner_buffer)
+                            .ip
+                            .map_or(inner_buffer.len(), |ip| ip.packet_length())
+                            .min(inner_buffer.len());
                         let (data, tail) = inner_buffer.split_at(offset);
                         buffer = Some(tail);
                         Cow::Borrowed(data)
@@ -225,14 +221,11 @@ impl WinDivert<layer::ForwardLayer> {
                 address: WinDivertAddress::<layer::NetworkLayer>::from_raw(addr),
                 data: buffer
                     .map(|inner_buffer| {
-                        let headers = SlicedPacket::from_ip(inner_buffer)
-                            .expect("WinDivert can't capture anything below ip");
-                        let offset = match headers.ip.unwrap() {
-                            InternetSlice::Ipv4(ip_header, _) => ip_header.total_len() as usize,
-                            InternetSlice::Ipv6(ip6header, _) => {
-                                ip6header.payload_length() as usize + 40
-                            }
-                        };
+                        // Packets are stored back to back, each one as long as its IP header says
+                        let offset = ParsedPacket::parse(inner_buffer)
+                            .ip
+                            .map_or(inner_buffer.len(), |ip| ip.packet_length())
+                            .min(inner_buffer.len());
                         let (data, tail) = inner_buffer.split_at(offset);
                         buffer = Some(tail);
                         Cow::Borrowed(data)
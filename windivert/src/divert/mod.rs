@@ -6,6 +6,7 @@ use std::{
     mem::MaybeUninit,
 };
 
+use crate::filter::{self, builder::IntoFilter, Filter, FilterObject};
 use crate::layer;
 use crate::prelude::*;
 use sys::{WinDivertParam, WinDivertShutdownMode};
@@ -42,6 +43,11 @@ impl<L: layer::WinDivertLayerTrait> WinDivert<L> {
         priority: i16,
         flags: WinDivertFlags,
     ) -> Result<Self, WinDivertError> {
+        if filter::is_object(filter) {
+            FilterObject::decode(filter)?.validate(layer)?;
+        } else {
+            Filter::parse(filter)?.validate(layer)?;
+        }
         let filter = CString::new(filter)?;
         let windivert_tls_idx = unsafe { TlsAlloc() };
         let handle = unsafe { sys::WinDivertOpen(filter.as_ptr(), layer, priority, flags) };
@@ -116,34 +122,44 @@ impl<L: layer::WinDivertLayerTrait> WinDivert<L> {
 impl WinDivert<layer::NetworkLayer> {
     /// WinDivert constructor for network layer.
     pub fn network(
-        filter: impl AsRef<str>,
+        filter: impl IntoFilter<layer::NetworkLayer>,
         priority: i16,
         flags: WinDivertFlags,
     ) -> Result<Self, WinDivertError> {
-        Self::new(filter.as_ref(), WinDivertLayer::Network, priority, flags)
+        Self::new(
+            &filter.into_filter()?,
+            WinDivertLayer::Network,
+            priority,
+            flags,
+        )
     }
 }
 
 impl WinDivert<layer::ForwardLayer> {
     /// WinDivert constructor for forward layer.
     pub fn forward(
-        filter: impl AsRef<str>,
+        filter: impl IntoFilter<layer::ForwardLayer>,
         priority: i16,
         flags: WinDivertFlags,
     ) -> Result<Self, WinDivertError> {
-        Self::new(filter.as_ref(), WinDivertLayer::Forward, priority, flags)
+        Self::new(
+            &filter.into_filter()?,
+            WinDivertLayer::Forward,
+            priority,
+            flags,
+        )
     }
 }
 
 impl WinDivert<layer::FlowLayer> {
     /// WinDivert constructor for flow layer.
     pub fn flow(
-        filter: &str,
+        filter: impl IntoFilter<layer::FlowLayer>,
         priority: i16,
         flags: WinDivertFlags,
     ) -> Result<Self, WinDivertError> {
         Self::new(
-            filter,
+            &filter.into_filter()?,
             WinDivertLayer::Flow,
             priority,
             flags.set_recv_only().set_sniff(),
@@ -154,12 +170,12 @@ impl WinDivert<layer::FlowLayer> {
 impl WinDivert<layer::SocketLayer> {
     /// WinDivert constructor for socket layer.
     pub fn socket(
-        filter: impl AsRef<str>,
+        filter: impl IntoFilter<layer::SocketLayer>,
         priority: i16,
         flags: WinDivertFlags,
     ) -> Result<Self, WinDivertError> {
         Self::new(
-            filter.as_ref(),
+            &filter.into_filter()?,
             WinDivertLayer::Socket,
             priority,
             flags.set_recv_only(),
@@ -170,12 +186,12 @@ impl WinDivert<layer::SocketLayer> {
 impl WinDivert<layer::ReflectLayer> {
     /// WinDivert constructor for reflect layer.
     pub fn reflect(
-        filter: impl AsRef<str>,
+        filter: impl IntoFilter<layer::ReflectLayer>,
         priority: i16,
         flags: WinDivertFlags,
     ) -> Result<Self, WinDivertError> {
         Self::new(
-            filter.as_ref(),
+            &filter.into_filter()?,
             WinDivertLayer::Reflect,
             priority,
             flags.set_recv_only().set_sniff(),
@@ -189,7 +205,7 @@ impl WinDivert<()> {
 
     /// Method that tries to uninstall WinDivert driver.
     pub fn uninstall() -> WinResult<()> {
-        let status: *mut SERVICE_STATUS = MaybeUninit::uninit().as_mut_ptr();
+        let mut status = MaybeUninit::<SERVICE_STATUS>::uninit();
         unsafe {
             let manager = OpenSCManagerA(None, None, SC_MANAGER_ALL_ACCESS)?;
             let service = OpenServiceA(
@@ -197,7 +213,7 @@ impl WinDivert<()> {
                 PCSTR::from_raw("WinDivert".as_ptr()),
                 SC_MANAGER_ALL_ACCESS,
             )?;
-            let res = ControlService(service, SERVICE_CONTROL_STOP, status);
+            let res = ControlService(service, SERVICE_CONTROL_STOP, status.as_mut_ptr());
             if !res.as_bool() {
                 return Err(WinError::from(GetLastError()));
             }
@@ -215,15 +231,11 @@ impl WinDivert<()> {
 }
 
 /// Action parameter for  [`WinDivert::close()`](`fn@WinDivert::close`)
+#[derive(Default)]
 pub enum CloseAction {
     /// Close the handle and try to uninstall the WinDivert driver.
     Uninstall,
     /// Close the handle without uninstalling the driver.
+    #[default]
     Nothing,
 }
-
-impl Default for CloseAction {
-    fn default() -> Self {
-        Self::Nothing
-    }
-}
@@ -4,6 +4,8 @@ use std::ffi::NulError;
 use thiserror::Error;
 use windivert_sys::{WinDivertParam, WinDivertValueError};
 
+pub use windivert_filter::FilterParseError;
+
 /**
 WinDivert error type.
 */
@@ -25,11 +27,35 @@ pub enum WinDivertError {
     #[error(transparent)]
     IOError(#[from] std::io::Error),
     /// Generic OS error.
+    #[cfg(target_os = "windows")]
     #[error(transparent)]
     OSError(#[from] windows::core::Error),
     /// Error indicating that a wrong parameter was used in [`set_param()`](fn@crate::WinDivert::set_param)
     #[error("Invalid parameter for set_param(). Parameter: {0:?}, Value: {1}")]
     Parameter(WinDivertParam, u64),
+    /// Invalid filter string, `position` is the byte offset of the offending token.
+    #[error("Invalid filter: {message} (at position {position})")]
+    Filter {
+        /// Description of the error.
+        message: String,
+        /// Byte offset of the offending token in the filter string.
+        position: usize,
+    },
+    /// Unknown parameter passed to [`FilterTemplate::bind()`](crate::filter::FilterTemplate::bind).
+    #[error("Unknown filter template parameter `${0}`")]
+    UnknownParameter(String),
+    /// Invalid CIDR passed to [`IpSet::insert_cidr()`](crate::filter::builder::IpSet::insert_cidr).
+    #[error("Invalid CIDR `{0}`")]
+    InvalidCidr(String),
+}
+
+impl From<FilterParseError> for WinDivertError {
+    fn from(error: FilterParseError) -> Self {
+        WinDivertError::Filter {
+            message: error.message,
+            position: error.span.start,
+        }
+    }
 }
 
 /**
@@ -127,3 +153,47 @@ impl TryFrom<std::io::Error> for WinDivertRecvError {
             .unwrap_or(Err(error))
     }
 }
+
+/**
+Possible errors when reading a capture file with [`CaptureReader`](crate::capture::CaptureReader).
+*/
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    /// Generic IO error, including files ending in the middle of a packet.
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    /// The file isn't a pcap or pcapng file.
+    #[error("Unknown capture file format")]
+    UnknownFormat,
+    /// The file is corrupted.
+    #[error("Malformed capture file: {0}")]
+    Malformed(&'static str),
+    /// The link type of a pcap file isn't supported.
+    #[error("Unsupported link type: {0}")]
+    UnsupportedLinkType(u32),
+}
+
+/**
+Error produced when an [`IpSet`](crate::filter::builder::IpSet) doesn't fit in a filter.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("IP set needs {tests} filter tests, filter objects are limited to {limit}")]
+pub struct IpSetTooLarge {
+    /// Number of tests needed to match the set.
+    pub tests: usize,
+    /// Maximum number of tests of a filter.
+    pub limit: usize,
+}
+
+/**
+Possible errors when building a packet with [`PacketBuilder`](crate::packet::PacketBuilder).
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PacketBuildError {
+    /// The packet doesn't fit in the 16 bit length field of its IP header.
+    #[error("Packet length {0} exceeds the IP length limit")]
+    TooLarge(usize),
+    /// The TCP options are longer than the 40 bytes a TCP header can hold.
+    #[error("TCP options of {0} bytes exceed the 40 bytes limit")]
+    TcpOptions(usize),
+}
@@ -0,0 +1,829 @@
+/*!
+Relations between filters.
+
+[`analyze()`] decides whether two filters can match the same events, which is what matters when
+several handles are open on the same layer: the handle with the highest priority receives the
+packets first, so a new handle whose filter is a [superset](Relation::Superset) of the filter of a
+lower priority handle leaves it nothing to capture.
+
+The filters are converted into the same normal form used by [`Filter::optimize()`], then three
+questions are answered: can an event match both filters, the first one only, or the second one
+only. Each question is decided by searching for a set of tests that can hold together and building
+a concrete event from it, which is checked with [`Filter::matches()`] before being trusted.
+*/
+
+use std::{
+    borrow::Cow,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use windivert_sys::{
+    address::{
+        WINDIVERT_ADDRESS, WINDIVERT_DATA_FLOW, WINDIVERT_DATA_NETWORK, WINDIVERT_DATA_REFLECT,
+    },
+    WinDivertEvent, WinDivertLayer,
+};
+
+use super::{
+    eval::{ipv4_mapped, Scalar},
+    optimize::{domain, flag_key, header, key, normalize, push_and, test_node, Key, Node, Ranges},
+    Field, Filter,
+};
+use crate::{
+    address::WinDivertAddress,
+    error::WinDivertError,
+    layer::{TypedLayer, WinDivertLayerTrait},
+    packet::WinDivertPacket,
+};
+
+/// Maximum number of conjunctions explored by a single search.
+const MAX_STEPS: usize = 100_000;
+/// Number of events built from a satisfiable conjunction before giving up on it.
+const CANDIDATES: u64 = 16;
+/// Number of events built when the conjunction tests a random field.
+const RANDOM_CANDIDATES: u64 = 4096;
+/// Largest packet built for a witness.
+const MAX_PACKET: usize = u16::MAX as usize;
+
+/// How the sets of events matched by two filters relate.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Relation {
+    /// No event matches both filters. Filters that never match are disjoint from every filter.
+    Disjoint,
+    /// Both filters match exactly the same events.
+    Equivalent,
+    /// Every event matched by the first filter is also matched by the second one.
+    Subset,
+    /// Every event matched by the second filter is also matched by the first one.
+    Superset,
+    /// Some events match both filters, and each filter may match events the other one doesn't.
+    Overlap,
+}
+
+/// Result of [`analyze()`].
+#[derive(Debug, Clone)]
+pub struct Analysis<L: WinDivertLayerTrait> {
+    /// Relation between the first and the second filter.
+    pub relation: Relation,
+    /// Event matched by both filters, `None` for disjoint filters or if no event could be built.
+    pub witness: Option<WinDivertPacket<'static, L>>,
+}
+
+/**
+Compares the events matched by two filters on the layer `L`.
+
+Both filters are [validated](Filter::validate) for the layer first. [`Relation::Disjoint`],
+[`Relation::Subset`], [`Relation::Superset`] and [`Relation::Equivalent`] are only reported when
+they are proven. When the search runs out of budget, or the events it finds can't be built (for
+instance tests on the random fields that no packet satisfies), the filters are assumed to possibly
+match the same events, so the result falls back to the weaker [`Relation::Overlap`].
+
+The witness is a packet, with its address, that both filters match. Header fields that the filters
+don't test get fixed defaults, such as documentation addresses and a zero checksum, so the witness
+is meant for inspection and testing rather than for injection.
+*/
+pub fn analyze<L: TypedLayer>(
+    first: &Filter,
+    second: &Filter,
+) -> Result<Analysis<L>, WinDivertError> {
+    first.validate(L::LAYER)?;
+    second.validate(L::LAYER)?;
+    let a = normalize(first.expr(), false, L::LAYER);
+    let b = normalize(second.expr(), false, L::LAYER);
+
+    let both = search::<L>(vec![a.clone(), b.clone()], &|packet| {
+        first.matches(packet) && second.matches(packet)
+    });
+    let witness = match both {
+        Outcome::Unsat => {
+            return Ok(Analysis {
+                relation: Relation::Disjoint,
+                witness: None,
+            })
+        }
+        Outcome::Sat(witness) => Some(witness),
+        Outcome::Unknown => None,
+    };
+    let first_only = search::<L>(vec![a.clone(), falsify(&b)], &|packet| {
+        first.matches(packet) && !second.matches(packet)
+    });
+    let second_only = search::<L>(vec![falsify(&a), b], &|packet| {
+        !first.matches(packet) && second.matches(packet)
+    });
+    let relation = match (first_only, second_only) {
+        (Outcome::Unsat, Outcome::Unsat) => Relation::Equivalent,
+        (Outcome::Unsat, _) => Relation::Subset,
+        (_, Outcome::Unsat) => Relation::Superset,
+        _ => Relation::Overlap,
+    };
+    Ok(Analysis { relation, witness })
+}
+
+/// Result of a search for an event matching a conjunction of nodes.
+enum Outcome<L: WinDivertLayerTrait> {
+    Sat(WinDivertPacket<'static, L>),
+    /// No event was found, but some conjunctions were not ruled out.
+    Unknown,
+    Unsat,
+}
+
+/// Searches for an event satisfying every node of `goals` and `check`.
+fn search<L: TypedLayer>(
+    goals: Vec<Node>,
+    check: &dyn Fn(&WinDivertPacket<L>) -> bool,
+) -> Outcome<L> {
+    let mut solver = Solver {
+        steps: 0,
+        unknown: false,
+        check,
+    };
+    match solver.solve(Vec::new(), goals) {
+        Some(witness) => Outcome::Sat(witness),
+        None if solver.unknown => Outcome::Unknown,
+        None => Outcome::Unsat,
+    }
+}
+
+struct Solver<'a, L: WinDivertLayerTrait> {
+    steps: usize,
+    /// Set when a conjunction was skipped without being proven unsatisfiable.
+    unknown: bool,
+    check: &'a dyn Fn(&WinDivertPacket<L>) -> bool,
+}
+
+impl<L: TypedLayer> Solver<'_, L> {
+    /// Splits the goals into conjunctions of tests, `tests` holds the tests already accepted.
+    fn solve(
+        &mut self,
+        mut tests: Vec<Node>,
+        mut goals: Vec<Node>,
+    ) -> Option<WinDivertPacket<'static, L>> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            self.unknown = true;
+            return None;
+        }
+        while let Some(goal) = goals.pop() {
+            match goal {
+                Node::Const(true) => {}
+                Node::Const(false) => return None,
+                Node::Test(key, ranges) => {
+                    if let Some(present) = present(key) {
+                        goals.push(present);
+                    }
+                    if !push_and(&mut tests, test_node(key, ranges)) {
+                        return None;
+                    }
+                }
+                Node::And(operands) => goals.extend(operands),
+                Node::Or(operands) => {
+                    for operand in operands {
+                        let mut goals = goals.clone();
+                        goals.push(operand);
+                        if let Some(witness) = self.solve(tests.clone(), goals) {
+                            return Some(witness);
+                        }
+                    }
+                    return None;
+                }
+                Node::Ternary(cond, then, otherwise) => {
+                    let branches = [[*cond.clone(), *then], [falsify(&cond), *otherwise]];
+                    for branch in branches {
+                        let mut goals = goals.clone();
+                        goals.extend(branch);
+                        if let Some(witness) = self.solve(tests.clone(), goals) {
+                            return Some(witness);
+                        }
+                    }
+                    return None;
+                }
+            }
+        }
+
+        let values = Values {
+            tests: &tests,
+            variant: 0,
+        };
+        if !values.possible(L::LAYER) {
+            return None;
+        }
+        let random = tests.iter().any(|test| {
+            matches!(
+                test,
+                Node::Test((Field::Random8 | Field::Random16 | Field::Random32, _), _)
+            )
+        });
+        let candidates = if random {
+            RANDOM_CANDIDATES
+        } else {
+            CANDIDATES
+        };
+        for variant in 0..candidates {
+            let values = Values {
+                tests: &tests,
+                variant,
+            };
+            if let Some(packet) = values.build::<L>() {
+                if (self.check)(&packet) {
+                    return Some(packet);
+                }
+            }
+        }
+        self.unknown = true;
+        None
+    }
+}
+
+/// Node matching the events on which `node` evaluates to false.
+///
+/// Tests on fields missing from an event are false, so they are false in both `node` and its
+/// negation and the result accepts them explicitly.
+fn falsify(node: &Node) -> Node {
+    match node {
+        Node::Const(value) => Node::Const(!value),
+        Node::Test(key, ranges) => {
+            let test = Node::Test(*key, ranges.complement(&domain(key.0)));
+            match missing(*key) {
+                Some(missing) => Node::Or(vec![missing, test]),
+                None => test,
+            }
+        }
+        Node::And(operands) => Node::Or(operands.iter().map(falsify).collect()),
+        Node::Or(operands) => Node::And(operands.iter().map(falsify).collect()),
+        Node::Ternary(cond, then, otherwise) => Node::Ternary(
+            cond.clone(),
+            Box::new(falsify(then)),
+            Box::new(falsify(otherwise)),
+        ),
+    }
+}
+
+/// Condition for the field of `key` to be missing from an event, `None` if it always exists.
+fn missing(key: Key) -> Option<Node> {
+    let absent = header(key.0).map(|header| {
+        Node::Test(
+            (header, None),
+            Ranges::new(flag_key(false), flag_key(false)),
+        )
+    });
+    let short = present(key).map(|present| match present {
+        Node::Test(length, ranges) => Node::Test(length, ranges.complement(&domain(length.0))),
+        _ => Node::Const(true),
+    });
+    match (absent, short) {
+        (Some(absent), Some(short)) => Some(Node::Or(vec![absent, short])),
+        (absent, short) => absent.or(short),
+    }
+}
+
+/// Length required for the word selected by an indexed field to exist, `None` for other fields.
+fn present((field, index): Key) -> Option<Node> {
+    use Field::*;
+    let index = index? as i128;
+    let length = match field {
+        Packet | Packet16 | Packet32 => Length,
+        TcpPayload | TcpPayload16 | TcpPayload32 => TcpPayloadLength,
+        _ => UdpPayloadLength,
+    };
+    let need = match (index, width(field)) {
+        (index, width) if index >= 0 => index + width,
+        (index, width) if -index >= width => -index,
+        _ => return Some(Node::Const(false)),
+    };
+    let (_, max) = domain(length).0[0];
+    Some(Node::Test(
+        (length, None),
+        Ranges::new(key(Scalar::Int(need)), max),
+    ))
+}
+
+fn width(field: Field) -> i128 {
+    field.index_width() as i128
+}
+
+/// Event built from the tests of a satisfiable conjunction.
+struct Values<'a> {
+    tests: &'a [Node],
+    /// Selects the values picked for the fields, `0` prefers the defaults.
+    variant: u64,
+}
+
+impl Values<'_> {
+    fn ranges(&self, key: Key) -> Option<&Ranges> {
+        self.tests.iter().find_map(|test| match test {
+            Node::Test(other, ranges) if *other == key => Some(ranges),
+            _ => None,
+        })
+    }
+
+    /// Value of `key`, picked among the accepted values.
+    fn raw(&self, key: Key, default: u128) -> u128 {
+        let Some(ranges) = self.ranges(key) else {
+            return default;
+        };
+        // Prefer the values the header can hold when the tests allow them
+        let ranges = match valid(key.0).map(|valid| ranges.intersect(&valid)) {
+            Some(valid) if !valid.is_empty() => Cow::Owned(valid),
+            _ => Cow::Borrowed(ranges),
+        };
+        if self.variant == 0 && ranges.contains(default) {
+            return default;
+        }
+        let mut hasher = DefaultHasher::new();
+        (self.variant, key).hash(&mut hasher);
+        let seed = hasher.finish();
+        let (first, last) = (ranges.0[0].0, ranges.0[ranges.0.len() - 1].1);
+        match (self.variant, seed % 3) {
+            (0, _) | (_, 0) => first,
+            (_, 1) => last,
+            _ => {
+                let (start, end) = ranges.0[(seed >> 8) as usize % ranges.0.len()];
+                let random = (seed as u128) << 64 | seed.rotate_left(29) as u128;
+                match (end - start).checked_add(1) {
+                    Some(span) => start + random % span,
+                    None => random,
+                }
+            }
+        }
+    }
+
+    fn int(&self, field: Field, default: i128) -> i128 {
+        let default = key(Scalar::Int(default));
+        (self.raw((field, None), default) ^ key(Scalar::Int(0))) as i128
+    }
+
+    fn byte(&self, field: Field, default: u8) -> u8 {
+        self.int(field, default as i128) as u8
+    }
+
+    fn word(&self, field: Field, default: u16) -> [u8; 2] {
+        (self.int(field, default as i128) as u16).to_be_bytes()
+    }
+
+    fn dword(&self, field: Field, default: u32) -> [u8; 4] {
+        (self.int(field, default as i128) as u32).to_be_bytes()
+    }
+
+    fn flag(&self, field: Field) -> Option<bool> {
+        self.ranges((field, None)).and_then(Ranges::flag)
+    }
+
+    /// Returns `true` if the protocol is set or a field of its header is tested.
+    fn requires(&self, protocol: Field) -> bool {
+        self.flag(protocol) == Some(true)
+            || self.tests.iter().any(|test| {
+                matches!(test, Node::Test((field, _), _) if header(*field) == Some(protocol))
+            })
+    }
+
+    /// Transport protocol required by the tests, with its protocol number.
+    fn transport(&self) -> Option<(Field, u8)> {
+        use Field::*;
+        [(Tcp, 6), (Udp, 17), (Icmp, 1), (Icmpv6, 58)]
+            .into_iter()
+            .find(|(field, _)| self.requires(*field))
+    }
+
+    fn allows(&self, field: Field, value: i128) -> bool {
+        self.ranges((field, None))
+            .map_or(true, |ranges| ranges.contains(key(Scalar::Int(value))))
+    }
+
+    /// Checks the relations between fields that no event can break, besides the ones checked
+    /// while building the conjunction.
+    fn possible(&self, layer: WinDivertLayer) -> bool {
+        use Field::*;
+        let allows_any =
+            |field: Field, values: &[i128]| values.iter().any(|value| self.allows(field, *value));
+        let event = match layer {
+            WinDivertLayer::Network | WinDivertLayer::Forward => &[0][..],
+            WinDivertLayer::Flow => &[1, 2],
+            WinDivertLayer::Socket => &[3, 4, 5, 6, 7],
+            WinDivertLayer::Reflect => &[8, 9],
+        };
+        if !allows_any(Event, event) || !allows_any(Layer, &[0, 1, 2, 3, 4]) {
+            return false;
+        }
+
+        let transport = self.transport();
+        if !matches!(layer, WinDivertLayer::Network | WinDivertLayer::Forward) {
+            return transport.map_or(true, |(_, number)| self.allows(Protocol, number as i128));
+        }
+        let header_lengths: Vec<_> = (5..=15).collect();
+        let fragmented = !self.allows(IpFragOff, 0) || self.flag(IpMf) == Some(true);
+        !(self.requires(Ip) && !allows_any(IpHdrLength, &header_lengths)
+            || self.requires(Tcp) && !allows_any(TcpHdrLength, &header_lengths)
+            || self.flag(Fragment) == Some(false) && fragmented
+            || transport.map_or(false, |(_, number)| {
+                // Non first fragments don't carry the transport header
+                !self.allows(IpFragOff, 0)
+                    || self.requires(Ip) && !self.allows(IpProtocol, number as i128)
+            }))
+    }
+
+    /// Builds the event, returns `None` if its packet would be too large.
+    fn build<L: TypedLayer>(&self) -> Option<WinDivertPacket<'static, L>> {
+        use Field::*;
+        let layer = L::LAYER;
+        let mut address = WINDIVERT_ADDRESS::default();
+        address.set_layer(layer);
+        address.timestamp = self.int(Timestamp, self.variant as i128) as i64;
+        address.set_outbound(self.flag(Outbound).unwrap_or(true));
+        address.set_loopback(self.flag(Loopback).unwrap_or(false));
+        address.set_impostor(self.flag(Impostor).unwrap_or(false));
+        let event = self.int(Event, 0) as u8;
+        address.set_event(WinDivertEvent::try_from(event).ok()?);
+
+        let data = match layer {
+            WinDivertLayer::Network | WinDivertLayer::Forward => {
+                address.union_field.Network = WINDIVERT_DATA_NETWORK {
+                    interface_id: self.int(IfIdx, 1) as u32,
+                    subinterface_id: self.int(SubIfIdx, 0) as u32,
+                };
+                self.packet(&mut address)?
+            }
+            WinDivertLayer::Flow | WinDivertLayer::Socket => {
+                let ipv6 =
+                    self.requires(Ipv6) || self.requires(Icmpv6) || self.flag(Ip) == Some(false);
+                address.set_ipv6(ipv6);
+                let protocol = self.transport().map_or_else(
+                    || {
+                        let defaults = [(Tcp, 6), (Udp, 17), (Icmp, 1), (Icmpv6, 58)];
+                        defaults
+                            .into_iter()
+                            .find(|(field, _)| self.flag(*field) != Some(false))
+                            .map_or(0, |(_, number)| number)
+                    },
+                    |(_, number)| number,
+                );
+                let (local, remote) = match ipv6 {
+                    true => (IPV6_SOURCE, IPV6_DESTINATION),
+                    false => (ipv4_mapped(IPV4_SOURCE), ipv4_mapped(IPV4_DESTINATION)),
+                };
+                // Flow and socket addresses share the same data layout
+                address.union_field.Flow = WINDIVERT_DATA_FLOW {
+                    endpoint_id: self.int(EndpointId, 1) as u64,
+                    parent_endpoint_id: self.int(ParentEndpointId, 0) as u64,
+                    process_id: self.int(ProcessId, 4) as u32,
+                    local_addr: words(self.raw((LocalAddr, None), local)),
+                    remote_addr: words(self.raw((RemoteAddr, None), remote)),
+                    local_port: self.int(LocalPort, 49152) as u16,
+                    remote_port: self.int(RemotePort, 80) as u16,
+                    protocol: self.byte(Protocol, protocol),
+                };
+                Vec::new()
+            }
+            WinDivertLayer::Reflect => {
+                let mut reflect = WINDIVERT_DATA_REFLECT {
+                    timestamp: address.timestamp,
+                    process_id: self.int(ProcessId, 4) as u32,
+                    priority: self.int(Priority, 0) as i16,
+                    ..Default::default()
+                };
+                reflect.layer = WinDivertLayer::try_from(self.int(Layer, 0) as u32).ok()?;
+                address.union_field.Reflect = reflect;
+                Vec::new()
+            }
+        };
+
+        Some(WinDivertPacket {
+            address: WinDivertAddress::from_raw(address),
+            data: Cow::Owned(data),
+        })
+    }
+
+    /// Builds the packet of a network or forward event.
+    fn packet(&self, address: &mut WINDIVERT_ADDRESS) -> Option<Vec<u8>> {
+        use Field::*;
+        let transport = self.transport();
+        let ipv6 = self.requires(Ipv6)
+            || matches!(transport, Some((Icmpv6, _)))
+            || self.flag(Ip) == Some(false) && self.flag(Ipv6) != Some(false);
+        let ipv4 = !ipv6 && self.flag(Ip) != Some(false);
+        address.set_ipv6(ipv6);
+
+        // Protocol carried by the IP header, and whether its header has to be written
+        let protocol = match (transport, ipv6) {
+            (Some((_, number)), _) => number,
+            (None, false) => self.byte(IpProtocol, RESERVED_PROTOCOL),
+            (None, true) => self.byte(Ipv6NextHdr, RESERVED_PROTOCOL),
+        };
+        let (transport_len, payload_length) = match (protocol, ipv6) {
+            (6, _) => (
+                self.int(TcpHdrLength, 5).clamp(5, 15) as usize * 4,
+                Some(TcpPayloadLength),
+            ),
+            (17, _) => (8, Some(UdpPayloadLength)),
+            (1, false) | (58, true) => (8, None),
+            _ => (0, None),
+        };
+        let payload_fields: &[Field] = match payload_length {
+            Some(TcpPayloadLength) => &[TcpPayload, TcpPayload16, TcpPayload32],
+            Some(_) => &[UdpPayload, UdpPayload16, UdpPayload32],
+            None => &[],
+        };
+        let payload_len = match payload_length {
+            Some(field) => self.int(field, self.needed(payload_fields, 0) as i128) as usize,
+            None => 0,
+        };
+
+        let fragment_header = ipv6
+            && (self.flag(Fragment) == Some(true)
+                || !self.allows(Ipv6NextHdr, protocol as i128) && self.allows(Ipv6NextHdr, 44));
+        let ip_len = match (ipv4, ipv6) {
+            (true, _) => self.int(IpHdrLength, 5).clamp(5, 15) as usize * 4,
+            (_, true) => 40 + if fragment_header { 8 } else { 0 },
+            _ => 0,
+        };
+        let total = ip_len + transport_len + payload_len;
+        let length = self.int(
+            Length,
+            self.needed(&[Packet, Packet16, Packet32], total) as i128,
+        ) as usize;
+        if total > MAX_PACKET || length > MAX_PACKET {
+            return None;
+        }
+        let mut data = vec![0; total.max(length)];
+
+        if ipv4 {
+            let header = &mut data[..ip_len];
+            // Fragments without the MF flag need an offset
+            let last = self.flag(Fragment) == Some(true) && self.flag(IpMf) == Some(false);
+            let frag_off = self.int(IpFragOff, last as i128) as u16 & 0x1FFF;
+            let df = self.flag(IpDf).unwrap_or(false);
+            let mf = self
+                .flag(IpMf)
+                .unwrap_or(self.flag(Fragment) == Some(true) && frag_off == 0);
+            header[0] = 0x40 | (self.byte(IpHdrLength, (ip_len / 4) as u8) & 0x0F);
+            header[1] = self.byte(IpTos, 0);
+            header[2..4].copy_from_slice(&self.word(IpLength, total as u16));
+            header[4..6].copy_from_slice(&self.word(IpId, 1));
+            let flags = (df as u16) << 14 | (mf as u16) << 13 | frag_off;
+            header[6..8].copy_from_slice(&flags.to_be_bytes());
+            header[8] = self.byte(IpTtl, 64);
+            header[9] = self.byte(IpProtocol, protocol);
+            header[10..12].copy_from_slice(&self.word(IpChecksum, 0));
+            let source = self.raw((IpSrcAddr, None), ipv4_mapped(IPV4_SOURCE)) as u32;
+            let destination = self.raw((IpDstAddr, None), ipv4_mapped(IPV4_DESTINATION)) as u32;
+            header[12..16].copy_from_slice(&source.to_be_bytes());
+            header[16..20].copy_from_slice(&destination.to_be_bytes());
+        } else if ipv6 {
+            let header = &mut data[..ip_len];
+            let class = self.byte(Ipv6TrafficClass, 0);
+            let label = self.int(Ipv6FlowLabel, 0) as u32 & 0xF_FFFF;
+            header[0..4].copy_from_slice(&(6 << 28 | (class as u32) << 20 | label).to_be_bytes());
+            header[4..6].copy_from_slice(&self.word(Ipv6Length, (total - 40) as u16));
+            let next = if fragment_header { 44 } else { protocol };
+            header[6] = self.byte(Ipv6NextHdr, next);
+            header[7] = self.byte(Ipv6HopLimit, 64);
+            let source = self.raw((Ipv6SrcAddr, None), IPV6_SOURCE);
+            let destination = self.raw((Ipv6DstAddr, None), IPV6_DESTINATION);
+            header[8..24].copy_from_slice(&source.to_be_bytes());
+            header[24..40].copy_from_slice(&destination.to_be_bytes());
+            if fragment_header {
+                // First fragment with more fragments following
+                header[40..48].copy_from_slice(&[protocol, 0, 0, 1, 0, 0, 0, 1]);
+            }
+        }
+
+        let header = &mut data[ip_len..ip_len + transport_len];
+        match (protocol, transport_len) {
+            (_, 0) => {}
+            (6, _) => {
+                header[0..2].copy_from_slice(&self.word(TcpSrcPort, 49152));
+                header[2..4].copy_from_slice(&self.word(TcpDstPort, 80));
+                header[4..8].copy_from_slice(&self.dword(TcpSeqNum, 0));
+                header[8..12].copy_from_slice(&self.dword(TcpAckNum, 0));
+                header[12] = self.byte(TcpHdrLength, (transport_len / 4) as u8) << 4;
+                let flags = [TcpUrg, TcpAck, TcpPsh, TcpRst, TcpSyn, TcpFin];
+                header[13] = flags.iter().fold(0, |bits, field| {
+                    bits << 1 | self.flag(*field).unwrap_or(false) as u8
+                });
+                header[14..16].copy_from_slice(&self.word(TcpWindow, 64240));
+                header[16..18].copy_from_slice(&self.word(TcpChecksum, 0));
+                header[18..20].copy_from_slice(&self.word(TcpUrgPtr, 0));
+            }
+            (17, _) => {
+                header[0..2].copy_from_slice(&self.word(UdpSrcPort, 49152));
+                header[2..4].copy_from_slice(&self.word(UdpDstPort, 53));
+                let udp_len = (transport_len + payload_len) as u16;
+                header[4..6].copy_from_slice(&self.word(UdpLength, udp_len));
+                header[6..8].copy_from_slice(&self.word(UdpChecksum, 0));
+            }
+            (1, _) => {
+                header[0] = self.byte(IcmpType, 8);
+                header[1] = self.byte(IcmpCode, 0);
+                header[2..4].copy_from_slice(&self.word(IcmpChecksum, 0));
+                header[4..8].copy_from_slice(&self.dword(IcmpBody, 0));
+            }
+            _ => {
+                header[0] = self.byte(Icmpv6Type, 128);
+                header[1] = self.byte(Icmpv6Code, 0);
+                header[2..4].copy_from_slice(&self.word(Icmpv6Checksum, 0));
+                header[4..8].copy_from_slice(&self.dword(Icmpv6Body, 0));
+            }
+        }
+
+        let payload = ip_len + transport_len;
+        self.write(&mut data[payload..total], payload_fields);
+        data.truncate(length);
+        self.write(&mut data, &[Packet, Packet16, Packet32]);
+        Some(data)
+    }
+
+    /// Length holding every tested word of the indexed `fields` past the first `len` bytes, with
+    /// the words indexed from the end placed after the other ones.
+    fn needed(&self, fields: &[Field], len: usize) -> usize {
+        let (mut start, mut end) = (len, 0);
+        for (field, index, _) in self.indexed(fields) {
+            match index >= 0 {
+                true => start = start.max(index as usize + width(field) as usize),
+                false => end = end.max(index.unsigned_abs() as usize),
+            }
+        }
+        start + end
+    }
+
+    /// Writes the values of the tested words of the indexed `fields` into `bytes`, keeping the
+    /// words, usually header fields, that already hold an accepted value.
+    fn write(&self, bytes: &mut [u8], fields: &[Field]) {
+        for (field, index, value) in self.indexed(fields) {
+            let width = width(field) as usize;
+            let start = match index >= 0 {
+                true => Some(index as usize),
+                false => bytes.len().checked_sub(index.unsigned_abs() as usize),
+            };
+            let Some(word) = start.and_then(|start| bytes.get_mut(start..start + width)) else {
+                continue;
+            };
+            let current = word.iter().fold(0, |acc, &byte| acc << 8 | byte as i128);
+            let accepted = self
+                .ranges((field, Some(index)))
+                .map_or(false, |ranges| ranges.contains(key(Scalar::Int(current))));
+            if !accepted {
+                word.copy_from_slice(&(value as u32).to_be_bytes()[4 - width..]);
+            }
+        }
+    }
+
+    /// Tested words of the indexed `fields`, with their index and value, widest first so that
+    /// narrower words overlapping them keep their value.
+    fn indexed(&self, fields: &[Field]) -> Vec<(Field, i32, i128)> {
+        let mut words: Vec<_> = self
+            .tests
+            .iter()
+            .filter_map(|test| match test {
+                Node::Test((field, Some(index)), _) if fields.contains(field) => {
+                    let value = self.raw((*field, Some(*index)), key(Scalar::Int(0)));
+                    Some((*field, *index, (value ^ key(Scalar::Int(0))) as i128))
+                }
+                _ => None,
+            })
+            .collect();
+        words.sort_by_key(|(field, _, _)| -width(*field));
+        words
+    }
+}
+
+/// Protocol number reserved for experimentation, used when the transport doesn't matter.
+const RESERVED_PROTOCOL: u8 = 253;
+/// `192.0.2.1`, from the TEST-NET-1 documentation range.
+const IPV4_SOURCE: u32 = 0xC000_0201;
+/// `198.51.100.1`, from the TEST-NET-2 documentation range.
+const IPV4_DESTINATION: u32 = 0xC633_6401;
+/// `2001:db8::1`, from the IPv6 documentation range.
+const IPV6_SOURCE: u128 = 0x2001_0db8 << 96 | 1;
+/// `2001:db8::2`, from the IPv6 documentation range.
+const IPV6_DESTINATION: u128 = 0x2001_0db8 << 96 | 2;
+
+/// Values of a field that keep the packet well formed, `None` if every value does.
+fn valid(field: Field) -> Option<Ranges> {
+    use Field::*;
+    let (start, end) = match field {
+        IpHdrLength | TcpHdrLength => (5, 15),
+        IpFragOff => (0, 0x1FFF),
+        Ipv6FlowLabel => (0, 0xF_FFFF),
+        _ => return None,
+    };
+    Some(Ranges::new(key(Scalar::Int(start)), key(Scalar::Int(end))))
+}
+
+/// Splits an address into the little endian words used by flow and socket addresses.
+fn words(addr: u128) -> [u32; 4] {
+    [0, 1, 2, 3].map(|word| (addr >> (32 * word)) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        layer::{FlowLayer, NetworkLayer, SocketLayer},
+        packet::ParsedPacket,
+    };
+
+    /// Relation between two filters, checking the witness against both filters.
+    fn relation<L: TypedLayer>(first: &str, second: &str) -> Relation {
+        let (first, second) = (
+            Filter::parse(first).unwrap(),
+            Filter::parse(second).unwrap(),
+        );
+        let analysis = analyze::<L>(&first, &second).unwrap();
+        match &analysis.witness {
+            Some(witness) => assert!(first.matches(witness) && second.matches(witness)),
+            None => assert_eq!(analysis.relation, Relation::Disjoint),
+        }
+        analysis.relation
+    }
+
+    /// Checks the relation of `first` and `second` in both orders.
+    fn check<L: TypedLayer>(first: &str, second: &str, expected: Relation) {
+        let swapped = match expected {
+            Relation::Subset => Relation::Superset,
+            Relation::Superset => Relation::Subset,
+            relation => relation,
+        };
+        assert_eq!(relation::<L>(first, second), expected, "{first} / {second}");
+        assert_eq!(relation::<L>(second, first), swapped, "{second} / {first}");
+    }
+
+    #[test]
+    fn disjoint() {
+        check::<NetworkLayer>("tcp", "udp", Relation::Disjoint);
+        check::<NetworkLayer>("not tcp", "tcp", Relation::Disjoint);
+        check::<NetworkLayer>("outbound", "inbound", Relation::Disjoint);
+        check::<NetworkLayer>("false", "true", Relation::Disjoint);
+        check::<NetworkLayer>(
+            "tcp.DstPort < 80",
+            "tcp.DstPort >= 80 and tcp.DstPort <= 90",
+            Relation::Disjoint,
+        );
+        check::<SocketLayer>("event == BIND", "event == CONNECT", Relation::Disjoint);
+    }
+
+    #[test]
+    fn subset() {
+        check::<NetworkLayer>("tcp.DstPort == 80", "tcp", Relation::Subset);
+        check::<NetworkLayer>("tcp.Syn", "tcp", Relation::Subset);
+        check::<NetworkLayer>("tcp", "ip or ipv6", Relation::Subset);
+        check::<FlowLayer>("remotePort == 443", "true", Relation::Subset);
+        check::<NetworkLayer>("outbound and udp", "not inbound", Relation::Subset);
+    }
+
+    #[test]
+    fn superset() {
+        check::<NetworkLayer>("tcp", "tcp.DstPort == 80", Relation::Superset);
+        check::<NetworkLayer>("not udp", "tcp", Relation::Superset);
+        check::<NetworkLayer>(
+            "tcp.DstPort >= 1 and tcp.DstPort <= 1024",
+            "tcp.DstPort == 80 or tcp.DstPort == 443",
+            Relation::Superset,
+        );
+    }
+
+    #[test]
+    fn overlap() {
+        check::<NetworkLayer>(
+            "tcp.DstPort >= 80 and tcp.DstPort <= 443",
+            "tcp.DstPort >= 400 and tcp.DstPort <= 8080",
+            Relation::Overlap,
+        );
+        check::<NetworkLayer>("tcp", "outbound", Relation::Overlap);
+        check::<NetworkLayer>("not tcp.Syn", "tcp.Ack", Relation::Overlap);
+    }
+
+    #[test]
+    fn equivalent() {
+        check::<NetworkLayer>("outbound", "not inbound", Relation::Equivalent);
+        check::<NetworkLayer>(
+            "not (tcp or udp)",
+            "not tcp and not udp",
+            Relation::Equivalent,
+        );
+        check::<NetworkLayer>(
+            "tcp.DstPort == 80 or tcp.DstPort == 81",
+            "tcp.DstPort >= 80 and tcp.DstPort <= 81",
+            Relation::Equivalent,
+        );
+        check::<NetworkLayer>("tcp", "tcp", Relation::Equivalent);
+    }
+
+    #[test]
+    fn witness() {
+        let first = Filter::parse("tcp.DstPort >= 80 and tcp.DstPort <= 443").unwrap();
+        let second = Filter::parse("tcp.DstPort >= 400 and outbound").unwrap();
+        let analysis = analyze::<NetworkLayer>(&first, &second).unwrap();
+        let witness = analysis.witness.unwrap();
+        assert!(witness.address.outbound());
+        let port = ParsedPacket::parse(&witness.data).tcp().unwrap().dst_port();
+        assert!((400..=443).contains(&port), "{port}");
+    }
+
+    #[test]
+    fn invalid() {
+        let first = Filter::parse("processId == 4").unwrap();
+        let second = Filter::parse("tcp").unwrap();
+        assert!(analyze::<NetworkLayer>(&first, &second).is_err());
+        assert!(analyze::<NetworkLayer>(&second, &first).is_err());
+    }
+}
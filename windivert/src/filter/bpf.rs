@@ -0,0 +1,1210 @@
+use std::fmt;
+
+use windivert_sys::WinDivertLayer;
+
+use super::{
+    eval::{compare_scalars, literal, Scalar},
+    CmpOp, Expr, ExprKind, Field, FieldRef, Filter, Value,
+};
+use crate::error::WinDivertError;
+
+// Instruction classes
+const LD: u16 = 0x00;
+const LDX: u16 = 0x01;
+const ST: u16 = 0x02;
+const STX: u16 = 0x03;
+const ALU: u16 = 0x04;
+const JMP: u16 = 0x05;
+const RET: u16 = 0x06;
+const MISC: u16 = 0x07;
+
+// Load sizes
+const W: u16 = 0x00;
+const H: u16 = 0x08;
+const B: u16 = 0x10;
+
+// Load modes
+const IMM: u16 = 0x00;
+const ABS: u16 = 0x20;
+const IND: u16 = 0x40;
+const MEM: u16 = 0x60;
+const LEN: u16 = 0x80;
+const MSH: u16 = 0xa0;
+
+// ALU operations
+const ADD: u16 = 0x00;
+const SUB: u16 = 0x10;
+const MUL: u16 = 0x20;
+const DIV: u16 = 0x30;
+const OR: u16 = 0x40;
+const AND: u16 = 0x50;
+const LSH: u16 = 0x60;
+const RSH: u16 = 0x70;
+const NEG: u16 = 0x80;
+const MOD: u16 = 0x90;
+const XOR: u16 = 0xa0;
+
+// Jump operations
+const JA: u16 = 0x00;
+const JEQ: u16 = 0x10;
+const JGT: u16 = 0x20;
+const JGE: u16 = 0x30;
+const JSET: u16 = 0x40;
+
+// Operand sources
+const K: u16 = 0x00;
+const X: u16 = 0x08;
+const A: u16 = 0x10;
+
+// Miscellaneous operations
+const TAX: u16 = 0x00;
+const TXA: u16 = 0x80;
+
+/// Number of scratch memory slots.
+const MEMORY: usize = 16;
+/// Value returned for accepted packets, the whole packet is kept.
+const ACCEPT: u32 = u32::MAX;
+/// Maximum number of IPv6 extension headers skipped before the transport header.
+const MAX_EXTENSIONS: usize = 8;
+
+// Scratch memory slots filled by the header parser
+/// IP version of a valid IP header, 0 otherwise.
+const SLOT_VERSION: u32 = 0;
+/// End of the IP packet, the smallest of the IP length and the captured length.
+const SLOT_END: u32 = 1;
+/// Transport protocol of a valid transport header, 0 otherwise.
+const SLOT_PROTO: u32 = 2;
+/// Offset of the transport header.
+const SLOT_TRANSPORT: u32 = 3;
+/// Offset of the transport payload.
+const SLOT_PAYLOAD: u32 = 4;
+/// 1 for IP fragments.
+const SLOT_FRAGMENT: u32 = 5;
+/// Protocol of the next IPv6 header.
+const SLOT_NEXT: u32 = 6;
+/// Temporary value of the header parser.
+const SLOT_TMP: u32 = 7;
+
+/// Classic BPF instruction, with the layout of the Linux `struct sock_filter`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct BpfInstruction {
+    /// Opcode.
+    pub code: u16,
+    /// Forward offset of the next instruction if the jump condition is true.
+    pub jt: u8,
+    /// Forward offset of the next instruction if the jump condition is false.
+    pub jf: u8,
+    /// Constant operand.
+    pub k: u32,
+}
+
+impl BpfInstruction {
+    fn stmt(code: u16, k: u32) -> Self {
+        Self {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+}
+
+/**
+Classic BPF program matching raw IP packets, created by [`Filter::to_bpf()`].
+
+The instructions have the layout of the Linux `struct sock_filter`, so
+[`instructions()`](BpfProgram::instructions) can be attached with `SO_ATTACH_FILTER` to a socket
+receiving packets from the IP header on, like `AF_PACKET`/`SOCK_DGRAM` sockets. Accepted packets
+return [`u32::MAX`], rejected packets return 0.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BpfProgram {
+    instructions: Vec<BpfInstruction>,
+}
+
+impl BpfProgram {
+    /// Maximum number of instructions of a program, `BPF_MAXINSNS` on Linux.
+    pub const MAX_LEN: usize = 4096;
+
+    /// Instructions of the program, execution starts with the first one.
+    pub fn instructions(&self) -> &[BpfInstruction] {
+        &self.instructions
+    }
+
+    /**
+    Runs the program on a raw IP packet and returns the number of bytes to keep, 0 for rejected
+    packets.
+
+    Follows the semantics of the Linux interpreter: out of bounds loads, divisions by zero and
+    invalid instructions end the program and reject the packet.
+    */
+    pub fn run(&self, packet: &[u8]) -> u32 {
+        let (mut a, mut x, mut memory) = (0u32, 0u32, [0u32; MEMORY]);
+        let load = |offset: u32, code: u16| -> Option<u32> {
+            let start = offset as usize;
+            let bytes = match code & 0x18 {
+                W => packet.get(start..start.checked_add(4)?)?,
+                H => packet.get(start..start.checked_add(2)?)?,
+                B => packet.get(start..start.checked_add(1)?)?,
+                _ => return None,
+            };
+            Some(bytes.iter().fold(0, |acc, &byte| acc << 8 | byte as u32))
+        };
+
+        let mut pc = 0;
+        while let Some(insn) = self.instructions.get(pc) {
+            pc += 1;
+            let k = insn.k;
+            let src = if insn.code & X != 0 { x } else { k };
+            let value = match (insn.code & 0x07, insn.code & 0xe0) {
+                (LD, IMM) | (LDX, IMM) => Some(k),
+                (LD, LEN) | (LDX, LEN) => Some(packet.len() as u32),
+                (LD, MEM) | (LDX, MEM) => memory.get(k as usize).copied(),
+                (LD, ABS) => load(k, insn.code),
+                (LD, IND) => load(x.wrapping_add(k), insn.code),
+                (LDX, MSH) => load(k, B).map(|byte| (byte & 0xf) * 4),
+                _ => None,
+            };
+            match insn.code & 0x07 {
+                LD => match value {
+                    Some(value) => a = value,
+                    None => return 0,
+                },
+                LDX => match value {
+                    Some(value) => x = value,
+                    None => return 0,
+                },
+                ST | STX => match memory.get_mut(k as usize) {
+                    Some(slot) => *slot = if insn.code & 0x07 == ST { a } else { x },
+                    None => return 0,
+                },
+                ALU => {
+                    a = match insn.code & 0xf0 {
+                        ADD => a.wrapping_add(src),
+                        SUB => a.wrapping_sub(src),
+                        MUL => a.wrapping_mul(src),
+                        DIV if src != 0 => a / src,
+                        MOD if src != 0 => a % src,
+                        OR => a | src,
+                        AND => a & src,
+                        LSH => a.checked_shl(src).unwrap_or(0),
+                        RSH => a.checked_shr(src).unwrap_or(0),
+                        NEG => a.wrapping_neg(),
+                        XOR => a ^ src,
+                        _ => return 0,
+                    }
+                }
+                JMP => {
+                    let jump = match insn.code & 0xf0 {
+                        JA => {
+                            pc = pc.saturating_add(k as usize);
+                            continue;
+                        }
+                        JEQ => a == src,
+                        JGT => a > src,
+                        JGE => a >= src,
+                        JSET => a & src != 0,
+                        _ => return 0,
+                    };
+                    pc += if jump { insn.jt } else { insn.jf } as usize;
+                }
+                RET => return if insn.code & A != 0 { a } else { k },
+                _ => match insn.code & 0xf8 {
+                    TAX => x = a,
+                    TXA => a = x,
+                    _ => return 0,
+                },
+            }
+        }
+        0
+    }
+
+    /// Returns `true` if the program accepts the raw IP packet.
+    pub fn matches(&self, packet: &[u8]) -> bool {
+        self.run(packet) != 0
+    }
+}
+
+/// Lists the instructions in the format of `tcpdump -d`.
+impl fmt::Display for BpfProgram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (pc, insn) in self.instructions.iter().enumerate() {
+            let k = insn.k;
+            let src = if insn.code & X != 0 {
+                "x".to_owned()
+            } else {
+                format!("#{k:#x}")
+            };
+            let size = match insn.code & 0x18 {
+                H => "h",
+                B => "b",
+                _ => "",
+            };
+            let operand = || match insn.code & 0xe0 {
+                IMM => format!("#{k:#x}"),
+                ABS => format!("[{k}]"),
+                IND => format!("[x + {k}]"),
+                MEM => format!("M[{k}]"),
+                LEN => "#pktlen".to_owned(),
+                MSH => format!("4*([{k}]&0xf)"),
+                _ => "?".to_owned(),
+            };
+            let (op, operand) = match insn.code & 0x07 {
+                LD => (format!("ld{size}"), operand()),
+                LDX if insn.code & 0xe0 == MSH => ("ldxb".to_owned(), operand()),
+                LDX => ("ldx".to_owned(), operand()),
+                ST => ("st".to_owned(), format!("M[{k}]")),
+                STX => ("stx".to_owned(), format!("M[{k}]")),
+                ALU => {
+                    let op = match insn.code & 0xf0 {
+                        ADD => "add",
+                        SUB => "sub",
+                        MUL => "mul",
+                        DIV => "div",
+                        MOD => "mod",
+                        OR => "or",
+                        AND => "and",
+                        LSH => "lsh",
+                        RSH => "rsh",
+                        NEG => "neg",
+                        XOR => "xor",
+                        _ => "?",
+                    };
+                    let src = if insn.code & 0xf0 == NEG { "" } else { &src };
+                    (op.to_owned(), src.to_owned())
+                }
+                JMP if insn.code & 0xf0 == JA => {
+                    ("ja".to_owned(), format!("{}", pc + 1 + k as usize))
+                }
+                JMP => {
+                    let op = match insn.code & 0xf0 {
+                        JEQ => "jeq",
+                        JGT => "jgt",
+                        JGE => "jge",
+                        JSET => "jset",
+                        _ => "?",
+                    };
+                    let (jt, jf) = (pc + 1 + insn.jt as usize, pc + 1 + insn.jf as usize);
+                    (op.to_owned(), format!("{src:<16} jt {jt}\tjf {jf}"))
+                }
+                RET if insn.code & A != 0 => ("ret".to_owned(), "a".to_owned()),
+                RET => ("ret".to_owned(), format!("#{k}")),
+                _ if insn.code & 0xf8 == TXA => ("txa".to_owned(), String::new()),
+                _ => ("tax".to_owned(), String::new()),
+            };
+            writeln!(f, "({pc:03}) {op:<8} {operand}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Filter {
+    /**
+    Lowers the filter into a classic BPF program matching raw IP packets.
+
+    The program gives the same verdicts as [`matches()`](Filter::matches) for network layer
+    packets, except for IPv6 packets with more than 8 extension headers, whose transport header
+    isn't parsed. The filter is [validated](Filter::validate) for the network layer first, fields
+    that don't come from the packet data, like `ifIdx`, `outbound` or `processId`, are rejected.
+    */
+    pub fn to_bpf(&self) -> Result<BpfProgram, WinDivertError> {
+        self.validate(WinDivertLayer::Network)?;
+        let mut generator = Generator {
+            asm: Assembler::default(),
+            headers: false,
+        };
+        let accept = generator.asm.label();
+        let reject = generator.asm.label();
+        generator.expr(self.expr(), accept, reject, false)?;
+        generator.asm.bind(accept);
+        generator.asm.stmt(RET | K, ACCEPT);
+        generator.asm.bind(reject);
+        generator.asm.stmt(RET | K, 0);
+
+        if generator.headers {
+            let body = std::mem::take(&mut generator.asm.items);
+            let start = generator.asm.label();
+            generator.parse_headers(start);
+            generator.asm.bind(start);
+            generator.asm.items.extend(body);
+        }
+
+        let instructions = generator.asm.assemble();
+        if instructions.len() > BpfProgram::MAX_LEN {
+            return Err(WinDivertError::Filter {
+                message: format!(
+                    "filter is too long, BPF programs are limited to {} instructions",
+                    BpfProgram::MAX_LEN
+                ),
+                position: 0,
+            });
+        }
+        Ok(BpfProgram { instructions })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Label(usize);
+
+enum Item {
+    Bind(Label),
+    Stmt(u16, u32),
+    Jump(u16, u32, Label, Label),
+    Ja(Label),
+}
+
+/// Instruction list with symbolic forward jumps.
+#[derive(Default)]
+struct Assembler {
+    items: Vec<Item>,
+    labels: usize,
+}
+
+impl Assembler {
+    fn label(&mut self) -> Label {
+        self.labels += 1;
+        Label(self.labels - 1)
+    }
+
+    fn bind(&mut self, label: Label) {
+        self.items.push(Item::Bind(label));
+    }
+
+    fn stmt(&mut self, code: u16, k: u32) {
+        self.items.push(Item::Stmt(code, k));
+    }
+
+    fn stmts(&mut self, stmts: &[(u16, u32)]) {
+        for &(code, k) in stmts {
+            self.stmt(code, k);
+        }
+    }
+
+    fn jump(&mut self, code: u16, k: u32, jt: Label, jf: Label) {
+        if jt == jf {
+            self.ja(jt);
+        } else {
+            self.items.push(Item::Jump(JMP | code, k, jt, jf));
+        }
+    }
+
+    fn ja(&mut self, label: Label) {
+        self.items.push(Item::Ja(label));
+    }
+
+    /// Resolves the labels. Conditional jumps only reach 255 instructions, farther targets are
+    /// reached through `ja` instructions following the jump.
+    fn assemble(self) -> Vec<BpfInstruction> {
+        // Drop jumps to the next instruction
+        let mut here = Vec::new();
+        let mut items = Vec::with_capacity(self.items.len());
+        for item in self.items.into_iter().rev() {
+            match item {
+                Item::Bind(label) => here.push(label),
+                Item::Ja(label) if here.contains(&label) => continue,
+                _ => here.clear(),
+            }
+            items.push(item);
+        }
+        items.reverse();
+
+        let mut far = vec![false; items.len()];
+        let mut positions = vec![0; self.labels];
+        loop {
+            let mut pos = 0;
+            let mut starts = Vec::with_capacity(items.len());
+            for (item, &far) in items.iter().zip(&far) {
+                starts.push(pos);
+                match item {
+                    Item::Bind(label) => positions[label.0] = pos,
+                    Item::Jump(..) if far => pos += 3,
+                    _ => pos += 1,
+                }
+            }
+
+            let mut changed = false;
+            for ((item, far), pos) in items.iter().zip(&mut far).zip(starts) {
+                if let Item::Jump(_, _, jt, jf) = item {
+                    let reach = |label: &Label| positions[label.0] - pos - 1 <= u8::MAX as usize;
+                    if !*far && (!reach(jt) || !reach(jf)) {
+                        *far = true;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let mut instructions = Vec::new();
+        let offset = |label: Label, pos: usize| positions[label.0] - pos - 1;
+        for (item, far) in items.iter().zip(far) {
+            let pos = instructions.len();
+            match *item {
+                Item::Bind(_) => {}
+                Item::Stmt(code, k) => instructions.push(BpfInstruction::stmt(code, k)),
+                Item::Ja(label) => {
+                    instructions.push(BpfInstruction::stmt(JMP | JA, offset(label, pos) as u32))
+                }
+                Item::Jump(code, k, jt, jf) if far => {
+                    instructions.push(BpfInstruction {
+                        code,
+                        jt: 0,
+                        jf: 1,
+                        k,
+                    });
+                    let (jt, jf) = (offset(jt, pos + 1), offset(jf, pos + 2));
+                    instructions.push(BpfInstruction::stmt(JMP | JA, jt as u32));
+                    instructions.push(BpfInstruction::stmt(JMP | JA, jf as u32));
+                }
+                Item::Jump(code, k, jt, jf) => instructions.push(BpfInstruction {
+                    code,
+                    jt: offset(jt, pos) as u8,
+                    jf: offset(jf, pos) as u8,
+                    k,
+                }),
+            }
+        }
+        instructions
+    }
+}
+
+/// Condition on the packet, statements loading the accumulator and the jump testing it.
+struct Cond {
+    load: Vec<(u16, u32)>,
+    jump: u16,
+    k: u32,
+}
+
+/// 32 bit word of a field value.
+enum Word {
+    Const(u32),
+    Load(Vec<(u16, u32)>),
+}
+
+/// Value of a field read from the packet.
+enum Source {
+    /// Flag, set when the condition is true.
+    Flag(Cond),
+    /// Integer or address words, most significant first.
+    Words(Vec<Word>),
+}
+
+struct Generator {
+    asm: Assembler,
+    /// Set if the program reads the scratch memory filled by the header parser.
+    headers: bool,
+}
+
+impl Generator {
+    /// Emits the code for `expr`, jumping to `success` or `failure`.
+    ///
+    /// Negations are compiled by swapping the targets, `neg` keeps tests on missing fields false.
+    fn expr(
+        &mut self,
+        expr: &Expr,
+        success: Label,
+        failure: Label,
+        neg: bool,
+    ) -> Result<(), WinDivertError> {
+        match &expr.kind {
+            ExprKind::Bool(value) => self.asm.ja(if *value { success } else { failure }),
+            ExprKind::Field(field) => self.test(expr, field, None, success, failure, neg)?,
+            ExprKind::Compare { field, op, value } => {
+                self.test(expr, field, Some((*op, value)), success, failure, neg)?
+            }
+            ExprKind::Not(expr) => self.expr(expr, failure, success, !neg)?,
+            ExprKind::And(lhs, rhs) => {
+                let next = self.asm.label();
+                self.expr(lhs, next, failure, neg)?;
+                self.asm.bind(next);
+                self.expr(rhs, success, failure, neg)?;
+            }
+            ExprKind::Or(lhs, rhs) => {
+                let next = self.asm.label();
+                self.expr(lhs, success, next, neg)?;
+                self.asm.bind(next);
+                self.expr(rhs, success, failure, neg)?;
+            }
+            ExprKind::Ternary {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let (then_label, otherwise_label) = (self.asm.label(), self.asm.label());
+                self.expr(cond, then_label, otherwise_label, false)?;
+                self.asm.bind(then_label);
+                self.expr(then, success, failure, neg)?;
+                self.asm.bind(otherwise_label);
+                self.expr(otherwise, success, failure, neg)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Emits a test on a field, `compare` is `None` for fields used as booleans.
+    fn test(
+        &mut self,
+        expr: &Expr,
+        field: &FieldRef,
+        compare: Option<(CmpOp, &Value)>,
+        success: Label,
+        failure: Label,
+        neg: bool,
+    ) -> Result<(), WinDivertError> {
+        let (conds, source) = self.field(field).ok_or_else(|| WinDivertError::Filter {
+            message: format!(
+                "field `{}` is not available in BPF programs, which only see the packet data",
+                field.field
+            ),
+            position: expr.span.start,
+        })?;
+
+        let missing = if neg { success } else { failure };
+        for cond in conds {
+            let next = self.asm.label();
+            self.asm.stmts(&cond.load);
+            self.asm.jump(cond.jump, cond.k, next, missing);
+            self.asm.bind(next);
+        }
+
+        let ty = field.field.field_type();
+        let (op, rhs) = match compare {
+            Some((op, value)) => (op, literal(value, ty, WinDivertLayer::Network)),
+            None => (CmpOp::Ne, Scalar::Int(0)),
+        };
+        let target = |result: bool| if result { success } else { failure };
+        match source {
+            Source::Flag(cond) => {
+                let set = target(compare_scalars(Scalar::Int(1), op, rhs));
+                let unset = target(compare_scalars(Scalar::Int(0), op, rhs));
+                if set == unset {
+                    self.asm.ja(set);
+                } else {
+                    self.asm.stmts(&cond.load);
+                    self.asm.jump(cond.jump, cond.k, set, unset);
+                }
+            }
+            Source::Words(words) => {
+                let (less, equal, greater) = match op {
+                    CmpOp::Eq => (failure, success, failure),
+                    CmpOp::Ne => (success, failure, success),
+                    CmpOp::Lt => (success, failure, failure),
+                    CmpOp::Le => (success, success, failure),
+                    CmpOp::Gt => (failure, failure, success),
+                    CmpOp::Ge => (failure, success, success),
+                };
+                let width = words.len() as u32 * 32;
+                // Constants outside of the range of the field have a fixed ordering
+                match rhs {
+                    Scalar::Int(int) if int < 0 => self.asm.ja(greater),
+                    Scalar::Int(int) if width < 128 && int >> width != 0 => self.asm.ja(less),
+                    Scalar::Addr(addr) if width < 128 && addr >> width != 0 => self.asm.ja(less),
+                    Scalar::Int(int) => self.order(words, int as u128, less, equal, greater),
+                    Scalar::Addr(addr) => self.order(words, addr, less, equal, greater),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares the words of a field with `rhs`, jumping to the label of the ordering.
+    fn order(&mut self, words: Vec<Word>, rhs: u128, less: Label, equal: Label, greater: Label) {
+        let count = words.len();
+        for (i, word) in words.into_iter().enumerate() {
+            let k = (rhs >> ((count - 1 - i) * 32)) as u32;
+            let next = if i == count - 1 {
+                equal
+            } else {
+                self.asm.label()
+            };
+            match word {
+                Word::Const(value) if value < k => return self.asm.ja(less),
+                Word::Const(value) if value > k => return self.asm.ja(greater),
+                Word::Const(_) if i == count - 1 => self.asm.ja(equal),
+                Word::Const(_) => {}
+                Word::Load(load) => {
+                    self.asm.stmts(&load);
+                    if less == greater {
+                        self.asm.jump(JEQ | K, k, next, less);
+                    } else if next == greater {
+                        self.asm.jump(JGE | K, k, next, less);
+                    } else if less == next {
+                        self.asm.jump(JGT | K, k, greater, less);
+                    } else {
+                        let not_greater = self.asm.label();
+                        self.asm.jump(JGT | K, k, greater, not_greater);
+                        self.asm.bind(not_greater);
+                        self.asm.jump(JEQ | K, k, next, less);
+                    }
+                }
+            }
+            if i != count - 1 {
+                self.asm.bind(next);
+            }
+        }
+    }
+
+    /// Conditions for the field to exist and its value, `None` for fields not found in packets.
+    fn field(&mut self, field: &FieldRef) -> Option<(Vec<Cond>, Source)> {
+        let memory = |slot| (LD | MEM, slot);
+        let is = |slot, value| Cond {
+            load: vec![memory(slot)],
+            jump: JEQ | K,
+            k: value,
+        };
+        let (ipv4, ipv6) = (is(SLOT_VERSION, 4), is(SLOT_VERSION, 6));
+        let (icmp, icmpv6) = (is(SLOT_PROTO, 1), is(SLOT_PROTO, 58));
+        let (tcp, udp) = (is(SLOT_PROTO, 6), is(SLOT_PROTO, 17));
+        let abs = |size, offset| vec![(LD | size | ABS, offset)];
+        let transport = |size, offset| vec![(LDX | MEM, SLOT_TRANSPORT), (LD | size | IND, offset)];
+        let int = |load| Source::Words(vec![Word::Load(load)]);
+        let masked = |mut load: Vec<_>, shift, mask| {
+            if shift != 0 {
+                load.push((ALU | RSH | K, shift));
+            }
+            load.push((ALU | AND | K, mask));
+            Source::Words(vec![Word::Load(load)])
+        };
+        let flag = |load, bit| {
+            Source::Flag(Cond {
+                load,
+                jump: JSET | K,
+                k: bit,
+            })
+        };
+        let ipv4_addr = |offset| {
+            Source::Words(vec![
+                Word::Const(0),
+                Word::Const(0),
+                Word::Const(0xFFFF),
+                Word::Load(abs(W, offset)),
+            ])
+        };
+        let ipv6_addr = |offset| {
+            Source::Words(
+                (0..4u32)
+                    .map(|i| Word::Load(abs(W, offset + 4 * i)))
+                    .collect(),
+            )
+        };
+        let payload_length = || {
+            int(vec![
+                (LDX | MEM, SLOT_PAYLOAD),
+                (LD | MEM, SLOT_END),
+                (ALU | SUB | X, 0),
+            ])
+        };
+
+        use Field::*;
+        let (conds, source) = match field.field {
+            Zero => (vec![], Source::Words(vec![Word::Const(0)])),
+            Length => (vec![], int(vec![(LD | W | LEN, 0)])),
+            Fragment => (vec![], flag(vec![memory(SLOT_FRAGMENT)], 1)),
+            Ip => (vec![], Source::Flag(ipv4)),
+            Ipv6 => (vec![], Source::Flag(ipv6)),
+            Icmp => (vec![], Source::Flag(icmp)),
+            Icmpv6 => (vec![], Source::Flag(icmpv6)),
+            Tcp => (vec![], Source::Flag(tcp)),
+            Udp => (vec![], Source::Flag(udp)),
+            IpHdrLength => (vec![ipv4], masked(abs(B, 0), 0, 0x0F)),
+            IpTos => (vec![ipv4], int(abs(B, 1))),
+            IpLength => (vec![ipv4], int(abs(H, 2))),
+            IpId => (vec![ipv4], int(abs(H, 4))),
+            IpDf => (vec![ipv4], flag(abs(B, 6), 0x40)),
+            IpMf => (vec![ipv4], flag(abs(B, 6), 0x20)),
+            IpFragOff => (vec![ipv4], masked(abs(H, 6), 0, 0x1FFF)),
+            IpTtl => (vec![ipv4], int(abs(B, 8))),
+            IpProtocol => (vec![ipv4], int(abs(B, 9))),
+            IpChecksum => (vec![ipv4], int(abs(H, 10))),
+            IpSrcAddr => (vec![ipv4], ipv4_addr(12)),
+            IpDstAddr => (vec![ipv4], ipv4_addr(16)),
+            Ipv6TrafficClass => (vec![ipv6], masked(abs(H, 0), 4, 0xFF)),
+            Ipv6FlowLabel => (vec![ipv6], masked(abs(W, 0), 0, 0xF_FFFF)),
+            Ipv6Length => (vec![ipv6], int(abs(H, 4))),
+            Ipv6NextHdr => (vec![ipv6], int(abs(B, 6))),
+            Ipv6HopLimit => (vec![ipv6], int(abs(B, 7))),
+            Ipv6SrcAddr => (vec![ipv6], ipv6_addr(8)),
+            Ipv6DstAddr => (vec![ipv6], ipv6_addr(24)),
+            IcmpType => (vec![icmp], int(transport(B, 0))),
+            IcmpCode => (vec![icmp], int(transport(B, 1))),
+            IcmpChecksum => (vec![icmp], int(transport(H, 2))),
+            IcmpBody => (vec![icmp], int(transport(W, 4))),
+            Icmpv6Type => (vec![icmpv6], int(transport(B, 0))),
+            Icmpv6Code => (vec![icmpv6], int(transport(B, 1))),
+            Icmpv6Checksum => (vec![icmpv6], int(transport(H, 2))),
+            Icmpv6Body => (vec![icmpv6], int(transport(W, 4))),
+            TcpSrcPort => (vec![tcp], int(transport(H, 0))),
+            TcpDstPort => (vec![tcp], int(transport(H, 2))),
+            TcpSeqNum => (vec![tcp], int(transport(W, 4))),
+            TcpAckNum => (vec![tcp], int(transport(W, 8))),
+            TcpHdrLength => (vec![tcp], masked(transport(B, 12), 4, 0x0F)),
+            TcpUrg => (vec![tcp], flag(transport(B, 13), 0x20)),
+            TcpAck => (vec![tcp], flag(transport(B, 13), 0x10)),
+            TcpPsh => (vec![tcp], flag(transport(B, 13), 0x08)),
+            TcpRst => (vec![tcp], flag(transport(B, 13), 0x04)),
+            TcpSyn => (vec![tcp], flag(transport(B, 13), 0x02)),
+            TcpFin => (vec![tcp], flag(transport(B, 13), 0x01)),
+            TcpWindow => (vec![tcp], int(transport(H, 14))),
+            TcpChecksum => (vec![tcp], int(transport(H, 16))),
+            TcpUrgPtr => (vec![tcp], int(transport(H, 18))),
+            TcpPayloadLength => (vec![tcp], payload_length()),
+            UdpSrcPort => (vec![udp], int(transport(H, 0))),
+            UdpDstPort => (vec![udp], int(transport(H, 2))),
+            UdpLength => (vec![udp], int(transport(H, 4))),
+            UdpChecksum => (vec![udp], int(transport(H, 6))),
+            UdpPayloadLength => (vec![udp], payload_length()),
+            Packet | Packet16 | Packet32 => return Some(packet(field)),
+            TcpPayload | TcpPayload16 | TcpPayload32 => {
+                let (mut conds, source) = payload(field);
+                conds.insert(0, tcp);
+                (conds, source)
+            }
+            UdpPayload | UdpPayload16 | UdpPayload32 => {
+                let (mut conds, source) = payload(field);
+                conds.insert(0, udp);
+                (conds, source)
+            }
+            Timestamp | Event | Random8 | Random16 | Random32 | Outbound | Inbound | IfIdx
+            | SubIfIdx | Loopback | Impostor | ProcessId | LocalAddr | LocalPort | RemoteAddr
+            | RemotePort | Protocol | EndpointId | ParentEndpointId | Layer | Priority => {
+                return None
+            }
+        };
+        self.headers |= !matches!(field.field, Zero | Length);
+        Some((conds, source))
+    }
+
+    /// Fills the scratch memory slots from the IP and transport headers, then continues with
+    /// `body`. Follows the parser of the filter evaluator.
+    fn parse_headers(&mut self, body: Label) {
+        let asm = &mut self.asm;
+        asm.stmt(LD | IMM, 0);
+        for slot in [SLOT_VERSION, SLOT_PROTO, SLOT_FRAGMENT, SLOT_NEXT, SLOT_TMP] {
+            asm.stmt(ST, slot);
+        }
+        asm.stmt(LD | W | LEN, 0);
+        for slot in [SLOT_END, SLOT_TRANSPORT, SLOT_PAYLOAD] {
+            asm.stmt(ST, slot);
+        }
+        let (version, not_ipv4, ipv4, ipv6, transport) = (
+            asm.label(),
+            asm.label(),
+            asm.label(),
+            asm.label(),
+            asm.label(),
+        );
+        asm.jump(JGE | K, 20, version, body);
+        asm.bind(version);
+        asm.stmt(LD | B | ABS, 0);
+        asm.stmt(ALU | RSH | K, 4);
+        asm.jump(JEQ | K, 4, ipv4, not_ipv4);
+        asm.bind(not_ipv4);
+        asm.jump(JEQ | K, 6, ipv6, body);
+
+        // IPv4, the transport header follows the options of non fragmented packets
+        asm.bind(ipv4);
+        let (length, valid, first) = (asm.label(), asm.label(), asm.label());
+        asm.stmt(LD | B | ABS, 0);
+        asm.stmt(ALU | AND | K, 0x0F);
+        asm.stmt(ALU | LSH | K, 2);
+        asm.stmt(ST, SLOT_TRANSPORT);
+        asm.jump(JGE | K, 20, length, body);
+        asm.bind(length);
+        asm.stmt(LD | H | ABS, 2);
+        end(asm);
+        asm.stmt(LDX | MEM, SLOT_TRANSPORT);
+        asm.jump(JGE | X, 0, valid, body);
+        asm.bind(valid);
+        asm.stmt(LD | IMM, 4);
+        asm.stmt(ST, SLOT_VERSION);
+        let (not_fragment, fragment) = (asm.label(), asm.label());
+        asm.stmt(LD | H | ABS, 6);
+        asm.stmt(ALU | AND | K, 0x3FFF);
+        asm.jump(JEQ | K, 0, not_fragment, fragment);
+        asm.bind(fragment);
+        asm.stmt(LD | IMM, 1);
+        asm.stmt(ST, SLOT_FRAGMENT);
+        asm.bind(not_fragment);
+        asm.stmt(LD | H | ABS, 6);
+        asm.stmt(ALU | AND | K, 0x1FFF);
+        asm.jump(JEQ | K, 0, first, body);
+        asm.bind(first);
+        asm.stmt(LD | B | ABS, 9);
+        asm.stmt(ST, SLOT_NEXT);
+        asm.ja(transport);
+
+        // IPv6, extension headers are skipped up to MAX_EXTENSIONS
+        asm.bind(ipv6);
+        let length = asm.label();
+        asm.stmt(LD | W | LEN, 0);
+        asm.jump(JGE | K, 40, length, body);
+        asm.bind(length);
+        asm.stmt(LD | H | ABS, 4);
+        asm.stmt(ALU | ADD | K, 40);
+        end(asm);
+        asm.stmt(LD | IMM, 6);
+        asm.stmt(ST, SLOT_VERSION);
+        asm.stmt(LD | IMM, 40);
+        asm.stmt(ST, SLOT_TRANSPORT);
+        asm.stmt(LD | B | ABS, 6);
+        asm.stmt(ST, SLOT_NEXT);
+        for _ in 0..MAX_EXTENSIONS {
+            let (extension, header, not_ah, not_fragment, ah, fragment, first, skip, advance) = (
+                asm.label(),
+                asm.label(),
+                asm.label(),
+                asm.label(),
+                asm.label(),
+                asm.label(),
+                asm.label(),
+                asm.label(),
+                asm.label(),
+            );
+            asm.stmt(LD | MEM, SLOT_NEXT);
+            for protocol in [0, 43, 60, 51] {
+                let next = asm.label();
+                asm.jump(JEQ | K, protocol, extension, next);
+                asm.bind(next);
+            }
+            asm.jump(JEQ | K, 44, extension, transport);
+            asm.bind(extension);
+            available(asm, 8, header, body);
+            asm.bind(header);
+            asm.stmt(LDX | MEM, SLOT_TRANSPORT);
+            asm.stmt(LD | MEM, SLOT_NEXT);
+            asm.jump(JEQ | K, 51, ah, not_ah);
+            asm.bind(not_ah);
+            asm.jump(JEQ | K, 44, fragment, not_fragment);
+            asm.bind(not_fragment);
+            asm.stmt(LD | B | IND, 1);
+            asm.stmt(ALU | ADD | K, 1);
+            asm.stmt(ALU | LSH | K, 3);
+            asm.ja(skip);
+            asm.bind(ah);
+            asm.stmt(LD | B | IND, 1);
+            asm.stmt(ALU | ADD | K, 2);
+            asm.stmt(ALU | LSH | K, 2);
+            asm.ja(skip);
+            asm.bind(fragment);
+            asm.stmt(LD | IMM, 1);
+            asm.stmt(ST, SLOT_FRAGMENT);
+            asm.stmt(LD | H | IND, 2);
+            asm.stmt(ALU | AND | K, 0xFFF8);
+            asm.jump(JEQ | K, 0, first, body);
+            asm.bind(first);
+            asm.stmt(LD | IMM, 8);
+            // The header length is in A and its offset in X
+            asm.bind(skip);
+            asm.stmt(ALU | ADD | X, 0);
+            asm.stmt(ST, SLOT_TMP);
+            asm.stmt(LDX | MEM, SLOT_TMP);
+            asm.stmt(LD | MEM, SLOT_END);
+            asm.jump(JGE | X, 0, advance, body);
+            asm.bind(advance);
+            asm.stmt(LDX | MEM, SLOT_TRANSPORT);
+            asm.stmt(LD | B | IND, 0);
+            asm.stmt(ST, SLOT_NEXT);
+            asm.stmt(LD | MEM, SLOT_TMP);
+            asm.stmt(ST, SLOT_TRANSPORT);
+        }
+
+        // Transport header, its offset is in SLOT_TRANSPORT and its protocol in SLOT_NEXT
+        asm.bind(transport);
+        let (tcp, udp, icmp, icmpv6) = (asm.label(), asm.label(), asm.label(), asm.label());
+        asm.stmt(LD | MEM, SLOT_NEXT);
+        for (protocol, label) in [(6, tcp), (17, udp), (1, icmp)] {
+            let next = asm.label();
+            asm.jump(JEQ | K, protocol, label, next);
+            asm.bind(next);
+        }
+        asm.jump(JEQ | K, 58, icmpv6, body);
+
+        let (header, length, valid) = (asm.label(), asm.label(), asm.label());
+        asm.bind(tcp);
+        available(asm, 20, header, body);
+        asm.bind(header);
+        asm.stmt(LDX | MEM, SLOT_TRANSPORT);
+        asm.stmt(LD | B | IND, 12);
+        asm.stmt(ALU | RSH | K, 4);
+        asm.stmt(ALU | LSH | K, 2);
+        asm.jump(JGE | K, 20, length, body);
+        asm.bind(length);
+        asm.stmt(ALU | ADD | X, 0);
+        asm.stmt(ST, SLOT_PAYLOAD);
+        asm.stmt(MISC | TAX, 0);
+        asm.stmt(LD | MEM, SLOT_END);
+        asm.jump(JGE | X, 0, valid, body);
+        asm.bind(valid);
+        asm.stmt(LD | IMM, 6);
+        asm.stmt(ST, SLOT_PROTO);
+        asm.ja(body);
+
+        for (label, protocol, version) in
+            [(udp, 17, None), (icmp, 1, Some(4)), (icmpv6, 58, Some(6))]
+        {
+            asm.bind(label);
+            if let Some(version) = version {
+                let next = asm.label();
+                asm.stmt(LD | MEM, SLOT_VERSION);
+                asm.jump(JEQ | K, version, next, body);
+                asm.bind(next);
+            }
+            let valid = asm.label();
+            asm.stmt(LD | MEM, SLOT_TRANSPORT);
+            asm.stmt(ALU | ADD | K, 8);
+            asm.stmt(ST, SLOT_PAYLOAD);
+            asm.stmt(MISC | TAX, 0);
+            asm.stmt(LD | MEM, SLOT_END);
+            asm.jump(JGE | X, 0, valid, body);
+            asm.bind(valid);
+            asm.stmt(LD | IMM, protocol);
+            asm.stmt(ST, SLOT_PROTO);
+            asm.ja(body);
+        }
+    }
+}
+
+/// Stores the end of the IP packet, the length in A clamped to the packet length.
+fn end(asm: &mut Assembler) {
+    let (clamp, store) = (asm.label(), asm.label());
+    asm.stmt(MISC | TAX, 0);
+    asm.stmt(LD | W | LEN, 0);
+    asm.jump(JGT | X, 0, clamp, store);
+    asm.bind(clamp);
+    asm.stmt(MISC | TXA, 0);
+    asm.bind(store);
+    asm.stmt(ST, SLOT_END);
+}
+
+/// Jumps to `success` if `len` bytes are left after the offset in `SLOT_TRANSPORT`.
+fn available(asm: &mut Assembler, len: u32, success: Label, failure: Label) {
+    asm.stmt(LD | MEM, SLOT_TRANSPORT);
+    asm.stmt(ALU | ADD | K, len);
+    asm.stmt(MISC | TAX, 0);
+    asm.stmt(LD | MEM, SLOT_END);
+    asm.jump(JGE | X, 0, success, failure);
+}
+
+fn width(field: Field) -> u32 {
+    field.index_width() as u32
+}
+
+fn size(width: u32) -> u16 {
+    match width {
+        1 => B,
+        2 => H,
+        _ => W,
+    }
+}
+
+/// `packet[i]`, negative indexes count from the end of the packet.
+fn packet(field: &FieldRef) -> (Vec<Cond>, Source) {
+    let width = width(field.field);
+    let index = field.index.unwrap_or_default() as i64;
+    let never = Cond {
+        load: vec![(LD | IMM, 0)],
+        jump: JEQ | K,
+        k: 1,
+    };
+    let (cond, load) = if index >= 0 {
+        let cond = Cond {
+            load: vec![(LD | W | LEN, 0)],
+            jump: JGE | K,
+            k: index as u32 + width,
+        };
+        (cond, vec![(LD | size(width) | ABS, index as u32)])
+    } else if -index < width as i64 {
+        (never, vec![])
+    } else {
+        let back = (-index) as u32;
+        let cond = Cond {
+            load: vec![(LD | W | LEN, 0)],
+            jump: JGE | K,
+            k: back,
+        };
+        let load = vec![
+            (LD | W | LEN, 0),
+            (ALU | SUB | K, back),
+            (MISC | TAX, 0),
+            (LD | size(width) | IND, 0),
+        ];
+        (cond, load)
+    };
+    (vec![cond], Source::Words(vec![Word::Load(load)]))
+}
+
+/// `tcp.Payload[i]` and `udp.Payload[i]`, negative indexes count from the end of the payload.
+fn payload(field: &FieldRef) -> (Vec<Cond>, Source) {
+    let width = width(field.field);
+    let index = field.index.unwrap_or_default() as i64;
+    let never = Cond {
+        load: vec![(LD | IMM, 0)],
+        jump: JEQ | K,
+        k: 1,
+    };
+    // The payload spans from SLOT_PAYLOAD to SLOT_END
+    let fits = |len: u32| Cond {
+        load: vec![
+            (LD | MEM, SLOT_PAYLOAD),
+            (ALU | ADD | K, len),
+            (MISC | TAX, 0),
+            (LD | MEM, SLOT_END),
+        ],
+        jump: JGE | X,
+        k: 0,
+    };
+    let (cond, load) = if index >= 0 {
+        let load = vec![
+            (LDX | MEM, SLOT_PAYLOAD),
+            (LD | size(width) | IND, index as u32),
+        ];
+        (fits(index as u32 + width), load)
+    } else if -index < width as i64 {
+        (never, vec![])
+    } else {
+        let back = (-index) as u32;
+        let load = vec![
+            (LD | MEM, SLOT_END),
+            (ALU | SUB | K, back),
+            (MISC | TAX, 0),
+            (LD | size(width) | IND, 0),
+        ];
+        (fits(back), load)
+    };
+    (vec![cond], Source::Words(vec![Word::Load(load)]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{
+            eval::{eval, Context},
+            testing::{random_packet, Rng},
+        },
+        *,
+    };
+
+    #[test]
+    fn rejects_address_fields() {
+        for (filter, position) in [
+            ("tcp and ifIdx == 1", 8),
+            ("outbound", 0),
+            ("not (loopback or udp)", 5),
+            ("tcp.DstPort == 80 or timestamp > 0", 21),
+            ("random8 < 128", 0),
+        ] {
+            let error = Filter::parse(filter).unwrap().to_bpf().unwrap_err();
+            match error {
+                WinDivertError::Filter {
+                    message,
+                    position: at,
+                } => {
+                    assert!(
+                        message.contains("not available in BPF programs"),
+                        "{message}"
+                    );
+                    assert_eq!(at, position, "{filter}");
+                }
+                error => panic!("{filter}: unexpected {error:?}"),
+            }
+        }
+        // Rejected by the network layer validation
+        let error = Filter::parse("processId == 4")
+            .unwrap()
+            .to_bpf()
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("not available on the network layer"));
+    }
+
+    /// Filters whose BPF program must give the verdicts of the evaluator.
+    const FILTERS: &[&str] = &[
+        "true",
+        "false",
+        "ip",
+        "ipv6",
+        "tcp",
+        "udp",
+        "icmp",
+        "icmpv6",
+        "not tcp and not udp",
+        "fragment",
+        "not fragment and ip",
+        "length < 40",
+        "length >= 60 or zero == 1",
+        "ip.HdrLength > 5",
+        "ip.TOS == 64 or ip.TTL <= 1",
+        "ip.Length < 40 or ip.Length > 60",
+        "ip.Id < 0x8000",
+        "ip.DF and not ip.MF",
+        "ip.MF or ip.FragOff != 0",
+        "ip.Protocol == 6 and not tcp",
+        "ip.Checksum > 0x7FFF",
+        "ip.SrcAddr == 10.0.0.1",
+        "ip.DstAddr >= 10.0.0.0 and ip.DstAddr <= 10.255.255.255",
+        "ip.DstAddr > 192.168.1.1 or ip.SrcAddr < 1.0.0.0",
+        "ipv6.TrafficClass >= 128",
+        "ipv6.FlowLabel == 0 or ipv6.FlowLabel > 0x80000",
+        "ipv6.Length < 20",
+        "ipv6.NextHdr == 44 or ipv6.NextHdr == 0",
+        "ipv6.HopLimit == 255",
+        "ipv6.SrcAddr == ::1",
+        "ipv6.DstAddr >= fe80:: and ipv6.DstAddr < ff00::",
+        "ipv6.SrcAddr > ::ffff:0.0.0.0 or ipv6.DstAddr == 2001:db8::1",
+        "icmp.Type == 8 and icmp.Code == 0",
+        "icmp.Checksum != 0 or icmp.Body > 0x10000",
+        "icmpv6.Type == 128 or icmpv6.Code > 5",
+        "icmpv6.Checksum < 100 or icmpv6.Body == 0",
+        "tcp.SrcPort == 443 or tcp.DstPort == 443",
+        "tcp.DstPort >= 1024 and tcp.DstPort != 8080",
+        "tcp.SeqNum > 0x80000000 or tcp.AckNum == 0",
+        "tcp.HdrLength == 6",
+        "tcp.Syn and not tcp.Ack",
+        "tcp.Urg or tcp.Psh or tcp.Rst or tcp.Fin",
+        "tcp.Window < 100 or tcp.Checksum == 0 or tcp.UrgPtr != 0",
+        "tcp.PayloadLength == 0",
+        "tcp.PayloadLength > 4 and tcp.Payload[4] != 0",
+        "tcp.Payload16[-2] == 0 or tcp.Payload32[0] > 0x7FFFFFFF",
+        "udp.SrcPort == 53 or udp.DstPort == 53",
+        "udp.Length < 16 and udp.Checksum != 0",
+        "udp.PayloadLength >= 8",
+        "udp.Payload[0] < 128 or udp.Payload16[6b] == 0 or udp.Payload32[-1] == 1",
+        "packet[0] == 0x45",
+        "packet[-1] > 128 or packet[-4] == 0",
+        "packet16[2] < 100 or packet16[-2] != 0",
+        "packet32[3b] == 0 or packet32[-1] != 0",
+        "not (tcp.DstPort == 80 or udp.DstPort == 53)",
+        "not tcp.Syn or not ip.DF",
+        "tcp.DstPort > 65535 or udp.DstPort < 0 or ip.TTL >= 0",
+        "not ip.SrcAddr == 10.0.0.1 or not ipv6.SrcAddr < fe80::",
+        "(tcp ? tcp.DstPort == 80 : udp.DstPort == 53) or icmp",
+        "(ip.MF ? ip.FragOff == 0 : not tcp.Syn) and length > 20",
+        "(tcp.PayloadLength == 0 ? tcp.Fin : tcp.Payload[0] == 0x16)",
+    ];
+
+    #[test]
+    fn random_packets() {
+        let mut rng = Rng::new(0x5EED_0010);
+        let packets: Vec<_> = (0..300_000).map(|_| random_packet(&mut rng)).collect();
+        let contexts: Vec<_> = packets
+            .iter()
+            .map(|packet| Context::new(&packet.data, packet.address.as_ref()))
+            .collect();
+        for filter in FILTERS {
+            let filter = Filter::parse(filter).unwrap();
+            let program = filter.to_bpf().unwrap();
+            for (packet, ctx) in packets.iter().zip(&contexts) {
+                assert_eq!(
+                    program.matches(&packet.data),
+                    eval(filter.expr(), ctx, false),
+                    "{filter} on {:02x?}",
+                    packet.data
+                );
+            }
+        }
+    }
+}
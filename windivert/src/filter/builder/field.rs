@@ -0,0 +1,380 @@
+/*!
+Typed filter fields.
+
+Every [`Field`] has a matching type here, generated from the [catalog](crate::filter::fields),
+whose methods only accept values of the field type and which implements [`AvailableOn`] for the
+layers that support it. Indexed fields take the byte index, so `packet[-4]` is written as
+`Packet(-4)`.
+*/
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use windivert_sys::{WinDivertEvent, WinDivertLayer};
+
+use super::{address, event_value, in_cidr, layer_value, FilterBuilder, IpSet};
+use crate::error::IpSetTooLarge;
+use crate::filter::{CmpOp, Field, FieldRef, Value};
+#[cfg(test)]
+use crate::layer::TypedLayer;
+use crate::layer::{FlowLayer, ForwardLayer, NetworkLayer, ReflectLayer, SocketLayer};
+
+/// Implemented by the fields that can be used in filters for the layer `L`.
+pub trait AvailableOn<L> {}
+
+macro_rules! fields {
+    ($(
+        #[doc = $doc:literal]
+        $variant:ident => $name:literal, $ty:ident, $layers:ident $(, $indexed:ident)?;
+    )*) => {$(
+        fields!(@field $variant, $name, $doc, $ty, $layers $(, $indexed)?);
+        fields!(@layers $variant, $layers);
+    )*
+
+        /// Layers of the [`AvailableOn`] implementations of every field.
+        #[cfg(test)]
+        fn available_layers(field: Field) -> &'static [WinDivertLayer] {
+            match field {
+                $(Field::$variant => $variant::LAYERS,)*
+            }
+        }
+    };
+
+    (@field $variant:ident, $name:literal, $doc:literal, $ty:ident, $layers:ident) => {
+        #[doc = concat!("`", $name, "`:", $doc)]
+        #[derive(Debug, Copy, Clone)]
+        pub struct $variant;
+
+        impl $variant {
+            fields!(@methods $ty);
+
+            fn field_ref(self) -> FieldRef {
+                FieldRef::new(Field::$variant)
+            }
+        }
+    };
+    (@field $variant:ident, $name:literal, $doc:literal, $ty:ident, $layers:ident, indexed) => {
+        #[doc = concat!("`", $name, "[i]`:", $doc, " Takes the byte offset of the value.")]
+        #[derive(Debug, Copy, Clone)]
+        pub struct $variant(pub i32);
+
+        impl $variant {
+            fields!(@methods $ty);
+
+            fn field_ref(self) -> FieldRef {
+                FieldRef::indexed(Field::$variant, self.0)
+            }
+        }
+    };
+
+    (@layers $name:ident, ALL) => {
+        fields!(@layers $name, [NetworkLayer, ForwardLayer, FlowLayer, SocketLayer, ReflectLayer]);
+    };
+    (@layers $name:ident, PACKET) => {
+        fields!(@layers $name, [NetworkLayer, ForwardLayer]);
+    };
+    (@layers $name:ident, DIRECTION) => {
+        fields!(@layers $name, [NetworkLayer, FlowLayer, SocketLayer]);
+    };
+    (@layers $name:ident, NOT_REFLECT) => {
+        fields!(@layers $name, [NetworkLayer, ForwardLayer, FlowLayer, SocketLayer]);
+    };
+    (@layers $name:ident, PROCESS) => {
+        fields!(@layers $name, [FlowLayer, SocketLayer, ReflectLayer]);
+    };
+    (@layers $name:ident, ENDPOINT) => {
+        fields!(@layers $name, [FlowLayer, SocketLayer]);
+    };
+    (@layers $name:ident, REFLECT) => {
+        fields!(@layers $name, [ReflectLayer]);
+    };
+    (@layers $name:ident, [$($layer:ident),+]) => {
+        $(impl AvailableOn<$layer> for $name {})+
+
+        #[cfg(test)]
+        impl $name {
+            const LAYERS: &'static [WinDivertLayer] = &[$(<$layer as TypedLayer>::LAYER),+];
+        }
+    };
+
+    (@methods U8) => { fields!(@methods Int(u8)); };
+    (@methods IcmpType) => { fields!(@methods Int(u8)); };
+    (@methods U16) => { fields!(@methods Int(u16)); };
+    (@methods U32) => { fields!(@methods Int(u32)); };
+    (@methods U64) => { fields!(@methods Int(u64)); };
+    (@methods I16) => { fields!(@methods Int(i16)); };
+    (@methods I64) => { fields!(@methods Int(i64)); };
+    (@methods Ipv4) => { fields!(@methods Addr(Ipv4Addr)); };
+    (@methods Ipv6) => { fields!(@methods Addr(Ipv6Addr)); };
+    (@methods IpAddr) => { fields!(@methods Addr(IpAddr)); };
+    (@methods Bool) => {
+        /// Matches when the flag is set.
+        pub fn is_true<L>(self) -> FilterBuilder<L>
+        where
+            Self: AvailableOn<L>,
+        {
+            FilterBuilder::field(self.field_ref())
+        }
+
+        /// Matches when the flag is not set.
+        pub fn is_false<L>(self) -> FilterBuilder<L>
+        where
+            Self: AvailableOn<L>,
+        {
+            FilterBuilder::compare(self.field_ref(), CmpOp::Eq, Value::Int(0))
+        }
+    };
+    (@methods Int($value:ty)) => {
+        fields!(@compare $value, int);
+
+        /// Matches values between `start` and `end`, both included.
+        pub fn in_range<L>(self, start: $value, end: $value) -> FilterBuilder<L>
+        where
+            Self: AvailableOn<L>,
+        {
+            self.ge(start).and(self.le(end))
+        }
+    };
+    (@methods Addr($value:ty)) => {
+        fields!(@compare impl Into<$value>, addr::<$value>);
+
+        /**
+        Matches the addresses of `cidr`, written as `address/prefix` or a single address.
+
+        An invalid CIDR, or one of the wrong address family, is reported by
+        [`FilterBuilder::build()`].
+        */
+        pub fn in_cidr<L>(self, cidr: &str) -> FilterBuilder<L>
+        where
+            Self: AvailableOn<L>,
+        {
+            let (ipv4, ipv6) = <$value as Address>::FAMILIES;
+            in_cidr(self.field_ref(), cidr, ipv4, ipv6)
+        }
+
+        /**
+        Matches the addresses of `set`, ignoring the prefixes of the other address family if the
+        field only holds IPv4 or IPv6 addresses.
+
+        Fails if the filter would need more tests than a
+        [filter object](crate::filter::FilterObject) can hold, in which case the set has to be
+        matched in userspace with [`IpSet::contains()`].
+        */
+        pub fn in_set<L>(self, set: &IpSet) -> Result<FilterBuilder<L>, IpSetTooLarge>
+        where
+            Self: AvailableOn<L>,
+        {
+            set.compile(self.field_ref())
+        }
+    };
+    (@methods Event) => {
+        fields!(@equality WinDivertEvent, event_value);
+    };
+    (@methods Layer) => {
+        fields!(@equality WinDivertLayer, layer_value);
+    };
+
+    (@equality $value:ty, $convert:path) => {
+        /// Matches when the field equals `value`.
+        pub fn eq<L>(self, value: $value) -> FilterBuilder<L>
+        where
+            Self: AvailableOn<L>,
+        {
+            FilterBuilder::compare(self.field_ref(), CmpOp::Eq, $convert(value))
+        }
+
+        /// Matches when the field differs from `value`.
+        pub fn ne<L>(self, value: $value) -> FilterBuilder<L>
+        where
+            Self: AvailableOn<L>,
+        {
+            FilterBuilder::compare(self.field_ref(), CmpOp::Ne, $convert(value))
+        }
+    };
+    (@compare $value:ty, $convert:path) => {
+        fields!(@equality $value, $convert);
+
+        /// Matches when the field is lower than `value`.
+        pub fn lt<L>(self, value: $value) -> FilterBuilder<L>
+        where
+            Self: AvailableOn<L>,
+        {
+            FilterBuilder::compare(self.field_ref(), CmpOp::Lt, $convert(value))
+        }
+
+        /// Matches when the field is lower than or equal to `value`.
+        pub fn le<L>(self, value: $value) -> FilterBuilder<L>
+        where
+            Self: AvailableOn<L>,
+        {
+            FilterBuilder::compare(self.field_ref(), CmpOp::Le, $convert(value))
+        }
+
+        /// Matches when the field is greater than `value`.
+        pub fn gt<L>(self, value: $value) -> FilterBuilder<L>
+        where
+            Self: AvailableOn<L>,
+        {
+            FilterBuilder::compare(self.field_ref(), CmpOp::Gt, $convert(value))
+        }
+
+        /// Matches when the field is greater than or equal to `value`.
+        pub fn ge<L>(self, value: $value) -> FilterBuilder<L>
+        where
+            Self: AvailableOn<L>,
+        {
+            FilterBuilder::compare(self.field_ref(), CmpOp::Ge, $convert(value))
+        }
+    };
+}
+
+/// Address types accepted by address fields.
+trait Address: Into<IpAddr> {
+    /// Whether IPv4 and IPv6 CIDRs are accepted.
+    const FAMILIES: (bool, bool);
+}
+
+impl Address for Ipv4Addr {
+    const FAMILIES: (bool, bool) = (true, false);
+}
+
+impl Address for Ipv6Addr {
+    const FAMILIES: (bool, bool) = (false, true);
+}
+
+impl Address for IpAddr {
+    const FAMILIES: (bool, bool) = (true, true);
+}
+
+fn int(value: impl Into<i128>) -> Value {
+    Value::Int(value.into())
+}
+
+fn addr<A: Address>(value: impl Into<A>) -> Value {
+    let value: A = value.into();
+    address(value.into())
+}
+
+windivert_filter::field_table!(fields);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::validate::filter_layer;
+
+    fn render<L: TypedLayer>(filter: FilterBuilder<L>) -> String {
+        filter.build().unwrap().to_string()
+    }
+
+    #[test]
+    fn integers() {
+        let filter = TcpDstPort.eq(443).or(TcpDstPort.in_range(8000, 8080));
+        assert_eq!(
+            render::<NetworkLayer>(filter),
+            "tcp.DstPort == 443 or tcp.DstPort >= 8000 and tcp.DstPort <= 8080"
+        );
+        let filter = IpTtl.lt(64).and(Ipv6FlowLabel.ne(7)).and(Length.gt(1500));
+        assert_eq!(
+            render::<ForwardLayer>(filter),
+            "ip.TTL < 64 and ipv6.FlowLabel != 7 and length > 1500"
+        );
+        let filter = Timestamp.le(-5).or(EndpointId.ge(u64::MAX));
+        assert_eq!(
+            render::<FlowLayer>(filter),
+            "timestamp <= -5 or endpointId >= 18446744073709551615"
+        );
+        assert_eq!(
+            render::<ReflectLayer>(Priority.eq(i16::MIN)),
+            "priority == -32768"
+        );
+    }
+
+    #[test]
+    fn indexed() {
+        let filter = Packet32(-4).eq(1).and(TcpPayload(0).eq(0x16));
+        assert_eq!(
+            render::<NetworkLayer>(filter),
+            "packet32[-1] == 1 and tcp.Payload[0] == 22"
+        );
+        assert_eq!(
+            render::<NetworkLayer>(UdpPayload16(3).ne(0)),
+            "udp.Payload16[3b] != 0"
+        );
+    }
+
+    #[test]
+    fn addresses() {
+        let filter = IpSrcAddr
+            .eq(Ipv4Addr::new(10, 0, 0, 1))
+            .or(IpDstAddr.in_cidr("192.168.0.0/16"));
+        assert_eq!(
+            render::<NetworkLayer>(filter),
+            "ip.SrcAddr == 10.0.0.1 or \
+             ip.DstAddr >= 192.168.0.0 and ip.DstAddr <= 192.168.255.255"
+        );
+        assert_eq!(
+            render::<NetworkLayer>(Ipv6DstAddr.eq(Ipv6Addr::LOCALHOST)),
+            "ipv6.DstAddr == ::1"
+        );
+        let filter = RemoteAddr
+            .eq(Ipv4Addr::new(1, 2, 3, 4))
+            .or(LocalAddr.in_cidr("fe80::/64"));
+        assert_eq!(
+            render::<SocketLayer>(filter),
+            "remoteAddr == 1.2.3.4 or \
+             localAddr >= fe80:: and localAddr <= fe80::ffff:ffff:ffff:ffff"
+        );
+
+        let error = |filter: FilterBuilder<NetworkLayer>| filter.build().unwrap_err().to_string();
+        assert_eq!(
+            error(IpSrcAddr.in_cidr("::1/128")),
+            "Invalid filter: CIDR `::1/128` doesn't match the address family of field \
+             `ip.SrcAddr` (at position 0)"
+        );
+        assert_eq!(
+            error(IpSrcAddr.in_cidr("10.0.0.0/33")),
+            "Invalid filter: invalid CIDR `10.0.0.0/33` (at position 0)"
+        );
+    }
+
+    #[test]
+    fn flags() {
+        let filter = Tcp.is_true().and(TcpSyn.is_true()).and(TcpAck.is_false());
+        assert_eq!(
+            render::<NetworkLayer>(filter),
+            "tcp and tcp.Syn and tcp.Ack == 0"
+        );
+        assert_eq!(render::<FlowLayer>(!Outbound.is_true()), "not outbound");
+    }
+
+    #[test]
+    fn events_and_layers() {
+        assert_eq!(
+            render::<SocketLayer>(Event.eq(WinDivertEvent::SocketConnect)),
+            "event == CONNECT"
+        );
+        let filter = Event
+            .ne(WinDivertEvent::ReflectOpen)
+            .and(Layer.eq(WinDivertLayer::Forward));
+        assert_eq!(
+            render::<ReflectLayer>(filter),
+            "event != OPEN and layer == NETWORK_FORWARD"
+        );
+        // Events are checked against the layer when building
+        let filter = Event.eq::<NetworkLayer>(WinDivertEvent::SocketConnect);
+        assert_eq!(
+            filter.build().unwrap_err().to_string(),
+            "Invalid filter: event `CONNECT` is not available on the network layer (at position 0)"
+        );
+    }
+
+    #[test]
+    fn layers_match_catalog() {
+        for field in Field::ALL {
+            let layers = available_layers(*field);
+            assert_eq!(layers.len(), field.info().layers.len(), "{field}");
+            for layer in layers {
+                assert!(field.available_on(filter_layer(*layer)), "{field}");
+            }
+        }
+    }
+}
@@ -0,0 +1,436 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::RangeInclusive,
+};
+
+use super::{prefix, FilterBuilder};
+use crate::{
+    error::{IpSetTooLarge, WinDivertError},
+    filter::{CmpOp, FieldRef, FieldType, FilterObject, Value},
+};
+
+/**
+Set of IPv4 and IPv6 prefixes, stored as a prefix trie.
+
+Overlapping prefixes are merged when inserted, as are sibling prefixes covering their whole parent,
+so `10.0.0.0/25` and `10.0.0.128/25` become `10.0.0.0/24`. The set is turned into a filter with
+the `in_set()` method of the address [fields](super::field), which emits one range clause per run
+of consecutive addresses instead of one comparison per prefix.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpSet {
+    ipv4: Node,
+    ipv6: Node,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Node {
+    /// Set when every address below the node is in the set.
+    full: bool,
+    children: [Option<Box<Node>>; 2],
+}
+
+impl IpSet {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `true` if the set holds no address.
+    pub fn is_empty(&self) -> bool {
+        self.ipv4 == Node::default() && self.ipv6 == Node::default()
+    }
+
+    /// Adds the addresses of `addr/prefix`. Prefixes longer than the address are treated as a
+    /// single address.
+    pub fn insert(&mut self, addr: IpAddr, prefix: u8) {
+        let (node, addr, bits) = match addr {
+            IpAddr::V4(addr) => (&mut self.ipv4, u128::from(u32::from(addr)), 32),
+            IpAddr::V6(addr) => (&mut self.ipv6, u128::from(addr), 128),
+        };
+        node.insert(addr, 0, u32::from(prefix).min(bits), bits);
+    }
+
+    /**
+    Adds the addresses of `cidr`, written as `address/prefix` or a single address.
+
+    Fails with [`WinDivertError::InvalidCidr`] if `cidr` can't be parsed or its prefix is longer
+    than the address.
+    */
+    pub fn insert_cidr(&mut self, cidr: &str) -> Result<(), WinDivertError> {
+        let (addr, prefix) =
+            prefix(cidr).map_err(|_| WinDivertError::InvalidCidr(cidr.to_owned()))?;
+        self.insert(addr, prefix as u8);
+        Ok(())
+    }
+
+    /// Returns `true` if `addr` is in the set.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match addr {
+            IpAddr::V4(addr) => self.ipv4.contains(u128::from(u32::from(addr)), 32),
+            IpAddr::V6(addr) => self.ipv6.contains(u128::from(addr), 128),
+        }
+    }
+
+    /// Merged prefixes of the set, IPv4 ones first, in address order.
+    pub fn prefixes(&self) -> Vec<(IpAddr, u8)> {
+        let mut ipv4 = Vec::new();
+        self.ipv4.prefixes(0, 0, 32, &mut ipv4);
+        let mut ipv6 = Vec::new();
+        self.ipv6.prefixes(0, 0, 128, &mut ipv6);
+
+        let ipv4 = ipv4
+            .into_iter()
+            .map(|(addr, prefix)| (IpAddr::V4(Ipv4Addr::from(addr as u32)), prefix as u8));
+        let ipv6 = ipv6
+            .into_iter()
+            .map(|(addr, prefix)| (IpAddr::V6(Ipv6Addr::from(addr)), prefix as u8));
+        ipv4.chain(ipv6).collect()
+    }
+
+    /// Runs of consecutive addresses of the set, IPv4 ones first, in address order.
+    pub fn ranges(&self) -> Vec<RangeInclusive<IpAddr>> {
+        let ipv4 = ranges(&self.ipv4, 32).into_iter().map(|(first, last)| {
+            IpAddr::V4(Ipv4Addr::from(first as u32))..=IpAddr::V4(Ipv4Addr::from(last as u32))
+        });
+        let ipv6 = ranges(&self.ipv6, 128).into_iter().map(|(first, last)| {
+            IpAddr::V6(Ipv6Addr::from(first))..=IpAddr::V6(Ipv6Addr::from(last))
+        });
+        ipv4.chain(ipv6).collect()
+    }
+
+    /**
+    Filter matching the addresses of the set in `field`, ignoring the prefixes of an address family
+    the field can't hold.
+
+    Each family is written either as a list of ranges, or as its bounds minus the gaps between the
+    ranges, whichever needs fewer tests.
+    */
+    pub(super) fn compile<L>(&self, field: FieldRef) -> Result<FilterBuilder<L>, IpSetTooLarge> {
+        let ty = field.field.field_type();
+        // IPv4 addresses are compared as IPv4-mapped IPv6 addresses. `ip.SrcAddr` and `ip.DstAddr`
+        // can't hold any other address, so tests against the bounds of IPv4 can be left out.
+        let families = [
+            (
+                matches!(ty, FieldType::Ipv4 | FieldType::IpAddr),
+                ranges(&self.ipv4, 32),
+                ty == FieldType::Ipv4,
+                u128::from(u32::MAX),
+            ),
+            (
+                matches!(ty, FieldType::Ipv6 | FieldType::IpAddr),
+                ranges(&self.ipv6, 128),
+                true,
+                u128::MAX,
+            ),
+        ];
+
+        let mut tests = 0;
+        let mut clauses = Vec::new();
+        for (family, (used, ranges, bounded, max)) in families.into_iter().enumerate() {
+            if !used || ranges.is_empty() {
+                continue;
+            }
+            let bounds = |first: u128, last: u128| {
+                let mut tests = Vec::new();
+                if !bounded || first > 0 {
+                    tests.push((CmpOp::Ge, first));
+                }
+                if !bounded || last < max {
+                    tests.push((CmpOp::Le, last));
+                }
+                tests
+            };
+
+            // Any of the ranges, each one a conjunction
+            let direct: Vec<_> = ranges
+                .iter()
+                .map(|&(first, last)| match first == last {
+                    true => vec![(CmpOp::Eq, first)],
+                    false => bounds(first, last),
+                })
+                .collect();
+            // Every test of the bounds and no gap, each gap a disjunction
+            let mut complement: Vec<_> = bounds(ranges[0].0, ranges[ranges.len() - 1].1)
+                .into_iter()
+                .map(|test| vec![test])
+                .collect();
+            complement.extend(ranges.windows(2).map(|pair| {
+                let (first, last) = (pair[0].1 + 1, pair[1].0 - 1);
+                match first == last {
+                    true => vec![(CmpOp::Ne, first)],
+                    false => vec![(CmpOp::Lt, first), (CmpOp::Gt, last)],
+                }
+            }));
+
+            let count = |groups: &[Vec<(CmpOp, u128)>]| groups.iter().map(Vec::len).sum::<usize>();
+            let (direct_tests, complement_tests) = (count(&direct), count(&complement));
+            let (groups, any) = match complement_tests > 0 && complement_tests < direct_tests {
+                true => (complement, false),
+                false => (direct, true),
+            };
+            tests += count(&groups).max(1);
+
+            let value = |addr: u128| match family {
+                0 => Value::Ipv4(Ipv4Addr::from(addr as u32)),
+                _ => Value::Ipv6(Ipv6Addr::from(addr)),
+            };
+            let compare = |(op, addr)| FilterBuilder::compare(field.clone(), op, value(addr));
+            let groups = groups.into_iter().map(|group| {
+                let group = group.into_iter().map(compare);
+                match any {
+                    true => group.reduce(FilterBuilder::and),
+                    false => group.reduce(FilterBuilder::or),
+                }
+                // The whole address space of the field
+                .unwrap_or_else(|| compare((CmpOp::Ge, 0)))
+            });
+            clauses.extend(match any {
+                true => groups.reduce(FilterBuilder::or),
+                false => groups.reduce(FilterBuilder::and),
+            });
+        }
+
+        if tests > FilterObject::MAX_LEN {
+            return Err(IpSetTooLarge {
+                tests,
+                limit: FilterObject::MAX_LEN,
+            });
+        }
+        Ok(clauses
+            .into_iter()
+            .reduce(FilterBuilder::or)
+            .unwrap_or_else(FilterBuilder::never))
+    }
+}
+
+impl Extend<(IpAddr, u8)> for IpSet {
+    fn extend<T: IntoIterator<Item = (IpAddr, u8)>>(&mut self, prefixes: T) {
+        for (addr, prefix) in prefixes {
+            self.insert(addr, prefix);
+        }
+    }
+}
+
+impl FromIterator<(IpAddr, u8)> for IpSet {
+    fn from_iter<T: IntoIterator<Item = (IpAddr, u8)>>(prefixes: T) -> Self {
+        let mut set = Self::new();
+        set.extend(prefixes);
+        set
+    }
+}
+
+impl Node {
+    /// Inserts `addr/prefix` below the node found at `depth` bits.
+    fn insert(&mut self, addr: u128, depth: u32, prefix: u32, bits: u32) {
+        if self.full {
+            return;
+        }
+        if depth == prefix {
+            self.full = true;
+            self.children = [None, None];
+            return;
+        }
+        let bit = (addr >> (bits - 1 - depth)) & 1;
+        self.children[bit as usize]
+            .get_or_insert_with(Default::default)
+            .insert(addr, depth + 1, prefix, bits);
+        if self
+            .children
+            .iter()
+            .all(|child| child.as_ref().map_or(false, |child| child.full))
+        {
+            self.full = true;
+            self.children = [None, None];
+        }
+    }
+
+    fn contains(&self, addr: u128, bits: u32) -> bool {
+        let mut node = self;
+        for depth in 0..bits {
+            if node.full {
+                return true;
+            }
+            let bit = (addr >> (bits - 1 - depth)) & 1;
+            match &node.children[bit as usize] {
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+        node.full
+    }
+
+    /// Appends the prefixes below the node for `addr/depth`.
+    fn prefixes(&self, addr: u128, depth: u32, bits: u32, prefixes: &mut Vec<(u128, u32)>) {
+        if self.full {
+            prefixes.push((addr, depth));
+            return;
+        }
+        for (bit, child) in self.children.iter().enumerate() {
+            if let Some(child) = child {
+                let addr = addr | (bit as u128) << (bits - 1 - depth);
+                child.prefixes(addr, depth + 1, bits, prefixes);
+            }
+        }
+    }
+}
+
+/// Runs of consecutive addresses below `root`, as inclusive bounds.
+fn ranges(root: &Node, bits: u32) -> Vec<(u128, u128)> {
+    let mut prefixes = Vec::new();
+    root.prefixes(0, 0, bits, &mut prefixes);
+    let mut ranges: Vec<(u128, u128)> = Vec::new();
+    for (addr, prefix) in prefixes {
+        let last = addr | u128::MAX.checked_shr(128 - bits + prefix).unwrap_or(0);
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == addr => *end = last,
+            _ => ranges.push((addr, last)),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filter::builder::field::{IpDstAddr, Ipv6SrcAddr, RemoteAddr},
+        layer::{NetworkLayer, SocketLayer, TypedLayer},
+    };
+
+    fn set(cidrs: &[&str]) -> IpSet {
+        let mut set = IpSet::new();
+        for cidr in cidrs {
+            set.insert_cidr(cidr).unwrap();
+        }
+        set
+    }
+
+    fn prefixes(set: &IpSet) -> Vec<String> {
+        set.prefixes()
+            .into_iter()
+            .map(|(addr, prefix)| format!("{addr}/{prefix}"))
+            .collect()
+    }
+
+    fn render<L: TypedLayer>(filter: FilterBuilder<L>) -> String {
+        filter.build().unwrap().to_string()
+    }
+
+    #[test]
+    fn merge() {
+        // Siblings merge into their parent, recursively
+        let merged = set(&["10.0.0.0/25", "10.0.0.128/26", "10.0.0.192/26"]);
+        assert_eq!(prefixes(&merged), ["10.0.0.0/24"]);
+        // Prefixes covered by a shorter one are dropped, in any order
+        let covered = ["10.0.0.0/8", "10.1.0.0/16", "10.2.3.4"];
+        assert_eq!(prefixes(&set(&covered)), ["10.0.0.0/8"]);
+        let mut reversed = covered;
+        reversed.reverse();
+        assert_eq!(set(&reversed), set(&covered));
+        // Adjacent prefixes that aren't siblings stay apart but form a single run
+        let adjacent = set(&["10.0.1.0/24", "10.0.2.0/24"]);
+        assert_eq!(prefixes(&adjacent), ["10.0.1.0/24", "10.0.2.0/24"]);
+        let ip = |addr: &str| addr.parse::<IpAddr>().unwrap();
+        assert_eq!(adjacent.ranges(), [ip("10.0.1.0")..=ip("10.0.2.255")]);
+
+        let mixed = set(&["2001:db8::/32", "192.0.2.1", "::/0", "192.0.2.0"]);
+        assert_eq!(prefixes(&mixed), ["192.0.2.0/31", "::/0"]);
+        assert!(mixed.contains(ip("192.0.2.1")));
+        assert!(!mixed.contains(ip("192.0.2.2")));
+        assert!(mixed.contains(ip("fe80::1")));
+
+        // Prefixes longer than the address are single addresses
+        let mut long = IpSet::new();
+        long.insert(ip("10.0.0.1"), 40);
+        assert_eq!(prefixes(&long), ["10.0.0.1/32"]);
+        assert!(IpSet::new().is_empty());
+        assert!(!long.is_empty());
+    }
+
+    #[test]
+    fn compile() {
+        let filter = IpDstAddr.in_set(&set(&["10.0.0.0/24", "10.0.2.0/24", "192.0.2.7"]));
+        assert_eq!(
+            render::<NetworkLayer>(filter.unwrap()),
+            "ip.DstAddr >= 10.0.0.0 and ip.DstAddr <= 10.0.0.255 or \
+             ip.DstAddr >= 10.0.2.0 and ip.DstAddr <= 10.0.2.255 or ip.DstAddr == 192.0.2.7"
+        );
+        // IPv4 prefixes are ignored by IPv6 fields, and the other way around
+        let both = set(&["10.0.0.0/8", "2001:db8::/32"]);
+        assert_eq!(
+            render::<NetworkLayer>(Ipv6SrcAddr.in_set(&both).unwrap()),
+            "ipv6.SrcAddr >= 2001:db8:: and ipv6.SrcAddr <= 2001:db8:ffff:ffff:ffff:ffff:ffff:ffff"
+        );
+        assert_eq!(
+            render::<NetworkLayer>(IpDstAddr.in_set(&set(&["::1"])).unwrap()),
+            "false"
+        );
+        assert_eq!(
+            render::<SocketLayer>(RemoteAddr.in_set(&both).unwrap()),
+            "remoteAddr >= 10.0.0.0 and remoteAddr <= 10.255.255.255 or \
+             remoteAddr >= 2001:db8:: and remoteAddr <= 2001:db8:ffff:ffff:ffff:ffff:ffff:ffff"
+        );
+        // The whole IPv4 space needs no test on IPv4 fields
+        assert_eq!(
+            render::<NetworkLayer>(IpDstAddr.in_set(&set(&["0.0.0.0/0"])).unwrap()),
+            "ip.DstAddr >= 0.0.0.0"
+        );
+    }
+
+    #[test]
+    fn complement() {
+        // A /24 with one hole is cheaper to write as its bounds minus the hole
+        let holed: IpSet = (0..=255)
+            .filter(|byte| *byte != 5)
+            .map(|byte| (IpAddr::V4(Ipv4Addr::new(10, 0, 0, byte)), 32))
+            .collect();
+        assert_eq!(
+            render::<NetworkLayer>(IpDstAddr.in_set(&holed).unwrap()),
+            "ip.DstAddr >= 10.0.0.0 and ip.DstAddr <= 10.0.0.255 and ip.DstAddr != 10.0.0.5"
+        );
+        // Wider holes are excluded with both of their bounds
+        let mut holed = set(&["10.0.0.0/25", "10.0.0.192/26"]);
+        holed.extend((1..=255).map(|byte| (IpAddr::V4(Ipv4Addr::new(10, 0, 1, byte)), 32)));
+        assert_eq!(
+            render::<NetworkLayer>(IpDstAddr.in_set(&holed).unwrap()),
+            "ip.DstAddr >= 10.0.0.0 and ip.DstAddr <= 10.0.1.255 and \
+             (ip.DstAddr < 10.0.0.128 or ip.DstAddr > 10.0.0.191) and ip.DstAddr != 10.0.1.0"
+        );
+    }
+
+    #[test]
+    fn too_large() {
+        let scattered = |count: u32| -> IpSet {
+            (0..count)
+                .map(|index| (IpAddr::V4(Ipv4Addr::from(0x0A00_0000 + 2 * index)), 32))
+                .collect()
+        };
+        let limit = FilterObject::MAX_LEN as u32;
+        assert!(IpDstAddr.in_set::<NetworkLayer>(&scattered(limit)).is_ok());
+        assert_eq!(
+            IpDstAddr
+                .in_set::<NetworkLayer>(&scattered(limit + 1))
+                .unwrap_err(),
+            IpSetTooLarge {
+                tests: FilterObject::MAX_LEN + 1,
+                limit: FilterObject::MAX_LEN,
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_cidr() {
+        let mut set = IpSet::new();
+        for cidr in ["10.0.0.0/33", "::/129", "10.0.0", "10.0.0.0/", "host"] {
+            assert!(
+                matches!(
+                    set.insert_cidr(cidr),
+                    Err(WinDivertError::InvalidCidr(input)) if input == cidr
+                ),
+                "{cidr}"
+            );
+        }
+        assert!(set.is_empty());
+    }
+}
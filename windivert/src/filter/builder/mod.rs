@@ -0,0 +1,242 @@
+/*!
+Type safe filter construction.
+
+Filters are assembled from the typed fields in [`field`], which only accept values of the right
+type and can only be used on the layers where WinDivert supports them. For instance
+`field::TcpDstPort.eq(443).and(field::IpSrcAddr.in_cidr("10.0.0.0/8"))` builds a
+[`FilterBuilder`] that can be passed straight to `WinDivert::network()`, while using
+`field::ProcessId` for a network handle fails to compile.
+*/
+
+pub mod field;
+mod ipset;
+
+use std::{
+    marker::PhantomData,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::Not,
+};
+
+use windivert_sys::{WinDivertEvent, WinDivertLayer};
+
+use super::{CmpOp, Expr, ExprKind, FieldRef, Filter, Symbol, Value};
+use crate::{error::WinDivertError, layer::TypedLayer};
+
+pub use ipset::IpSet;
+
+/**
+Filter under construction for the layer typestate `L`.
+
+Created from the comparison methods of the [typed fields](field) and combined with
+[`and()`](FilterBuilder::and), [`or()`](FilterBuilder::or) and `!`.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterBuilder<L> {
+    expr: Result<Expr, String>,
+    _layer: PhantomData<L>,
+}
+
+impl<L> FilterBuilder<L> {
+    fn new(expr: Result<Expr, String>) -> Self {
+        Self {
+            expr,
+            _layer: PhantomData,
+        }
+    }
+
+    /// Filter matching every event, `true`.
+    pub fn always() -> Self {
+        Self::new(Ok(Expr::new(ExprKind::Bool(true))))
+    }
+
+    /// Filter matching no event, `false`.
+    pub fn never() -> Self {
+        Self::new(Ok(Expr::new(ExprKind::Bool(false))))
+    }
+
+    /// Matches when both `self` and `other` match.
+    pub fn and(self, other: FilterBuilder<L>) -> Self {
+        self.combine(other, ExprKind::And)
+    }
+
+    /// Matches when either `self` or `other` match.
+    pub fn or(self, other: FilterBuilder<L>) -> Self {
+        self.combine(other, ExprKind::Or)
+    }
+
+    /// Matches `then` when `self` matches and `otherwise` when it doesn't.
+    pub fn then_else(self, then: FilterBuilder<L>, otherwise: FilterBuilder<L>) -> Self {
+        let expr = self.expr.and_then(|cond| {
+            Ok(Expr::new(ExprKind::Ternary {
+                cond: Box::new(cond),
+                then: Box::new(then.expr?),
+                otherwise: Box::new(otherwise.expr?),
+            }))
+        });
+        Self::new(expr)
+    }
+
+    fn combine(
+        self,
+        other: FilterBuilder<L>,
+        kind: impl FnOnce(Box<Expr>, Box<Expr>) -> ExprKind,
+    ) -> Self {
+        let expr = self
+            .expr
+            .and_then(|lhs| Ok(Expr::new(kind(Box::new(lhs), Box::new(other.expr?)))));
+        Self::new(expr)
+    }
+
+    pub(crate) fn compare(field: FieldRef, op: CmpOp, value: Value) -> Self {
+        Self::new(Ok(Expr::new(ExprKind::Compare { field, op, value })))
+    }
+
+    pub(crate) fn field(field: FieldRef) -> Self {
+        Self::new(Ok(Expr::new(ExprKind::Field(field))))
+    }
+
+    pub(crate) fn error(message: String) -> Self {
+        Self::new(Err(message))
+    }
+}
+
+impl<L: TypedLayer> FilterBuilder<L> {
+    /**
+    Renders the filter.
+
+    Fails if one of the values couldn't be parsed, like an invalid CIDR passed to `in_cidr()`, or if
+    the filter isn't valid for the layer, like an event of a different layer.
+    */
+    pub fn build(self) -> Result<Filter, WinDivertError> {
+        let filter = Filter::from(self.expr.map_err(|message| WinDivertError::Filter {
+            message,
+            position: 0,
+        })?);
+        filter.validate(L::LAYER)?;
+        Ok(filter)
+    }
+}
+
+impl<L> Not for FilterBuilder<L> {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self::new(
+            self.expr
+                .map(|expr| Expr::new(ExprKind::Not(Box::new(expr)))),
+        )
+    }
+}
+
+/// Filters accepted by the `WinDivert` constructors for the layer `L`.
+pub trait IntoFilter<L> {
+    /// Renders the filter string.
+    fn into_filter(self) -> Result<String, WinDivertError>;
+}
+
+impl<L, T: AsRef<str>> IntoFilter<L> for T {
+    fn into_filter(self) -> Result<String, WinDivertError> {
+        Ok(self.as_ref().to_owned())
+    }
+}
+
+impl<L: TypedLayer> IntoFilter<L> for FilterBuilder<L> {
+    fn into_filter(self) -> Result<String, WinDivertError> {
+        Ok(self.build()?.to_string())
+    }
+}
+
+/// Address and prefix length of `cidr`, either `address/prefix` or a single address.
+fn prefix(cidr: &str) -> Result<(IpAddr, u32), String> {
+    let invalid = || format!("invalid CIDR `{cidr}`");
+    let (addr, prefix) = match cidr.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (cidr, None),
+    };
+    let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.trim().parse::<u32>().map_err(|_| invalid())?,
+        None => bits,
+    };
+    if prefix > bits {
+        return Err(invalid());
+    }
+    Ok((addr, prefix))
+}
+
+/// Address range covered by `cidr`, either `address/prefix` or a single address.
+fn cidr(cidr: &str) -> Result<(IpAddr, IpAddr), String> {
+    let (addr, prefix) = prefix(cidr)?;
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    let host = u128::MAX.checked_shr(prefix + 128 - bits).unwrap_or(0);
+    Ok(match addr {
+        IpAddr::V4(addr) => {
+            let (host, addr) = (host as u32, u32::from(addr));
+            (
+                IpAddr::V4(Ipv4Addr::from(addr & !host)),
+                IpAddr::V4(Ipv4Addr::from(addr | host)),
+            )
+        }
+        IpAddr::V6(addr) => {
+            let addr = u128::from(addr);
+            (
+                IpAddr::V6(Ipv6Addr::from(addr & !host)),
+                IpAddr::V6(Ipv6Addr::from(addr | host)),
+            )
+        }
+    })
+}
+
+/// Filter matching the addresses of `cidr`, `ipv4` and `ipv6` select the accepted families.
+fn in_cidr<L>(field: FieldRef, cidr: &str, ipv4: bool, ipv6: bool) -> FilterBuilder<L> {
+    let (first, last) = match self::cidr(cidr) {
+        Ok(range) => range,
+        Err(message) => return FilterBuilder::error(message),
+    };
+    if (first.is_ipv4() && !ipv4) || (first.is_ipv6() && !ipv6) {
+        return FilterBuilder::error(format!(
+            "CIDR `{cidr}` doesn't match the address family of field `{}`",
+            field.field
+        ));
+    }
+    if first == last {
+        return FilterBuilder::compare(field, CmpOp::Eq, address(first));
+    }
+    FilterBuilder::compare(field.clone(), CmpOp::Ge, address(first)).and(FilterBuilder::compare(
+        field,
+        CmpOp::Le,
+        address(last),
+    ))
+}
+
+fn address(addr: IpAddr) -> Value {
+    match addr {
+        IpAddr::V4(addr) => Value::Ipv4(addr),
+        IpAddr::V6(addr) => Value::Ipv6(addr),
+    }
+}
+
+fn event_value(event: WinDivertEvent) -> Value {
+    Value::Symbol(match event {
+        WinDivertEvent::NetworkPacket => Symbol::Packet,
+        WinDivertEvent::FlowStablished => Symbol::Established,
+        WinDivertEvent::FlowDeleted => Symbol::Deleted,
+        WinDivertEvent::SocketBind => Symbol::Bind,
+        WinDivertEvent::SocketConnect => Symbol::Connect,
+        WinDivertEvent::SocketListen => Symbol::Listen,
+        WinDivertEvent::SocketAccept => Symbol::Accept,
+        WinDivertEvent::SocketClose | WinDivertEvent::ReflectClose => Symbol::Close,
+        WinDivertEvent::ReflectOpen => Symbol::Open,
+    })
+}
+
+fn layer_value(layer: WinDivertLayer) -> Value {
+    Value::Symbol(match layer {
+        WinDivertLayer::Network => Symbol::Network,
+        WinDivertLayer::Forward => Symbol::NetworkForward,
+        WinDivertLayer::Flow => Symbol::Flow,
+        WinDivertLayer::Socket => Symbol::Socket,
+        WinDivertLayer::Reflect => Symbol::Reflect,
+    })
+}
@@ -0,0 +1,316 @@
+/*!
+Coverage of a filter over a set of packets.
+
+A [`Coverage`] report is built by recording packets one at a time, usually read from a capture
+file with [`CaptureReader`](crate::capture::CaptureReader). It counts how many times every
+sub-expression of the filter was evaluated and how many times it held, which shows the clauses
+that never matched anything, and lists the packets that only matched through a given alternative
+of an `or` expression, which shows what would stop matching if the alternative was removed.
+*/
+
+use std::fmt;
+
+use windivert_filter::chain;
+
+use super::{
+    eval::{eval, Context},
+    Expr, ExprKind, Filter, Span,
+};
+use crate::{layer::WinDivertLayerTrait, packet::WinDivertPacket};
+
+/// Hit counts of a sub-expression of the filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Clause {
+    /// Sub-expression in canonical form.
+    pub expr: String,
+    /// Source location of the sub-expression.
+    pub span: Span,
+    /**
+    Nesting level, `0` for the root expression. Chains of `and`/`or` are flattened like in
+    [`Trace`](super::Trace).
+    */
+    pub depth: usize,
+    /**
+    Number of packets for which the sub-expression was evaluated, that is, not skipped because the
+    result was already decided.
+    */
+    pub evaluated: usize,
+    /// Number of evaluated packets for which the sub-expression, on its own, was true.
+    pub hits: usize,
+    /**
+    Set when the sub-expression is an alternative: an operand of an `or` expression, or of an
+    `and` expression under a `not`.
+    */
+    pub branch: bool,
+    /// Indices of the matched packets that wouldn't be matched if this alternative was removed.
+    pub exclusive: Vec<usize>,
+}
+
+/// Coverage report of a filter, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    filter: Filter,
+    clauses: Vec<Clause>,
+    /// Filter with each alternative replaced by a constant that makes it fail.
+    alternatives: Vec<Option<Expr>>,
+    packets: usize,
+    matched: usize,
+}
+
+impl Coverage {
+    /// Creates an empty report for `filter`.
+    pub fn new(filter: &Filter) -> Self {
+        let mut clauses = Vec::new();
+        let mut branches = Vec::new();
+        collect(filter.expr(), 0, false, false, &mut clauses, &mut branches);
+        let alternatives = branches
+            .iter()
+            .map(|branch| {
+                branch.map(|(target, negated)| {
+                    replace(filter.expr(), target, &Expr::new(ExprKind::Bool(negated)))
+                })
+            })
+            .collect();
+        Self {
+            filter: filter.clone(),
+            clauses,
+            alternatives,
+            packets: 0,
+            matched: 0,
+        }
+    }
+
+    /// Evaluates the filter against `packet`, updating the report. Returns `true` if it matched.
+    pub fn record<L: WinDivertLayerTrait>(&mut self, packet: &WinDivertPacket<L>) -> bool {
+        let ctx = Context::new(&packet.data, packet.address.as_ref());
+        let mut walk = Walk {
+            ctx: &ctx,
+            clauses: &mut self.clauses,
+            index: 0,
+            branches: Vec::new(),
+        };
+        let matched = walk.walk(self.filter.expr(), false, true);
+        let branches = walk.branches;
+
+        if matched {
+            for index in branches {
+                let alternative = self.alternatives[index].as_ref();
+                if alternative.map_or(false, |expr| !eval(expr, &ctx, false)) {
+                    self.clauses[index].exclusive.push(self.packets);
+                }
+            }
+            self.matched += 1;
+        }
+        self.packets += 1;
+        matched
+    }
+
+    /// Filter the report is about.
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    /// Number of packets recorded.
+    pub fn packets(&self) -> usize {
+        self.packets
+    }
+
+    /// Number of recorded packets matched by the filter.
+    pub fn matched(&self) -> usize {
+        self.matched
+    }
+
+    /// Sub-expressions of the filter, in the order they appear in the filter string.
+    pub fn clauses(&self) -> &[Clause] {
+        &self.clauses
+    }
+
+    /// Sub-expressions that were never true for any of the recorded packets.
+    pub fn never_matched(&self) -> impl Iterator<Item = &Clause> {
+        self.clauses.iter().filter(|clause| clause.hits == 0)
+    }
+}
+
+impl<'a, L: WinDivertLayerTrait + 'a> Extend<&'a WinDivertPacket<'a, L>> for Coverage {
+    fn extend<T: IntoIterator<Item = &'a WinDivertPacket<'a, L>>>(&mut self, packets: T) {
+        for packet in packets {
+            self.record(packet);
+        }
+    }
+}
+
+/// Renders a table of the hit counts followed by the clauses that never matched.
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} of {} packets matched", self.matched, self.packets)?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:>10} {:>10} {:>10}  clause",
+            "hits", "evaluated", "exclusive"
+        )?;
+        for clause in &self.clauses {
+            let exclusive = match clause.branch {
+                true => clause.exclusive.len().to_string(),
+                false => "-".to_owned(),
+            };
+            writeln!(
+                f,
+                "{:>10} {:>10} {:>10}  {:indent$}{}",
+                clause.hits,
+                clause.evaluated,
+                exclusive,
+                "",
+                clause.expr,
+                indent = clause.depth * 2
+            )?;
+        }
+
+        let mut never = self.never_matched().peekable();
+        if never.peek().is_some() {
+            writeln!(f)?;
+            writeln!(f, "Never matched:")?;
+            for clause in never {
+                writeln!(f, "  {} (at {})", clause.expr, clause.span)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+Lists the sub-expressions of `expr` in pre-order, and for each one the alternative it is, if any,
+as a pointer to the sub-expression and whether it is evaluated negated.
+*/
+fn collect(
+    expr: &Expr,
+    depth: usize,
+    negate: bool,
+    branch: bool,
+    clauses: &mut Vec<Clause>,
+    branches: &mut Vec<Option<(*const Expr, bool)>>,
+) {
+    clauses.push(Clause {
+        expr: expr.to_canonical_string(),
+        span: expr.span,
+        depth,
+        evaluated: 0,
+        hits: 0,
+        branch,
+        exclusive: Vec::new(),
+    });
+    branches.push(branch.then_some((expr as *const Expr, negate)));
+
+    match &expr.kind {
+        ExprKind::Bool(_) | ExprKind::Field(_) | ExprKind::Compare { .. } => {}
+        ExprKind::Not(operand) => collect(operand, depth + 1, !negate, false, clauses, branches),
+        ExprKind::And(..) | ExprKind::Or(..) => {
+            let mut operands = Vec::new();
+            chain(expr, &mut operands);
+            // `not (a and b)` is evaluated as `not a or not b`
+            let any = matches!(expr.kind, ExprKind::Or(..)) != negate;
+            for operand in operands {
+                collect(operand, depth + 1, negate, any, clauses, branches);
+            }
+        }
+        ExprKind::Ternary {
+            cond,
+            then,
+            otherwise,
+        } => {
+            collect(cond, depth + 1, false, false, clauses, branches);
+            collect(then, depth + 1, negate, false, clauses, branches);
+            collect(otherwise, depth + 1, negate, false, clauses, branches);
+        }
+    }
+}
+
+/// Copy of `expr` with the sub-expression at `target` replaced by `replacement`.
+fn replace(expr: &Expr, target: *const Expr, replacement: &Expr) -> Expr {
+    if std::ptr::eq(expr, target) {
+        return replacement.clone();
+    }
+    let boxed = |operand: &Expr| Box::new(replace(operand, target, replacement));
+    let kind = match &expr.kind {
+        ExprKind::Bool(_) | ExprKind::Field(_) | ExprKind::Compare { .. } => return expr.clone(),
+        ExprKind::Not(operand) => ExprKind::Not(boxed(operand)),
+        ExprKind::And(lhs, rhs) => ExprKind::And(boxed(lhs), boxed(rhs)),
+        ExprKind::Or(lhs, rhs) => ExprKind::Or(boxed(lhs), boxed(rhs)),
+        ExprKind::Ternary {
+            cond,
+            then,
+            otherwise,
+        } => ExprKind::Ternary {
+            cond: boxed(cond),
+            then: boxed(then),
+            otherwise: boxed(otherwise),
+        },
+    };
+    Expr::with_span(kind, expr.span)
+}
+
+struct Walk<'a> {
+    ctx: &'a Context<'a>,
+    clauses: &'a mut [Clause],
+    index: usize,
+    /// Alternatives that were evaluated and held.
+    branches: Vec<usize>,
+}
+
+impl Walk<'_> {
+    /**
+    Evaluates `expr`, or `not expr` if `negate` is set, in the same order as [`eval()`] and in the
+    same order as [`collect()`] lists the sub-expressions. Sub-expressions are only counted if
+    `evaluated` is set, otherwise they are skipped.
+    */
+    fn walk(&mut self, expr: &Expr, negate: bool, evaluated: bool) -> bool {
+        let index = self.index;
+        self.index += 1;
+        if evaluated {
+            self.clauses[index].evaluated += 1;
+            if eval(expr, self.ctx, false) {
+                self.clauses[index].hits += 1;
+            }
+        }
+
+        let result = match &expr.kind {
+            ExprKind::Bool(_) | ExprKind::Field(_) | ExprKind::Compare { .. } => {
+                evaluated && eval(expr, self.ctx, negate)
+            }
+            ExprKind::Not(operand) => self.walk(operand, !negate, evaluated),
+            ExprKind::And(..) | ExprKind::Or(..) => {
+                let mut operands = Vec::new();
+                chain(expr, &mut operands);
+                let all = matches!(expr.kind, ExprKind::And(..)) != negate;
+                let mut result = None;
+                for operand in operands {
+                    let pending = evaluated && result.is_none();
+                    let value = self.walk(operand, negate, pending);
+                    if pending && value != all {
+                        result = Some(value);
+                    }
+                }
+                result.unwrap_or(all)
+            }
+            ExprKind::Ternary {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let cond = self.walk(cond, false, evaluated);
+                let then = self.walk(then, negate, evaluated && cond);
+                let otherwise = self.walk(otherwise, negate, evaluated && !cond);
+                match cond {
+                    true => then,
+                    false => otherwise,
+                }
+            }
+        };
+
+        if evaluated && result && self.clauses[index].branch {
+            self.branches.push(index);
+        }
+        result
+    }
+}
@@ -0,0 +1,586 @@
+use std::{
+    cmp::Ordering,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use windivert_sys::{address::WINDIVERT_ADDRESS, WinDivertLayer};
+
+use super::{
+    validate::filter_layer, CmpOp, Expr, ExprKind, Field, FieldRef, FieldType, Filter, Symbol,
+    Value,
+};
+use crate::{
+    layer::WinDivertLayerTrait,
+    packet::{IpHeader, ParsedPacket, TransportHeader, WinDivertPacket},
+};
+
+impl Filter {
+    /**
+    Evaluates the filter against a packet, equivalent to
+    [`WinDivertHelperEvalFilter()`](fn@windivert_sys::WinDivertHelperEvalFilter).
+
+    Field values are read from the packet data and from the packet [address](crate::address::WinDivertAddress),
+    using the layer stored in the address.
+
+    Following WinDivert semantics, a test on a field that doesn't exist for the packet (e.g.
+    `tcp.DstPort` on an UDP packet or `packet[100]` on a shorter packet) is always false, even
+    when negated: both `tcp.DstPort == 80` and `not tcp.DstPort == 80` are false for UDP packets.
+    Checksum fields hold the value found in the header, like the driver they don't take into account
+    the checksum flags of the address. The random fields are derived from a hash of the packet so
+    the result is deterministic.
+    */
+    pub fn matches<L: WinDivertLayerTrait>(&self, packet: &WinDivertPacket<L>) -> bool {
+        let ctx = Context::new(&packet.data, packet.address.as_ref());
+        eval(self.expr(), &ctx, false)
+    }
+}
+
+/// Evaluates `expr`, or `not expr` if `negate` is set, pushing negations down to the tests.
+pub(crate) fn eval(expr: &Expr, ctx: &Context, negate: bool) -> bool {
+    match &expr.kind {
+        ExprKind::Bool(value) => *value != negate,
+        ExprKind::Field(field) => match ctx.read(field) {
+            Some(value) => value.is_zero() == negate,
+            None => false,
+        },
+        ExprKind::Compare { field, op, value } => {
+            let op = if negate { op.negate() } else { *op };
+            ctx.compare(field, op, value).unwrap_or(false)
+        }
+        ExprKind::Not(expr) => eval(expr, ctx, !negate),
+        ExprKind::And(lhs, rhs) if negate => eval(lhs, ctx, true) || eval(rhs, ctx, true),
+        ExprKind::And(lhs, rhs) => eval(lhs, ctx, false) && eval(rhs, ctx, false),
+        ExprKind::Or(lhs, rhs) if negate => eval(lhs, ctx, true) && eval(rhs, ctx, true),
+        ExprKind::Or(lhs, rhs) => eval(lhs, ctx, false) || eval(rhs, ctx, false),
+        ExprKind::Ternary {
+            cond,
+            then,
+            otherwise,
+        } => {
+            if eval(cond, ctx, false) {
+                eval(then, ctx, negate)
+            } else {
+                eval(otherwise, ctx, negate)
+            }
+        }
+    }
+}
+
+/// Field value read from a packet or address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Scalar {
+    /// Integer fields, signed fields keep their sign.
+    Int(i128),
+    /// Address fields, IPv4 addresses are stored IPv4-mapped.
+    Addr(u128),
+}
+
+impl Scalar {
+    fn is_zero(&self) -> bool {
+        matches!(self, Scalar::Int(0) | Scalar::Addr(0))
+    }
+}
+
+pub(crate) fn ipv4_mapped(addr: u32) -> u128 {
+    0xFFFF_0000_0000 | addr as u128
+}
+
+/// Converts a constant to the representation used by values of a field of type `ty`.
+pub(crate) fn literal(value: &Value, ty: FieldType, layer: WinDivertLayer) -> Scalar {
+    let address = matches!(ty, FieldType::Ipv4 | FieldType::Ipv6 | FieldType::IpAddr);
+    match value {
+        Value::Ipv4(addr) => Scalar::Addr(ipv4_mapped(u32::from(*addr))),
+        Value::Ipv6(addr) => Scalar::Addr(u128::from(*addr)),
+        Value::Int(value) if address && *value >= 0 => {
+            if ty == FieldType::Ipv4 && *value <= u32::MAX as i128 {
+                Scalar::Addr(ipv4_mapped(*value as u32))
+            } else {
+                Scalar::Addr(*value as u128)
+            }
+        }
+        Value::Int(value) => Scalar::Int(*value),
+        Value::Symbol(symbol) => Scalar::Int(symbol.value(filter_layer(layer))),
+    }
+}
+
+/// Converts a field value back to a constant, the inverse of [`literal()`].
+pub(crate) fn constant(scalar: Scalar, ty: FieldType, layer: WinDivertLayer) -> Value {
+    match scalar {
+        Scalar::Addr(addr) if addr >> 32 == 0xFFFF && ty != FieldType::Ipv6 => {
+            Value::Ipv4(Ipv4Addr::from(addr as u32))
+        }
+        Scalar::Addr(addr) => Value::Ipv6(Ipv6Addr::from(addr)),
+        Scalar::Int(int) => {
+            let symbols: &[Symbol] = match ty {
+                FieldType::Event => &[
+                    Symbol::Packet,
+                    Symbol::Established,
+                    Symbol::Deleted,
+                    Symbol::Bind,
+                    Symbol::Connect,
+                    Symbol::Listen,
+                    Symbol::Accept,
+                    Symbol::Close,
+                    Symbol::Open,
+                ],
+                FieldType::Layer => &[
+                    Symbol::Network,
+                    Symbol::NetworkForward,
+                    Symbol::Flow,
+                    Symbol::Socket,
+                    Symbol::Reflect,
+                ],
+                _ => &[],
+            };
+            symbols
+                .iter()
+                .find(|symbol| symbol.value(filter_layer(layer)) == int)
+                .map_or(Value::Int(int), |symbol| Value::Symbol(*symbol))
+        }
+    }
+}
+
+/// Rewrites ordering comparisons against values just outside of the range of `ty`, so the
+/// constant always fits the field.
+pub(crate) fn clamp(ty: FieldType, op: CmpOp, value: &Value) -> (CmpOp, Value) {
+    let range = ty.int_range();
+    let &Value::Int(int) = value else {
+        return (op, value.clone());
+    };
+    let (below, above) = (int < range.min, int > 0 && !range.contains(int));
+    match op {
+        CmpOp::Lt | CmpOp::Le if below => (CmpOp::Lt, Value::Int(range.min)),
+        CmpOp::Gt | CmpOp::Ge if below => (CmpOp::Ge, Value::Int(range.min)),
+        // Values above the range are at most `i128::MAX`, so the maximum fits
+        CmpOp::Lt | CmpOp::Le if above => (CmpOp::Le, Value::Int(range.max as i128)),
+        CmpOp::Gt | CmpOp::Ge if above => (CmpOp::Gt, Value::Int(range.max as i128)),
+        op => (op, value.clone()),
+    }
+}
+
+pub(crate) fn compare_scalars(lhs: Scalar, op: CmpOp, rhs: Scalar) -> bool {
+    let ordering = match (lhs, rhs) {
+        (Scalar::Int(lhs), Scalar::Int(rhs)) => lhs.cmp(&rhs),
+        (Scalar::Addr(lhs), Scalar::Addr(rhs)) => lhs.cmp(&rhs),
+        // Only negative constants can be compared with addresses
+        (Scalar::Addr(_), Scalar::Int(_)) => Ordering::Greater,
+        (Scalar::Int(_), Scalar::Addr(_)) => Ordering::Less,
+    };
+    match op {
+        CmpOp::Eq => ordering == Ordering::Equal,
+        CmpOp::Ne => ordering != Ordering::Equal,
+        CmpOp::Lt => ordering == Ordering::Less,
+        CmpOp::Le => ordering != Ordering::Greater,
+        CmpOp::Gt => ordering == Ordering::Greater,
+        CmpOp::Ge => ordering != Ordering::Less,
+    }
+}
+
+enum Transport<'a> {
+    None,
+    Tcp(&'a [u8]),
+    Udp(&'a [u8]),
+    Icmp(&'a [u8]),
+    Icmpv6(&'a [u8]),
+}
+
+/// Parsed view of the data required to read field values.
+pub(crate) struct Context<'a> {
+    data: &'a [u8],
+    addr: &'a WINDIVERT_ADDRESS,
+    layer: WinDivertLayer,
+    ipv4: Option<&'a [u8]>,
+    ipv6: Option<&'a [u8]>,
+    fragment: bool,
+    transport: Transport<'a>,
+    payload: &'a [u8],
+}
+
+impl<'a> Context<'a> {
+    pub(crate) fn new(data: &'a [u8], addr: &'a WINDIVERT_ADDRESS) -> Self {
+        let mut ctx = Self {
+            data,
+            addr,
+            layer: addr.layer(),
+            ipv4: None,
+            ipv6: None,
+            fragment: false,
+            transport: Transport::None,
+            payload: &[],
+        };
+        if matches!(ctx.layer, WinDivertLayer::Network | WinDivertLayer::Forward) {
+            ctx.parse();
+        }
+        ctx
+    }
+
+    fn parse(&mut self) {
+        let packet = ParsedPacket::parse(self.data);
+        self.fragment = packet.is_fragment();
+        match packet.ip {
+            Some(IpHeader::V4(header)) => self.ipv4 = Some(header.as_bytes()),
+            Some(IpHeader::V6(header)) => self.ipv6 = Some(header.as_bytes()),
+            None => {}
+        }
+        self.transport = match packet.transport {
+            Some(TransportHeader::Tcp(header)) => Transport::Tcp(header.as_bytes()),
+            Some(TransportHeader::Udp(header)) => Transport::Udp(header.as_bytes()),
+            Some(TransportHeader::Icmp(header)) => Transport::Icmp(header.as_bytes()),
+            Some(TransportHeader::Icmpv6(header)) => Transport::Icmpv6(header.as_bytes()),
+            None => Transport::None,
+        };
+        self.payload = packet.payload;
+    }
+
+    /// Compares a field with a constant, returns `None` if the field doesn't exist.
+    pub(crate) fn compare(&self, field: &FieldRef, op: CmpOp, value: &Value) -> Option<bool> {
+        let lhs = self.read(field)?;
+        let rhs = literal(value, field.field.field_type(), self.layer);
+        Some(compare_scalars(lhs, op, rhs))
+    }
+
+    /// Reads the value of a field, returns `None` if the field doesn't exist.
+    pub(crate) fn read(&self, field: &FieldRef) -> Option<Scalar> {
+        if !field.field.available_on(filter_layer(self.layer)) {
+            return None;
+        }
+        let network = matches!(
+            self.layer,
+            WinDivertLayer::Network | WinDivertLayer::Forward
+        );
+        let int = |value: u64| Some(Scalar::Int(value as i128));
+        let be16 =
+            |bytes: &[u8], at: usize| int(u16::from_be_bytes([bytes[at], bytes[at + 1]]) as u64);
+        let be32 = |bytes: &[u8], at: usize| {
+            int(u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]) as u64)
+        };
+        let flag = |value: bool| int(value as u64);
+
+        use Field::*;
+        match field.field {
+            Zero => int(0),
+            Timestamp => Some(Scalar::Int(self.addr.timestamp as i128)),
+            Event => int(u8::from(self.addr.event()) as u64),
+            Random8 => int(self.random() & 0xFF),
+            Random16 => int(self.random() & 0xFFFF),
+            Random32 => int(self.random() & 0xFFFF_FFFF),
+            Length => int(self.data.len() as u64),
+            Outbound => flag(self.addr.outbound()),
+            Inbound => flag(!self.addr.outbound()),
+            Fragment => flag(self.fragment),
+            // SAFETY: Network and forward addresses use the network data
+            IfIdx => int(unsafe { self.addr.union_field.Network }.interface_id as u64),
+            SubIfIdx => int(unsafe { self.addr.union_field.Network }.subinterface_id as u64),
+            Loopback => flag(self.addr.loopback()),
+            Impostor => flag(self.addr.impostor()),
+            ProcessId if matches!(self.layer, WinDivertLayer::Reflect) => {
+                // SAFETY: Reflect addresses use the reflect data
+                int(unsafe { self.addr.union_field.Reflect }.process_id as u64)
+            }
+            // SAFETY: Flow and socket addresses share the same data layout
+            ProcessId => int(unsafe { self.addr.union_field.Flow }.process_id as u64),
+            LocalAddr => Some(Scalar::Addr(words_to_u128(
+                unsafe { self.addr.union_field.Flow }.local_addr,
+            ))),
+            RemoteAddr => Some(Scalar::Addr(words_to_u128(
+                unsafe { self.addr.union_field.Flow }.remote_addr,
+            ))),
+            LocalPort => int(unsafe { self.addr.union_field.Flow }.local_port as u64),
+            RemotePort => int(unsafe { self.addr.union_field.Flow }.remote_port as u64),
+            Protocol => int(unsafe { self.addr.union_field.Flow }.protocol as u64),
+            EndpointId => int(unsafe { self.addr.union_field.Flow }.endpoint_id),
+            ParentEndpointId => int(unsafe { self.addr.union_field.Flow }.parent_endpoint_id),
+            // SAFETY: Reflect addresses use the reflect data
+            Layer => int(u8::from(unsafe { self.addr.union_field.Reflect }.layer) as u64),
+            Priority => Some(Scalar::Int(
+                unsafe { self.addr.union_field.Reflect }.priority as i128,
+            )),
+            Ip if network => flag(self.ipv4.is_some()),
+            Ipv6 if network => flag(self.ipv6.is_some()),
+            Icmp if network => flag(matches!(self.transport, Transport::Icmp(_))),
+            Icmpv6 if network => flag(matches!(self.transport, Transport::Icmpv6(_))),
+            Tcp if network => flag(matches!(self.transport, Transport::Tcp(_))),
+            Udp if network => flag(matches!(self.transport, Transport::Udp(_))),
+            Ip => flag(!self.addr.ipv6()),
+            Ipv6 => flag(self.addr.ipv6()),
+            Icmp => flag(!self.addr.ipv6() && self.flow_protocol() == 1),
+            Icmpv6 => flag(self.addr.ipv6() && self.flow_protocol() == 58),
+            Tcp => flag(self.flow_protocol() == 6),
+            Udp => flag(self.flow_protocol() == 17),
+            IpHdrLength => int((self.ipv4?[0] & 0x0F) as u64),
+            IpTos => int(self.ipv4?[1] as u64),
+            IpLength => be16(self.ipv4?, 2),
+            IpId => be16(self.ipv4?, 4),
+            IpDf => flag(self.ipv4?[6] & 0x40 != 0),
+            IpMf => flag(self.ipv4?[6] & 0x20 != 0),
+            IpFragOff => int((u16::from_be_bytes([self.ipv4?[6], self.ipv4?[7]]) & 0x1FFF) as u64),
+            IpTtl => int(self.ipv4?[8] as u64),
+            IpProtocol => int(self.ipv4?[9] as u64),
+            IpChecksum => be16(self.ipv4?, 10),
+            IpSrcAddr => ipv4_addr(&self.ipv4?[12..16]),
+            IpDstAddr => ipv4_addr(&self.ipv4?[16..20]),
+            Ipv6TrafficClass => {
+                let header = self.ipv6?;
+                int(((header[0] & 0x0F) << 4 | header[1] >> 4) as u64)
+            }
+            Ipv6FlowLabel => {
+                let header = self.ipv6?;
+                int(u32::from_be_bytes([0, header[1] & 0x0F, header[2], header[3]]) as u64)
+            }
+            Ipv6Length => be16(self.ipv6?, 4),
+            Ipv6NextHdr => int(self.ipv6?[6] as u64),
+            Ipv6HopLimit => int(self.ipv6?[7] as u64),
+            Ipv6SrcAddr => ipv6_addr(&self.ipv6?[8..24]),
+            Ipv6DstAddr => ipv6_addr(&self.ipv6?[24..40]),
+            IcmpType => int(self.icmp()?[0] as u64),
+            IcmpCode => int(self.icmp()?[1] as u64),
+            IcmpChecksum => be16(self.icmp()?, 2),
+            IcmpBody => be32(self.icmp()?, 4),
+            Icmpv6Type => int(self.icmpv6()?[0] as u64),
+            Icmpv6Code => int(self.icmpv6()?[1] as u64),
+            Icmpv6Checksum => be16(self.icmpv6()?, 2),
+            Icmpv6Body => be32(self.icmpv6()?, 4),
+            TcpSrcPort => be16(self.tcp()?, 0),
+            TcpDstPort => be16(self.tcp()?, 2),
+            TcpSeqNum => be32(self.tcp()?, 4),
+            TcpAckNum => be32(self.tcp()?, 8),
+            TcpHdrLength => int((self.tcp()?[12] >> 4) as u64),
+            TcpUrg => flag(self.tcp()?[13] & 0x20 != 0),
+            TcpAck => flag(self.tcp()?[13] & 0x10 != 0),
+            TcpPsh => flag(self.tcp()?[13] & 0x08 != 0),
+            TcpRst => flag(self.tcp()?[13] & 0x04 != 0),
+            TcpSyn => flag(self.tcp()?[13] & 0x02 != 0),
+            TcpFin => flag(self.tcp()?[13] & 0x01 != 0),
+            TcpWindow => be16(self.tcp()?, 14),
+            TcpChecksum => be16(self.tcp()?, 16),
+            TcpUrgPtr => be16(self.tcp()?, 18),
+            TcpPayloadLength => {
+                self.tcp()?;
+                int(self.payload.len() as u64)
+            }
+            UdpSrcPort => be16(self.udp()?, 0),
+            UdpDstPort => be16(self.udp()?, 2),
+            UdpLength => be16(self.udp()?, 4),
+            UdpChecksum => be16(self.udp()?, 6),
+            UdpPayloadLength => {
+                self.udp()?;
+                int(self.payload.len() as u64)
+            }
+            Packet | Packet16 | Packet32 => self.index(self.data, field),
+            TcpPayload | TcpPayload16 | TcpPayload32 => {
+                self.tcp()?;
+                self.index(self.payload, field)
+            }
+            UdpPayload | UdpPayload16 | UdpPayload32 => {
+                self.udp()?;
+                self.index(self.payload, field)
+            }
+        }
+    }
+
+    fn flow_protocol(&self) -> u8 {
+        // SAFETY: Only called for flow and socket addresses, which share the same data layout
+        unsafe { self.addr.union_field.Flow }.protocol
+    }
+
+    fn tcp(&self) -> Option<&'a [u8]> {
+        match self.transport {
+            Transport::Tcp(header) => Some(header),
+            _ => None,
+        }
+    }
+
+    fn udp(&self) -> Option<&'a [u8]> {
+        match self.transport {
+            Transport::Udp(header) => Some(header),
+            _ => None,
+        }
+    }
+
+    fn icmp(&self) -> Option<&'a [u8]> {
+        match self.transport {
+            Transport::Icmp(header) => Some(header),
+            _ => None,
+        }
+    }
+
+    fn icmpv6(&self) -> Option<&'a [u8]> {
+        match self.transport {
+            Transport::Icmpv6(header) => Some(header),
+            _ => None,
+        }
+    }
+
+    /// Reads the big endian word selected by an indexed field.
+    fn index(&self, bytes: &[u8], field: &FieldRef) -> Option<Scalar> {
+        let width = field.field.index_width() as usize;
+        let index = field.index? as isize;
+        let start = if index < 0 {
+            bytes.len() as isize + index
+        } else {
+            index
+        };
+        let start = usize::try_from(start).ok()?;
+        let word = bytes.get(start..start.checked_add(width)?)?;
+        Some(Scalar::Int(
+            word.iter()
+                .fold(0i128, |acc, &byte| acc << 8 | byte as i128),
+        ))
+    }
+
+    /// Deterministic FNV-1a hash of the packet, used for the random fields.
+    fn random(&self) -> u64 {
+        self.data
+            .iter()
+            .chain(self.addr.timestamp.to_le_bytes().iter())
+            .fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+}
+
+fn ipv4_addr(bytes: &[u8]) -> Option<Scalar> {
+    let addr = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    Some(Scalar::Addr(ipv4_mapped(u32::from(addr))))
+}
+
+fn ipv6_addr(bytes: &[u8]) -> Option<Scalar> {
+    let mut addr = [0; 16];
+    addr.copy_from_slice(bytes);
+    Some(Scalar::Addr(u128::from_be_bytes(addr)))
+}
+
+/// Flow and socket addresses are stored as little endian 32 bit words, IPv4 addresses are mapped.
+fn words_to_u128(words: [u32; 4]) -> u128 {
+    words
+        .iter()
+        .rev()
+        .fold(0u128, |acc, &word| acc << 32 | word as u128)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::NetworkLayer;
+
+    /// 10.0.0.2:51000 -> 8.8.8.8:53, DNS query for example.com.
+    const UDP_DNS: &str = "450000391c4640004011045d0a00000208080808c738003500253c83\
+        123401000001000000000000076578616d706c6503636f6d0000010001";
+    /// 10.0.0.2:49152 -> 93.184.216.34:443, SYN.
+    const TCP_SYN: &str =
+        "450000283a1f40004006c0d40a0000025db8d822c00001bb0badcafe000000005002faf0dcad0000";
+
+    fn packet(hex: &str) -> WinDivertPacket<'static, NetworkLayer> {
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).unwrap())
+            .collect();
+        // SAFETY: The zeroed address is a valid inbound network layer address
+        unsafe { WinDivertPacket::<NetworkLayer>::new(data) }
+    }
+
+    fn matches(filter: &str, packet: &WinDivertPacket<NetworkLayer>) -> bool {
+        Filter::parse(filter).unwrap().matches(packet)
+    }
+
+    #[test]
+    fn missing_fields() {
+        let udp = packet(UDP_DNS);
+        assert!(matches("udp and udp.DstPort == 53", &udp));
+        for filter in [
+            "tcp.DstPort == 53",
+            "not tcp.DstPort == 53",
+            "tcp.DstPort != 53",
+            "not (tcp.Syn or tcp.DstPort == 53)",
+            "tcp.PayloadLength >= 0",
+            "tcp.Payload[0] == 0x12",
+            "icmp.Type == 0 or ipv6.HopLimit == 64",
+            "packet[57] == 0 or not packet[-58] == 0",
+        ] {
+            assert!(!matches(filter, &udp), "{filter}");
+        }
+        assert!(matches("not tcp", &udp));
+        assert!(matches("packet[56] == 0x01 and packet[-57] == 0x45", &udp));
+
+        let tcp = packet(TCP_SYN);
+        assert!(matches(
+            "tcp.Syn and not tcp.Ack and tcp.DstPort == 443",
+            &tcp
+        ));
+        assert!(!matches("udp.SrcPort == 49152", &tcp));
+        assert!(!matches("not udp.SrcPort == 49152", &tcp));
+    }
+
+    #[test]
+    fn values() {
+        let udp = packet(UDP_DNS);
+        assert!(matches(
+            "ip.SrcAddr == 10.0.0.2 and ip.DstAddr == 8.8.8.8 and ip.Id == 0x1c46 and ip.DF \
+             and not ip.MF and ip.TTL == 64 and ip.Length == 57 and length == 57 \
+             and udp.Length == 37 and udp.PayloadLength == 29 and udp.Payload16[0] == 0x1234",
+            &udp
+        ));
+        let tcp = packet(TCP_SYN);
+        assert!(matches(
+            "tcp.SrcPort == 49152 and tcp.SeqNum == 0x0badcafe and tcp.AckNum == 0 \
+             and tcp.HdrLength == 5 and tcp.Window == 64240 and tcp.PayloadLength == 0 \
+             and ip.DstAddr == 93.184.216.34 and not fragment",
+            &tcp
+        ));
+    }
+
+    #[test]
+    fn address_flags() {
+        let mut udp = packet(UDP_DNS);
+        assert!(matches(
+            "inbound and not outbound and not loopback and not impostor and ifIdx == 0",
+            &udp
+        ));
+
+        udp.address.set_outbound(true);
+        udp.address.set_impostor(true);
+        udp.address.set_interface_index(7);
+        udp.address.set_subinterface_index(3);
+        udp.address.as_mut().set_loopback(true);
+        assert!(matches(
+            "outbound and not inbound and loopback and impostor and ifIdx == 7 and subIfIdx == 3",
+            &udp
+        ));
+        assert!(!matches("inbound or not loopback or ifIdx != 7", &udp));
+
+        udp.address.set_outbound(false);
+        udp.address.as_mut().set_loopback(false);
+        assert!(matches("inbound and not loopback and impostor", &udp));
+    }
+
+    #[test]
+    fn checksums() {
+        let mut udp = packet(UDP_DNS);
+        let filter = "ip.Checksum == 0x045d and udp.Checksum == 0x3c83";
+        assert!(matches(filter, &udp));
+
+        // The address checksum flags don't change the header values
+        udp.address.set_ip_checksum(true);
+        udp.address.set_udp_checksum(true);
+        assert!(matches(filter, &udp));
+        udp.address.set_ip_checksum(false);
+        udp.address.set_udp_checksum(false);
+        assert!(matches(filter, &udp));
+
+        // Invalid checksums are read as is
+        udp.data.to_mut()[10] = 0xFF;
+        udp.data.to_mut()[27] = 0x00;
+        assert!(matches(
+            "ip.Checksum == 0xFF5d and udp.Checksum == 0x3c00",
+            &udp
+        ));
+
+        let mut tcp = packet(TCP_SYN);
+        tcp.address.set_tcp_checksum(true);
+        assert!(matches(
+            "tcp.Checksum == 0xdcad and ip.Checksum == 0xc0d4",
+            &tcp
+        ));
+        assert!(!matches("udp.Checksum == 0 or not udp.Checksum == 0", &tcp));
+    }
+}
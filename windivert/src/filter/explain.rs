@@ -0,0 +1,366 @@
+use std::fmt;
+
+use windivert_filter::chain;
+use windivert_sys::WinDivertLayer;
+
+use super::{
+    eval::{constant, eval, Context},
+    Expr, ExprKind, Filter, Span, Value,
+};
+use crate::{layer::WinDivertLayerTrait, packet::WinDivertPacket};
+
+impl Filter {
+    /**
+    Evaluates the filter against a packet like [`Filter::matches()`], recording how every
+    sub-expression was evaluated.
+
+    The result mirrors the expression tree: tests record the value read from the packet or its
+    address, and sub-expressions skipped because the result was already decided by a previous
+    operand of an `and`/`or`, or by the condition of a ternary, are kept with no result. The
+    [`Display`](fmt::Display) implementation renders the trace as indented text.
+    */
+    pub fn explain<L: WinDivertLayerTrait>(&self, packet: &WinDivertPacket<L>) -> Trace {
+        let address = packet.address.as_ref();
+        let ctx = Context::new(&packet.data, address);
+        Tracer {
+            ctx: &ctx,
+            layer: address.layer(),
+        }
+        .trace(self.expr(), false)
+    }
+}
+
+/// Evaluation trace of a filter expression, returned by [`Filter::explain()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Trace {
+    /// Sub-expression in canonical form.
+    pub expr: String,
+    /// Source location of the sub-expression.
+    pub span: Span,
+    /**
+    Set when the sub-expression was evaluated as `not expr` because of an enclosing `not`.
+
+    Negations are pushed down to the tests, so a test on a field missing from the packet is false
+    whether it is negated or not.
+    */
+    pub negated: bool,
+    /// Result of the evaluation, taking `negated` into account. `None` if it was skipped.
+    pub result: Option<bool>,
+    /// Type of the sub-expression and the traces of its operands.
+    pub kind: TraceKind,
+}
+
+/// Type of a traced sub-expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum TraceKind {
+    /// `true` or `false`.
+    Constant,
+    /// Test on a field, with the value read. `None` if the field doesn't exist for the packet.
+    Test(Option<Value>),
+    /// `not` expression.
+    Not(Box<Trace>),
+    /// Chain of `and` expressions, evaluated from left to right.
+    And(Vec<Trace>),
+    /// Chain of `or` expressions, evaluated from left to right.
+    Or(Vec<Trace>),
+    /// Ternary expression.
+    Ternary {
+        /// Condition trace.
+        cond: Box<Trace>,
+        /// Trace of the expression used if the condition is true.
+        then: Box<Trace>,
+        /// Trace of the expression used otherwise.
+        otherwise: Box<Trace>,
+    },
+}
+
+impl Trace {
+    /// Returns `true` if a chain of `and`/`or` expressions was decided before its last operand.
+    pub fn short_circuits(&self) -> bool {
+        match &self.kind {
+            TraceKind::And(operands) | TraceKind::Or(operands) => {
+                self.result.is_some()
+                    && operands
+                        .last()
+                        .map_or(false, |operand| operand.result.is_none())
+            }
+            _ => false,
+        }
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        let result = match self.result {
+            Some(true) => "true",
+            Some(false) => "false",
+            None => "skipped",
+        };
+        write!(
+            f,
+            "{:indent$}{result}: {}",
+            "",
+            self.expr,
+            indent = depth * 2
+        )?;
+        let mut notes = Vec::new();
+        if self.negated {
+            notes.push("negated".to_owned());
+        }
+        if let (TraceKind::Test(value), Some(_)) = (&self.kind, self.result) {
+            notes.push(match value {
+                Some(value) => format!("read {value}"),
+                None => "field missing".to_owned(),
+            });
+        }
+        if self.short_circuits() {
+            notes.push("short-circuit".to_owned());
+        }
+        if !notes.is_empty() {
+            write!(f, " ({})", notes.join(", "))?;
+        }
+        writeln!(f)?;
+
+        match &self.kind {
+            TraceKind::Constant | TraceKind::Test(_) => Ok(()),
+            TraceKind::Not(operand) => operand.write(f, depth + 1),
+            TraceKind::And(operands) | TraceKind::Or(operands) => operands
+                .iter()
+                .try_for_each(|operand| operand.write(f, depth + 1)),
+            TraceKind::Ternary {
+                cond,
+                then,
+                otherwise,
+            } => [cond, then, otherwise]
+                .iter()
+                .try_for_each(|operand| operand.write(f, depth + 1)),
+        }
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+struct Tracer<'a> {
+    ctx: &'a Context<'a>,
+    layer: WinDivertLayer,
+}
+
+impl Tracer<'_> {
+    /// Traces `expr`, or `not expr` if `negate` is set, following the evaluation order of [`eval()`].
+    fn trace(&self, expr: &Expr, negate: bool) -> Trace {
+        let (result, kind) = match &expr.kind {
+            ExprKind::Bool(_) => (eval(expr, self.ctx, negate), TraceKind::Constant),
+            ExprKind::Field(field) | ExprKind::Compare { field, .. } => {
+                let value = self
+                    .ctx
+                    .read(field)
+                    .map(|scalar| constant(scalar, field.field.field_type(), self.layer));
+                (eval(expr, self.ctx, negate), TraceKind::Test(value))
+            }
+            ExprKind::Not(operand) => {
+                let operand = self.trace(operand, !negate);
+                (
+                    operand.result == Some(true),
+                    TraceKind::Not(Box::new(operand)),
+                )
+            }
+            ExprKind::And(..) | ExprKind::Or(..) => {
+                let mut operands = Vec::new();
+                chain(expr, &mut operands);
+                // `not (a and b)` is evaluated as `not a or not b`
+                let all = matches!(expr.kind, ExprKind::And(..)) != negate;
+                let mut result = None;
+                let operands = operands
+                    .into_iter()
+                    .map(|operand| match result {
+                        Some(_) => skipped(operand, negate),
+                        None => {
+                            let trace = self.trace(operand, negate);
+                            if trace.result != Some(all) {
+                                result = trace.result;
+                            }
+                            trace
+                        }
+                    })
+                    .collect();
+                let kind = match expr.kind {
+                    ExprKind::And(..) => TraceKind::And(operands),
+                    _ => TraceKind::Or(operands),
+                };
+                (result.unwrap_or(all), kind)
+            }
+            ExprKind::Ternary {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let cond = self.trace(cond, false);
+                let (then, otherwise) = match cond.result {
+                    Some(true) => (self.trace(then, negate), skipped(otherwise, negate)),
+                    _ => (skipped(then, negate), self.trace(otherwise, negate)),
+                };
+                let result = then.result.or(otherwise.result) == Some(true);
+                let kind = TraceKind::Ternary {
+                    cond: Box::new(cond),
+                    then: Box::new(then),
+                    otherwise: Box::new(otherwise),
+                };
+                (result, kind)
+            }
+        };
+        Trace {
+            expr: expr.to_canonical_string(),
+            span: expr.span,
+            negated: negate,
+            result: Some(result),
+            kind,
+        }
+    }
+}
+
+/// Trace of a sub-expression that wasn't evaluated.
+fn skipped(expr: &Expr, negate: bool) -> Trace {
+    let kind = match &expr.kind {
+        ExprKind::Bool(_) => TraceKind::Constant,
+        ExprKind::Field(_) | ExprKind::Compare { .. } => TraceKind::Test(None),
+        ExprKind::Not(operand) => TraceKind::Not(Box::new(skipped(operand, !negate))),
+        ExprKind::And(..) | ExprKind::Or(..) => {
+            let mut operands = Vec::new();
+            chain(expr, &mut operands);
+            let operands = operands
+                .into_iter()
+                .map(|operand| skipped(operand, negate))
+                .collect();
+            match expr.kind {
+                ExprKind::And(..) => TraceKind::And(operands),
+                _ => TraceKind::Or(operands),
+            }
+        }
+        ExprKind::Ternary {
+            cond,
+            then,
+            otherwise,
+        } => TraceKind::Ternary {
+            cond: Box::new(skipped(cond, false)),
+            then: Box::new(skipped(then, negate)),
+            otherwise: Box::new(skipped(otherwise, negate)),
+        },
+    };
+    Trace {
+        expr: expr.to_canonical_string(),
+        span: expr.span,
+        negated: negate,
+        result: None,
+        kind,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::NetworkLayer;
+
+    /// 10.0.0.2:49152 -> 93.184.216.34:443, SYN.
+    const TCP_SYN: &str =
+        "450000283a1f40004006c0d40a0000025db8d822c00001bb0badcafe000000005002faf0dcad0000";
+
+    fn explain(filter: &str) -> String {
+        let data = (0..TCP_SYN.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&TCP_SYN[at..at + 2], 16).unwrap())
+            .collect();
+        // SAFETY: The zeroed address is a valid inbound network layer address
+        let packet = unsafe { WinDivertPacket::<NetworkLayer>::new(data) };
+        Filter::parse(filter).unwrap().explain(&packet).to_string()
+    }
+
+    #[test]
+    fn chains() {
+        assert_eq!(
+            explain("tcp.Syn and tcp.DstPort == 443"),
+            "\
+true: tcp.Syn and tcp.DstPort == 443
+  true: tcp.Syn (read 1)
+  true: tcp.DstPort == 443 (read 443)
+"
+        );
+        assert_eq!(
+            explain("udp or tcp.DstPort == 80 or outbound"),
+            "\
+false: udp or tcp.DstPort == 80 or outbound
+  false: udp (read 0)
+  false: tcp.DstPort == 80 (read 443)
+  false: outbound (read 0)
+"
+        );
+        assert_eq!(
+            explain("tcp and (tcp.Ack or tcp.DstPort == 443) and ip.TTL > 1"),
+            "\
+true: tcp and (tcp.Ack or tcp.DstPort == 443) and ip.TTL > 1
+  true: tcp (read 1)
+  true: tcp.Ack or tcp.DstPort == 443
+    false: tcp.Ack (read 0)
+    true: tcp.DstPort == 443 (read 443)
+  true: ip.TTL > 1 (read 64)
+"
+        );
+    }
+
+    #[test]
+    fn short_circuits() {
+        assert_eq!(
+            explain("tcp.Syn or udp.DstPort == 53"),
+            "\
+true: tcp.Syn or udp.DstPort == 53 (short-circuit)
+  true: tcp.Syn (read 1)
+  skipped: udp.DstPort == 53
+"
+        );
+        assert_eq!(
+            explain("false and (tcp or udp)"),
+            "\
+false: false and (tcp or udp) (short-circuit)
+  false: false
+  skipped: tcp or udp
+    skipped: tcp
+    skipped: udp
+"
+        );
+        // Negated tests on missing fields are false too
+        assert_eq!(
+            explain("not (udp.DstPort == 53 or tcp.Rst)"),
+            "\
+false: not (udp.DstPort == 53 or tcp.Rst)
+  false: udp.DstPort == 53 or tcp.Rst (negated, short-circuit)
+    false: udp.DstPort == 53 (negated, field missing)
+    skipped: tcp.Rst (negated)
+"
+        );
+    }
+
+    #[test]
+    fn ternaries() {
+        assert_eq!(
+            explain("tcp ? tcp.DstPort == 443 : udp.DstPort == 53"),
+            "\
+true: tcp ? tcp.DstPort == 443 : udp.DstPort == 53
+  true: tcp (read 1)
+  true: tcp.DstPort == 443 (read 443)
+  skipped: udp.DstPort == 53
+"
+        );
+        assert_eq!(
+            explain("udp ? udp.DstPort == 53 : true"),
+            "\
+true: udp ? udp.DstPort == 53 : true
+  false: udp (read 0)
+  skipped: udp.DstPort == 53
+  true: true
+"
+        );
+    }
+}
@@ -0,0 +1,140 @@
+/*!
+Pure Rust implementation of the WinDivert [filter language].
+
+Filters are parsed into a typed [`Expr`] tree without calling into the WinDivert library, so they
+can be inspected and tested on any platform. Filters can also be assembled from typed fields with
+the [`builder`] module, compared with each other with the [`analysis`] module, and measured
+against captured packets with the [`coverage`] module. The fields of the language are listed by
+the [`fields`] module.
+
+[filter language]: https://reqrypt.org/windivert-doc.html#filter_language
+*/
+
+pub mod analysis;
+mod bpf;
+pub mod builder;
+pub mod coverage;
+mod eval;
+mod explain;
+mod object;
+mod optimize;
+mod pcap;
+mod template;
+#[cfg(test)]
+mod testing;
+mod validate;
+
+use std::{fmt, str::FromStr};
+
+pub use bpf::{BpfInstruction, BpfProgram};
+pub use explain::{Trace, TraceKind};
+#[cfg(target_os = "windows")]
+pub(crate) use object::is_object;
+pub use object::{FilterObject, Instruction, Target, Test};
+pub use template::{FilterTemplate, TemplateValue};
+pub use windivert_filter::{
+    fields, CmpOp, Expr, ExprKind, Field, FieldRef, FieldType, FilterLayer, IntRange, Span, Symbol,
+    Value,
+};
+
+use crate::error::FilterParseError;
+
+/// Parsed WinDivert filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+impl Filter {
+    /// Parses a filter string.
+    pub fn parse(filter: &str) -> Result<Self, FilterParseError> {
+        Ok(Self {
+            source: filter.to_owned(),
+            expr: windivert_filter::parse(filter)?,
+        })
+    }
+
+    /// Filter string as provided by the user.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Root expression of the filter.
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+
+    /// Consumes the filter returning the root expression.
+    pub fn into_expr(self) -> Expr {
+        self.expr
+    }
+
+    /// Renders the filter in canonical form, see [`Expr::to_canonical_string()`].
+    pub fn to_canonical_string(&self) -> String {
+        self.expr().to_canonical_string()
+    }
+
+    /// Renders the filter over multiple lines, see [`Expr::to_pretty_string()`].
+    pub fn to_pretty_string(&self, width: usize) -> String {
+        self.expr().to_pretty_string(width)
+    }
+}
+
+impl From<Expr> for Filter {
+    fn from(expr: Expr) -> Self {
+        Self {
+            source: expr.to_canonical_string(),
+            expr,
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = FilterParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl AsRef<str> for Filter {
+    fn as_ref(&self) -> &str {
+        &self.source
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use windivert_sys::WinDivertLayer;
+
+    use super::*;
+    use crate::{layer::NetworkLayer, packet::WinDivertPacket};
+
+    #[test]
+    fn tallest_tree() {
+        // Walking the tallest tree accepted by the parser fits in the stack of a test thread
+        let chain = (0..windivert_filter::MAX_HEIGHT)
+            .map(|port| format!("tcp.DstPort == {port}"))
+            .collect::<Vec<_>>()
+            .join(" and ");
+        let filter = Filter::parse(&chain).unwrap();
+        let packet = unsafe { WinDivertPacket::<NetworkLayer>::new(vec![0x45; 40]) };
+        assert!(!filter.matches(&packet));
+        filter.explain(&packet);
+        assert_eq!(
+            Filter::parse(&filter.to_canonical_string()),
+            Ok(filter.clone())
+        );
+        filter.to_pretty_string(80);
+        filter.optimize(WinDivertLayer::Network).unwrap();
+        filter.to_bpf().unwrap();
+        assert!(filter.compile(WinDivertLayer::Network).is_err());
+    }
+}
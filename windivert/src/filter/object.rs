@@ -0,0 +1,1092 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use windivert_filter::{validate_field, validate_value};
+use windivert_sys::WinDivertLayer;
+
+use super::{
+    eval::{clamp, compare_scalars, constant, literal, Scalar},
+    validate::filter_layer,
+    CmpOp, Expr, ExprKind, Field, FieldRef, FieldType, Filter, Span, Value,
+};
+use crate::error::WinDivertError;
+
+/// Prefix of every encoded filter object.
+const MAGIC: &str = "@WinDiv_";
+/// Object format version.
+const VERSION: u32 = 0;
+/// Digits used to encode numbers, 5 bits each. The last digit of a number uses the second half.
+const DIGITS: &[u8; 64] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz+=";
+/// Offset added to the index of indexed fields, so negative indexes encode as positive numbers.
+const INDEX_BIAS: i32 = u16::MAX as i32;
+
+/// Test performed by a filter object [`Instruction`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Test {
+    /// `field == arg`
+    Eq,
+    /// `field != arg`
+    Ne,
+    /// `field < arg`
+    Lt,
+    /// `field <= arg`
+    Le,
+    /// `field > arg`
+    Gt,
+    /// `field >= arg`
+    Ge,
+}
+
+impl Test {
+    fn id(&self) -> u32 {
+        match self {
+            Test::Eq => 0,
+            Test::Ne => 1,
+            Test::Lt => 2,
+            Test::Le => 3,
+            Test::Gt => 4,
+            Test::Ge => 5,
+        }
+    }
+
+    fn from_id(id: u32) -> Option<Test> {
+        Some(match id {
+            0 => Test::Eq,
+            1 => Test::Ne,
+            2 => Test::Lt,
+            3 => Test::Le,
+            4 => Test::Gt,
+            5 => Test::Ge,
+            _ => return None,
+        })
+    }
+
+    /// Comparison operator of the test.
+    pub fn op(&self) -> CmpOp {
+        match self {
+            Test::Eq => CmpOp::Eq,
+            Test::Ne => CmpOp::Ne,
+            Test::Lt => CmpOp::Lt,
+            Test::Le => CmpOp::Le,
+            Test::Gt => CmpOp::Gt,
+            Test::Ge => CmpOp::Ge,
+        }
+    }
+}
+
+impl From<CmpOp> for Test {
+    fn from(op: CmpOp) -> Self {
+        match op {
+            CmpOp::Eq => Test::Eq,
+            CmpOp::Ne => Test::Ne,
+            CmpOp::Lt => Test::Lt,
+            CmpOp::Le => Test::Le,
+            CmpOp::Gt => Test::Gt,
+            CmpOp::Ge => Test::Ge,
+        }
+    }
+}
+
+/// Jump target of a filter object [`Instruction`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// Continue with the instruction at the given index.
+    Instruction(u16),
+    /// Stop evaluating and accept the packet.
+    Accept,
+    /// Stop evaluating and reject the packet.
+    Reject,
+}
+
+/**
+Single test of a compiled filter.
+
+The field is compared against `arg`, a 128 bit value stored as little endian 32 bit words, and
+evaluation continues with `success` or `failure` depending on the result. If the field doesn't
+exist for the packet, evaluation continues with `failure`. Negative arguments store their magnitude
+and set `neg`. Indexed fields store the index in `arg[1]`.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Instruction {
+    /// Tested field.
+    pub field: Field,
+    /// Test kind.
+    pub test: Test,
+    /// Next step if the test succeeds.
+    pub success: Target,
+    /// Next step if the test fails.
+    pub failure: Target,
+    /// Set if the argument is negative.
+    pub neg: bool,
+    /// Test argument.
+    pub arg: [u32; 4],
+}
+
+impl Instruction {
+    /// Constant part of the test argument, without the index of indexed fields.
+    fn value(&self) -> Scalar {
+        let magnitude = if self.field.is_indexed() {
+            self.arg[0] as u128
+        } else {
+            self.arg
+                .iter()
+                .rev()
+                .fold(0u128, |acc, &word| acc << 32 | word as u128)
+        };
+        match self.field.field_type() {
+            FieldType::Ipv4 | FieldType::Ipv6 | FieldType::IpAddr => Scalar::Addr(magnitude),
+            _ if self.neg => Scalar::Int((magnitude as i128).wrapping_neg()),
+            _ => Scalar::Int(magnitude as i128),
+        }
+    }
+}
+
+/**
+Compiled filter object.
+
+This is the encoded form accepted by [`WinDivertOpen()`](fn@windivert_sys::WinDivertOpen) in place
+of a filter string, as produced by
+[`WinDivertHelperCompileFilter()`](fn@windivert_sys::WinDivertHelperCompileFilter). Filters are
+compiled into a list of tests with forward jumps, so evaluation always terminates.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FilterObject {
+    instructions: Vec<Instruction>,
+}
+
+impl FilterObject {
+    /// Maximum number of instructions of a filter object.
+    pub const MAX_LEN: usize = windivert_filter::MAX_TESTS;
+
+    /// Instructions of the object, evaluation starts with the first one.
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// Encodes the object into its string representation.
+    pub fn encode(&self) -> String {
+        self.encode_spans().0
+    }
+
+    /// Encoded object and the span of each instruction in it.
+    fn encode_spans(&self) -> (String, Vec<Span>) {
+        let mut out = String::from(MAGIC);
+        put_number(&mut out, VERSION);
+        put_number(&mut out, self.instructions.len() as u32);
+        let mut spans = Vec::with_capacity(self.instructions.len());
+        for inst in &self.instructions {
+            let start = out.len();
+            out.push('_');
+            put_number(&mut out, field_id(inst.field));
+            put_number(&mut out, inst.test.id());
+            put_number(&mut out, inst.neg as u32);
+            put_number(&mut out, inst.arg[0]);
+            if inst.field.is_indexed() {
+                put_number(&mut out, (inst.arg[1] as i32 + INDEX_BIAS) as u32);
+            } else {
+                for word in &inst.arg[1..arg_words(inst.field)] {
+                    put_number(&mut out, *word);
+                }
+            }
+            put_target(&mut out, inst.success);
+            put_target(&mut out, inst.failure);
+            spans.push(Span::new(start, out.len()));
+        }
+        (out, spans)
+    }
+
+    /// Decodes an object from its string representation.
+    pub fn decode(object: &str) -> Result<Self, WinDivertError> {
+        let mut reader = Reader {
+            bytes: object.as_bytes(),
+            pos: 0,
+        };
+        if !object.starts_with(MAGIC) {
+            return Err(reader.error("missing filter object header"));
+        }
+        reader.pos = MAGIC.len();
+        if reader.number(4)? != VERSION {
+            return Err(reader.error("unsupported filter object version"));
+        }
+        let len = reader.number(2)? as usize;
+        if len == 0 || len > Self::MAX_LEN {
+            return Err(reader.error("invalid filter object length"));
+        }
+
+        let mut instructions = Vec::with_capacity(len);
+        for index in 0..len {
+            if reader.bytes.get(reader.pos) != Some(&b'_') {
+                return Err(reader.error("expected instruction"));
+            }
+            reader.pos += 1;
+            let field = reader.number(2)?;
+            let field = field_from_id(field).ok_or_else(|| reader.error("unknown field"))?;
+            let test = reader.number(2)?;
+            let test = Test::from_id(test).ok_or_else(|| reader.error("unknown test"))?;
+            let neg = match reader.number(1)? {
+                0 => false,
+                1 => true,
+                _ => return Err(reader.error("invalid sign")),
+            };
+            let mut arg = [reader.number(7)?, 0, 0, 0];
+            if field.is_indexed() {
+                arg[1] = (reader.number(7)? as i32 - INDEX_BIAS) as u32;
+            } else if matches!(field, Field::IpSrcAddr | Field::IpDstAddr) {
+                // IPv4 addresses are IPv4-mapped
+                arg[1] = 0xFFFF;
+            } else {
+                for word in &mut arg[1..arg_words(field)] {
+                    *word = reader.number(7)?;
+                }
+            }
+            let success = reader.target(index, len)?;
+            let failure = reader.target(index, len)?;
+            instructions.push(Instruction {
+                field,
+                test,
+                success,
+                failure,
+                neg,
+                arg,
+            });
+        }
+        if reader.pos != reader.bytes.len() {
+            return Err(reader.error("unexpected data after filter object"));
+        }
+        Ok(Self { instructions })
+    }
+
+    /**
+    Rebuilds a filter expression from the object.
+
+    Objects produced by [`Filter::compile()`] decode back to an expression equivalent to the
+    original one, but spellings that compile to the same tests decode to a single form: chains of
+    `and`/`or` are grouped to the left, negations are applied to the tests (`not (tcp.Syn and
+    tcp.DstPort == 80)` becomes `not tcp.Syn or tcp.DstPort != 80`), constants are folded,
+    symbolic values other than events and layers decode as numbers (`ip.Protocol == TCP` becomes
+    `ip.Protocol == 6`) and boolean fields compared with `0` decode as the bare field.
+    */
+    pub fn to_expr(&self, layer: WinDivertLayer) -> Expr {
+        let mut decoder = Decoder {
+            instructions: &self.instructions,
+            layer,
+            memo: HashMap::new(),
+        };
+        decoder
+            .structured(0, Target::Accept, Target::Reject)
+            .unwrap_or_else(|| decoder.ternary(Target::Instruction(0)))
+    }
+
+    /// Rebuilds a filter from the object, see [`FilterObject::to_expr()`].
+    pub fn to_filter(&self, layer: WinDivertLayer) -> Filter {
+        Filter::from(self.to_expr(layer))
+    }
+
+    /**
+    Checks that every test of the object is valid for `layer`, like [`Filter::validate()`] does
+    for filter strings.
+
+    The layer isn't part of the encoding, so [`FilterObject::decode()`] accepts objects compiled
+    for any layer. The position of the returned error is the offset of the offending instruction
+    in the [encoded](FilterObject::encode) object.
+    */
+    pub fn validate(&self, layer: WinDivertLayer) -> Result<(), WinDivertError> {
+        let (_, spans) = self.encode_spans();
+        for (inst, span) in self.instructions.iter().zip(spans) {
+            let field = FieldRef {
+                span,
+                ..FieldRef::new(inst.field)
+            };
+            validate_field(&field, filter_layer(layer))?;
+            let value = constant(inst.value(), inst.field.field_type(), layer);
+            validate_value(&field, inst.test.op(), &value, span, filter_layer(layer))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FilterObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.encode())
+    }
+}
+
+impl FromStr for FilterObject {
+    type Err = WinDivertError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::decode(s)
+    }
+}
+
+/// Returns `true` if `filter` is an encoded filter object instead of a filter string.
+#[cfg(target_os = "windows")]
+pub(crate) fn is_object(filter: &str) -> bool {
+    filter.starts_with(MAGIC)
+}
+
+impl Filter {
+    /**
+    Compiles the filter for `layer`, equivalent to
+    [`WinDivertHelperCompileFilter()`](fn@windivert_sys::WinDivertHelperCompileFilter).
+
+    The filter is [validated](Filter::validate) first. Filters that need more than
+    [`FilterObject::MAX_LEN`] instructions are rejected.
+    */
+    pub fn compile(&self, layer: WinDivertLayer) -> Result<FilterObject, WinDivertError> {
+        self.validate(layer)?;
+        let mut compiler = Compiler {
+            layer,
+            instructions: Vec::new(),
+        };
+        let entry = compiler.expr(self.expr(), Target::Accept, Target::Reject, false)?;
+        let entry = match entry {
+            Target::Instruction(entry) => entry as usize,
+            // Constant filters are a single test that always jumps to the result
+            target => {
+                let instructions = vec![Instruction {
+                    field: Field::Zero,
+                    test: Test::Eq,
+                    success: target,
+                    failure: target,
+                    neg: false,
+                    arg: [0; 4],
+                }];
+                return Ok(FilterObject { instructions });
+            }
+        };
+
+        // Instructions were emitted in reverse order, flip them so jumps go forward. Those emitted
+        // after the entry point are unreachable.
+        let remap = |target: Target| match target {
+            Target::Instruction(index) => Target::Instruction((entry - index as usize) as u16),
+            target => target,
+        };
+        compiler.instructions.truncate(entry + 1);
+        let instructions = compiler
+            .instructions
+            .into_iter()
+            .rev()
+            .map(|inst| Instruction {
+                success: remap(inst.success),
+                failure: remap(inst.failure),
+                ..inst
+            })
+            .collect();
+        Ok(FilterObject { instructions })
+    }
+}
+
+struct Compiler {
+    layer: WinDivertLayer,
+    instructions: Vec<Instruction>,
+}
+
+impl Compiler {
+    /// Emits the instructions for `expr`, or `not expr` if `negate` is set, and returns the entry
+    /// point.
+    ///
+    /// Negations are pushed down to the tests so that tests on missing fields stay false, constants
+    /// are folded into the jumps.
+    fn expr(
+        &mut self,
+        expr: &Expr,
+        success: Target,
+        failure: Target,
+        negate: bool,
+    ) -> Result<Target, WinDivertError> {
+        let (field, op, value) = match &expr.kind {
+            ExprKind::Bool(value) => return Ok(if *value != negate { success } else { failure }),
+            ExprKind::Field(field) => (field, CmpOp::Ne, Value::Int(0)),
+            ExprKind::Compare { field, op, value } => (field, *op, value.clone()),
+            ExprKind::Not(expr) => return self.expr(expr, success, failure, !negate),
+            ExprKind::And(lhs, rhs) | ExprKind::Or(lhs, rhs) => {
+                let and = matches!(expr.kind, ExprKind::And(..)) != negate;
+                let rhs = self.expr(rhs, success, failure, negate)?;
+                return match and {
+                    true => self.expr(lhs, rhs, failure, negate),
+                    false => self.expr(lhs, success, rhs, negate),
+                };
+            }
+            ExprKind::Ternary {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let otherwise = self.expr(otherwise, success, failure, negate)?;
+                let then = self.expr(then, success, failure, negate)?;
+                return self.expr(cond, then, otherwise, false);
+            }
+        };
+        let op = if negate { op.negate() } else { op };
+        if field.field == Field::Zero {
+            let value = literal(&value, FieldType::U32, self.layer);
+            let result = compare_scalars(Scalar::Int(0), op, value);
+            return Ok(if result { success } else { failure });
+        }
+        let (op, neg, value) = self.value(field, op, &value);
+
+        if self.instructions.len() == FilterObject::MAX_LEN {
+            return Err(WinDivertError::Filter {
+                message: format!(
+                    "filter is too long, objects are limited to {} tests",
+                    FilterObject::MAX_LEN
+                ),
+                position: expr.span.start,
+            });
+        }
+        self.instructions.push(Instruction {
+            field: field.field,
+            test: Test::from(op),
+            success,
+            failure,
+            neg,
+            arg: self.arg(field, value),
+        });
+        Ok(Target::Instruction(self.instructions.len() as u16 - 1))
+    }
+
+    /// Returns the test operator, the sign and the magnitude of the argument.
+    fn value(&self, field: &FieldRef, op: CmpOp, value: &Value) -> (CmpOp, bool, u128) {
+        let ty = field.field.field_type();
+        let (op, value) = clamp(ty, op, value);
+        match literal(&value, ty, self.layer) {
+            Scalar::Int(int) => (op, int < 0, int.unsigned_abs()),
+            Scalar::Addr(addr) => (op, false, addr),
+        }
+    }
+
+    fn arg(&self, field: &FieldRef, value: u128) -> [u32; 4] {
+        let mut arg = [
+            value as u32,
+            (value >> 32) as u32,
+            (value >> 64) as u32,
+            (value >> 96) as u32,
+        ];
+        if let Some(index) = field.index {
+            arg[1] = index as u32;
+        }
+        arg
+    }
+}
+
+struct Decoder<'a> {
+    instructions: &'a [Instruction],
+    layer: WinDivertLayer,
+    memo: HashMap<(usize, Target, Target), Option<Expr>>,
+}
+
+impl Decoder<'_> {
+    /**
+    Finds an expression that, starting at instruction `index`, jumps to `success` when it holds
+    and to `failure` otherwise.
+
+    This mirrors [`Compiler::expr()`]: operands occupy contiguous instruction ranges, with the left
+    operand first. Splitting at the latest possible instruction rebuilds left associative chains.
+    */
+    fn structured(&mut self, index: usize, success: Target, failure: Target) -> Option<Expr> {
+        let key = (index, success, failure);
+        if let Some(expr) = self.memo.get(&key) {
+            return expr.clone();
+        }
+        let expr = self.structured_uncached(index, success, failure);
+        self.memo.insert(key, expr.clone());
+        expr
+    }
+
+    fn structured_uncached(
+        &mut self,
+        index: usize,
+        success: Target,
+        failure: Target,
+    ) -> Option<Expr> {
+        let inst = self.instructions[index];
+        if inst.success == success && inst.failure == failure {
+            return Some(self.test(&inst));
+        }
+
+        // The expression ends before the instructions it jumps to
+        let end = [success, failure]
+            .iter()
+            .filter_map(|target| match target {
+                Target::Instruction(target) => Some(*target as usize),
+                _ => None,
+            })
+            .fold(self.instructions.len(), usize::min);
+        for split in (index + 1..end).rev() {
+            let target = Target::Instruction(split as u16);
+            if self.exits_to(index, split, &[target, failure]) {
+                if let (Some(lhs), Some(rhs)) = (
+                    self.structured(index, target, failure),
+                    self.structured(split, success, failure),
+                ) {
+                    return Some(Expr::new(ExprKind::And(Box::new(lhs), Box::new(rhs))));
+                }
+            }
+            if self.exits_to(index, split, &[success, target]) {
+                if let (Some(lhs), Some(rhs)) = (
+                    self.structured(index, success, target),
+                    self.structured(split, success, failure),
+                ) {
+                    return Some(Expr::new(ExprKind::Or(Box::new(lhs), Box::new(rhs))));
+                }
+            }
+        }
+
+        for otherwise in (index + 2..end).rev() {
+            for then in (index + 1..otherwise).rev() {
+                let then_target = Target::Instruction(then as u16);
+                let otherwise_target = Target::Instruction(otherwise as u16);
+                if !self.exits_to(index, then, &[then_target, otherwise_target])
+                    || !self.exits_to(then, otherwise, &[success, failure])
+                {
+                    continue;
+                }
+                if let (Some(cond), Some(then), Some(otherwise)) = (
+                    self.structured(index, then_target, otherwise_target),
+                    self.structured(then, success, failure),
+                    self.structured(otherwise, success, failure),
+                ) {
+                    return Some(Expr::new(ExprKind::Ternary {
+                        cond: Box::new(cond),
+                        then: Box::new(then),
+                        otherwise: Box::new(otherwise),
+                    }));
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns `true` if every jump out of `start..end` goes to one of `targets`.
+    fn exits_to(&self, start: usize, end: usize, targets: &[Target]) -> bool {
+        self.instructions[start..end].iter().all(|inst| {
+            [inst.success, inst.failure]
+                .iter()
+                .all(|target| match target {
+                    Target::Instruction(index) if (start..end).contains(&(*index as usize)) => true,
+                    target => targets.contains(target),
+                })
+        })
+    }
+
+    /// Fallback for objects that weren't produced by [`Filter::compile()`], builds one
+    /// conditional expression per instruction.
+    fn ternary(&self, target: Target) -> Expr {
+        let index = match target {
+            Target::Instruction(index) => index as usize,
+            Target::Accept => return Expr::new(ExprKind::Bool(true)),
+            Target::Reject => return Expr::new(ExprKind::Bool(false)),
+        };
+        let inst = self.instructions[index];
+        if inst.success == inst.failure {
+            return self.ternary(inst.success);
+        }
+        let cond = self.test(&inst);
+        let then = self.ternary(inst.success);
+        let otherwise = self.ternary(inst.failure);
+        let kind = match (&then.kind, &otherwise.kind) {
+            (ExprKind::Bool(true), ExprKind::Bool(false)) => return cond,
+            (ExprKind::Bool(true), _) => ExprKind::Or(Box::new(cond), Box::new(otherwise)),
+            (_, ExprKind::Bool(false)) => ExprKind::And(Box::new(cond), Box::new(then)),
+            _ => ExprKind::Ternary {
+                cond: Box::new(cond),
+                then: Box::new(then),
+                otherwise: Box::new(otherwise),
+            },
+        };
+        Expr::new(kind)
+    }
+
+    /// Expression of a single instruction test, ignoring its targets.
+    fn test(&self, inst: &Instruction) -> Expr {
+        let field = match inst.field.is_indexed() {
+            true => FieldRef::indexed(inst.field, inst.arg[1] as i32),
+            false => FieldRef::new(inst.field),
+        };
+        let value = inst.value();
+        let boolean = inst.field.field_type() == FieldType::Bool && value == Scalar::Int(0);
+        let kind = match inst.test {
+            // Constant filters test the zero field
+            _ if inst.field == Field::Zero => {
+                ExprKind::Bool(compare_scalars(Scalar::Int(0), inst.test.op(), value))
+            }
+            Test::Ne if boolean => ExprKind::Field(field),
+            Test::Eq if boolean => ExprKind::Not(Box::new(Expr::new(ExprKind::Field(field)))),
+            test => ExprKind::Compare {
+                value: constant(value, inst.field.field_type(), self.layer),
+                field,
+                op: test.op(),
+            },
+        };
+        Expr::new(kind)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn error(&self, message: &str) -> WinDivertError {
+        WinDivertError::Filter {
+            message: message.to_owned(),
+            position: self.pos,
+        }
+    }
+
+    /// Reads a number of at most `digits` digits.
+    fn number(&mut self, digits: usize) -> Result<u32, WinDivertError> {
+        let mut value = 0u32;
+        for _ in 0..digits {
+            if value >> 27 != 0 {
+                return Err(self.error("number is too large"));
+            }
+            let digit = self
+                .bytes
+                .get(self.pos)
+                .and_then(|byte| DIGITS.iter().position(|digit| digit == byte))
+                .ok_or_else(|| self.error("invalid number"))? as u32;
+            self.pos += 1;
+            value = value << 5 | (digit & 0x1F);
+            if digit & 0x20 != 0 {
+                return Ok(value);
+            }
+        }
+        Err(self.error("number is too large"))
+    }
+
+    fn target(&mut self, index: usize, len: usize) -> Result<Target, WinDivertError> {
+        let start = self.pos;
+        self.pos += 1;
+        let target = match self.bytes.get(start) {
+            Some(b'A') => return Ok(Target::Accept),
+            Some(b'X') => return Ok(Target::Reject),
+            Some(b'L') => self.number(2)? as usize,
+            _ => len,
+        };
+        if target > index && target < len {
+            Ok(Target::Instruction(target as u16))
+        } else {
+            self.pos = start;
+            Err(self.error("invalid jump target"))
+        }
+    }
+}
+
+/// Writes `value` most significant digit first, the last digit marks the end of the number.
+fn put_number(out: &mut String, value: u32) {
+    let digits = (1..7)
+        .find(|digits| value >> (5 * digits) == 0)
+        .unwrap_or(7);
+    for digit in (0..digits).rev() {
+        let mut bits = (value >> (5 * digit)) & 0x1F;
+        if digit == 0 {
+            bits |= 0x20;
+        }
+        out.push(DIGITS[bits as usize] as char);
+    }
+}
+
+fn put_target(out: &mut String, target: Target) {
+    match target {
+        Target::Instruction(index) => {
+            out.push('L');
+            put_number(out, index as u32);
+        }
+        Target::Accept => out.push('A'),
+        Target::Reject => out.push('X'),
+    }
+}
+
+/// Number of argument words stored in the encoded object, for fields that aren't indexed.
+fn arg_words(field: Field) -> usize {
+    use Field::*;
+    match field {
+        Ipv6SrcAddr | Ipv6DstAddr | LocalAddr | RemoteAddr => 4,
+        EndpointId | ParentEndpointId | Timestamp => 2,
+        _ => 1,
+    }
+}
+
+fn field_from_id(id: u32) -> Option<Field> {
+    Field::ALL
+        .iter()
+        .copied()
+        .find(|field| field_id(*field) == id)
+}
+
+/// Field identifiers used by the driver.
+fn field_id(field: Field) -> u32 {
+    use Field::*;
+    match field {
+        Zero => 0,
+        Inbound => 1,
+        Outbound => 2,
+        IfIdx => 3,
+        SubIfIdx => 4,
+        Ip => 5,
+        Ipv6 => 6,
+        Icmp => 7,
+        Tcp => 8,
+        Udp => 9,
+        Icmpv6 => 10,
+        IpHdrLength => 11,
+        IpTos => 12,
+        IpLength => 13,
+        IpId => 14,
+        IpDf => 15,
+        IpMf => 16,
+        IpFragOff => 17,
+        IpTtl => 18,
+        IpProtocol => 19,
+        IpChecksum => 20,
+        IpSrcAddr => 21,
+        IpDstAddr => 22,
+        Ipv6TrafficClass => 23,
+        Ipv6FlowLabel => 24,
+        Ipv6Length => 25,
+        Ipv6NextHdr => 26,
+        Ipv6HopLimit => 27,
+        Ipv6SrcAddr => 28,
+        Ipv6DstAddr => 29,
+        IcmpType => 30,
+        IcmpCode => 31,
+        IcmpChecksum => 32,
+        IcmpBody => 33,
+        Icmpv6Type => 34,
+        Icmpv6Code => 35,
+        Icmpv6Checksum => 36,
+        Icmpv6Body => 37,
+        TcpSrcPort => 38,
+        TcpDstPort => 39,
+        TcpSeqNum => 40,
+        TcpAckNum => 41,
+        TcpHdrLength => 42,
+        TcpUrg => 43,
+        TcpAck => 44,
+        TcpPsh => 45,
+        TcpRst => 46,
+        TcpSyn => 47,
+        TcpFin => 48,
+        TcpWindow => 49,
+        TcpChecksum => 50,
+        TcpUrgPtr => 51,
+        TcpPayloadLength => 52,
+        UdpSrcPort => 53,
+        UdpDstPort => 54,
+        UdpLength => 55,
+        UdpChecksum => 56,
+        UdpPayloadLength => 57,
+        Loopback => 58,
+        Impostor => 59,
+        ProcessId => 60,
+        LocalAddr => 61,
+        RemoteAddr => 62,
+        LocalPort => 63,
+        RemotePort => 64,
+        Protocol => 65,
+        EndpointId => 66,
+        ParentEndpointId => 67,
+        Layer => 68,
+        Priority => 69,
+        Event => 70,
+        Packet => 71,
+        Packet16 => 72,
+        Packet32 => 73,
+        TcpPayload => 74,
+        TcpPayload16 => 75,
+        TcpPayload32 => 76,
+        UdpPayload => 77,
+        UdpPayload16 => 78,
+        UdpPayload32 => 79,
+        Length => 80,
+        Timestamp => 81,
+        Random8 => 82,
+        Random16 => 83,
+        Random32 => 84,
+        Fragment => 85,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Objects produced by `WinDivertHelperCompileFilter()` for the same filters.
+    const GOLDEN: &[(WinDivertLayer, &str, &str)] = &[
+        (WinDivertLayer::Network, "true", "@WinDiv_WX_WWWWAA"),
+        (WinDivertLayer::Network, "false", "@WinDiv_WX_WWWWXX"),
+        (WinDivertLayer::Network, "tcp", "@WinDiv_WX_eXWWAX"),
+        (
+            WinDivertLayer::Network,
+            "tcp.DstPort == 80",
+            "@WinDiv_WX_1dWW2mAX",
+        ),
+        (
+            WinDivertLayer::Network,
+            "tcp and udp",
+            "@WinDiv_WY_eXWWLXX_fXWWAX",
+        ),
+        (
+            WinDivertLayer::Network,
+            "tcp.Syn and not tcp.Ack",
+            "@WinDiv_WY_1lXWWLXX_1iWWWAX",
+        ),
+        (
+            WinDivertLayer::Network,
+            "tcp.DstPort == 80 or tcp.DstPort == 443",
+            "@WinDiv_WY_1dWW2mALX_1dWWDxAX",
+        ),
+        (
+            WinDivertLayer::Network,
+            "(tcp ? tcp.DstPort == 1 : udp.DstPort == 2)",
+            "@WinDiv_WZ_eXWWLXLY_1dWWXAX_1sWWYAX",
+        ),
+        (
+            WinDivertLayer::Network,
+            "outbound and ip.DstAddr >= 10.0.0.0 and ip.DstAddr <= 10.255.255.255",
+            "@WinDiv_WZ_YXWWLXX_sbW50000WLYX_sZW5FVVV=AX",
+        ),
+        (
+            WinDivertLayer::Network,
+            "ip.SrcAddr == 1.2.3.4",
+            "@WinDiv_WX_rWWG40OaAX",
+        ),
+        (
+            WinDivertLayer::Network,
+            "ipv6.DstAddr == 2001:db8::1234:5678",
+            "@WinDiv_WX_zWW938LJuWWG023DuAX",
+        ),
+        (
+            WinDivertLayer::Network,
+            "packet[-4] == 5",
+            "@WinDiv_WX_2dWWb1VVxAX",
+        ),
+        (
+            WinDivertLayer::Network,
+            "packet32[2] != 0x12345678",
+            "@WinDiv_WX_2fXW938LJu200dAX",
+        ),
+        (
+            WinDivertLayer::Network,
+            "tcp.PayloadLength > 0 and tcp.Payload[0] == 0x16",
+            "@WinDiv_WY_1qaWWLXX_2gWWs1VV=AX",
+        ),
+        (
+            WinDivertLayer::Network,
+            "timestamp > -5",
+            "@WinDiv_WX_2naXbWAX",
+        ),
+        (
+            WinDivertLayer::Network,
+            "icmp.Type == 8 or icmpv6.Type == 128",
+            "@WinDiv_WY_+WWeALX_1YWW4WAX",
+        ),
+        (
+            WinDivertLayer::Network,
+            "udp.DstPort == 53 and (ip or ipv6) and length < 512",
+            "@WinDiv_Wa_1sWW1rLXX_bXWWLZLY_cXWWLZX_2mYWGWAX",
+        ),
+        (
+            WinDivertLayer::Flow,
+            "localPort == 80 or remotePort == 443",
+            "@WinDiv_WY_1=WW2mALX_2WWWDxAX",
+        ),
+        (
+            WinDivertLayer::Flow,
+            "processId == 4 and not loopback",
+            "@WinDiv_WY_1yWWaLXX_1wWWWAX",
+        ),
+        (
+            WinDivertLayer::Socket,
+            "event == CONNECT and processId == 4",
+            "@WinDiv_WY_2cWWaLXX_1yWWaAX",
+        ),
+        (
+            WinDivertLayer::Socket,
+            "endpointId == 5",
+            "@WinDiv_WX_2YWWbWAX",
+        ),
+    ];
+
+    fn compile(filter: &str, layer: WinDivertLayer) -> FilterObject {
+        Filter::parse(filter).unwrap().compile(layer).unwrap()
+    }
+
+    #[test]
+    fn golden() {
+        for &(layer, filter, object) in GOLDEN {
+            let compiled = compile(filter, layer);
+            assert_eq!(compiled.encode(), object, "{filter}");
+            assert_eq!(FilterObject::decode(object).unwrap(), compiled, "{filter}");
+            compiled.validate(layer).unwrap();
+        }
+    }
+
+    #[test]
+    fn field_ids() {
+        for field in Field::ALL {
+            assert_eq!(field_from_id(field_id(*field)), Some(*field));
+        }
+        let mut ids: Vec<_> = Field::ALL.iter().map(|field| field_id(*field)).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, (0..=85).collect::<Vec<_>>());
+        assert_eq!(field_from_id(86), None);
+    }
+
+    #[test]
+    fn numbers() {
+        for value in [0, 1, 31, 32, 1023, 1024, 0xFFFF, 0x0800_0000, u32::MAX] {
+            let mut out = String::new();
+            put_number(&mut out, value);
+            let mut reader = Reader {
+                bytes: out.as_bytes(),
+                pos: 0,
+            };
+            assert_eq!(reader.number(7).unwrap(), value, "{out}");
+            assert_eq!(reader.pos, out.len());
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let filters = [
+            (
+                WinDivertLayer::Network,
+                "not (tcp.Syn and tcp.DstPort == 80)",
+            ),
+            (WinDivertLayer::Network, "not (tcp or udp) and ip.Ttl < 64"),
+            (
+                WinDivertLayer::Network,
+                "(tcp.DstPort == 80 or tcp.DstPort == 8080) and not (ip.SrcAddr == 10.0.0.1)",
+            ),
+            (
+                WinDivertLayer::Network,
+                "ipv6 ? ipv6.HopLimit > 1 : (ip.Ttl > 1 and not ip.MF)",
+            ),
+            (
+                WinDivertLayer::Network,
+                "packet16[3b] == 1 and packet32[-2] == 1",
+            ),
+            (
+                WinDivertLayer::Network,
+                "udp.Payload32[-1] != 0 or udp.PayloadLength == 0",
+            ),
+            (WinDivertLayer::Network, "timestamp < -1000 or random8 < 16"),
+            (WinDivertLayer::Network, "ip.Protocol == TCP"),
+            (
+                WinDivertLayer::Forward,
+                "ip.DstAddr == 192.168.0.0 or ipv6.SrcAddr == ::1",
+            ),
+            (
+                WinDivertLayer::Flow,
+                "remoteAddr == ::ffff:1.2.3.4 and endpointId != 7",
+            ),
+            (WinDivertLayer::Socket, "event == BIND or event == LISTEN"),
+            (
+                WinDivertLayer::Reflect,
+                "event == OPEN and layer == NETWORK",
+            ),
+        ];
+        for (layer, filter) in filters {
+            let compiled = compile(filter, layer);
+            let decoded = FilterObject::decode(&compiled.encode()).unwrap();
+            assert_eq!(decoded, compiled, "{filter}");
+            decoded.validate(layer).unwrap();
+            let rebuilt = decoded.to_filter(layer);
+            assert_eq!(
+                rebuilt.compile(layer).unwrap(),
+                compiled,
+                "{filter} -> {rebuilt}"
+            );
+        }
+    }
+
+    #[test]
+    fn canonical_form() {
+        let cases = [
+            ("true", "true"),
+            ("tcp and false", "false"),
+            ("tcp and (true or udp)", "tcp"),
+            (
+                "not (tcp.Syn and tcp.DstPort == 80)",
+                "not tcp.Syn or tcp.DstPort != 80",
+            ),
+            ("ip.Protocol == TCP", "ip.Protocol == 6"),
+            ("tcp.Syn != 0", "tcp.Syn"),
+        ];
+        for (filter, expected) in cases {
+            let object = compile(filter, WinDivertLayer::Network);
+            let rebuilt = object.to_filter(WinDivertLayer::Network);
+            assert_eq!(rebuilt.to_string(), expected, "{filter}");
+        }
+    }
+
+    #[test]
+    fn validate() {
+        let error = |filter, compiled_for, layer| {
+            let object = compile(filter, compiled_for).encode();
+            match FilterObject::decode(&object).unwrap().validate(layer) {
+                Err(WinDivertError::Filter { message, position }) => {
+                    (message, object[position..].to_owned())
+                }
+                result => panic!("{filter}: expected an error, found {result:?}"),
+            }
+        };
+        assert_eq!(
+            error(
+                "tcp or processId == 4",
+                WinDivertLayer::Socket,
+                WinDivertLayer::Network
+            ),
+            // Second instruction
+            (
+                "field `processId` is not available on the network layer".to_owned(),
+                "_1yWWaAX".to_owned()
+            )
+        );
+        assert_eq!(
+            error(
+                "event == CONNECT",
+                WinDivertLayer::Socket,
+                WinDivertLayer::Network
+            )
+            .0,
+            "event `CONNECT` is not available on the network layer"
+        );
+    }
+
+    #[test]
+    fn decode_errors() {
+        let cases = [
+            ("WinDiv_WX_WWWWAA", "missing filter object header", 0),
+            ("@WinDiv_XX_WWWWAA", "unsupported filter object version", 9),
+            ("@WinDiv_WW", "invalid filter object length", 10),
+            ("@WinDiv_WX_WWWW", "invalid jump target", 15),
+            ("@WinDiv_WX_W!WWAX", "invalid number", 12),
+            ("@WinDiv_WX_3WWWWAX", "unknown field", 13),
+            ("@WinDiv_WX_WeWWAX", "unknown test", 13),
+            ("@WinDiv_WX_WWYWAX", "invalid sign", 14),
+            ("@WinDiv_WX_WWW0000000AX", "number is too large", 21),
+            ("@WinDiv_WX_WWWV00000WAX", "number is too large", 20),
+            ("@WinDiv_WY_WWWWLXX_WWWWLWX", "invalid jump target", 23),
+            ("@WinDiv_WX_WWWWLXX", "invalid jump target", 15),
+            (
+                "@WinDiv_WX_WWWWAX_",
+                "unexpected data after filter object",
+                17,
+            ),
+            ("@WinDiv_WY_WWWWAX", "expected instruction", 17),
+        ];
+        for (object, message, position) in cases {
+            match FilterObject::decode(object) {
+                Err(WinDivertError::Filter {
+                    message: got,
+                    position: at,
+                }) => assert_eq!((got.as_str(), at), (message, position), "{object}"),
+                result => panic!("{object}: unexpected {result:?}"),
+            }
+        }
+    }
+}
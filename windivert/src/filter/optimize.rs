@@ -0,0 +1,704 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use windivert_sys::WinDivertLayer;
+
+use super::{
+    eval::{clamp, constant, ipv4_mapped, literal, Scalar},
+    CmpOp, Expr, ExprKind, Field, FieldRef, FieldType, Filter, Value,
+};
+use crate::error::WinDivertError;
+
+/// Offset applied to signed values so that unsigned ordering matches signed ordering.
+const SIGN: u128 = 1 << 127;
+
+impl Filter {
+    /**
+    Rewrites the filter into an equivalent, usually shorter, filter for `layer`.
+
+    The filter is [validated](Filter::validate) first, then:
+    - Negations are pushed down to the tests and constants are folded.
+    - Nested `and`/`or` expressions are flattened and repeated clauses removed.
+    - Tests on the same field are merged, so `tcp.DstPort == 80 or tcp.DstPort == 81` becomes
+      `tcp.DstPort >= 80 and tcp.DstPort <= 81`.
+    - Contradictions such as `tcp and udp` or `inbound and outbound` become `false`, and
+      tautologies over fields every packet has, like `outbound or inbound`, become `true`.
+
+    The result matches exactly the same packets as the original filter, including the handling of
+    fields missing from a packet.
+    */
+    pub fn optimize(&self, layer: WinDivertLayer) -> Result<Filter, WinDivertError> {
+        self.validate(layer)?;
+        let optimizer = Optimizer {
+            layer,
+            literals: RefCell::default(),
+        };
+        let node = optimizer.node(self.expr(), false);
+        Ok(Filter::from(optimizer.expr(&optimizer.simplify(node))))
+    }
+}
+
+/// Converts `expr`, or `not expr` if `negate` is set, into a simplified [`Node`] for `layer`.
+pub(super) fn normalize(expr: &Expr, negate: bool, layer: WinDivertLayer) -> Node {
+    let optimizer = Optimizer {
+        layer,
+        literals: RefCell::default(),
+    };
+    optimizer.simplify(optimizer.node(expr, negate))
+}
+
+/// Set of values stored as sorted, disjoint and non adjacent inclusive ranges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Ranges(pub(super) Vec<(u128, u128)>);
+
+impl Ranges {
+    pub(super) fn new(start: u128, end: u128) -> Self {
+        if start <= end {
+            Self(vec![(start, end)])
+        } else {
+            Self(Vec::new())
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(super) fn contains(&self, value: u128) -> bool {
+        self.0
+            .iter()
+            .any(|&(start, end)| start <= value && value <= end)
+    }
+
+    /// Value of a boolean test that only accepts one value.
+    pub(super) fn flag(&self) -> Option<bool> {
+        match self.0.as_slice() {
+            [(start, end)] if start == end && *start == flag_key(false) => Some(false),
+            [(start, end)] if start == end && *start == flag_key(true) => Some(true),
+            _ => None,
+        }
+    }
+
+    fn union(&self, other: &Ranges) -> Ranges {
+        let mut ranges: Vec<_> = self.0.iter().chain(other.0.iter()).copied().collect();
+        ranges.sort_unstable();
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Ranges(merged)
+    }
+
+    pub(super) fn intersect(&self, other: &Ranges) -> Ranges {
+        let mut out = Vec::new();
+        for &(a_start, a_end) in &self.0 {
+            for &(b_start, b_end) in &other.0 {
+                let (start, end) = (a_start.max(b_start), a_end.min(b_end));
+                if start <= end {
+                    out.push((start, end));
+                }
+            }
+        }
+        Ranges(out).union(&Ranges(Vec::new()))
+    }
+
+    pub(super) fn complement(&self, domain: &Ranges) -> Ranges {
+        let (min, max) = domain.0[0];
+        let mut out = Vec::new();
+        let mut next = Some(min);
+        for &(start, end) in &self.0 {
+            if let Some(from) = next {
+                if start > from {
+                    out.push((from, start - 1));
+                }
+            }
+            next = end.checked_add(1);
+        }
+        if let Some(from) = next {
+            if from <= max {
+                out.push((from, max));
+            }
+        }
+        Ranges(out).intersect(domain)
+    }
+}
+
+/// Field tested by a [`Node::Test`], including the index of indexed fields.
+pub(super) type Key = (Field, Option<i32>);
+
+/// Filter in negation normal form, tests are reduced to the set of values they accept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Node {
+    Const(bool),
+    Test(Key, Ranges),
+    And(Vec<Node>),
+    Or(Vec<Node>),
+    Ternary(Box<Node>, Box<Node>, Box<Node>),
+}
+
+struct Optimizer {
+    layer: WinDivertLayer,
+    /// Values compared against in the original filter, used to write the result the same way.
+    literals: RefCell<HashMap<(Field, u128), Value>>,
+}
+
+impl Optimizer {
+    /// Converts `expr`, or `not expr` if `negate` is set, into a [`Node`].
+    fn node(&self, expr: &Expr, negate: bool) -> Node {
+        match &expr.kind {
+            ExprKind::Bool(value) => Node::Const(*value != negate),
+            ExprKind::Field(field) => {
+                let zero = match field.field.field_type() {
+                    FieldType::Ipv4 | FieldType::Ipv6 | FieldType::IpAddr => Scalar::Addr(0),
+                    _ => Scalar::Int(0),
+                };
+                self.test(field, CmpOp::Ne, zero, negate)
+            }
+            ExprKind::Compare { field, op, value } => {
+                let ty = field.field.field_type();
+                let (op, value) = clamp(ty, *op, value);
+                let scalar = literal(&value, ty, self.layer);
+                self.literals
+                    .borrow_mut()
+                    .entry((field.field, key(scalar)))
+                    .or_insert(value);
+                self.test(field, op, scalar, negate)
+            }
+            ExprKind::Not(expr) => self.node(expr, !negate),
+            ExprKind::And(lhs, rhs) | ExprKind::Or(lhs, rhs) => {
+                let operands = vec![self.node(lhs, negate), self.node(rhs, negate)];
+                match (&expr.kind, negate) {
+                    (ExprKind::And(..), false) | (ExprKind::Or(..), true) => Node::And(operands),
+                    _ => Node::Or(operands),
+                }
+            }
+            ExprKind::Ternary {
+                cond,
+                then,
+                otherwise,
+            } => Node::Ternary(
+                Box::new(self.node(cond, false)),
+                Box::new(self.node(then, negate)),
+                Box::new(self.node(otherwise, negate)),
+            ),
+        }
+    }
+
+    fn test(&self, field: &FieldRef, op: CmpOp, value: Scalar, negate: bool) -> Node {
+        let domain = domain(field.field);
+        let (min, max) = domain.0[0];
+        let value = key(value);
+        let ranges = match op {
+            CmpOp::Eq => Ranges::new(value, value),
+            CmpOp::Ne => Ranges::new(value, value).complement(&domain),
+            CmpOp::Lt if value == 0 => Ranges::new(1, 0),
+            CmpOp::Lt => Ranges::new(min, value - 1),
+            CmpOp::Le => Ranges::new(min, value),
+            CmpOp::Gt if value == u128::MAX => Ranges::new(1, 0),
+            CmpOp::Gt => Ranges::new(value + 1, max),
+            CmpOp::Ge => Ranges::new(value, max),
+        }
+        .intersect(&domain);
+        let ranges = if negate {
+            ranges.complement(&domain)
+        } else {
+            ranges
+        };
+
+        // `inbound` is the opposite of `outbound` on every layer that supports it
+        if field.field == Field::Inbound {
+            let mut outbound = Ranges(Vec::new());
+            for (inbound, outbound_value) in [(false, true), (true, false)] {
+                if ranges.contains(flag_key(inbound)) {
+                    let value = flag_key(outbound_value);
+                    outbound = outbound.union(&Ranges::new(value, value));
+                }
+            }
+            return Node::Test((Field::Outbound, None), outbound);
+        }
+        Node::Test((field.field, field.index), ranges)
+    }
+
+    fn simplify(&self, node: Node) -> Node {
+        match node {
+            Node::Const(_) => node,
+            Node::Test(key, ranges) => test_node(key, ranges),
+            Node::And(operands) => {
+                let mut out = Vec::new();
+                for operand in operands {
+                    if !push_and(&mut out, self.simplify(operand)) {
+                        return Node::Const(false);
+                    }
+                }
+                finish(out, true)
+            }
+            Node::Or(operands) => {
+                let mut out = Vec::new();
+                for operand in operands {
+                    if !push_or(&mut out, self.simplify(operand)) {
+                        return Node::Const(true);
+                    }
+                }
+                finish(out, false)
+            }
+            Node::Ternary(cond, then, otherwise) => {
+                let cond = self.simplify(*cond);
+                let then = self.simplify(*then);
+                let otherwise = self.simplify(*otherwise);
+                match (cond, then, otherwise) {
+                    (Node::Const(true), then, _) => then,
+                    (Node::Const(false), _, otherwise) => otherwise,
+                    (_, then, otherwise) if then == otherwise => then,
+                    (cond, Node::Const(true), Node::Const(false)) => cond,
+                    (cond, then, Node::Const(false)) => self.simplify(Node::And(vec![cond, then])),
+                    (cond, Node::Const(true), otherwise) => {
+                        self.simplify(Node::Or(vec![cond, otherwise]))
+                    }
+                    (cond, then, otherwise) => {
+                        Node::Ternary(Box::new(cond), Box::new(then), Box::new(otherwise))
+                    }
+                }
+            }
+        }
+    }
+
+    fn expr(&self, node: &Node) -> Expr {
+        match node {
+            Node::Const(value) => Expr::new(ExprKind::Bool(*value)),
+            Node::Test(key, ranges) => self.test_expr(*key, ranges),
+            Node::And(operands) => chain(operands.iter().map(|node| self.expr(node)), true),
+            Node::Or(operands) => chain(operands.iter().map(|node| self.expr(node)), false),
+            Node::Ternary(cond, then, otherwise) => Expr::new(ExprKind::Ternary {
+                cond: Box::new(self.expr(cond)),
+                then: Box::new(self.expr(then)),
+                otherwise: Box::new(self.expr(otherwise)),
+            }),
+        }
+    }
+
+    fn test_expr(&self, (field, index): Key, ranges: &Ranges) -> Expr {
+        let field = FieldRef {
+            index,
+            ..FieldRef::new(field)
+        };
+        let domain = domain(field.field);
+        let (min, max) = domain.0[0];
+        let compare = |op: CmpOp, value: u128| {
+            Expr::new(ExprKind::Compare {
+                field: field.clone(),
+                op,
+                value: self.value(field.field, value),
+            })
+        };
+
+        if field.field.field_type() == FieldType::Bool {
+            let bare = |field: Field| Expr::new(ExprKind::Field(FieldRef::new(field)));
+            match (field.field, ranges.flag()) {
+                (Field::Outbound, Some(false)) => return bare(Field::Inbound),
+                (_, Some(true)) => return Expr::new(ExprKind::Field(field.clone())),
+                (_, Some(false)) => return Expr::new(ExprKind::Not(Box::new(bare(field.field)))),
+                _ => {}
+            }
+        }
+        // Bounds are written with the operator of the original filter when it used the neighbour
+        let known = |value: u128| self.literals.borrow().contains_key(&(field.field, value));
+        let lower = |start: u128| match start.checked_sub(1) {
+            Some(below) if !known(start) && known(below) => compare(CmpOp::Gt, below),
+            _ => compare(CmpOp::Ge, start),
+        };
+        let upper = |end: u128| match end.checked_add(1) {
+            Some(above) if !known(end) && known(above) => compare(CmpOp::Lt, above),
+            _ => compare(CmpOp::Le, end),
+        };
+        let interval = |start: u128, end: u128| match (start, end) {
+            (start, end) if start == end => vec![compare(CmpOp::Eq, start)],
+            (start, end) if start == min && end == max => vec![compare(CmpOp::Ge, start)],
+            (start, end) if start == min => vec![upper(end)],
+            (start, end) if end == max => vec![lower(start)],
+            (start, end) => vec![lower(start), upper(end)],
+        };
+
+        // Either list the accepted intervals, or bound the values and exclude the gaps
+        let intervals: Vec<_> = ranges
+            .0
+            .iter()
+            .map(|&(start, end)| interval(start, end))
+            .collect();
+        let (first, last) = (ranges.0[0].0, ranges.0[ranges.0.len() - 1].1);
+        let mut bounded = match (first, last) {
+            (first, last) if first == min && last == max => Vec::new(),
+            (first, last) => interval(first, last),
+        };
+        bounded.extend(ranges.0.windows(2).map(|pair| {
+            let (start, end) = (pair[0].1 + 1, pair[1].0 - 1);
+            if start == end {
+                compare(CmpOp::Ne, start)
+            } else {
+                chain([upper(start - 1), lower(end + 1)], false)
+            }
+        }));
+
+        let listed = intervals.iter().flatten().map(tests).sum::<usize>();
+        let excluded = bounded.iter().map(tests).sum::<usize>();
+        if !bounded.is_empty() && excluded < listed {
+            chain(bounded, true)
+        } else {
+            chain(intervals.into_iter().map(|tests| chain(tests, true)), false)
+        }
+    }
+
+    fn value(&self, field: Field, key: u128) -> Value {
+        if let Some(value) = self.literals.borrow().get(&(field, key)) {
+            return value.clone();
+        }
+        let ty = field.field_type();
+        let scalar = match ty {
+            FieldType::Ipv4 | FieldType::Ipv6 | FieldType::IpAddr => Scalar::Addr(key),
+            _ => Scalar::Int((key ^ SIGN) as i128),
+        };
+        constant(scalar, ty, self.layer)
+    }
+}
+
+/// Simplifies a single test, returns a constant if the result doesn't depend on the packet.
+pub(super) fn test_node((field, index): Key, ranges: Ranges) -> Node {
+    if ranges.is_empty() {
+        return Node::Const(false);
+    }
+    if ranges != domain(field) || index.is_some() {
+        return Node::Test((field, index), ranges);
+    }
+    match header(field) {
+        // Tests accepting every value only check that the header exists
+        Some(header) => Node::Test((header, None), Ranges::new(flag_key(true), flag_key(true))),
+        None => Node::Const(true),
+    }
+}
+
+/// Adds an operand to a conjunction, returns `false` if the conjunction can't hold.
+pub(super) fn push_and(out: &mut Vec<Node>, node: Node) -> bool {
+    match node {
+        Node::Const(true) => true,
+        Node::Const(false) => false,
+        Node::And(operands) => operands.into_iter().all(|node| push_and(out, node)),
+        Node::Test(key, ranges) => {
+            let position = out
+                .iter()
+                .position(|node| matches!(node, Node::Test(other, _) if *other == key));
+            match position {
+                Some(position) => {
+                    let Node::Test(_, existing) = out.remove(position) else {
+                        unreachable!()
+                    };
+                    match test_node(key, existing.intersect(&ranges)) {
+                        Node::Test(merged, ranges) if merged == key => {
+                            out.insert(position, Node::Test(key, ranges));
+                        }
+                        node => return push_and(out, node),
+                    }
+                }
+                None => out.push(Node::Test(key, ranges)),
+            }
+            consistent(out)
+        }
+        node => {
+            if !out.contains(&node) {
+                out.push(node);
+            }
+            true
+        }
+    }
+}
+
+/// Adds an operand to a disjunction, returns `false` if the disjunction always holds.
+fn push_or(out: &mut Vec<Node>, node: Node) -> bool {
+    match node {
+        Node::Const(false) => true,
+        Node::Const(true) => false,
+        Node::Or(operands) => operands.into_iter().all(|node| push_or(out, node)),
+        Node::Test(key, ranges) => {
+            let position = out
+                .iter()
+                .position(|node| matches!(node, Node::Test(other, _) if *other == key));
+            match position {
+                Some(position) => {
+                    let Node::Test(_, existing) = out.remove(position) else {
+                        unreachable!()
+                    };
+                    match test_node(key, existing.union(&ranges)) {
+                        Node::Const(true) => false,
+                        Node::Test(merged, ranges) if merged == key => {
+                            out.insert(position, Node::Test(key, ranges));
+                            true
+                        }
+                        node => push_or(out, node),
+                    }
+                }
+                None => {
+                    out.push(Node::Test(key, ranges));
+                    true
+                }
+            }
+        }
+        node => {
+            if !out.contains(&node) {
+                out.push(node);
+            }
+            true
+        }
+    }
+}
+
+fn finish(mut out: Vec<Node>, and: bool) -> Node {
+    match out.len() {
+        0 => Node::Const(and),
+        1 => out.remove(0),
+        _ if and => Node::And(out),
+        _ => Node::Or(out),
+    }
+}
+
+/// Checks that the tests of a conjunction don't require incompatible headers.
+fn consistent(operands: &[Node]) -> bool {
+    use Field::*;
+    let mut required = Vec::new();
+    let mut excluded = Vec::new();
+    for operand in operands {
+        if let Node::Test((field, _), ranges) = operand {
+            if let Some(header) = header(*field) {
+                required.push(header);
+            } else if matches!(field, Ip | Ipv6 | Icmp | Icmpv6 | Tcp | Udp) {
+                match ranges.flag() {
+                    Some(true) => required.push(*field),
+                    Some(false) => excluded.push(*field),
+                    None => {}
+                }
+            }
+        }
+    }
+    let requires = |field: Field| required.contains(&field);
+    let transports = [Icmp, Icmpv6, Tcp, Udp]
+        .iter()
+        .filter(|field| requires(**field))
+        .count();
+    !(required.iter().any(|field| excluded.contains(field))
+        || transports > 1
+        || requires(Ip) && requires(Ipv6)
+        || requires(Icmp) && (requires(Ipv6) || excluded.contains(&Ip))
+        || requires(Icmpv6) && (requires(Ip) || excluded.contains(&Ipv6))
+        || transports > 0 && excluded.contains(&Ip) && excluded.contains(&Ipv6))
+}
+
+fn chain(operands: impl IntoIterator<Item = Expr>, and: bool) -> Expr {
+    operands
+        .into_iter()
+        .reduce(|lhs, rhs| {
+            Expr::new(match and {
+                true => ExprKind::And(Box::new(lhs), Box::new(rhs)),
+                false => ExprKind::Or(Box::new(lhs), Box::new(rhs)),
+            })
+        })
+        .unwrap_or_else(|| Expr::new(ExprKind::Bool(and)))
+}
+
+/// Number of tests in `expr`.
+fn tests(expr: &Expr) -> usize {
+    match &expr.kind {
+        ExprKind::Bool(_) => 0,
+        ExprKind::Field(_) | ExprKind::Compare { .. } => 1,
+        ExprKind::Not(expr) => tests(expr),
+        ExprKind::And(lhs, rhs) | ExprKind::Or(lhs, rhs) => tests(lhs) + tests(rhs),
+        ExprKind::Ternary {
+            cond,
+            then,
+            otherwise,
+        } => tests(cond) + tests(then) + tests(otherwise),
+    }
+}
+
+/// Order preserving key of a field value.
+pub(super) fn key(value: Scalar) -> u128 {
+    match value {
+        Scalar::Int(int) => int as u128 ^ SIGN,
+        Scalar::Addr(addr) => addr,
+    }
+}
+
+pub(super) fn flag_key(value: bool) -> u128 {
+    key(Scalar::Int(value as i128))
+}
+
+/// Values a field can hold.
+pub(super) fn domain(field: Field) -> Ranges {
+    match field.field_type() {
+        FieldType::Ipv4 => Ranges::new(ipv4_mapped(0), ipv4_mapped(u32::MAX)),
+        FieldType::Ipv6 | FieldType::IpAddr => Ranges::new(0, u128::MAX),
+        ty => {
+            let range = ty.int_range();
+            Ranges::new(
+                key(Scalar::Int(range.min)),
+                key(Scalar::Int(range.max as i128)),
+            )
+        }
+    }
+}
+
+/// Protocol flag that is set whenever the field exists.
+pub(super) fn header(field: Field) -> Option<Field> {
+    let name = field.name();
+    let (protocol, _) = name.split_once('.')?;
+    Field::from_name(protocol)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{
+            eval::{eval, Context},
+            testing::{random_packet, Rng},
+        },
+        *,
+    };
+
+    fn optimize(filter: &str) -> String {
+        let filter = Filter::parse(filter).unwrap();
+        filter
+            .optimize(WinDivertLayer::Network)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn contradictions() {
+        for filter in [
+            "tcp and udp",
+            "tcp.DstPort == 80 and udp.DstPort == 53",
+            "inbound and outbound",
+            "ip and ipv6",
+            "icmp and ipv6",
+            "tcp.DstPort == 80 and tcp.DstPort == 81",
+            "tcp.DstPort < 10 and tcp.DstPort > 20",
+            "tcp.Syn and not tcp",
+        ] {
+            assert_eq!(optimize(filter), "false", "{filter}");
+        }
+        for filter in ["outbound or inbound", "tcp or not tcp", "true or udp"] {
+            assert_eq!(optimize(filter), "true", "{filter}");
+        }
+    }
+
+    #[test]
+    fn merged_ranges() {
+        let cases = [
+            (
+                "tcp.DstPort == 80 or tcp.DstPort == 81",
+                "tcp.DstPort >= 80 and tcp.DstPort <= 81",
+            ),
+            (
+                "tcp.DstPort == 81 or tcp.DstPort == 80 or tcp.DstPort == 82 or tcp.DstPort == 83",
+                "tcp.DstPort >= 80 and tcp.DstPort <= 83",
+            ),
+            ("tcp.DstPort < 80 or tcp.DstPort == 80", "tcp.DstPort <= 80"),
+            (
+                "tcp.DstPort >= 1024 and tcp.DstPort != 1024",
+                "tcp.DstPort > 1024",
+            ),
+            (
+                "tcp.DstPort == 80 or tcp.DstPort == 443",
+                "tcp.DstPort == 80 or tcp.DstPort == 443",
+            ),
+            ("tcp.DstPort >= 0", "tcp"),
+            ("not (tcp.DstPort != 80)", "tcp.DstPort == 80"),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!(optimize(filter), expected, "{filter}");
+        }
+    }
+
+    /// Filters whose optimized form must match the same packets.
+    const FILTERS: &[&str] = &[
+        "true",
+        "false",
+        "tcp",
+        "not tcp",
+        "tcp and udp",
+        "tcp or udp",
+        "not (tcp or udp)",
+        "ip and not ipv6",
+        "icmp or icmpv6",
+        "not icmp and not icmpv6 and ip",
+        "tcp.DstPort == 80",
+        "not tcp.DstPort == 80",
+        "tcp.DstPort != 80",
+        "tcp.DstPort == 80 or tcp.DstPort == 81",
+        "tcp.DstPort == 80 or tcp.DstPort == 81 or tcp.DstPort == 82 or tcp.DstPort == 443",
+        "tcp.DstPort >= 80 and tcp.DstPort <= 82 and tcp.DstPort != 81",
+        "tcp.DstPort < 1024 or tcp.DstPort > 1024",
+        "tcp.DstPort <= 65535",
+        "tcp.DstPort > 65535 or tcp.DstPort < 0",
+        "not (tcp.DstPort >= 0)",
+        "tcp.DstPort == 80 or udp.DstPort == 53",
+        "(tcp.DstPort == 80 or udp.DstPort == 80) and not (tcp.DstPort == 80)",
+        "tcp.SrcPort == 53 or tcp.DstPort == 53 or udp.SrcPort == 53 or udp.DstPort == 53",
+        "not (tcp.SrcPort == 80 and tcp.DstPort == 80)",
+        "tcp.Syn and not tcp.Ack",
+        "tcp.Syn or tcp.Fin or tcp.Rst",
+        "not (tcp.Syn and tcp.Ack) and tcp",
+        "tcp.Syn and tcp",
+        "tcp.Syn == 1 or tcp.Syn == 0",
+        "tcp.PayloadLength > 0 and tcp.Payload[0] == 0",
+        "udp.PayloadLength == 0 or udp.Payload16[0] != 1",
+        "tcp.Payload32[-1] > 0x7FFFFFFF",
+        "packet[0] == 0x45 or packet[0] == 0x46",
+        "packet16[2b] < 40 and packet[-1] != 0",
+        "packet32[10] == 0 or not packet[20] == 0",
+        "ip.DstAddr >= 10.0.0.0 and ip.DstAddr <= 10.255.255.255",
+        "ip.SrcAddr == 10.0.0.1 or ip.SrcAddr == 10.0.0.2 or ip.DstAddr == 127.0.0.1",
+        "not (ip.SrcAddr < 11.0.0.0) and ip",
+        "ipv6.SrcAddr == ::1 or ipv6.DstAddr == ::ffff:10.0.0.1",
+        "ipv6.DstAddr > fe80:: and ipv6.DstAddr != ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff",
+        "ip.Ttl < 64 or ipv6.HopLimit < 64",
+        "ip.Ttl <= 1 and ip.Ttl >= 1",
+        "ip.TTL > 255 or not ip.TTL > 255",
+        "ip.MF or ip.FragOff > 0",
+        "fragment and not ip.MF",
+        "not fragment and (tcp or udp)",
+        "ip.Protocol == TCP or ip.Protocol == UDP",
+        "ipv6.NextHdr == 6 and not tcp",
+        "outbound and not loopback",
+        "inbound or outbound",
+        "inbound and (impostor or ifIdx == 1)",
+        "ifIdx >= 1 and ifIdx <= 1 and subIfIdx != 2",
+        "timestamp < 0 or timestamp == 1",
+        "timestamp > -5 and timestamp <= 1000",
+        "length < 20 or length > 60",
+        "(tcp ? tcp.DstPort == 80 : udp.DstPort == 53)",
+        "(ip ? ip.Ttl > 1 : ipv6.HopLimit > 1) and not (tcp ? tcp.Rst : false)",
+        "(tcp.Syn ? true : udp) or icmp.Type == 8",
+        "random8 < 128 and (tcp or not ipv6)",
+    ];
+
+    #[test]
+    fn random_packets() {
+        let mut rng = Rng::new(0x5EED_0006);
+        let packets: Vec<_> = (0..200_000).map(|_| random_packet(&mut rng)).collect();
+        let contexts: Vec<_> = packets
+            .iter()
+            .map(|packet| Context::new(&packet.data, packet.address.as_ref()))
+            .collect();
+        for filter in FILTERS {
+            let filter = Filter::parse(filter).unwrap();
+            let optimized = filter.optimize(WinDivertLayer::Network).unwrap();
+            for (packet, ctx) in packets.iter().zip(&contexts) {
+                assert_eq!(
+                    eval(optimized.expr(), ctx, false),
+                    eval(filter.expr(), ctx, false),
+                    "{filter} -> {optimized} on {:02x?}",
+                    packet.data
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,1299 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::{CmpOp, Expr, ExprKind, Field, FieldRef, Filter, FilterObject, Span, Value};
+use crate::error::FilterParseError;
+
+/// Maximum nesting depth accepted by the translator.
+const MAX_DEPTH: usize = 128;
+/**
+Maximum number of primitives accepted by the translator, bounding the height of the translated
+tree. Each primitive is at least one test, more than a filter object can hold.
+*/
+const MAX_PRIMITIVES: usize = FilterObject::MAX_LEN;
+
+/// Primitives that need headers WinDivert never sees.
+const LINK_LAYER: &[&str] = &[
+    "ether", "fddi", "tr", "wlan", "ppp", "slip", "link", "arp", "rarp", "vlan", "mpls", "pppoed",
+    "pppoes", "geneve", "llc", "atalk", "aarp", "decnet", "iso", "stp", "ipx", "netbeui", "lat",
+    "moprc", "mopdl", "sca", "isis", "clnp", "esis", "radio", "type", "subtype", "dir", "gateway",
+];
+
+/// TCP flags and the fields testing them.
+const TCP_FLAGS: [(&str, u32, Option<Field>); 8] = [
+    ("tcp-fin", 0x01, Some(Field::TcpFin)),
+    ("tcp-syn", 0x02, Some(Field::TcpSyn)),
+    ("tcp-rst", 0x04, Some(Field::TcpRst)),
+    ("tcp-push", 0x08, Some(Field::TcpPsh)),
+    ("tcp-ack", 0x10, Some(Field::TcpAck)),
+    ("tcp-urg", 0x20, Some(Field::TcpUrg)),
+    ("tcp-ece", 0x40, None),
+    ("tcp-cwr", 0x80, None),
+];
+
+/// Named constants accepted in comparisons.
+const CONSTANTS: &[(&str, u32)] = &[
+    ("icmp-echoreply", 0),
+    ("icmp-unreach", 3),
+    ("icmp-sourcequench", 4),
+    ("icmp-redirect", 5),
+    ("icmp-echo", 8),
+    ("icmp-routeradvert", 9),
+    ("icmp-routersolicit", 10),
+    ("icmp-timxceed", 11),
+    ("icmp-paramprob", 12),
+    ("icmp-tstamp", 13),
+    ("icmp-tstampreply", 14),
+    ("icmp-ireq", 15),
+    ("icmp-ireqreply", 16),
+    ("icmp-maskreq", 17),
+    ("icmp-maskreply", 18),
+    ("icmp6-destinationunreach", 1),
+    ("icmp6-packettoobig", 2),
+    ("icmp6-timeexceeded", 3),
+    ("icmp6-parameterproblem", 4),
+    ("icmp6-echo", 128),
+    ("icmp6-echoreply", 129),
+    ("icmp6-multicastlistenerquery", 130),
+    ("icmp6-multicastlistenerreportv1", 131),
+    ("icmp6-multicastlistenerdone", 132),
+    ("icmp6-routersolicit", 133),
+    ("icmp6-routeradvert", 134),
+    ("icmp6-neighborsolicit", 135),
+    ("icmp6-neighboradvert", 136),
+    ("icmp6-redirect", 137),
+];
+
+impl Filter {
+    /**
+    Translates a [pcap-filter] expression, the syntax of tcpdump and capture filters, into a
+    WinDivert filter.
+
+    The supported subset covers:
+    - `host`, `net` (with `/len`, `mask` or a partial address), `port` and `portrange` primitives
+      with the `src`, `dst`, `src or dst` and `src and dst` qualifiers.
+    - The `ip`, `ip6`, `tcp`, `udp`, `icmp` and `icmp6` protocols, `proto`, and `ip multicast`.
+    - `and`, `or`, `not`, parentheses and omitted qualifiers, as in `host 10.0.0.1 or 10.0.0.2`.
+    - `less`, `greater`, `inbound` and `outbound`.
+    - Comparisons on `len`, `ip[i]`, `ip6[i]`, the TCP, UDP and ICMP header fields WinDivert
+      exposes, and the TCP flags, as in `tcp[tcpflags] & (tcp-syn|tcp-ack) == tcp-syn`.
+
+    WinDivert sees packets from the IP header on, so link layer primitives such as `ether` or
+    `vlan` are rejected and `len` is the length of the IP packet. Host and service names aren't
+    resolved. Errors point to the offending token of `expression`.
+
+    [pcap-filter]: https://www.tcpdump.org/manpages/pcap-filter.7.html
+    */
+    pub fn from_pcap(expression: &str) -> Result<Filter, FilterParseError> {
+        let tokens = tokenize(expression)?;
+        let mut translator = Translator {
+            tokens,
+            pos: 0,
+            depth: 0,
+            primitives: 0,
+            last: None,
+        };
+        let expr = match translator.peek().kind {
+            TokenKind::Eof => Expr::new(ExprKind::Bool(true)),
+            _ => translator.expr()?,
+        };
+        let token = translator.peek();
+        if token.kind != TokenKind::Eof {
+            return Err(FilterParseError::new(
+                format!("unexpected {}", token.kind.describe()),
+                token.span,
+            ));
+        }
+        Filter::parse(&expr.to_canonical_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TokenKind {
+    Word(String),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Not,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    Cmp(CmpOp),
+    Eof,
+}
+
+impl TokenKind {
+    fn describe(&self) -> String {
+        match self {
+            TokenKind::Word(word) => format!("`{word}`"),
+            TokenKind::LParen => "`(`".into(),
+            TokenKind::RParen => "`)`".into(),
+            TokenKind::LBracket => "`[`".into(),
+            TokenKind::RBracket => "`]`".into(),
+            TokenKind::Not => "`not`".into(),
+            TokenKind::And => "`and`".into(),
+            TokenKind::Or => "`or`".into(),
+            TokenKind::BitAnd => "`&`".into(),
+            TokenKind::BitOr => "`|`".into(),
+            TokenKind::Cmp(op) => format!("`{op}`"),
+            TokenKind::Eof => "end of expression".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    span: Span,
+}
+
+fn is_word_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"_.:-/\\".contains(&byte)
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, FilterParseError> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let start = pos;
+        let next = bytes.get(pos + 1).copied();
+        let (kind, len) = match bytes[pos] {
+            byte if byte.is_ascii_whitespace() => {
+                pos += 1;
+                continue;
+            }
+            b'(' => (TokenKind::LParen, 1),
+            b')' => (TokenKind::RParen, 1),
+            b'[' => (TokenKind::LBracket, 1),
+            b']' => (TokenKind::RBracket, 1),
+            b'&' if next == Some(b'&') => (TokenKind::And, 2),
+            b'&' => (TokenKind::BitAnd, 1),
+            b'|' if next == Some(b'|') => (TokenKind::Or, 2),
+            b'|' => (TokenKind::BitOr, 1),
+            b'!' if next == Some(b'=') => (TokenKind::Cmp(CmpOp::Ne), 2),
+            b'!' => (TokenKind::Not, 1),
+            b'=' if next == Some(b'=') => (TokenKind::Cmp(CmpOp::Eq), 2),
+            b'=' => (TokenKind::Cmp(CmpOp::Eq), 1),
+            b'<' if next == Some(b'=') => (TokenKind::Cmp(CmpOp::Le), 2),
+            b'<' => (TokenKind::Cmp(CmpOp::Lt), 1),
+            b'>' if next == Some(b'=') => (TokenKind::Cmp(CmpOp::Ge), 2),
+            b'>' => (TokenKind::Cmp(CmpOp::Gt), 1),
+            byte if is_word_byte(byte) => {
+                let len = bytes[pos..]
+                    .iter()
+                    .position(|&byte| !is_word_byte(byte))
+                    .unwrap_or(bytes.len() - pos);
+                let kind = match &source[pos..pos + len] {
+                    "and" => TokenKind::And,
+                    "or" => TokenKind::Or,
+                    "not" => TokenKind::Not,
+                    word => TokenKind::Word(word.to_owned()),
+                };
+                (kind, len)
+            }
+            _ => {
+                let c = source[pos..].chars().next().unwrap_or_default();
+                return Err(FilterParseError::new(
+                    format!("unexpected character `{c}`"),
+                    Span::new(start, start + c.len_utf8()),
+                ));
+            }
+        };
+        pos += len;
+        tokens.push(Token {
+            kind,
+            span: Span::new(start, pos),
+        });
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        span: Span::new(bytes.len(), bytes.len()),
+    });
+    Ok(tokens)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Proto {
+    Ip,
+    Ip6,
+    Tcp,
+    Udp,
+    Icmp,
+    Icmp6,
+}
+
+impl Proto {
+    fn from_name(name: &str) -> Option<Proto> {
+        match name {
+            "ip" => Some(Proto::Ip),
+            "ip6" => Some(Proto::Ip6),
+            "tcp" => Some(Proto::Tcp),
+            "udp" => Some(Proto::Udp),
+            "icmp" => Some(Proto::Icmp),
+            "icmp6" => Some(Proto::Icmp6),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Proto::Ip => "ip",
+            Proto::Ip6 => "ip6",
+            Proto::Tcp => "tcp",
+            Proto::Udp => "udp",
+            Proto::Icmp => "icmp",
+            Proto::Icmp6 => "icmp6",
+        }
+    }
+
+    /// WinDivert field that is set for packets of the protocol.
+    fn field(&self) -> Field {
+        match self {
+            Proto::Ip => Field::Ip,
+            Proto::Ip6 => Field::Ipv6,
+            Proto::Tcp => Field::Tcp,
+            Proto::Udp => Field::Udp,
+            Proto::Icmp => Field::Icmp,
+            Proto::Icmp6 => Field::Icmpv6,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Dir {
+    SrcOrDst,
+    SrcAndDst,
+    Src,
+    Dst,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Kind {
+    Host,
+    Net,
+    Port,
+    PortRange,
+}
+
+/// Qualifiers of a primitive, reused by the following primitives that only give an id.
+#[derive(Debug, Copy, Clone)]
+struct Qualifiers {
+    proto: Option<Proto>,
+    dir: Dir,
+    kind: Kind,
+}
+
+struct Translator {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+    primitives: usize,
+    last: Option<Qualifiers>,
+}
+
+impl Translator {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+
+    fn peek_at(&self, offset: usize) -> &TokenKind {
+        let index = (self.pos + offset).min(self.tokens.len() - 1);
+        &self.tokens[index].kind
+    }
+
+    fn peek_word(&self) -> Option<&str> {
+        match &self.peek().kind {
+            TokenKind::Word(word) => Some(word),
+            _ => None,
+        }
+    }
+
+    fn bump(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> Option<Token> {
+        if &self.peek().kind == kind {
+            Some(self.bump())
+        } else {
+            None
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<Token, FilterParseError> {
+        self.eat(&kind).ok_or_else(|| {
+            let token = self.peek();
+            FilterParseError::new(
+                format!(
+                    "expected {}, found {}",
+                    kind.describe(),
+                    token.kind.describe()
+                ),
+                token.span,
+            )
+        })
+    }
+
+    fn word(&mut self, expected: &str) -> Result<(String, Span), FilterParseError> {
+        let token = self.bump();
+        match token.kind {
+            TokenKind::Word(word) => Ok((word, token.span)),
+            kind => Err(FilterParseError::new(
+                format!("expected {expected}, found {}", kind.describe()),
+                token.span,
+            )),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), FilterParseError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(FilterParseError::new(
+                "expression is nested too deeply",
+                self.peek().span,
+            ));
+        }
+        Ok(())
+    }
+
+    /// `expr := unary (('and' | 'or') unary)*`, both operators have the same precedence.
+    fn expr(&mut self) -> Result<Expr, FilterParseError> {
+        self.enter()?;
+        let mut lhs = self.unary()?;
+        loop {
+            let and = match self.peek().kind {
+                TokenKind::And => true,
+                TokenKind::Or => false,
+                _ => break,
+            };
+            self.bump();
+            let rhs = self.unary()?;
+            lhs = if and {
+                and_expr(lhs, rhs)
+            } else {
+                or_expr(lhs, rhs)
+            };
+        }
+        self.depth -= 1;
+        Ok(lhs)
+    }
+
+    /// `unary := ('not' | '!') unary | '(' expr ')' | primitive`
+    fn unary(&mut self) -> Result<Expr, FilterParseError> {
+        if self.eat(&TokenKind::Not).is_some() {
+            self.enter()?;
+            let expr = self.unary()?;
+            self.depth -= 1;
+            return Ok(not_expr(expr));
+        }
+        if self.eat(&TokenKind::LParen).is_some() {
+            let expr = self.expr()?;
+            self.expect(TokenKind::RParen)?;
+            return Ok(expr);
+        }
+        self.primitive()
+    }
+
+    fn primitive(&mut self) -> Result<Expr, FilterParseError> {
+        let span = self.peek().span;
+        self.primitives += 1;
+        if self.primitives > MAX_PRIMITIVES {
+            return Err(FilterParseError::new(
+                "expression has too many primitives",
+                span,
+            ));
+        }
+        let word = match self.peek_word() {
+            Some(word) => word.to_owned(),
+            None => {
+                return Err(FilterParseError::new(
+                    format!("unexpected {}", self.peek().kind.describe()),
+                    span,
+                ))
+            }
+        };
+
+        if LINK_LAYER.contains(&word.as_str()) {
+            return Err(FilterParseError::new(
+                format!(
+                    "`{word}` has no WinDivert equivalent, packets are captured from the IP \
+                     header on"
+                ),
+                span,
+            ));
+        }
+        match word.as_str() {
+            "inbound" | "outbound" => {
+                self.bump();
+                let field = if word == "inbound" {
+                    Field::Inbound
+                } else {
+                    Field::Outbound
+                };
+                return Ok(field_expr(field));
+            }
+            "less" | "greater" => {
+                self.bump();
+                let (value, span) = self.word("a length")?;
+                let value = number(&value, span)?;
+                let op = if word == "less" { CmpOp::Le } else { CmpOp::Ge };
+                return Ok(compare(Field::Length, None, op, value));
+            }
+            "len" => return self.relation(),
+            "broadcast" | "multicast" => {
+                return Err(FilterParseError::new(
+                    format!("link layer `{word}` has no WinDivert equivalent, use `ip {word}`"),
+                    span,
+                ))
+            }
+            "sctp" | "igmp" | "igrp" | "pim" | "ah" | "esp" | "vrrp" | "carp" => {
+                return Err(FilterParseError::new(
+                    format!("`{word}` is not supported, use `proto` with its protocol number"),
+                    span,
+                ))
+            }
+            _ => {}
+        }
+
+        // [proto] [dir] [kind] id
+        let proto = Proto::from_name(&word);
+        if let Some(proto) = proto {
+            self.bump();
+            if self.peek().kind == TokenKind::LBracket {
+                self.pos -= 1;
+                return self.relation();
+            }
+            match self.peek_word() {
+                Some("proto") => {
+                    self.bump();
+                    return self.proto(Some(proto), span);
+                }
+                Some(word @ ("multicast" | "broadcast")) => {
+                    let word = word.to_owned();
+                    let token = self.bump();
+                    return multicast(proto, &word, span.to(token.span));
+                }
+                _ => {}
+            }
+        } else if word == "proto" {
+            self.bump();
+            return self.proto(None, span);
+        }
+        let dir = self.dir();
+        let kind = match self.peek_word() {
+            Some("host") => Some(Kind::Host),
+            Some("net") => Some(Kind::Net),
+            Some("port") => Some(Kind::Port),
+            Some("portrange") => Some(Kind::PortRange),
+            _ => None,
+        };
+        if kind.is_some() {
+            self.bump();
+        }
+
+        let qualifiers = match (proto, dir, kind) {
+            (Some(proto), None, None) => return Ok(field_expr(proto.field())),
+            (None, None, None) => self.last.unwrap_or(Qualifiers {
+                proto: None,
+                dir: Dir::SrcOrDst,
+                kind: Kind::Host,
+            }),
+            (proto, dir, kind) => Qualifiers {
+                proto,
+                dir: dir.unwrap_or(Dir::SrcOrDst),
+                kind: kind.unwrap_or(Kind::Host),
+            },
+        };
+        if self.peek().kind == TokenKind::Eof {
+            return Err(FilterParseError::new(
+                "expected a host, network or port",
+                self.peek().span,
+            ));
+        }
+        let expr = self.id(qualifiers, span)?;
+        self.last = Some(qualifiers);
+        Ok(expr)
+    }
+
+    /// `dir := 'src' | 'dst' | 'src' ('or' | 'and') 'dst'`
+    fn dir(&mut self) -> Option<Dir> {
+        let dir = match self.peek_word() {
+            Some("src") => Dir::Src,
+            Some("dst") => Dir::Dst,
+            _ => return None,
+        };
+        self.bump();
+        let other = match dir {
+            Dir::Src => "dst",
+            _ => "src",
+        };
+        if self.peek_at(1) == &TokenKind::Word(other.to_owned()) {
+            match self.peek().kind {
+                TokenKind::Or => {
+                    self.pos += 2;
+                    return Some(Dir::SrcOrDst);
+                }
+                TokenKind::And => {
+                    self.pos += 2;
+                    return Some(Dir::SrcAndDst);
+                }
+                _ => {}
+            }
+        }
+        Some(dir)
+    }
+
+    fn id(&mut self, qualifiers: Qualifiers, start: Span) -> Result<Expr, FilterParseError> {
+        let (id, span) = self.word("a host, network or port")?;
+        let proto_error = |kind: &str| {
+            FilterParseError::new(
+                format!(
+                    "`{}` can't be applied to `{kind}`",
+                    qualifiers.proto.map_or("", |proto| proto.name())
+                ),
+                start.to(span),
+            )
+        };
+        match qualifiers.kind {
+            Kind::Host | Kind::Net => {
+                if !matches!(qualifiers.proto, None | Some(Proto::Ip) | Some(Proto::Ip6)) {
+                    return Err(proto_error(if qualifiers.kind == Kind::Host {
+                        "host"
+                    } else {
+                        "net"
+                    }));
+                }
+                let (first, last) = if qualifiers.kind == Kind::Host {
+                    let addr = id.parse::<IpAddr>().map_err(|_| {
+                        FilterParseError::new(
+                            format!(
+                                "host names are not supported, use an address instead of `{id}`"
+                            ),
+                            span,
+                        )
+                    })?;
+                    (addr, addr)
+                } else {
+                    self.net(&id, span)?
+                };
+                let ipv6 = first.is_ipv6();
+                if qualifiers.proto == Some(if ipv6 { Proto::Ip } else { Proto::Ip6 }) {
+                    return Err(FilterParseError::new(
+                        format!(
+                            "`{id}` is not an {} address",
+                            if ipv6 { "IPv4" } else { "IPv6" }
+                        ),
+                        span,
+                    ));
+                }
+                let (proto, src, dst) = if ipv6 {
+                    (Field::Ipv6, Field::Ipv6SrcAddr, Field::Ipv6DstAddr)
+                } else {
+                    (Field::Ip, Field::IpSrcAddr, Field::IpDstAddr)
+                };
+                let (first, last) = (address(first), address(last));
+                let test = |field| range(field, None, first.clone(), last.clone());
+                Ok(and_expr(
+                    field_expr(proto),
+                    directional(qualifiers.dir, test(src), test(dst)),
+                ))
+            }
+            Kind::Port | Kind::PortRange => {
+                let protos = match qualifiers.proto {
+                    None => vec![Proto::Tcp, Proto::Udp],
+                    Some(proto @ (Proto::Tcp | Proto::Udp)) => vec![proto],
+                    Some(_) => {
+                        return Err(proto_error(if qualifiers.kind == Kind::Port {
+                            "port"
+                        } else {
+                            "portrange"
+                        }))
+                    }
+                };
+                let (first, last) = if qualifiers.kind == Kind::Port {
+                    let port = port(&id, span)?;
+                    (Value::Int(port.into()), Value::Int(port.into()))
+                } else {
+                    let (first, last) = id.split_once('-').ok_or_else(|| {
+                        FilterParseError::new(
+                            format!("invalid port range `{id}`, expected `first-last`"),
+                            span,
+                        )
+                    })?;
+                    let (first, last) = (port(first, span)?, port(last, span)?);
+                    (
+                        Value::Int(first.min(last).into()),
+                        Value::Int(first.max(last).into()),
+                    )
+                };
+                let tests = protos.into_iter().map(|proto| {
+                    let (src, dst) = match proto {
+                        Proto::Tcp => (Field::TcpSrcPort, Field::TcpDstPort),
+                        _ => (Field::UdpSrcPort, Field::UdpDstPort),
+                    };
+                    let test = |field| range(field, None, first.clone(), last.clone());
+                    and_expr(
+                        field_expr(proto.field()),
+                        directional(qualifiers.dir, test(src), test(dst)),
+                    )
+                });
+                Ok(tests.reduce(or_expr).expect("at least one protocol"))
+            }
+        }
+    }
+
+    /// Address range of a `net` primitive, including the optional `mask` that follows it.
+    fn net(&mut self, id: &str, span: Span) -> Result<(IpAddr, IpAddr), FilterParseError> {
+        let invalid = || FilterParseError::new(format!("invalid network `{id}`"), span);
+        let (addr, prefix) = match id.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u32>().map_err(|_| invalid())?)),
+            None => (id, None),
+        };
+
+        let (addr, bits, prefix) = if addr.contains(':') {
+            let addr = addr.parse::<Ipv6Addr>().map_err(|_| invalid())?;
+            (u128::from(addr), 128, prefix.unwrap_or(128))
+        } else {
+            // Partial addresses such as `10.1` cover the omitted octets
+            let octets = addr
+                .split('.')
+                .map(|octet| octet.parse::<u8>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid())?;
+            if octets.len() > 4 {
+                return Err(invalid());
+            }
+            let addr = octets
+                .iter()
+                .chain(std::iter::repeat(&0))
+                .take(4)
+                .fold(0u32, |addr, &octet| addr << 8 | u32::from(octet));
+            let mut prefix = prefix.unwrap_or(8 * octets.len() as u32);
+            if self.peek_word() == Some("mask") {
+                self.bump();
+                let (mask, mask_span) = self.word("a network mask")?;
+                let mask = mask
+                    .parse::<Ipv4Addr>()
+                    .map(u32::from)
+                    .ok()
+                    .filter(|mask| mask.leading_ones() + mask.trailing_zeros() == 32)
+                    .ok_or_else(|| {
+                        FilterParseError::new(
+                            format!("`{mask}` is not a contiguous network mask"),
+                            mask_span,
+                        )
+                    })?;
+                prefix = mask.leading_ones();
+            }
+            (u128::from(addr), 32, prefix)
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+
+        let host = u128::MAX.checked_shr(prefix + 128 - bits).unwrap_or(0);
+        if addr & host != 0 {
+            return Err(FilterParseError::new(
+                format!("network `{id}` has host bits set"),
+                span,
+            ));
+        }
+        Ok(if bits == 32 {
+            (
+                IpAddr::V4(Ipv4Addr::from(addr as u32)),
+                IpAddr::V4(Ipv4Addr::from((addr | host) as u32)),
+            )
+        } else {
+            (
+                IpAddr::V6(Ipv6Addr::from(addr)),
+                IpAddr::V6(Ipv6Addr::from(addr | host)),
+            )
+        })
+    }
+
+    /// `[ip | ip6] proto id`
+    fn proto(&mut self, proto: Option<Proto>, start: Span) -> Result<Expr, FilterParseError> {
+        let (id, span) = self.word("a protocol")?;
+        let value = match id.trim_start_matches('\\') {
+            "icmp" => 1,
+            "tcp" => 6,
+            "udp" => 17,
+            "icmp6" => 58,
+            id => number(id, span)?,
+        };
+        if value > u8::MAX as u32 {
+            return Err(FilterParseError::new(
+                format!("protocol `{id}` is out of range"),
+                span,
+            ));
+        }
+        let ipv4 = and_expr(
+            field_expr(Field::Ip),
+            compare(Field::IpProtocol, None, CmpOp::Eq, value),
+        );
+        let ipv6 = and_expr(
+            field_expr(Field::Ipv6),
+            compare(Field::Ipv6NextHdr, None, CmpOp::Eq, value),
+        );
+        match proto {
+            None => Ok(or_expr(ipv4, ipv6)),
+            Some(Proto::Ip) => Ok(ipv4),
+            Some(Proto::Ip6) => Ok(ipv6),
+            Some(proto) => Err(FilterParseError::new(
+                format!("`{}` can't be applied to `proto`", proto.name()),
+                start.to(span),
+            )),
+        }
+    }
+
+    /// `relation := access ['&' value] op value`
+    fn relation(&mut self) -> Result<Expr, FilterParseError> {
+        let start = self.peek().span;
+        let (name, _) = self.word("a header accessor")?;
+        let access = if name == "len" {
+            Access::Field(Field::Length, None, None)
+        } else {
+            let proto = Proto::from_name(&name).expect("protocol accessor");
+            self.expect(TokenKind::LBracket)?;
+            let (index, index_span) = self.word("a header offset")?;
+            let end = self.expect(TokenKind::RBracket)?;
+            access(proto, &index, index_span, start.to(end.span))?
+        };
+        let access_span = start.to(self.tokens[self.pos - 1].span);
+
+        let mask = match self.eat(&TokenKind::BitAnd) {
+            Some(_) => Some(self.value()?),
+            None => None,
+        };
+        let op_token = self.bump();
+        let op = match op_token.kind {
+            TokenKind::Cmp(op) => op,
+            kind => {
+                return Err(FilterParseError::new(
+                    format!("expected a comparison, found {}", kind.describe()),
+                    op_token.span,
+                ))
+            }
+        };
+        let (value, value_span) = self.value()?;
+
+        match access {
+            Access::Flags => flags(mask.map(|(mask, _)| mask), op, value, access_span),
+            Access::Field(field, index, guard) => {
+                if let Some((_, mask_span)) = mask {
+                    return Err(FilterParseError::new(
+                        "bit masks are only supported on `tcp[tcpflags]`",
+                        mask_span,
+                    ));
+                }
+                let max = u32::try_from(field.field_type().int_range().max).unwrap_or(u32::MAX);
+                if value > max {
+                    return Err(FilterParseError::new(
+                        format!("`{value}` is out of range for the accessed field"),
+                        value_span,
+                    ));
+                }
+                let test = compare(field, index, op, value);
+                Ok(match guard {
+                    Some(guard) => and_expr(field_expr(guard), test),
+                    None => test,
+                })
+            }
+        }
+    }
+
+    /// `value := atom ('|' atom)*`, `atom := number | constant | '(' value ')'`
+    fn value(&mut self) -> Result<(u32, Span), FilterParseError> {
+        let (mut value, mut span) = self.value_atom()?;
+        while self.eat(&TokenKind::BitOr).is_some() {
+            let (rhs, rhs_span) = self.value_atom()?;
+            value |= rhs;
+            span = span.to(rhs_span);
+        }
+        Ok((value, span))
+    }
+
+    fn value_atom(&mut self) -> Result<(u32, Span), FilterParseError> {
+        if let Some(open) = self.eat(&TokenKind::LParen) {
+            self.enter()?;
+            let (value, _) = self.value()?;
+            self.depth -= 1;
+            let close = self.expect(TokenKind::RParen)?;
+            return Ok((value, open.span.to(close.span)));
+        }
+        let (word, span) = self.word("a value")?;
+        let constant = TCP_FLAGS
+            .iter()
+            .map(|&(name, value, _)| (name, value))
+            .chain(CONSTANTS.iter().copied())
+            .find(|&(name, _)| name == word);
+        match constant {
+            Some((_, value)) => Ok((value, span)),
+            None => Ok((number(&word, span)?, span)),
+        }
+    }
+}
+
+/// Target of a comparison.
+enum Access {
+    /// `tcp[tcpflags]`, translated into the flag fields.
+    Flags,
+    /// Field, its index and the protocol field guarding it.
+    Field(Field, Option<i32>, Option<Field>),
+}
+
+/// Field read by `proto[index]` or `proto[offset:size]`.
+fn access(proto: Proto, index: &str, span: Span, whole: Span) -> Result<Access, FilterParseError> {
+    let (offset, size) = match index.split_once(':') {
+        Some((offset, size)) => (offset, number(size, span)?),
+        None => (index, 1),
+    };
+    let offset = match (proto, offset) {
+        (Proto::Tcp, "tcpflags") => 13,
+        (Proto::Icmp, "icmptype") | (Proto::Icmp6, "icmp6type") => 0,
+        (Proto::Icmp, "icmpcode") | (Proto::Icmp6, "icmp6code") => 1,
+        (_, offset) => number(offset, span)?,
+    };
+    if !matches!(size, 1 | 2 | 4) {
+        return Err(FilterParseError::new(
+            format!("invalid size `{size}`, expected 1, 2 or 4"),
+            span,
+        ));
+    }
+
+    let field = match (proto, offset, size) {
+        (Proto::Tcp, 13, 1) => return Ok(Access::Flags),
+        (Proto::Ip | Proto::Ip6, offset, size) => {
+            let field = match size {
+                1 => Field::Packet,
+                2 => Field::Packet16,
+                _ => Field::Packet32,
+            };
+            let offset = i32::try_from(offset).map_err(|_| {
+                FilterParseError::new(format!("offset `{offset}` is too large"), span)
+            })?;
+            return Ok(Access::Field(field, Some(offset), Some(proto.field())));
+        }
+        (Proto::Tcp, 0, 2) => Field::TcpSrcPort,
+        (Proto::Tcp, 2, 2) => Field::TcpDstPort,
+        (Proto::Tcp, 4, 4) => Field::TcpSeqNum,
+        (Proto::Tcp, 8, 4) => Field::TcpAckNum,
+        (Proto::Tcp, 14, 2) => Field::TcpWindow,
+        (Proto::Tcp, 16, 2) => Field::TcpChecksum,
+        (Proto::Tcp, 18, 2) => Field::TcpUrgPtr,
+        (Proto::Udp, 0, 2) => Field::UdpSrcPort,
+        (Proto::Udp, 2, 2) => Field::UdpDstPort,
+        (Proto::Udp, 4, 2) => Field::UdpLength,
+        (Proto::Udp, 6, 2) => Field::UdpChecksum,
+        (Proto::Icmp, 0, 1) => Field::IcmpType,
+        (Proto::Icmp, 1, 1) => Field::IcmpCode,
+        (Proto::Icmp, 2, 2) => Field::IcmpChecksum,
+        (Proto::Icmp, 4, 4) => Field::IcmpBody,
+        (Proto::Icmp6, 0, 1) => Field::Icmpv6Type,
+        (Proto::Icmp6, 1, 1) => Field::Icmpv6Code,
+        (Proto::Icmp6, 2, 2) => Field::Icmpv6Checksum,
+        (Proto::Icmp6, 4, 4) => Field::Icmpv6Body,
+        _ => {
+            return Err(FilterParseError::new(
+                "no WinDivert field matches this header access",
+                whole,
+            ))
+        }
+    };
+    Ok(Access::Field(field, None, Some(proto.field())))
+}
+
+/// Translates `tcp[tcpflags] & mask op value` into tests on the flag fields.
+fn flags(mask: Option<u32>, op: CmpOp, value: u32, span: Span) -> Result<Expr, FilterParseError> {
+    let mask = match mask {
+        Some(mask) if mask & 0xC0 != 0 => {
+            return Err(FilterParseError::new(
+                "`tcp-ece` and `tcp-cwr` have no WinDivert field",
+                span,
+            ))
+        }
+        Some(mask) => mask & 0xFF,
+        None => {
+            return Err(FilterParseError::new(
+                "`tcp-ece` and `tcp-cwr` have no WinDivert field, mask them out with `&`",
+                span,
+            ))
+        }
+    };
+    let equal = match op {
+        CmpOp::Eq => true,
+        CmpOp::Ne => false,
+        _ => {
+            return Err(FilterParseError::new(
+                format!("only `==` and `!=` are supported on TCP flags, found `{op}`"),
+                span,
+            ))
+        }
+    };
+
+    let flags = TCP_FLAGS
+        .iter()
+        .filter(|&&(_, bit, _)| mask & bit != 0)
+        .filter_map(|&(_, bit, field)| Some((bit, field?)));
+    if value & !mask != 0 {
+        // The masked flags can never equal `value`
+        return Ok(if equal {
+            Expr::new(ExprKind::Bool(false))
+        } else {
+            field_expr(Field::Tcp)
+        });
+    }
+    let test = if !equal && value == 0 {
+        flags
+            .map(|(_, field)| field_expr(field))
+            .reduce(or_expr)
+            .unwrap_or(Expr::new(ExprKind::Bool(false)))
+    } else {
+        let test = flags
+            .map(|(bit, field)| match value & bit {
+                0 => compare(field, None, CmpOp::Eq, 0),
+                _ => field_expr(field),
+            })
+            .reduce(and_expr)
+            .unwrap_or(Expr::new(ExprKind::Bool(true)));
+        if equal {
+            test
+        } else {
+            not_expr(test)
+        }
+    };
+    Ok(and_expr(field_expr(Field::Tcp), test))
+}
+
+/// `ip multicast` and `ip6 multicast`.
+fn multicast(proto: Proto, word: &str, span: Span) -> Result<Expr, FilterParseError> {
+    let (field, first, last) = match (proto, word) {
+        (Proto::Ip, "multicast") => (
+            Field::IpDstAddr,
+            Value::Ipv4(Ipv4Addr::new(224, 0, 0, 0)),
+            Value::Ipv4(Ipv4Addr::new(239, 255, 255, 255)),
+        ),
+        (Proto::Ip6, "multicast") => (
+            Field::Ipv6DstAddr,
+            Value::Ipv6(Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0)),
+            Value::Ipv6(Ipv6Addr::from(u128::MAX)),
+        ),
+        (Proto::Ip, "broadcast") => {
+            return Err(FilterParseError::new(
+                "`ip broadcast` depends on the interface netmask, use `ip dst host` instead",
+                span,
+            ))
+        }
+        _ => {
+            return Err(FilterParseError::new(
+                format!("`{} {word}` is not supported", proto.name()),
+                span,
+            ))
+        }
+    };
+    Ok(and_expr(
+        field_expr(proto.field()),
+        range(field, None, first, last),
+    ))
+}
+
+fn number(text: &str, span: Span) -> Result<u32, FilterParseError> {
+    let value = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse::<u32>(),
+    };
+    value.map_err(|_| FilterParseError::new(format!("invalid number `{text}`"), span))
+}
+
+fn port(text: &str, span: Span) -> Result<u16, FilterParseError> {
+    match text.parse::<u16>() {
+        Ok(port) => Ok(port),
+        Err(_) if text.bytes().all(|byte| byte.is_ascii_digit()) => Err(FilterParseError::new(
+            format!("port `{text}` is out of range"),
+            span,
+        )),
+        Err(_) => Err(FilterParseError::new(
+            format!("service names are not supported, use a port number instead of `{text}`"),
+            span,
+        )),
+    }
+}
+
+fn address(addr: IpAddr) -> Value {
+    match addr {
+        IpAddr::V4(addr) => Value::Ipv4(addr),
+        IpAddr::V6(addr) => Value::Ipv6(addr),
+    }
+}
+
+fn directional(dir: Dir, src: Expr, dst: Expr) -> Expr {
+    match dir {
+        Dir::SrcOrDst => or_expr(src, dst),
+        Dir::SrcAndDst => and_expr(src, dst),
+        Dir::Src => src,
+        Dir::Dst => dst,
+    }
+}
+
+fn range(field: Field, index: Option<i32>, first: Value, last: Value) -> Expr {
+    let field = FieldRef {
+        index,
+        ..FieldRef::new(field)
+    };
+    let compare = |op, value| {
+        Expr::new(ExprKind::Compare {
+            field: field.clone(),
+            op,
+            value,
+        })
+    };
+    if first == last {
+        compare(CmpOp::Eq, first)
+    } else {
+        and_expr(compare(CmpOp::Ge, first), compare(CmpOp::Le, last))
+    }
+}
+
+fn compare(field: Field, index: Option<i32>, op: CmpOp, value: u32) -> Expr {
+    Expr::new(ExprKind::Compare {
+        field: FieldRef {
+            index,
+            ..FieldRef::new(field)
+        },
+        op,
+        value: Value::Int(value.into()),
+    })
+}
+
+fn field_expr(field: Field) -> Expr {
+    Expr::new(ExprKind::Field(FieldRef::new(field)))
+}
+
+fn not_expr(expr: Expr) -> Expr {
+    Expr::new(ExprKind::Not(Box::new(expr)))
+}
+
+fn and_expr(lhs: Expr, rhs: Expr) -> Expr {
+    Expr::new(ExprKind::And(Box::new(lhs), Box::new(rhs)))
+}
+
+fn or_expr(lhs: Expr, rhs: Expr) -> Expr {
+    Expr::new(ExprKind::Or(Box::new(lhs), Box::new(rhs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate(expression: &str) -> String {
+        match Filter::from_pcap(expression) {
+            Ok(filter) => filter.as_str().to_owned(),
+            Err(error) => panic!("{expression}: {}", error.message),
+        }
+    }
+
+    fn error(expression: &str) -> (String, Span) {
+        let error = Filter::from_pcap(expression).unwrap_err();
+        (error.message, error.span)
+    }
+
+    #[test]
+    fn primitives() {
+        assert_eq!(translate(""), "true");
+        assert_eq!(translate("tcp"), "tcp");
+        assert_eq!(
+            translate("host 10.0.0.1"),
+            "ip and (ip.SrcAddr == 10.0.0.1 or ip.DstAddr == 10.0.0.1)"
+        );
+        assert_eq!(
+            translate("src host 10.0.0.1 or 10.0.0.2"),
+            "ip and ip.SrcAddr == 10.0.0.1 or ip and ip.SrcAddr == 10.0.0.2"
+        );
+        assert_eq!(
+            translate("dst net 192.168.0.0/16"),
+            "ip and ip.DstAddr >= 192.168.0.0 and ip.DstAddr <= 192.168.255.255"
+        );
+        assert_eq!(
+            translate("net 10 mask 255.0.0.0"),
+            "ip and (ip.SrcAddr >= 10.0.0.0 and ip.SrcAddr <= 10.255.255.255 or \
+             ip.DstAddr >= 10.0.0.0 and ip.DstAddr <= 10.255.255.255)"
+        );
+        assert_eq!(
+            translate("ip6 host ::1"),
+            "ipv6 and (ipv6.SrcAddr == ::1 or ipv6.DstAddr == ::1)"
+        );
+        assert_eq!(translate("tcp dst port 443"), "tcp and tcp.DstPort == 443");
+        assert_eq!(
+            translate("src or dst port 53"),
+            "tcp and (tcp.SrcPort == 53 or tcp.DstPort == 53) or \
+             udp and (udp.SrcPort == 53 or udp.DstPort == 53)"
+        );
+        assert_eq!(
+            translate("src and dst port 53"),
+            "tcp and tcp.SrcPort == 53 and tcp.DstPort == 53 or \
+             udp and udp.SrcPort == 53 and udp.DstPort == 53"
+        );
+        assert_eq!(
+            translate("udp portrange 8000-8080"),
+            "udp and (udp.SrcPort >= 8000 and udp.SrcPort <= 8080 or \
+             udp.DstPort >= 8000 and udp.DstPort <= 8080)"
+        );
+        assert_eq!(
+            translate("proto 47"),
+            "ip and ip.Protocol == 47 or ipv6 and ipv6.NextHdr == 47"
+        );
+        assert_eq!(
+            translate("ip multicast"),
+            "ip and ip.DstAddr >= 224.0.0.0 and ip.DstAddr <= 239.255.255.255"
+        );
+        assert_eq!(
+            translate("less 100 and outbound"),
+            "length <= 100 and outbound"
+        );
+        assert_eq!(translate("not (icmp or icmp6)"), "not (icmp or icmpv6)");
+    }
+
+    #[test]
+    fn relations() {
+        assert_eq!(translate("len > 1000"), "length > 1000");
+        assert_eq!(translate("ip[8] == 64"), "ip and packet[8] == 64");
+        assert_eq!(translate("ip6[6] == 17"), "ipv6 and packet[6] == 17");
+        assert_eq!(translate("tcp[2:2] == 80"), "tcp and tcp.DstPort == 80");
+        assert_eq!(translate("udp[4:2] < 100"), "udp and udp.Length < 100");
+        assert_eq!(
+            translate("icmp[icmptype] == icmp-echo"),
+            "icmp and icmp.Type == 8"
+        );
+    }
+
+    #[test]
+    fn tcp_flags() {
+        assert_eq!(translate("tcp[13] & 2 != 0"), "tcp and tcp.Syn");
+        assert_eq!(translate("tcp[tcpflags] & tcp-syn != 0"), "tcp and tcp.Syn");
+        assert_eq!(
+            translate("tcp[tcpflags] & (tcp-syn|tcp-ack) == tcp-syn"),
+            "tcp and tcp.Syn and tcp.Ack == 0"
+        );
+        assert_eq!(
+            translate("tcp[tcpflags] & (tcp-syn|tcp-fin) != 0"),
+            "tcp and (tcp.Fin or tcp.Syn)"
+        );
+        assert_eq!(translate("tcp[tcpflags] & tcp-rst == tcp-syn"), "false");
+        assert_eq!(translate("tcp[tcpflags] & tcp-rst != tcp-syn"), "tcp");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error("ether host 00:11:22:33:44:55"),
+            (
+                "`ether` has no WinDivert equivalent, packets are captured from the IP header on"
+                    .to_owned(),
+                Span::new(0, 5)
+            )
+        );
+        assert_eq!(
+            error("tcp and vlan 100"),
+            (
+                "`vlan` has no WinDivert equivalent, packets are captured from the IP header on"
+                    .to_owned(),
+                Span::new(8, 12)
+            )
+        );
+        assert_eq!(
+            error("broadcast"),
+            (
+                "link layer `broadcast` has no WinDivert equivalent, use `ip broadcast`".to_owned(),
+                Span::new(0, 9)
+            )
+        );
+        assert_eq!(
+            error("tcp[13] == 2"),
+            (
+                "`tcp-ece` and `tcp-cwr` have no WinDivert field, mask them out with `&`"
+                    .to_owned(),
+                Span::new(0, 7)
+            )
+        );
+        assert_eq!(
+            error("tcp[tcpflags] & tcp-ece != 0"),
+            (
+                "`tcp-ece` and `tcp-cwr` have no WinDivert field".to_owned(),
+                Span::new(0, 13)
+            )
+        );
+        assert_eq!(
+            error("tcp[tcpflags] & tcp-syn > 0"),
+            (
+                "only `==` and `!=` are supported on TCP flags, found `>`".to_owned(),
+                Span::new(0, 13)
+            )
+        );
+        assert_eq!(
+            error("tcp[3:3] == 0"),
+            (
+                "invalid size `3`, expected 1, 2 or 4".to_owned(),
+                Span::new(4, 7)
+            )
+        );
+        assert_eq!(
+            error("tcp[20] == 0"),
+            (
+                "no WinDivert field matches this header access".to_owned(),
+                Span::new(0, 7)
+            )
+        );
+        assert_eq!(
+            error("udp[0:2] & 1 == 0"),
+            (
+                "bit masks are only supported on `tcp[tcpflags]`".to_owned(),
+                Span::new(11, 12)
+            )
+        );
+        assert_eq!(
+            error("tcp[0:2] == 65536"),
+            (
+                "`65536` is out of range for the accessed field".to_owned(),
+                Span::new(12, 17)
+            )
+        );
+        assert_eq!(
+            error("port http"),
+            (
+                "service names are not supported, use a port number instead of `http`".to_owned(),
+                Span::new(5, 9)
+            )
+        );
+        assert_eq!(
+            error("host 10.0.0.1 )"),
+            ("unexpected `)`".to_owned(), Span::new(14, 15))
+        );
+        assert_eq!(
+            error("sctp"),
+            (
+                "`sctp` is not supported, use `proto` with its protocol number".to_owned(),
+                Span::new(0, 4)
+            )
+        );
+    }
+
+    #[test]
+    fn primitives_limit() {
+        let expression = vec!["portrange 1-2"; MAX_PRIMITIVES].join(" and ");
+        assert!(Filter::from_pcap(&expression).is_ok());
+
+        let expression = vec!["tcp"; 10_000].join(" and ");
+        let error = Filter::from_pcap(&expression).unwrap_err();
+        assert_eq!(error.message, "expression has too many primitives");
+        let offset = MAX_PRIMITIVES * "tcp and ".len();
+        assert_eq!(error.span, Span::new(offset, offset + 3));
+    }
+}
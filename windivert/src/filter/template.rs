@@ -0,0 +1,626 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::RangeInclusive,
+};
+
+use windivert_filter::{parse, Placeholders};
+
+use super::{CmpOp, Expr, ExprKind, FieldRef, FieldType, Filter, Span, Value};
+use crate::error::{FilterParseError, WinDivertError};
+
+/**
+Filter with `$name` parameters, bound to typed values before use.
+
+Parameters stand either for the value of a comparison, like `tcp.DstPort == $port`, or for a whole
+boolean expression, like `tcp and $outbound_only`. Bound values never go through the filter
+parser: they are checked against the type and the range of the field they are compared with and
+inserted into the parsed expression, so a value coming from user input can't change the structure
+of the filter.
+
+Lists and ranges can be bound to parameters compared with `==` or `!=`. `ip.DstAddr == $dst` bound
+to a list of addresses expands into `ip.DstAddr == a or ip.DstAddr == b`, and consecutive values
+are merged into ranges, `tcp.DstPort >= 8000 and tcp.DstPort <= 8080`.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterTemplate {
+    source: String,
+    expr: Expr,
+    params: Vec<Param>,
+    values: BTreeMap<String, TemplateValue>,
+}
+
+/// Occurrence of a parameter in the template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Param {
+    name: String,
+    /// Offset of the `$` in the template.
+    position: usize,
+    /// Span of the comparison or constant holding the parameter in the parsed template.
+    leaf: Span,
+    /// Compared field, `None` for expression parameters.
+    compare: Option<(FieldRef, CmpOp)>,
+}
+
+impl FilterTemplate {
+    /// Parses a filter template.
+    pub fn parse(template: &str) -> Result<Self, FilterParseError> {
+        let placeholders = Placeholders::new(template, param_spans(template)?);
+        let expr = parse(placeholders.parsed()).map_err(|error| FilterParseError {
+            span: placeholders.original_span(error.span),
+            ..error
+        })?;
+
+        let mut params = Vec::new();
+        for placeholder in placeholders.iter() {
+            let name = &template[placeholder.span.start + 1..placeholder.span.end];
+            let invalid = |message: &str| {
+                FilterParseError::new(format!("parameter `${name}` {message}"), placeholder.span)
+            };
+            let leaf = placeholder
+                .leaf(&expr)
+                .ok_or_else(|| invalid("is not a comparison value or an expression"))?;
+            let compare = match &leaf.kind {
+                ExprKind::Compare { field, op, .. } => {
+                    if matches!(
+                        field.field.field_type(),
+                        FieldType::Event | FieldType::Layer
+                    ) {
+                        return Err(invalid(&format!(
+                            "can't be compared with field `{}`",
+                            field.field
+                        )));
+                    }
+                    Some((field.clone(), *op))
+                }
+                _ => None,
+            };
+            params.push(Param {
+                name: name.to_owned(),
+                position: placeholder.span.start,
+                leaf: leaf.span,
+                compare,
+            });
+        }
+
+        Ok(Self {
+            source: template.to_owned(),
+            expr,
+            params,
+            values: BTreeMap::new(),
+        })
+    }
+
+    /// Template string as provided by the user.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Names of the parameters, without the `$`, in order of first appearance.
+    pub fn params(&self) -> impl Iterator<Item = &str> {
+        let mut seen = Vec::new();
+        self.params
+            .iter()
+            .map(|param| param.name.as_str())
+            .filter(move |name| {
+                let first = !seen.contains(name);
+                seen.push(*name);
+                first
+            })
+    }
+
+    /**
+    Binds `value` to the parameter `name`, replacing any previous value.
+
+    Fails if the template has no such parameter, or if the value doesn't fit every place where the
+    parameter is used: its type must match the compared field, integers must be in the range of
+    the field and ranges can't end before their start. Expression parameters only accept `bool`
+    values.
+    */
+    pub fn bind(
+        &mut self,
+        name: &str,
+        value: impl Into<TemplateValue>,
+    ) -> Result<&mut Self, WinDivertError> {
+        let name = name.strip_prefix('$').unwrap_or(name);
+        let value = value.into();
+        let mut found = false;
+        for param in self.params.iter().filter(|param| param.name == name) {
+            check(param, &value).map_err(|message| WinDivertError::Filter {
+                message: format!("parameter `${name}`: {message}"),
+                position: param.position,
+            })?;
+            found = true;
+        }
+        if !found {
+            return Err(WinDivertError::UnknownParameter(name.to_owned()));
+        }
+        self.values.insert(name.to_owned(), value);
+        Ok(self)
+    }
+
+    /// Renders the filter, failing if a parameter isn't bound.
+    pub fn build(&self) -> Result<Filter, WinDivertError> {
+        let expr = self.render(&self.expr)?;
+        Ok(Filter::parse(&expr.to_canonical_string())?)
+    }
+
+    /// Copy of `expr` with the parameters replaced by their values.
+    fn render(&self, expr: &Expr) -> Result<Expr, WinDivertError> {
+        let boxed = |operand: &Expr| self.render(operand).map(Box::new);
+        let kind = match &expr.kind {
+            ExprKind::Bool(_) | ExprKind::Compare { .. } => {
+                match self.params.iter().find(|param| param.leaf == expr.span) {
+                    Some(param) => return self.param(param),
+                    None => return Ok(expr.clone()),
+                }
+            }
+            ExprKind::Field(_) => return Ok(expr.clone()),
+            ExprKind::Not(operand) => ExprKind::Not(boxed(operand)?),
+            ExprKind::And(lhs, rhs) => ExprKind::And(boxed(lhs)?, boxed(rhs)?),
+            ExprKind::Or(lhs, rhs) => ExprKind::Or(boxed(lhs)?, boxed(rhs)?),
+            ExprKind::Ternary {
+                cond,
+                then,
+                otherwise,
+            } => ExprKind::Ternary {
+                cond: boxed(cond)?,
+                then: boxed(then)?,
+                otherwise: boxed(otherwise)?,
+            },
+        };
+        Ok(Expr::new(kind))
+    }
+
+    /// Expression replacing the leaf holding `param`.
+    fn param(&self, param: &Param) -> Result<Expr, WinDivertError> {
+        let value = self
+            .values
+            .get(&param.name)
+            .ok_or_else(|| WinDivertError::Filter {
+                message: format!("parameter `${}` is not bound", param.name),
+                position: param.position,
+            })?;
+        let (field, op) = match (&param.compare, value) {
+            (None, TemplateValue::Bool(value)) => return Ok(Expr::new(ExprKind::Bool(*value))),
+            (Some(compare), _) => compare,
+            // Rejected by `bind()`
+            (None, _) => unreachable!(),
+        };
+        let compare = |op, value| {
+            Expr::new(ExprKind::Compare {
+                field: field.clone(),
+                op,
+                value,
+            })
+        };
+        let scalar = |value: &TemplateValue| match value {
+            TemplateValue::Bool(value) => Value::Int(i128::from(*value)),
+            TemplateValue::Int(value) => Value::Int(*value),
+            TemplateValue::Ipv4(addr) => Value::Ipv4(*addr),
+            TemplateValue::Ipv6(addr) => Value::Ipv6(*addr),
+            TemplateValue::List(_) | TemplateValue::Range(..) => unreachable!(),
+        };
+
+        let mut ranges = Vec::new();
+        match value {
+            TemplateValue::List(_) | TemplateValue::Range(..) => value.ranges(&mut ranges),
+            value => return Ok(compare(*op, scalar(value))),
+        }
+        let ranges = merge(
+            ranges
+                .into_iter()
+                .map(|(first, last)| (scalar(first), scalar(last))),
+        );
+
+        // `!=` excludes every range, `==` accepts any of them
+        let eq = *op == CmpOp::Eq;
+        let combine = |lhs: Expr, rhs: Expr| {
+            let (lhs, rhs) = (Box::new(lhs), Box::new(rhs));
+            Expr::new(match eq {
+                true => ExprKind::Or(lhs, rhs),
+                false => ExprKind::And(lhs, rhs),
+            })
+        };
+        let ranges = ranges.into_iter().map(|(first, last)| {
+            if first == last {
+                return compare(*op, first);
+            }
+            let (lower, upper) = match eq {
+                true => (CmpOp::Ge, CmpOp::Le),
+                false => (CmpOp::Lt, CmpOp::Gt),
+            };
+            let (lower, upper) = (
+                Box::new(compare(lower, first)),
+                Box::new(compare(upper, last)),
+            );
+            Expr::new(match eq {
+                true => ExprKind::And(lower, upper),
+                false => ExprKind::Or(lower, upper),
+            })
+        });
+        Ok(ranges
+            .reduce(combine)
+            .unwrap_or_else(|| Expr::new(ExprKind::Bool(!eq))))
+    }
+}
+
+/**
+Value bound to a [`FilterTemplate`] parameter.
+
+Usually created with `into()` from `bool`, integers, IP addresses, inclusive ranges of them, and
+vectors, slices or arrays of any of those.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateValue {
+    /// Boolean, for flags and expression parameters.
+    Bool(bool),
+    /// Integer.
+    Int(i128),
+    /// IPv4 address.
+    Ipv4(Ipv4Addr),
+    /// IPv6 address.
+    Ipv6(Ipv6Addr),
+    /// List of values, matching any of them.
+    List(Vec<TemplateValue>),
+    /// Inclusive range of values.
+    Range(Box<TemplateValue>, Box<TemplateValue>),
+}
+
+impl TemplateValue {
+    fn describe(&self) -> &'static str {
+        match self {
+            TemplateValue::Bool(_) => "a boolean",
+            TemplateValue::Int(_) => "an integer",
+            TemplateValue::Ipv4(_) => "an IPv4 address",
+            TemplateValue::Ipv6(_) => "an IPv6 address",
+            TemplateValue::List(_) => "a list",
+            TemplateValue::Range(..) => "a range",
+        }
+    }
+
+    /// Flattens lists into inclusive ranges of scalars.
+    fn ranges<'a>(&'a self, ranges: &mut Vec<(&'a Self, &'a Self)>) {
+        match self {
+            TemplateValue::List(values) => values.iter().for_each(|value| value.ranges(ranges)),
+            TemplateValue::Range(first, last) => ranges.push((first, last)),
+            value => ranges.push((value, value)),
+        }
+    }
+}
+
+macro_rules! template_values {
+    ($($ty:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl From<$ty> for TemplateValue {
+                fn from(value: $ty) -> Self {
+                    TemplateValue::$variant(value.into())
+                }
+            }
+        )*
+    };
+}
+
+template_values! {
+    bool => Bool,
+    u8 => Int,
+    u16 => Int,
+    u32 => Int,
+    u64 => Int,
+    i8 => Int,
+    i16 => Int,
+    i32 => Int,
+    i64 => Int,
+    Ipv4Addr => Ipv4,
+    Ipv6Addr => Ipv6,
+}
+
+impl From<IpAddr> for TemplateValue {
+    fn from(value: IpAddr) -> Self {
+        match value {
+            IpAddr::V4(addr) => TemplateValue::Ipv4(addr),
+            IpAddr::V6(addr) => TemplateValue::Ipv6(addr),
+        }
+    }
+}
+
+impl<T: Into<TemplateValue>> From<RangeInclusive<T>> for TemplateValue {
+    fn from(range: RangeInclusive<T>) -> Self {
+        let (first, last) = range.into_inner();
+        TemplateValue::Range(Box::new(first.into()), Box::new(last.into()))
+    }
+}
+
+impl<T: Into<TemplateValue>> From<Vec<T>> for TemplateValue {
+    fn from(values: Vec<T>) -> Self {
+        TemplateValue::List(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<TemplateValue> + Clone> From<&[T]> for TemplateValue {
+    fn from(values: &[T]) -> Self {
+        TemplateValue::List(values.iter().cloned().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<TemplateValue>, const N: usize> From<[T; N]> for TemplateValue {
+    fn from(values: [T; N]) -> Self {
+        TemplateValue::List(values.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Checks that `value` can replace `param`.
+fn check(param: &Param, value: &TemplateValue) -> Result<(), String> {
+    let Some((field, op)) = &param.compare else {
+        return match value {
+            TemplateValue::Bool(_) => Ok(()),
+            value => Err(format!(
+                "expected a boolean expression, found {}",
+                value.describe()
+            )),
+        };
+    };
+
+    let ty = field.field.field_type();
+    let scalar = |value: &TemplateValue| {
+        let expected = match (value, ty) {
+            (TemplateValue::Bool(_), FieldType::Bool) => return Ok(()),
+            (TemplateValue::Ipv4(_), FieldType::Ipv4 | FieldType::IpAddr) => return Ok(()),
+            (TemplateValue::Ipv6(_), FieldType::Ipv6 | FieldType::IpAddr) => return Ok(()),
+            (TemplateValue::Int(value), _)
+                if !matches!(
+                    ty,
+                    FieldType::Bool | FieldType::Ipv4 | FieldType::Ipv6 | FieldType::IpAddr
+                ) =>
+            {
+                return match ty.int_range().contains(*value) {
+                    true => Ok(()),
+                    false => Err(format!(
+                        "`{value}` is out of range for field `{}`",
+                        field.field
+                    )),
+                };
+            }
+            (_, FieldType::Bool) => "a boolean",
+            (_, FieldType::Ipv4) => "an IPv4 address",
+            (_, FieldType::Ipv6) => "an IPv6 address",
+            (_, FieldType::IpAddr) => "an IP address",
+            _ => "an integer",
+        };
+        Err(format!(
+            "expected {expected} for field `{}`, found {}",
+            field.field,
+            value.describe()
+        ))
+    };
+
+    let mut ranges = Vec::new();
+    match value {
+        TemplateValue::List(_) | TemplateValue::Range(..) => value.ranges(&mut ranges),
+        value => return scalar(value),
+    }
+    if !matches!(op, CmpOp::Eq | CmpOp::Ne) {
+        return Err(format!(
+            "{} can only be compared with `==` or `!=`",
+            value.describe()
+        ));
+    }
+    for (first, last) in ranges {
+        scalar(first)?;
+        scalar(last)?;
+        let (start, end, reversed) = match (first, last) {
+            (TemplateValue::Int(start), TemplateValue::Int(end)) => {
+                (start.to_string(), end.to_string(), start > end)
+            }
+            (TemplateValue::Ipv4(start), TemplateValue::Ipv4(end)) => {
+                (start.to_string(), end.to_string(), start > end)
+            }
+            (TemplateValue::Ipv6(start), TemplateValue::Ipv6(end)) => {
+                (start.to_string(), end.to_string(), start > end)
+            }
+            (TemplateValue::Bool(start), TemplateValue::Bool(end)) => {
+                (start.to_string(), end.to_string(), start > end)
+            }
+            _ => return Err("range bounds must have the same type".to_owned()),
+        };
+        if reversed {
+            return Err(format!("range `{start}..={end}` is empty"));
+        }
+    }
+    Ok(())
+}
+
+/// Sorts inclusive ranges, merging the ones that overlap or are adjacent.
+fn merge(ranges: impl Iterator<Item = (Value, Value)>) -> Vec<(Value, Value)> {
+    // Integers and addresses of each family are ordered separately
+    let key = |value: &Value| match value {
+        Value::Int(value) => (0, (*value as u128) ^ (1 << 127)),
+        Value::Ipv4(addr) => (1, u128::from(u32::from(*addr))),
+        Value::Ipv6(addr) => (2, u128::from(*addr)),
+        Value::Symbol(_) => unreachable!(),
+    };
+    let mut ranges: Vec<_> = ranges
+        .map(|(first, last)| (key(&first), key(&last)))
+        .collect();
+    ranges.sort_unstable();
+
+    let mut merged: Vec<((u8, u128), (u8, u128))> = Vec::new();
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some((_, end)) if end.0 == first.0 && end.1.saturating_add(1) >= first.1 => {
+                *end = (*end).max(last);
+            }
+            _ => merged.push((first, last)),
+        }
+    }
+
+    let value = |(family, key): (u8, u128)| match family {
+        0 => Value::Int((key ^ (1 << 127)) as i128),
+        1 => Value::Ipv4(Ipv4Addr::from(key as u32)),
+        _ => Value::Ipv6(Ipv6Addr::from(key)),
+    };
+    merged
+        .into_iter()
+        .map(|(first, last)| (value(first), value(last)))
+        .collect()
+}
+
+/// Spans of the `$name` parameters of the template string.
+fn param_spans(template: &str) -> Result<Vec<Span>, FilterParseError> {
+    let mut spans = Vec::new();
+    let mut start = 0;
+    while let Some(index) = template[start..].find('$') {
+        let position = start + index;
+        let name = &template[position + 1..];
+        let len = name
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(name.len());
+        if !name[..len].starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            return Err(FilterParseError::new(
+                "expected a parameter name after `$`",
+                Span::new(position, position + 1),
+            ));
+        }
+        start = position + 1 + len;
+        spans.push(Span::new(position, start));
+    }
+    Ok(spans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(template: &str, name: &str, value: impl Into<TemplateValue>) -> String {
+        let mut template = FilterTemplate::parse(template).unwrap();
+        template.bind(name, value).unwrap();
+        template.build().unwrap().as_str().to_owned()
+    }
+
+    fn bind_error(template: &str, value: impl Into<TemplateValue>) -> (String, usize) {
+        match FilterTemplate::parse(template)
+            .unwrap()
+            .bind("value", value)
+        {
+            Err(WinDivertError::Filter { message, position }) => (message, position),
+            result => panic!("{template}: expected a filter error, found {result:?}"),
+        }
+    }
+
+    #[test]
+    fn lists_expand() {
+        let port = "tcp.DstPort == $port";
+        assert_eq!(build(port, "port", 443u16), "tcp.DstPort == 443");
+        assert_eq!(
+            build(port, "port", [443u16, 80]),
+            "tcp.DstPort == 80 or tcp.DstPort == 443"
+        );
+        // Adjacent and overlapping values are merged into ranges
+        assert_eq!(
+            build(
+                port,
+                "port",
+                vec![8081u16..=8090, 22..=22, 8000..=8080, 8085..=8085]
+            ),
+            "tcp.DstPort == 22 or tcp.DstPort >= 8000 and tcp.DstPort <= 8090"
+        );
+        assert_eq!(
+            build("tcp.DstPort != $port", "port", [80u16, 81, 443]),
+            "(tcp.DstPort < 80 or tcp.DstPort > 81) and tcp.DstPort != 443"
+        );
+        assert_eq!(build(port, "port", Vec::<u16>::new()), "false");
+        assert_eq!(build("tcp.DstPort != $port", "port", [0u16; 0]), "true");
+        assert_eq!(
+            build(
+                "ip.DstAddr == $dst",
+                "dst",
+                [Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1)]
+            ),
+            "ip.DstAddr >= 10.0.0.1 and ip.DstAddr <= 10.0.0.2"
+        );
+        assert_eq!(
+            build("tcp and $port or udp", "port", false),
+            "tcp and false or udp"
+        );
+    }
+
+    #[test]
+    fn out_of_range() {
+        let template = "tcp and tcp.DstPort == $value";
+        let message = "parameter `$value`: `65536` is out of range for field `tcp.DstPort`";
+        assert_eq!(bind_error(template, 65536u32), (message.to_owned(), 23));
+        assert_eq!(bind_error(template, [80u32, 65536]).0, message);
+        assert_eq!(bind_error(template, 0u32..=65536).0, message);
+        assert_eq!(
+            bind_error(template, -1).0,
+            "parameter `$value`: `-1` is out of range for field `tcp.DstPort`"
+        );
+        assert_eq!(
+            build(template, "value", 65535u32),
+            "tcp and tcp.DstPort == 65535"
+        );
+
+        let template = "tcp.SeqNum == $value";
+        assert_eq!(
+            bind_error(template, 1u64 << 32).0,
+            "parameter `$value`: `4294967296` is out of range for field `tcp.SeqNum`"
+        );
+        assert_eq!(bind_error(template, [-1i64]).1, 14);
+        assert_eq!(
+            build(template, "value", u32::MAX),
+            "tcp.SeqNum == 4294967295"
+        );
+
+        assert_eq!(
+            bind_error("ip.DstAddr == $value", 1u32).0,
+            "parameter `$value`: expected an IPv4 address for field `ip.DstAddr`, found an integer"
+        );
+        assert_eq!(
+            bind_error("tcp.DstPort < $value", [1u16]).0,
+            "parameter `$value`: a list can only be compared with `==` or `!=`"
+        );
+    }
+
+    #[test]
+    #[allow(clippy::reversed_empty_ranges)]
+    fn reversed_ranges() {
+        assert_eq!(
+            bind_error("tcp.DstPort == $value", 8080u16..=8000).0,
+            "parameter `$value`: range `8080..=8000` is empty"
+        );
+        assert_eq!(
+            bind_error("tcp.DstPort == $value", [80u16..=80, 443..=442]).0,
+            "parameter `$value`: range `443..=442` is empty"
+        );
+        assert_eq!(
+            bind_error(
+                "ip.DstAddr == $value",
+                Ipv4Addr::new(10, 0, 0, 9)..=Ipv4Addr::new(10, 0, 0, 1)
+            )
+            .0,
+            "parameter `$value`: range `10.0.0.9..=10.0.0.1` is empty"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let mut template = FilterTemplate::parse("tcp.DstPort == $port").unwrap();
+        assert!(matches!(
+            template.bind("$dst", 80u16),
+            Err(WinDivertError::UnknownParameter(name)) if name == "dst"
+        ));
+        assert!(matches!(
+            template.build(),
+            Err(WinDivertError::Filter { position: 15, .. })
+        ));
+
+        let error = |template| FilterTemplate::parse(template).unwrap_err();
+        // Offsets after a parameter account for the length of its name
+        assert_eq!(error("tcp.DstPort == $port and").span, Span::new(24, 24));
+        assert_eq!(error("tcp and $1").span, Span::new(8, 9));
+        assert_eq!(
+            error("tcp and event == $event").message,
+            "parameter `$event` can't be compared with field `event`"
+        );
+    }
+}
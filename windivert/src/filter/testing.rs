@@ -0,0 +1,199 @@
+//! Seeded random packets used to compare filter implementations with [`Filter::matches()`].
+//!
+//! [`Filter::matches()`]: super::Filter::matches
+
+use crate::{layer::NetworkLayer, packet::WinDivertPacket};
+
+/// Small xorshift generator, so test failures can be reproduced from the seed.
+pub(super) struct Rng(u64);
+
+impl Rng {
+    pub(super) fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub(super) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Random number in `0..n`.
+    pub(super) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// Returns `true` with a probability of `1 / n`.
+    pub(super) fn one_in(&mut self, n: usize) -> bool {
+        self.below(n) == 0
+    }
+
+    pub(super) fn pick<T: Copy>(&mut self, values: &[T]) -> T {
+        values[self.below(values.len())]
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+/// Values around the constants used by the test filters, so comparisons go both ways.
+const PORTS: &[u16] = &[
+    0, 1, 52, 53, 54, 79, 80, 81, 82, 443, 1023, 1024, 8080, 65535,
+];
+const SMALL: &[u8] = &[0, 1, 5, 6, 8, 17, 63, 64, 65, 128, 255];
+const IPV4: &[[u8; 4]] = &[
+    [0, 0, 0, 0],
+    [10, 0, 0, 1],
+    [10, 255, 255, 255],
+    [11, 0, 0, 0],
+    [127, 0, 0, 1],
+    [192, 168, 1, 1],
+    [255, 255, 255, 255],
+];
+const IPV6: &[[u8; 16]] = &[
+    [0; 16],
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 10, 0, 0, 1],
+    [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+    [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+    [0xFF; 16],
+];
+
+/**
+Builds a random network layer packet.
+
+Most packets are well formed IPv4 or IPv6 packets carrying TCP, UDP, ICMP or ICMPv6. Some carry
+IPv4 options, IPv6 extension headers or an unknown protocol, some are fragments, and some are
+truncated at a random length or aren't IP packets at all.
+*/
+pub(super) fn random_packet(rng: &mut Rng) -> WinDivertPacket<'static, NetworkLayer> {
+    let data = if rng.one_in(40) {
+        let len = rng.below(48);
+        rng.bytes(len)
+    } else {
+        let mut data = random_ip(rng);
+        if rng.one_in(8) {
+            data.truncate(rng.below(data.len()));
+        }
+        data
+    };
+    // SAFETY: The zeroed address is a valid inbound network layer address
+    let mut packet = unsafe { WinDivertPacket::<NetworkLayer>::new(data) };
+    packet.address.set_outbound(rng.one_in(2));
+    packet.address.set_impostor(rng.one_in(4));
+    packet.address.set_interface_index(rng.below(3) as u32);
+    packet.address.set_subinterface_index(rng.below(3) as u32);
+    packet.address.as_mut().set_loopback(rng.one_in(4));
+    packet.address.as_mut().timestamp = rng.pick(&[-5, -1, 0, 1, 1000, i64::MAX]);
+    packet
+}
+
+fn random_ip(rng: &mut Rng) -> Vec<u8> {
+    let ipv6 = rng.one_in(2);
+    let protocol = match rng.below(10) {
+        0..=3 => 6,
+        4..=6 => 17,
+        7 if ipv6 => 58,
+        7 => 1,
+        8 => rng.pick(&[1, 58]),
+        _ => rng.pick(&[0, 47, 255]),
+    };
+    let transport = random_transport(rng, protocol);
+
+    let mut data;
+    if ipv6 {
+        data = vec![
+            0x60 | rng.below(16) as u8,
+            rng.next() as u8,
+            0,
+            rng.below(3) as u8,
+        ];
+        data.extend_from_slice(&[0, 0, 0, rng.pick(SMALL)]);
+        data.extend_from_slice(&rng.pick(IPV6));
+        data.extend_from_slice(&rng.pick(IPV6));
+
+        // Extension headers, each one stores the protocol of the next one
+        let mut next = protocol;
+        let mut extensions = Vec::new();
+        for _ in 0..rng.pick(&[0, 0, 0, 1, 2]) {
+            let kind = rng.pick(&[0, 43, 44, 51, 60]);
+            let mut header = match kind {
+                44 => {
+                    let offset = rng.pick(&[0, 0, 8, 16]) as u16;
+                    let more = rng.one_in(2) as u16;
+                    let [high, low] = (offset | more).to_be_bytes();
+                    vec![next, 0, high, low, 0, 0, 0, 1]
+                }
+                // Length in 32 bit words minus 2
+                51 => vec![next, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                _ => vec![next, 0, 1, 4, 0, 0, 0, 0],
+            };
+            header.extend(extensions);
+            extensions = header;
+            next = kind;
+        }
+        data[6] = next;
+        let length = (extensions.len() + transport.len()) as u16;
+        data[4..6].copy_from_slice(&length.to_be_bytes());
+        data.extend(extensions);
+    } else {
+        let options = rng.pick(&[0, 0, 0, 4]);
+        data = vec![0x45 + options as u8 / 4, rng.pick(SMALL), 0, 0];
+        data.extend_from_slice(&rng.next().to_be_bytes()[..2]);
+        let flags = match rng.below(6) {
+            0 => 0x2000,
+            1 => 0x2000 | rng.pick(&[1, 2, 185]),
+            2 => rng.pick(&[1, 2, 185]),
+            _ => rng.pick(&[0, 0x4000]),
+        };
+        data.extend_from_slice(&u16::to_be_bytes(flags));
+        data.extend_from_slice(&[rng.pick(SMALL), protocol, 0, 0]);
+        data.extend_from_slice(&rng.pick(IPV4));
+        data.extend_from_slice(&rng.pick(IPV4));
+        data.resize(data.len() + options, 1);
+        let length = (data.len() + transport.len()) as u16;
+        data[2..4].copy_from_slice(&length.to_be_bytes());
+        data[10..12].copy_from_slice(&rng.next().to_be_bytes()[..2]);
+    }
+    data.extend(transport);
+    data
+}
+
+fn random_transport(rng: &mut Rng, protocol: u8) -> Vec<u8> {
+    let mut header = match protocol {
+        6 => {
+            let mut header = Vec::new();
+            header.extend_from_slice(&rng.pick(PORTS).to_be_bytes());
+            header.extend_from_slice(&rng.pick(PORTS).to_be_bytes());
+            header.extend(rng.bytes(8));
+            let options = rng.pick(&[0, 0, 0, 4]);
+            header.push(((5 + options / 4) << 4) as u8);
+            header.push(rng.next() as u8);
+            header.extend(rng.bytes(6));
+            header.resize(header.len() + options, 1);
+            header
+        }
+        17 => {
+            let mut header = Vec::new();
+            header.extend_from_slice(&rng.pick(PORTS).to_be_bytes());
+            header.extend_from_slice(&rng.pick(PORTS).to_be_bytes());
+            header.extend(rng.bytes(4));
+            header
+        }
+        1 | 58 => {
+            let mut header = vec![rng.pick(SMALL), rng.pick(SMALL)];
+            header.extend(rng.bytes(6));
+            header
+        }
+        _ => Vec::new(),
+    };
+    let payload = rng.pick(&[0, 0, 1, 2, 4, 8, 16]);
+    header.extend(rng.bytes(payload));
+    if protocol == 17 {
+        let length = header.len() as u16;
+        header[4..6].copy_from_slice(&length.to_be_bytes());
+    }
+    header
+}
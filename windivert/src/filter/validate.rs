@@ -0,0 +1,112 @@
+use windivert_sys::WinDivertLayer;
+
+use super::{Filter, FilterLayer};
+use crate::error::WinDivertError;
+
+/// Layer of the filter language matching `layer`.
+pub(crate) fn filter_layer(layer: WinDivertLayer) -> FilterLayer {
+    match layer {
+        WinDivertLayer::Network => FilterLayer::Network,
+        WinDivertLayer::Forward => FilterLayer::Forward,
+        WinDivertLayer::Flow => FilterLayer::Flow,
+        WinDivertLayer::Socket => FilterLayer::Socket,
+        WinDivertLayer::Reflect => FilterLayer::Reflect,
+    }
+}
+
+impl Filter {
+    /**
+    Checks that every field and value of the filter is valid for `layer`.
+
+    This catches the mistakes that make [`WinDivertOpen()`](fn@windivert_sys::WinDivertOpen) fail
+    with a generic invalid parameter error, such as using `processId` on the network layer or
+    comparing `tcp.DstPort` with an IP address. The returned [`WinDivertError::Filter`] points to
+    the offending token.
+    */
+    pub fn validate(&self, layer: WinDivertLayer) -> Result<(), WinDivertError> {
+        windivert_filter::validate(self.as_str(), self.expr(), filter_layer(layer))
+            .map_err(WinDivertError::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(filter: &str, layer: WinDivertLayer) -> (String, usize) {
+        match Filter::parse(filter).unwrap().validate(layer) {
+            Err(WinDivertError::Filter { message, position }) => (message, position),
+            result => panic!("{filter}: expected an error, found {result:?}"),
+        }
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(
+            error("processId == 4", WinDivertLayer::Network),
+            (
+                "field `processId` is not available on the network layer".into(),
+                0
+            )
+        );
+        assert_eq!(
+            error("tcp and tcp.Payload[0] == 0x16", WinDivertLayer::Flow),
+            (
+                "field `tcp.Payload` is not available on the flow layer".into(),
+                8
+            )
+        );
+        let valid = |filter: &str, layer| Filter::parse(filter).unwrap().validate(layer);
+        assert!(valid("processId == 4", WinDivertLayer::Socket).is_ok());
+        assert!(valid("tcp.Payload[0] == 0x16", WinDivertLayer::Network).is_ok());
+        assert!(valid("tcp.Payload[0] == 0x16", WinDivertLayer::Forward).is_ok());
+    }
+
+    #[test]
+    fn events() {
+        assert_eq!(
+            error("event == CONNECT", WinDivertLayer::Network),
+            (
+                "event `CONNECT` is not available on the network layer".into(),
+                9
+            )
+        );
+        let filter = Filter::parse("event == CONNECT").unwrap();
+        assert!(filter.validate(WinDivertLayer::Socket).is_ok());
+        assert_eq!(
+            error("layer == CONNECT", WinDivertLayer::Reflect).0,
+            "`CONNECT` is not a valid value for field `layer`"
+        );
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(
+            error("tcp.DstPort == 65536", WinDivertLayer::Network),
+            ("`65536` is out of range for field `tcp.DstPort`".into(), 15)
+        );
+        assert_eq!(
+            error("tcp.DstPort == 10.0.0.1", WinDivertLayer::Network).0,
+            "`10.0.0.1` is not a valid value for field `tcp.DstPort`"
+        );
+        // Ordering comparisons may use the values just outside of the range
+        let filter = Filter::parse("tcp.DstPort < 65536 and ip.Ttl > -1").unwrap();
+        assert!(filter.validate(WinDivertLayer::Network).is_ok());
+
+        let valid = |filter: &str| {
+            Filter::parse(filter)
+                .unwrap()
+                .validate(WinDivertLayer::Network)
+                .is_ok()
+        };
+        assert!(valid("ipv6.SrcAddr == 18446744073709551615"));
+        assert!(!valid("ip.SrcAddr == 4294967296"));
+        assert!(valid("icmpv6.Type == 255"));
+        assert!(!valid("icmp.Type == 256"));
+
+        // Addresses above the largest 64 bit literal still exist
+        let filter = Filter::parse("ipv6.SrcAddr <= 18446744073709551615").unwrap();
+        let optimized = filter.optimize(WinDivertLayer::Network).unwrap();
+        assert_eq!(optimized.as_str(), "ipv6.SrcAddr <= 18446744073709551615");
+    }
+}
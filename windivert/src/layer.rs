@@ -33,6 +33,39 @@ impl WinDivertLayerTrait for WinDivertLayer {}
 
 impl WinDivertLayerTrait for () {}
 
+/// Typestates bound to a single [`WinDivertLayer`].
+pub trait TypedLayer: WinDivertLayerTrait {
+    /// Layer represented by the typestate.
+    const LAYER: WinDivertLayer;
+}
+
+impl TypedLayer for NetworkLayer {
+    const LAYER: WinDivertLayer = WinDivertLayer::Network;
+}
+
+impl TypedLayer for ForwardLayer {
+    const LAYER: WinDivertLayer = WinDivertLayer::Forward;
+}
+
+impl TypedLayer for FlowLayer {
+    const LAYER: WinDivertLayer = WinDivertLayer::Flow;
+}
+
+impl TypedLayer for SocketLayer {
+    const LAYER: WinDivertLayer = WinDivertLayer::Socket;
+}
+
+impl TypedLayer for ReflectLayer {
+    const LAYER: WinDivertLayer = WinDivertLayer::Reflect;
+}
+
+/// Typestates of the layers whose events carry an IP packet.
+pub trait PacketLayer: TypedLayer {}
+
+impl PacketLayer for NetworkLayer {}
+
+impl PacketLayer for ForwardLayer {}
+
 mod sealed {
     pub trait Sealed {}
 
@@ -5,22 +5,29 @@ Wrapper around [`windivert_sys`] ffi crate.
 
 /// WinDivert address data structures
 pub mod address;
+pub mod capture;
+#[cfg(target_os = "windows")]
 mod divert;
 /// WinDivert error types
 pub mod error;
+pub mod filter;
 /// Layer types used for typestate pattern
 pub mod layer;
 /// WinDivert packet types
 pub mod packet;
+pub mod tcp;
 
+#[cfg(target_os = "windows")]
 pub use divert::*;
 
 /// Prelude module for [`WinDivert`].
 pub mod prelude {
     pub use windivert_sys::{
-        WinDivertEvent, WinDivertFlags, WinDivertLayer, WinDivertParam, WinDivertShutdownMode,
+        ChecksumFlags, WinDivertEvent, WinDivertFlags, WinDivertLayer, WinDivertParam,
+        WinDivertShutdownMode,
     };
 
+    #[cfg(target_os = "windows")]
     pub use crate::divert::*;
     pub use crate::error::*;
     pub use crate::layer::*;
@@ -0,0 +1,647 @@
+use std::{
+    borrow::Cow,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use windivert_sys::{
+    address::{WINDIVERT_ADDRESS, WINDIVERT_DATA_NETWORK},
+    ChecksumFlags, WinDivertEvent,
+};
+
+use super::{checksum, WinDivertPacket};
+use crate::{address::WinDivertAddress, error::PacketBuildError, layer::PacketLayer};
+
+/// Maximum length of the TCP options.
+const TCP_OPTIONS_MAX: usize = 40;
+
+/**
+Builder of IPv4 and IPv6 packets ready to be injected.
+
+The packet is made of an IP header, an optional TCP, UDP, ICMP or ICMPv6 header and a payload.
+[`build()`](PacketBuilder::build) fills in the lengths and checksums, and sets the address of the
+packet from the builder, so the packet can be sent as is. Packets are outbound by default.
+*/
+#[derive(Debug, Clone)]
+pub struct PacketBuilder {
+    src_addr: IpAddr,
+    dst_addr: IpAddr,
+    tos: u8,
+    flow_label: u32,
+    id: u16,
+    df: bool,
+    ttl: u8,
+    transport: Transport,
+    payload: Vec<u8>,
+    outbound: bool,
+    impostor: bool,
+    interface_index: u32,
+    subinterface_index: u32,
+}
+
+#[derive(Debug, Clone)]
+enum Transport {
+    Raw(u8),
+    Tcp(TcpBuilder),
+    Udp {
+        src_port: u16,
+        dst_port: u16,
+    },
+    Icmp {
+        msg_type: u8,
+        msg_code: u8,
+        body: u32,
+    },
+}
+
+impl PacketBuilder {
+    fn new(src_addr: IpAddr, dst_addr: IpAddr) -> Self {
+        Self {
+            src_addr,
+            dst_addr,
+            tos: 0,
+            flow_label: 0,
+            id: 0,
+            df: false,
+            ttl: 64,
+            // No next header
+            transport: Transport::Raw(59),
+            payload: Vec::new(),
+            outbound: true,
+            impostor: false,
+            interface_index: 0,
+            subinterface_index: 0,
+        }
+    }
+
+    /// IPv4 packet from `src_addr` to `dst_addr`.
+    pub fn ipv4(src_addr: Ipv4Addr, dst_addr: Ipv4Addr) -> Self {
+        Self::new(IpAddr::V4(src_addr), IpAddr::V4(dst_addr))
+    }
+
+    /// IPv6 packet from `src_addr` to `dst_addr`.
+    pub fn ipv6(src_addr: Ipv6Addr, dst_addr: Ipv6Addr) -> Self {
+        Self::new(IpAddr::V6(src_addr), IpAddr::V6(dst_addr))
+    }
+
+    /// Type of service of IPv4 packets, or traffic class of IPv6 packets. `0` by default.
+    pub fn tos(mut self, tos: u8) -> Self {
+        self.tos = tos;
+        self
+    }
+
+    /// Flow label of IPv6 packets, only its 20 low bits are used. Ignored for IPv4 packets.
+    pub fn flow_label(mut self, flow_label: u32) -> Self {
+        self.flow_label = flow_label & 0xFFFFF;
+        self
+    }
+
+    /// Identification of IPv4 packets, `0` by default. Ignored for IPv6 packets.
+    pub fn id(mut self, id: u16) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Don't fragment flag of IPv4 packets, unset by default. Ignored for IPv6 packets.
+    pub fn df(mut self, df: bool) -> Self {
+        self.df = df;
+        self
+    }
+
+    /// Time to live of IPv4 packets, or hop limit of IPv6 packets. `64` by default.
+    pub fn ttl(mut self, ttl: u8) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /**
+    Protocol of the payload when there is no transport header, `59` (no next header) by default.
+    Discards the transport header set by the other methods.
+    */
+    pub fn protocol(mut self, protocol: u8) -> Self {
+        self.transport = Transport::Raw(protocol);
+        self
+    }
+
+    /// Adds the TCP header built by `tcp`.
+    pub fn tcp(mut self, tcp: TcpBuilder) -> Self {
+        self.transport = Transport::Tcp(tcp);
+        self
+    }
+
+    /// Adds a UDP header.
+    pub fn udp(mut self, src_port: u16, dst_port: u16) -> Self {
+        self.transport = Transport::Udp { src_port, dst_port };
+        self
+    }
+
+    /**
+    Adds an ICMP header to IPv4 packets, or an ICMPv6 header to IPv6 packets. `body` is the rest
+    of the header, whose meaning depends on the message type.
+    */
+    pub fn icmp(mut self, msg_type: u8, msg_code: u8, body: u32) -> Self {
+        self.transport = Transport::Icmp {
+            msg_type,
+            msg_code,
+            body,
+        };
+        self
+    }
+
+    /// Data following the headers, empty by default.
+    pub fn payload(mut self, payload: impl Into<Vec<u8>>) -> Self {
+        self.payload = payload.into();
+        self
+    }
+
+    /// Direction of the packet, `true` by default.
+    pub fn outbound(mut self, outbound: bool) -> Self {
+        self.outbound = outbound;
+        self
+    }
+
+    /// Impostor flag of the packet, unset by default.
+    pub fn impostor(mut self, impostor: bool) -> Self {
+        self.impostor = impostor;
+        self
+    }
+
+    /// Interface on which the packet is injected, `0` by default.
+    pub fn interface_index(mut self, interface_index: u32) -> Self {
+        self.interface_index = interface_index;
+        self
+    }
+
+    /// Sub-interface on which the packet is injected, `0` by default.
+    pub fn subinterface_index(mut self, subinterface_index: u32) -> Self {
+        self.subinterface_index = subinterface_index;
+        self
+    }
+
+    /// Assembles the packet and its address for the layer typestate `L`.
+    pub fn build<L: PacketLayer>(&self) -> Result<WinDivertPacket<'static, L>, PacketBuildError> {
+        let ipv6 = self.src_addr.is_ipv6();
+        let (protocol, mut transport) = self.transport.header(ipv6)?;
+        let header_len = if ipv6 { 40 } else { 20 };
+        let length = header_len + transport.len() + self.payload.len();
+        let payload_length = match ipv6 {
+            true => length - header_len,
+            false => length,
+        };
+        let payload_length =
+            u16::try_from(payload_length).map_err(|_| PacketBuildError::TooLarge(length))?;
+
+        let mut data = Vec::with_capacity(length);
+        match (self.src_addr, self.dst_addr) {
+            (IpAddr::V4(src_addr), IpAddr::V4(dst_addr)) => {
+                data.extend_from_slice(&[0x45, self.tos]);
+                data.extend_from_slice(&payload_length.to_be_bytes());
+                data.extend_from_slice(&self.id.to_be_bytes());
+                data.extend_from_slice(&[if self.df { 0x40 } else { 0 }, 0]);
+                data.extend_from_slice(&[self.ttl, protocol, 0, 0]);
+                data.extend_from_slice(&src_addr.octets());
+                data.extend_from_slice(&dst_addr.octets());
+            }
+            (IpAddr::V6(src_addr), IpAddr::V6(dst_addr)) => {
+                let first = (6 << 28) | ((self.tos as u32) << 20) | self.flow_label;
+                data.extend_from_slice(&first.to_be_bytes());
+                data.extend_from_slice(&payload_length.to_be_bytes());
+                data.extend_from_slice(&[protocol, self.ttl]);
+                data.extend_from_slice(&src_addr.octets());
+                data.extend_from_slice(&dst_addr.octets());
+            }
+            _ => unreachable!("addresses of a packet builder are of the same family"),
+        }
+        if let Transport::Udp { .. } = self.transport {
+            let udp_length = (transport.len() + self.payload.len()) as u16;
+            transport[4..6].copy_from_slice(&udp_length.to_be_bytes());
+        }
+        data.extend_from_slice(&transport);
+        data.extend_from_slice(&self.payload);
+        let calculated = checksum::calc_checksums(&mut data, ChecksumFlags::new());
+
+        let mut address = WinDivertAddress::<L>::from_raw(WINDIVERT_ADDRESS::default());
+        address.set_outbound(self.outbound);
+        address.set_impostor(self.impostor);
+        address.set_ip_checksum(calculated.ip);
+        address.set_tcp_checksum(calculated.tcp);
+        address.set_udp_checksum(calculated.udp);
+        let raw = address.as_mut();
+        raw.set_layer(L::LAYER);
+        raw.set_event(WinDivertEvent::NetworkPacket);
+        raw.set_ipv6(ipv6);
+        raw.union_field.Network = WINDIVERT_DATA_NETWORK {
+            interface_id: self.interface_index,
+            subinterface_id: self.subinterface_index,
+        };
+
+        Ok(WinDivertPacket {
+            address,
+            data: Cow::Owned(data),
+        })
+    }
+}
+
+impl Transport {
+    /// Protocol number and bytes of the header, with a zero checksum.
+    fn header(&self, ipv6: bool) -> Result<(u8, Vec<u8>), PacketBuildError> {
+        let mut header = Vec::new();
+        let protocol = match *self {
+            Transport::Raw(protocol) => protocol,
+            Transport::Tcp(ref tcp) => {
+                if tcp.options.len() > TCP_OPTIONS_MAX {
+                    return Err(PacketBuildError::TcpOptions(tcp.options.len()));
+                }
+                // Options are padded with end of option list bytes
+                let options_len = (tcp.options.len() + 3) / 4 * 4;
+                header.extend_from_slice(&tcp.src_port.to_be_bytes());
+                header.extend_from_slice(&tcp.dst_port.to_be_bytes());
+                header.extend_from_slice(&tcp.seq_number.to_be_bytes());
+                header.extend_from_slice(&tcp.ack_number.to_be_bytes());
+                header.extend_from_slice(&[(((20 + options_len) / 4) << 4) as u8, tcp.flags]);
+                header.extend_from_slice(&tcp.window.to_be_bytes());
+                header.extend_from_slice(&[0, 0]);
+                header.extend_from_slice(&tcp.urg_ptr.to_be_bytes());
+                header.extend_from_slice(&tcp.options);
+                header.resize(20 + options_len, 0);
+                6
+            }
+            Transport::Udp { src_port, dst_port } => {
+                header.extend_from_slice(&src_port.to_be_bytes());
+                header.extend_from_slice(&dst_port.to_be_bytes());
+                // The length is set along with the payload
+                header.extend_from_slice(&[0, 0, 0, 0]);
+                17
+            }
+            Transport::Icmp {
+                msg_type,
+                msg_code,
+                body,
+            } => {
+                header.extend_from_slice(&[msg_type, msg_code, 0, 0]);
+                header.extend_from_slice(&body.to_be_bytes());
+                if ipv6 {
+                    58
+                } else {
+                    1
+                }
+            }
+        };
+        Ok((protocol, header))
+    }
+}
+
+/**
+TCP header of a [`PacketBuilder`].
+
+Every field is zero by default, except the window which is `65535`.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpBuilder {
+    src_port: u16,
+    dst_port: u16,
+    seq_number: u32,
+    ack_number: u32,
+    flags: u8,
+    window: u16,
+    urg_ptr: u16,
+    options: Vec<u8>,
+}
+
+impl TcpBuilder {
+    /// TCP header from `src_port` to `dst_port`.
+    pub fn new(src_port: u16, dst_port: u16) -> Self {
+        Self {
+            src_port,
+            dst_port,
+            seq_number: 0,
+            ack_number: 0,
+            flags: 0,
+            window: 65535,
+            urg_ptr: 0,
+            options: Vec::new(),
+        }
+    }
+
+    fn flag(mut self, mask: u8, value: bool) -> Self {
+        match value {
+            true => self.flags |= mask,
+            false => self.flags &= !mask,
+        }
+        self
+    }
+
+    /// Sequence number.
+    pub fn seq_number(mut self, seq_number: u32) -> Self {
+        self.seq_number = seq_number;
+        self
+    }
+
+    /// Acknowledgement number, only meaningful with the ACK flag.
+    pub fn ack_number(mut self, ack_number: u32) -> Self {
+        self.ack_number = ack_number;
+        self
+    }
+
+    /// URG flag.
+    pub fn urg(self, urg: bool) -> Self {
+        self.flag(0x20, urg)
+    }
+
+    /// ACK flag.
+    pub fn ack(self, ack: bool) -> Self {
+        self.flag(0x10, ack)
+    }
+
+    /// PSH flag.
+    pub fn psh(self, psh: bool) -> Self {
+        self.flag(0x08, psh)
+    }
+
+    /// RST flag.
+    pub fn rst(self, rst: bool) -> Self {
+        self.flag(0x04, rst)
+    }
+
+    /// SYN flag.
+    pub fn syn(self, syn: bool) -> Self {
+        self.flag(0x02, syn)
+    }
+
+    /// FIN flag.
+    pub fn fin(self, fin: bool) -> Self {
+        self.flag(0x01, fin)
+    }
+
+    /// Window size.
+    pub fn window(mut self, window: u16) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Urgent pointer, only meaningful with the URG flag.
+    pub fn urg_ptr(mut self, urg_ptr: u16) -> Self {
+        self.urg_ptr = urg_ptr;
+        self
+    }
+
+    /**
+    Raw options, such as `[2, 4, 0x05, 0xB4]` for a maximum segment size of 1460. They are padded
+    to a multiple of 4 bytes with end of option list bytes, and can't be longer than 40 bytes.
+    */
+    pub fn options(mut self, options: impl Into<Vec<u8>>) -> Self {
+        self.options = options.into();
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use windivert_sys::WinDivertLayer;
+
+    use super::*;
+    use crate::{
+        layer::{ForwardLayer, NetworkLayer},
+        packet::{IpHeader, ParsedPacket, TransportHeader},
+    };
+
+    const SRC_V4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const DST_V4: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+    const SRC_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+    const DST_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+    /// Checks the IPv4 header and transport checksums from their definitions.
+    fn assert_checksums(packet: &ParsedPacket<'_>) {
+        let ip = packet.ip.unwrap();
+        let transport = packet.transport.unwrap();
+        let segment = [transport.as_bytes(), packet.payload].concat();
+        let protocol = packet.protocol.unwrap();
+        let mut pseudo = Vec::new();
+        match ip {
+            IpHeader::V4(header) => {
+                assert_eq!(checksum::checksum(header.as_bytes()), 0);
+                pseudo.extend_from_slice(&header.src_addr().octets());
+                pseudo.extend_from_slice(&header.dst_addr().octets());
+                pseudo.extend_from_slice(&[0, protocol]);
+                pseudo.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+            }
+            IpHeader::V6(header) => {
+                pseudo.extend_from_slice(&header.src_addr().octets());
+                pseudo.extend_from_slice(&header.dst_addr().octets());
+                pseudo.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+                pseudo.extend_from_slice(&[0, 0, 0, protocol]);
+            }
+        }
+        // ICMP is the only checksum without a pseudo header
+        let covered = match transport {
+            TransportHeader::Icmp(_) => segment,
+            _ => [pseudo, segment].concat(),
+        };
+        assert_eq!(checksum::checksum(&covered), 0, "{transport:?}");
+    }
+
+    #[test]
+    fn tcp() {
+        let tcp = TcpBuilder::new(49152, 443)
+            .seq_number(0x0BAD_CAFE)
+            .ack_number(0x1234_5678)
+            .ack(true)
+            .psh(true)
+            .window(64240)
+            .options([2, 4, 0x05, 0xB4, 1, 3, 3]);
+        let builders = [
+            PacketBuilder::ipv4(SRC_V4, DST_V4).id(0x1C46).df(true),
+            PacketBuilder::ipv6(SRC_V6, DST_V6).flow_label(0xABCDE),
+        ];
+        for builder in builders {
+            let packet = builder
+                .tos(0xB8)
+                .tcp(tcp.clone())
+                .payload(*b"hello")
+                .build::<NetworkLayer>()
+                .unwrap();
+            let parsed = packet.parse();
+            assert!(!parsed.truncated);
+            assert_eq!(parsed.protocol, Some(6));
+            assert_eq!(parsed.payload, b"hello");
+            assert_checksums(&parsed);
+            assert!(packet.address.ip_checksum() == parsed.ipv4().is_some());
+            assert!(packet.address.tcp_checksum());
+            assert!(!packet.address.udp_checksum());
+
+            let header = parsed.tcp().unwrap();
+            assert_eq!((header.src_port(), header.dst_port()), (49152, 443));
+            assert_eq!(header.seq_number(), 0x0BAD_CAFE);
+            assert_eq!(header.ack_number(), 0x1234_5678);
+            assert!(header.ack() && header.psh());
+            assert!(!header.syn() && !header.fin() && !header.rst() && !header.urg());
+            assert_eq!(header.window(), 64240);
+            // Options are padded with an end of option list byte
+            assert_eq!(header.header_length(), 7);
+            assert_eq!(header.options(), [2, 4, 0x05, 0xB4, 1, 3, 3, 0]);
+
+            match parsed.ip.unwrap() {
+                IpHeader::V4(header) => {
+                    assert_eq!((header.src_addr(), header.dst_addr()), (SRC_V4, DST_V4));
+                    assert_eq!(header.length(), 20 + 28 + 5);
+                    assert_eq!(
+                        (header.tos(), header.id(), header.ttl()),
+                        (0xB8, 0x1C46, 64)
+                    );
+                    assert!(header.df() && !header.mf());
+                }
+                IpHeader::V6(header) => {
+                    assert_eq!((header.src_addr(), header.dst_addr()), (SRC_V6, DST_V6));
+                    assert_eq!(header.length(), 28 + 5);
+                    assert_eq!(header.traffic_class(), 0xB8);
+                    assert_eq!((header.flow_label(), header.hop_limit()), (0xABCDE, 64));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn udp() {
+        let builders = [
+            PacketBuilder::ipv4(SRC_V4, DST_V4),
+            PacketBuilder::ipv6(SRC_V6, DST_V6),
+        ];
+        for builder in builders {
+            // Odd payload length
+            let packet = builder
+                .udp(51000, 53)
+                .payload(*b"abc")
+                .build::<NetworkLayer>()
+                .unwrap();
+            let parsed = packet.parse();
+            assert!(!parsed.truncated);
+            assert_eq!(parsed.protocol, Some(17));
+            assert_eq!(parsed.payload, b"abc");
+            assert_checksums(&parsed);
+            assert!(packet.address.udp_checksum());
+            assert!(!packet.address.tcp_checksum());
+
+            let header = parsed.udp().unwrap();
+            assert_eq!((header.src_port(), header.dst_port()), (51000, 53));
+            assert_eq!(header.length(), 8 + 3);
+            assert_eq!(parsed.ip.unwrap().packet_length(), packet.data.len());
+        }
+    }
+
+    #[test]
+    fn icmp() {
+        let packet = PacketBuilder::ipv4(SRC_V4, DST_V4)
+            .icmp(8, 0, 0x1234_0001)
+            .payload(*b"ping")
+            .build::<NetworkLayer>()
+            .unwrap();
+        let parsed = packet.parse();
+        assert_eq!(parsed.protocol, Some(1));
+        assert_eq!(parsed.payload, b"ping");
+        assert_checksums(&parsed);
+        let header = parsed.icmp().unwrap();
+        assert_eq!((header.msg_type(), header.msg_code()), (8, 0));
+        assert_eq!(header.body(), 0x1234_0001);
+
+        let packet = PacketBuilder::ipv6(SRC_V6, DST_V6)
+            .icmp(128, 0, 0x1234_0001)
+            .payload(*b"ping")
+            .build::<NetworkLayer>()
+            .unwrap();
+        let parsed = packet.parse();
+        assert_eq!(parsed.protocol, Some(58));
+        assert_eq!(parsed.payload, b"ping");
+        assert_checksums(&parsed);
+        let header = parsed.icmpv6().unwrap();
+        assert_eq!((header.msg_type(), header.msg_code()), (128, 0));
+        assert_eq!(header.body(), 0x1234_0001);
+    }
+
+    #[test]
+    fn raw() {
+        let packet = PacketBuilder::ipv6(SRC_V6, DST_V6)
+            .payload(*b"data")
+            .build::<NetworkLayer>()
+            .unwrap();
+        let parsed = packet.parse();
+        assert_eq!(parsed.protocol, Some(59));
+        assert_eq!(parsed.transport, None);
+        assert_eq!(parsed.payload, b"data");
+
+        // The protocol replaces the transport header
+        let packet = PacketBuilder::ipv4(SRC_V4, DST_V4)
+            .udp(1, 2)
+            .protocol(47)
+            .build::<NetworkLayer>()
+            .unwrap();
+        let parsed = packet.parse();
+        assert_eq!(parsed.protocol, Some(47));
+        assert_eq!(packet.data.len(), 20);
+        assert_eq!(checksum::checksum(&packet.data), 0);
+    }
+
+    #[test]
+    fn address() {
+        let packet = PacketBuilder::ipv6(SRC_V6, DST_V6)
+            .outbound(false)
+            .impostor(true)
+            .interface_index(7)
+            .subinterface_index(3)
+            .build::<ForwardLayer>()
+            .unwrap();
+        let address = &packet.address;
+        assert!(matches!(address.event_layer(), WinDivertLayer::Forward));
+        assert!(matches!(address.event(), WinDivertEvent::NetworkPacket));
+        assert!(!address.outbound() && address.impostor() && address.ipv6());
+        assert_eq!(address.interface_index(), 7);
+        assert_eq!(address.subinterface_index(), 3);
+
+        let packet = PacketBuilder::ipv4(SRC_V4, DST_V4)
+            .build::<NetworkLayer>()
+            .unwrap();
+        assert!(matches!(
+            packet.address.event_layer(),
+            WinDivertLayer::Network
+        ));
+        assert!(packet.address.outbound() && !packet.address.ipv6());
+    }
+
+    #[test]
+    fn errors() {
+        let options = TcpBuilder::new(1, 2).options([1; 41]);
+        assert_eq!(
+            PacketBuilder::ipv4(SRC_V4, DST_V4)
+                .tcp(options.clone().options([1; 40]))
+                .build::<NetworkLayer>()
+                .unwrap()
+                .parse()
+                .tcp()
+                .unwrap()
+                .header_length(),
+            15
+        );
+        assert!(matches!(
+            PacketBuilder::ipv4(SRC_V4, DST_V4)
+                .tcp(options)
+                .build::<NetworkLayer>(),
+            Err(PacketBuildError::TcpOptions(41))
+        ));
+
+        // IPv6 lengths don't include the fixed header
+        let builder = PacketBuilder::ipv6(SRC_V6, DST_V6).udp(1, 2);
+        assert!(builder
+            .clone()
+            .payload(vec![0; 65535 - 8])
+            .build::<NetworkLayer>()
+            .is_ok());
+        assert!(matches!(
+            builder.payload(vec![0; 65536 - 8]).build::<NetworkLayer>(),
+            Err(PacketBuildError::TooLarge(65576))
+        ));
+        assert!(matches!(
+            PacketBuilder::ipv4(SRC_V4, DST_V4)
+                .payload(vec![0; 65536 - 20])
+                .build::<NetworkLayer>(),
+            Err(PacketBuildError::TooLarge(65536))
+        ));
+    }
+}
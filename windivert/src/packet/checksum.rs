@@ -0,0 +1,500 @@
+/*!
+Internet checksums of network packets, computed without the WinDivert library.
+
+[`calc_checksums()`] is the pure Rust counterpart of
+[`WinDivertHelperCalcChecksums()`](fn@windivert_sys::WinDivertHelperCalcChecksums) and recomputes
+the IPv4 header, ICMP, ICMPv6, TCP and UDP checksums of a packet from scratch. When only a few bytes
+of a packet change, such as an address or a port, [`patch()`] writes them and adjusts the checksums
+covering them with the incremental update of [RFC 1624], which doesn't need to read the payload.
+
+[RFC 1624]: https://www.rfc-editor.org/rfc/rfc1624
+*/
+
+use windivert_sys::ChecksumFlags;
+
+use super::{IpHeader, ParsedPacket, TransportHeader};
+
+/// Adds the 16 bit big endian words of `data` to `sum`, padding an odd trailing byte with zero.
+fn add(mut sum: u64, data: &[u8]) -> u64 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u64;
+    }
+    if let [byte] = words.remainder() {
+        sum += (*byte as u64) << 8;
+    }
+    sum
+}
+
+/// Ones' complement of the words of `data` added to `sum`, as [`add()`] does.
+fn add_complement(mut sum: u64, data: &[u8]) -> u64 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += !u16::from_be_bytes([word[0], word[1]]) as u64;
+    }
+    if let [byte] = words.remainder() {
+        sum += !((*byte as u16) << 8) as u64;
+    }
+    sum
+}
+
+/// Folds the carries of `sum` into a 16 bit ones' complement sum.
+fn fold(mut sum: u64) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Internet checksum of `data`, the ones' complement of the ones' complement sum of its words.
+pub fn checksum(data: &[u8]) -> u16 {
+    !fold(add(0, data))
+}
+
+/**
+Checksum updated for a change of the covered bytes from `old` to `new`, following equation 3 of
+RFC 1624.
+
+`old` and `new` must have the same length and start at an even offset from the start of the data
+covered by the checksum, which is always the case for address and port fields.
+*/
+pub fn update(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    debug_assert_eq!(old.len(), new.len());
+    !fold(add(add_complement(!checksum as u64, old), new))
+}
+
+/// Checksums computed by [`calc_checksums()`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Calculated {
+    /// IPv4 header checksum.
+    pub ip: bool,
+    /// ICMP checksum.
+    pub icmp: bool,
+    /// ICMPv6 checksum.
+    pub icmpv6: bool,
+    /// TCP checksum.
+    pub tcp: bool,
+    /// UDP checksum.
+    pub udp: bool,
+}
+
+/// Offsets of the checksummed parts of a packet.
+struct Layout {
+    /// IPv4 header length, `None` for IPv6 packets.
+    ipv4: Option<usize>,
+    /// Protocol of the transport header, `0` if there is none.
+    protocol: u8,
+    /// Offset of the transport header, and end of the transport payload.
+    transport: Option<(usize, usize)>,
+    /// Set for fragments, whose transport checksum covers data of other packets.
+    fragment: bool,
+}
+
+impl Layout {
+    /// Locates the checksummed parts of `data`, `None` if it doesn't start with an IP header.
+    fn new(data: &[u8]) -> Option<Self> {
+        let packet = ParsedPacket::parse(data);
+        let offset = |bytes: &[u8]| bytes.as_ptr() as usize - data.as_ptr() as usize;
+        let ipv4 = match packet.ip? {
+            IpHeader::V4(header) => Some(header.as_bytes().len()),
+            IpHeader::V6(_) => None,
+        };
+        let transport = packet.transport.map(|header| {
+            let end = offset(packet.payload) + packet.payload.len();
+            (offset(header.as_bytes()), end)
+        });
+        let protocol = match packet.transport {
+            Some(TransportHeader::Icmp(_)) => 1,
+            Some(TransportHeader::Icmpv6(_)) => 58,
+            Some(TransportHeader::Tcp(_)) => 6,
+            Some(TransportHeader::Udp(_)) => 17,
+            None => 0,
+        };
+        Some(Self {
+            ipv4,
+            protocol,
+            transport,
+            fragment: packet.is_fragment(),
+        })
+    }
+
+    /// Offset of the transport checksum field.
+    fn transport_checksum(&self) -> Option<usize> {
+        let (start, _) = self.transport?;
+        match self.protocol {
+            6 => Some(start + 16),
+            17 => Some(start + 6),
+            // ICMP and ICMPv6
+            _ => Some(start + 2),
+        }
+    }
+
+    /// Byte ranges of the packet covered by the transport checksum, pseudo-header included.
+    fn transport_ranges(&self) -> Vec<(usize, usize)> {
+        let Some(transport) = self.transport else {
+            return Vec::new();
+        };
+        match (self.ipv4, self.protocol) {
+            (Some(_), 1) => vec![transport],
+            (Some(_), _) => vec![(12, 20), transport],
+            (None, _) => vec![(8, 40), transport],
+        }
+    }
+}
+
+/**
+Recomputes the checksums of an IP packet, skipping the ones disabled by `flags`.
+
+Transport checksums of fragments are left untouched as they also cover the data of the other
+fragments. A computed UDP checksum of zero is sent as `0xFFFF`, as zero means no checksum.
+*/
+pub fn calc_checksums(data: &mut [u8], flags: ChecksumFlags) -> Calculated {
+    let mut calculated = Calculated::default();
+    let Some(layout) = Layout::new(data) else {
+        return calculated;
+    };
+
+    if let Some(header_len) = layout.ipv4.filter(|_| !flags.no_ip()) {
+        data[10..12].fill(0);
+        let sum = checksum(&data[..header_len]);
+        data[10..12].copy_from_slice(&sum.to_be_bytes());
+        calculated.ip = true;
+    }
+
+    let (Some((start, end)), Some(at)) = (layout.transport, layout.transport_checksum()) else {
+        return calculated;
+    };
+    let enabled = match layout.protocol {
+        1 => !flags.no_icmp(),
+        58 => !flags.no_icmpv6(),
+        6 => !flags.no_tcp(),
+        _ => !flags.no_udp(),
+    };
+    if layout.fragment || !enabled {
+        return calculated;
+    }
+
+    data[at..at + 2].fill(0);
+    let length = end - start;
+    let mut sum = add(0, &data[start..end]);
+    match layout.ipv4 {
+        Some(_) if layout.protocol == 1 => {}
+        Some(_) => sum = add(sum, &data[12..20]) + layout.protocol as u64 + length as u64,
+        None => sum = add(sum, &data[8..40]) + layout.protocol as u64 + length as u64,
+    }
+    let mut sum = !fold(sum);
+    if layout.protocol == 17 && sum == 0 {
+        sum = 0xFFFF;
+    }
+    data[at..at + 2].copy_from_slice(&sum.to_be_bytes());
+
+    match layout.protocol {
+        1 => calculated.icmp = true,
+        58 => calculated.icmpv6 = true,
+        6 => calculated.tcp = true,
+        _ => calculated.udp = true,
+    }
+    calculated
+}
+
+/**
+Writes `bytes` at `offset` in an IP packet and incrementally updates the IPv4 header and transport
+checksums covering them, including the pseudo-header addresses of TCP, UDP and ICMPv6.
+
+Meant for fields that don't change the layout of the packet, such as addresses, ports or the TTL.
+An IPv4 UDP checksum of zero, meaning no checksum, is kept as is. Changing the addresses of a non
+first fragment can't update the transport checksum, which is in the first fragment.
+
+Returns `false`, leaving the packet untouched, if the data doesn't start with an IP header, if the
+bytes don't fit or if they overlap a checksum field.
+*/
+pub fn patch(data: &mut [u8], offset: usize, bytes: &[u8]) -> bool {
+    let end = offset + bytes.len();
+    let Some(layout) = Layout::new(data) else {
+        return false;
+    };
+    let overlaps = |at: usize| offset < at + 2 && at < end;
+    if end > data.len()
+        || layout.ipv4.is_some() && overlaps(10)
+        || layout.transport_checksum().map_or(false, overlaps)
+    {
+        return false;
+    }
+
+    let old = data[offset..end].to_vec();
+    data[offset..end].copy_from_slice(bytes);
+    fix(data, offset, &old, ChecksumFlags::new());
+    true
+}
+
+/**
+Incrementally updates the checksums covering the bytes at `offset`, which were `old` before being
+overwritten, as [`patch()`] does. Checksums disabled by `flags` are left as they were.
+
+Returns the checksums covering the change that were disabled, and are thus no longer valid.
+*/
+pub(crate) fn fix(data: &mut [u8], offset: usize, old: &[u8], flags: ChecksumFlags) -> Calculated {
+    let mut stale = Calculated::default();
+    let end = offset + old.len();
+    let Some(layout) = Layout::new(data) else {
+        return stale;
+    };
+
+    // Words are aligned on even offsets from the start of every checksummed range
+    let (first, last) = (offset & !1, (end + (end & 1)).min(data.len()));
+    let mut window = data[first..last].to_vec();
+    window[offset - first..end - first].copy_from_slice(old);
+
+    let covers = |ranges: &[(usize, usize)]| {
+        ranges
+            .iter()
+            .any(|&(start, stop)| start < last && first < stop)
+    };
+    let delta = |data: &[u8], ranges: &[(usize, usize)], checksum: u16| {
+        ranges.iter().fold(checksum, |checksum, &(start, stop)| {
+            let (start, stop) = (start.max(first), stop.min(last));
+            match start < stop {
+                true => update(
+                    checksum,
+                    &window[start - first..stop - first],
+                    &data[start..stop],
+                ),
+                false => checksum,
+            }
+        })
+    };
+
+    if let Some(header_len) = layout.ipv4.filter(|&len| covers(&[(0, len)])) {
+        match flags.no_ip() {
+            true => stale.ip = true,
+            false => {
+                let checksum = u16::from_be_bytes([data[10], data[11]]);
+                let checksum = delta(data, &[(0, header_len)], checksum);
+                data[10..12].copy_from_slice(&checksum.to_be_bytes());
+            }
+        }
+    }
+
+    let ranges = layout.transport_ranges();
+    let Some(at) = layout.transport_checksum().filter(|_| covers(&ranges)) else {
+        return stale;
+    };
+    let disabled = match layout.protocol {
+        1 => flags.no_icmp(),
+        58 => flags.no_icmpv6(),
+        6 => flags.no_tcp(),
+        _ => flags.no_udp(),
+    };
+    if disabled {
+        match layout.protocol {
+            1 => stale.icmp = true,
+            58 => stale.icmpv6 = true,
+            6 => stale.tcp = true,
+            _ => stale.udp = true,
+        }
+        return stale;
+    }
+    let checksum = u16::from_be_bytes([data[at], data[at + 1]]);
+    if layout.protocol == 17 && layout.ipv4.is_some() && checksum == 0 {
+        return stale;
+    }
+    let mut checksum = delta(data, &ranges, checksum);
+    if layout.protocol == 17 && checksum == 0 {
+        checksum = 0xFFFF;
+    }
+    data[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+    stale
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    // Packets with valid checksums, as computed by `WinDivertHelperCalcChecksums()`
+
+    /// 10.0.0.2:49152 -> 93.184.216.34:443, "hello".
+    const IPV4_TCP: &str = "4500002d1c4640004006dea80a0000025db8d822\
+        c00001bb0badcafe123456785018faf03014000068656c6c6f";
+    /// 10.0.0.2:51000 -> 93.184.216.34:53, odd payload length.
+    const IPV4_UDP: &str =
+        "450000251c4640004011dea50a0000025db8d822c7380035001120ea123401000001616263";
+    /// 10.0.0.2 -> 93.184.216.34, echo request.
+    const IPV4_ICMP: &str = "450000201c4640004001deba0a0000025db8d822080006fa1234000170696e67";
+    /// [2001:db8::2]:49152 -> [2001:db8::1]:443, "hello".
+    const IPV6_TCP: &str = "600000000019064020010db800000000000000000000000220010db8\
+        000000000000000000000001c00001bb0badcafe123456785018faf0147c000068656c6c6f";
+    /// [2001:db8::2]:51000 -> [2001:db8::1]:53, odd payload length.
+    const IPV6_UDP: &str = "600000000011114020010db800000000000000000000000220010db8\
+        000000000000000000000001c738003500110552123401000001616263";
+    /// 2001:db8::2 -> 2001:db8::1, echo request.
+    const IPV6_ICMPV6: &str = "60000000000c3a4020010db800000000000000000000000220010db8\
+        0000000000000000000000018000333e1234000170696e67";
+    /// 10.0.0.2:51000 -> 8.8.8.8:53, with a payload making the computed UDP checksum zero.
+    const UDP_ZERO: &str = "4500001e1c464000401104780a00000208080808c7380035000affff1e5b";
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Packet, offset of its transport checksum and checksums expected from `calc_checksums()`.
+    fn packets() -> Vec<(Vec<u8>, usize, Calculated)> {
+        let none = Calculated::default();
+        let ip = Calculated { ip: true, ..none };
+        vec![
+            (bytes(IPV4_TCP), 36, Calculated { tcp: true, ..ip }),
+            (bytes(IPV4_UDP), 26, Calculated { udp: true, ..ip }),
+            (bytes(IPV4_ICMP), 22, Calculated { icmp: true, ..ip }),
+            (bytes(IPV6_TCP), 56, Calculated { tcp: true, ..none }),
+            (bytes(IPV6_UDP), 46, Calculated { udp: true, ..none }),
+            (
+                bytes(IPV6_ICMPV6),
+                42,
+                Calculated {
+                    icmpv6: true,
+                    ..none
+                },
+            ),
+        ]
+    }
+
+    /// Copy of `packet` with wrong IPv4 header and transport checksums.
+    fn corrupt(packet: &[u8], at: usize) -> Vec<u8> {
+        let mut packet = packet.to_vec();
+        if packet[0] >> 4 == 4 {
+            packet[10..12].copy_from_slice(&[0xDE, 0xAD]);
+        }
+        packet[at..at + 2].copy_from_slice(&[0xBE, 0xEF]);
+        packet
+    }
+
+    #[test]
+    fn known_packets() {
+        for (packet, at, expected) in packets() {
+            let mut data = corrupt(&packet, at);
+            assert_eq!(calc_checksums(&mut data, ChecksumFlags::new()), expected);
+            assert_eq!(data, packet);
+            if expected.ip {
+                assert_eq!(checksum(&data[..20]), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn udp_zero() {
+        let packet = bytes(UDP_ZERO);
+        assert_eq!(packet[26..28], [0xFF, 0xFF]);
+        let mut data = corrupt(&packet, 26);
+        calc_checksums(&mut data, ChecksumFlags::new());
+        assert_eq!(data, packet);
+
+        // IPv4 UDP packets without a checksum keep none
+        let mut data = bytes(IPV4_UDP);
+        data[26..28].fill(0);
+        assert!(patch(&mut data, 22, &[0x01, 0xBB]));
+        assert_eq!(data[26..28], [0, 0]);
+        assert_eq!(checksum(&data[..20]), 0);
+    }
+
+    #[test]
+    fn patch_matches_recompute() {
+        for (packet, at, calculated) in packets() {
+            let ipv4 = packet[0] >> 4 == 4;
+            let transport = match ipv4 {
+                true => 20,
+                false => 40,
+            };
+            let mut edits = vec![
+                // Source address and TTL or hop limit, the latter at an odd offset for IPv6
+                match ipv4 {
+                    true => (12, vec![192, 168, 1, 100]),
+                    false => (
+                        8,
+                        Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 7).octets().to_vec(),
+                    ),
+                },
+                (if ipv4 { 8 } else { 7 }, vec![3]),
+                // Transport header bytes, starting with the destination port, and the payload
+                (transport + 2, vec![0x1F, 0x90]),
+                (transport + 4, vec![0x42]),
+                (packet.len() - 1, vec![b'!']),
+            ];
+            if !ipv4 {
+                edits.push((24, Ipv6Addr::LOCALHOST.octets().to_vec()));
+            }
+            if calculated.icmp || calculated.icmpv6 {
+                // The destination port offset holds the ICMP checksum
+                edits.retain(|(offset, _)| *offset != transport + 2);
+            }
+
+            for (offset, new) in edits {
+                let mut patched = packet.clone();
+                assert!(patch(&mut patched, offset, &new), "{offset}");
+                let mut expected = patched.clone();
+                calc_checksums(&mut expected, ChecksumFlags::new());
+                assert_eq!(patched, expected, "{offset}");
+            }
+
+            // Checksum fields and out of bounds bytes are rejected
+            let mut patched = packet.clone();
+            assert!(!patch(&mut patched, at - 1, &[0, 0]));
+            assert!(!patch(&mut patched, packet.len() - 1, &[0, 0]));
+            if ipv4 {
+                assert!(!patch(&mut patched, 11, &[0]));
+            }
+            assert_eq!(patched, packet);
+        }
+    }
+
+    #[test]
+    fn flags() {
+        let all = [
+            ChecksumFlags::new().set_no_ip(),
+            ChecksumFlags::new().set_no_icmp(),
+            ChecksumFlags::new().set_no_icmpv6(),
+            ChecksumFlags::new().set_no_tcp(),
+            ChecksumFlags::new().set_no_udp(),
+        ];
+        for (packet, at, expected) in packets() {
+            for flags in all {
+                let mut data = corrupt(&packet, at);
+                let calculated = calc_checksums(&mut data, flags);
+                let skipped = |computed: bool, skip: bool| computed && !skip;
+                assert_eq!(
+                    calculated,
+                    Calculated {
+                        ip: skipped(expected.ip, flags.no_ip()),
+                        icmp: skipped(expected.icmp, flags.no_icmp()),
+                        icmpv6: skipped(expected.icmpv6, flags.no_icmpv6()),
+                        tcp: skipped(expected.tcp, flags.no_tcp()),
+                        udp: skipped(expected.udp, flags.no_udp()),
+                    }
+                );
+                // Skipped checksums keep their wrong value
+                let wrong = corrupt(&packet, at);
+                let transport =
+                    calculated.icmp || calculated.icmpv6 || calculated.tcp || calculated.udp;
+                let source = |computed| if computed { &packet } else { &wrong };
+                assert_eq!(data[at..at + 2], source(transport)[at..at + 2]);
+                assert_eq!(data[10..12], source(calculated.ip)[10..12]);
+            }
+        }
+
+        // Transport checksums of fragments are left as they are
+        let mut data = corrupt(&bytes(IPV4_TCP), 36);
+        data[6] |= 0x20;
+        let calculated = calc_checksums(&mut data, ChecksumFlags::new());
+        assert_eq!(
+            calculated,
+            Calculated {
+                ip: true,
+                ..Calculated::default()
+            }
+        );
+        assert_eq!(data[36..38], [0xBE, 0xEF]);
+    }
+}
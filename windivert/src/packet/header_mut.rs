@@ -0,0 +1,741 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use windivert_sys::{address::WINDIVERT_ADDRESS, ChecksumFlags};
+
+use super::{
+    checksum, IcmpHeader, Icmpv6Header, IpHeader, Ipv4Header, Ipv6Header, ParsedPacket, TcpHeader,
+    TransportHeader, UdpHeader, WinDivertPacket,
+};
+use crate::layer::PacketLayer;
+
+/**
+Header bytes borrowed mutably from a packet, along with a copy of their original value.
+
+When dropped, the checksums covering the changed bytes are updated incrementally with
+[`checksum::fix()`]. Checksums disabled by the flags are left as they are, and the matching
+checksum flags of the address are cleared as they are no longer valid.
+*/
+struct Tracked<'p> {
+    data: &'p mut [u8],
+    address: &'p mut WINDIVERT_ADDRESS,
+    offset: usize,
+    original: Vec<u8>,
+    flags: ChecksumFlags,
+}
+
+impl<'p> Tracked<'p> {
+    /// Tracks the header selected by `header` in the data of `packet`.
+    fn new<L: PacketLayer>(
+        packet: &'p mut WinDivertPacket<'_, L>,
+        header: impl for<'d> FnOnce(&ParsedPacket<'d>) -> Option<&'d [u8]>,
+    ) -> Option<Self> {
+        let data = packet.data.to_mut();
+        let (offset, len) = {
+            let parsed = ParsedPacket::parse(data);
+            let bytes = header(&parsed)?;
+            (
+                bytes.as_ptr() as usize - data.as_ptr() as usize,
+                bytes.len(),
+            )
+        };
+        Some(Self {
+            original: data[offset..offset + len].to_vec(),
+            data,
+            address: packet.address.as_mut(),
+            offset,
+            flags: ChecksumFlags::new(),
+        })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.data[self.offset..self.offset + self.original.len()]
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.data[self.offset..self.offset + self.original.len()]
+    }
+
+    fn set_u16(&mut self, at: usize, value: u16) {
+        self.bytes_mut()[at..at + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn set_u32(&mut self, at: usize, value: u32) {
+        self.bytes_mut()[at..at + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn set_bit(&mut self, at: usize, mask: u8, value: bool) {
+        match value {
+            true => self.bytes_mut()[at] |= mask,
+            false => self.bytes_mut()[at] &= !mask,
+        }
+    }
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        let changed = |(old, new): (&u8, &u8)| old != new;
+        let pairs = || self.original.iter().zip(self.bytes());
+        let (Some(first), Some(last)) = (pairs().position(changed), pairs().rposition(changed))
+        else {
+            return;
+        };
+        let old = &self.original[first..=last];
+        let stale = checksum::fix(self.data, self.offset + first, old, self.flags);
+        if stale.ip {
+            self.address.set_ipchecksum(false);
+        }
+        if stale.tcp {
+            self.address.set_tcpchecksum(false);
+        }
+        if stale.udp {
+            self.address.set_udpchecksum(false);
+        }
+    }
+}
+
+macro_rules! header_mut {
+    ($(#[$doc:meta])* $name:ident => $header:ident) => {
+        $(#[$doc])*
+        pub struct $name<'p> {
+            tracked: Tracked<'p>,
+        }
+
+        impl<'p> $name<'p> {
+            /// Current value of the header.
+            pub fn header(&self) -> $header<'_> {
+                $header {
+                    bytes: self.tracked.bytes(),
+                }
+            }
+
+            /**
+            Sets the checksums to leave untouched when the view is dropped. The checksum flags of
+            the address are cleared for the ones covering a change, as they are no longer valid.
+            */
+            pub fn set_checksum_flags(&mut self, flags: ChecksumFlags) -> &mut Self {
+                self.tracked.flags = flags;
+                self
+            }
+        }
+
+        impl std::fmt::Debug for $name<'_> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_struct(stringify!($name))
+                    .field("header", &self.header())
+                    .finish()
+            }
+        }
+    };
+}
+
+header_mut! {
+    /// Mutable view of an IPv4 header, returned by [`WinDivertPacket::ipv4_mut()`].
+    Ipv4HeaderMut => Ipv4Header
+}
+
+impl Ipv4HeaderMut<'_> {
+    /// Sets the type of service.
+    pub fn set_tos(&mut self, tos: u8) -> &mut Self {
+        self.tracked.bytes_mut()[1] = tos;
+        self
+    }
+
+    /// Sets the identification.
+    pub fn set_id(&mut self, id: u16) -> &mut Self {
+        self.tracked.set_u16(4, id);
+        self
+    }
+
+    /// Sets the don't fragment flag.
+    pub fn set_df(&mut self, df: bool) -> &mut Self {
+        self.tracked.set_bit(6, 0x40, df);
+        self
+    }
+
+    /// Sets the time to live.
+    pub fn set_ttl(&mut self, ttl: u8) -> &mut Self {
+        self.tracked.bytes_mut()[8] = ttl;
+        self
+    }
+
+    /// Sets the source address.
+    pub fn set_src_addr(&mut self, addr: Ipv4Addr) -> &mut Self {
+        self.tracked.bytes_mut()[12..16].copy_from_slice(&addr.octets());
+        self
+    }
+
+    /// Sets the destination address.
+    pub fn set_dst_addr(&mut self, addr: Ipv4Addr) -> &mut Self {
+        self.tracked.bytes_mut()[16..20].copy_from_slice(&addr.octets());
+        self
+    }
+}
+
+header_mut! {
+    /// Mutable view of an IPv6 fixed header, returned by [`WinDivertPacket::ipv6_mut()`].
+    Ipv6HeaderMut => Ipv6Header
+}
+
+impl Ipv6HeaderMut<'_> {
+    /// Sets the traffic class.
+    pub fn set_traffic_class(&mut self, traffic_class: u8) -> &mut Self {
+        let bytes = self.tracked.bytes_mut();
+        bytes[0] = 0x60 | traffic_class >> 4;
+        bytes[1] = (traffic_class << 4) | (bytes[1] & 0x0F);
+        self
+    }
+
+    /// Sets the flow label, only its 20 low bits are used.
+    pub fn set_flow_label(&mut self, flow_label: u32) -> &mut Self {
+        let bytes = self.tracked.bytes_mut();
+        let label = flow_label.to_be_bytes();
+        bytes[1] = (bytes[1] & 0xF0) | (label[1] & 0x0F);
+        bytes[2..4].copy_from_slice(&label[2..]);
+        self
+    }
+
+    /// Sets the hop limit.
+    pub fn set_hop_limit(&mut self, hop_limit: u8) -> &mut Self {
+        self.tracked.bytes_mut()[7] = hop_limit;
+        self
+    }
+
+    /// Sets the source address.
+    pub fn set_src_addr(&mut self, addr: Ipv6Addr) -> &mut Self {
+        self.tracked.bytes_mut()[8..24].copy_from_slice(&addr.octets());
+        self
+    }
+
+    /// Sets the destination address.
+    pub fn set_dst_addr(&mut self, addr: Ipv6Addr) -> &mut Self {
+        self.tracked.bytes_mut()[24..40].copy_from_slice(&addr.octets());
+        self
+    }
+}
+
+header_mut! {
+    /// Mutable view of a TCP header, returned by [`WinDivertPacket::tcp_mut()`].
+    TcpHeaderMut => TcpHeader
+}
+
+impl TcpHeaderMut<'_> {
+    /// Sets the source port.
+    pub fn set_src_port(&mut self, port: u16) -> &mut Self {
+        self.tracked.set_u16(0, port);
+        self
+    }
+
+    /// Sets the destination port.
+    pub fn set_dst_port(&mut self, port: u16) -> &mut Self {
+        self.tracked.set_u16(2, port);
+        self
+    }
+
+    /// Sets the sequence number.
+    pub fn set_seq_number(&mut self, seq_number: u32) -> &mut Self {
+        self.tracked.set_u32(4, seq_number);
+        self
+    }
+
+    /// Sets the acknowledgement number.
+    pub fn set_ack_number(&mut self, ack_number: u32) -> &mut Self {
+        self.tracked.set_u32(8, ack_number);
+        self
+    }
+
+    /// Sets the URG flag.
+    pub fn set_urg(&mut self, urg: bool) -> &mut Self {
+        self.tracked.set_bit(13, 0x20, urg);
+        self
+    }
+
+    /// Sets the ACK flag.
+    pub fn set_ack(&mut self, ack: bool) -> &mut Self {
+        self.tracked.set_bit(13, 0x10, ack);
+        self
+    }
+
+    /// Sets the PSH flag.
+    pub fn set_psh(&mut self, psh: bool) -> &mut Self {
+        self.tracked.set_bit(13, 0x08, psh);
+        self
+    }
+
+    /// Sets the RST flag.
+    pub fn set_rst(&mut self, rst: bool) -> &mut Self {
+        self.tracked.set_bit(13, 0x04, rst);
+        self
+    }
+
+    /// Sets the SYN flag.
+    pub fn set_syn(&mut self, syn: bool) -> &mut Self {
+        self.tracked.set_bit(13, 0x02, syn);
+        self
+    }
+
+    /// Sets the FIN flag.
+    pub fn set_fin(&mut self, fin: bool) -> &mut Self {
+        self.tracked.set_bit(13, 0x01, fin);
+        self
+    }
+
+    /// Sets the window size.
+    pub fn set_window(&mut self, window: u16) -> &mut Self {
+        self.tracked.set_u16(14, window);
+        self
+    }
+
+    /// Sets the urgent pointer.
+    pub fn set_urg_ptr(&mut self, urg_ptr: u16) -> &mut Self {
+        self.tracked.set_u16(18, urg_ptr);
+        self
+    }
+
+    /// Header options, which can be rewritten in place.
+    pub fn options_mut(&mut self) -> &mut [u8] {
+        &mut self.tracked.bytes_mut()[20..]
+    }
+}
+
+header_mut! {
+    /// Mutable view of a UDP header, returned by [`WinDivertPacket::udp_mut()`].
+    UdpHeaderMut => UdpHeader
+}
+
+impl UdpHeaderMut<'_> {
+    /// Sets the source port.
+    pub fn set_src_port(&mut self, port: u16) -> &mut Self {
+        self.tracked.set_u16(0, port);
+        self
+    }
+
+    /// Sets the destination port.
+    pub fn set_dst_port(&mut self, port: u16) -> &mut Self {
+        self.tracked.set_u16(2, port);
+        self
+    }
+}
+
+macro_rules! icmp_header_mut {
+    ($(#[$doc:meta])* $name:ident => $header:ident) => {
+        header_mut! {
+            $(#[$doc])*
+            $name => $header
+        }
+
+        impl $name<'_> {
+            /// Sets the message type.
+            pub fn set_msg_type(&mut self, msg_type: u8) -> &mut Self {
+                self.tracked.bytes_mut()[0] = msg_type;
+                self
+            }
+
+            /// Sets the message code.
+            pub fn set_msg_code(&mut self, msg_code: u8) -> &mut Self {
+                self.tracked.bytes_mut()[1] = msg_code;
+                self
+            }
+
+            /// Sets the rest of the header.
+            pub fn set_body(&mut self, body: u32) -> &mut Self {
+                self.tracked.set_u32(4, body);
+                self
+            }
+        }
+    };
+}
+
+icmp_header_mut! {
+    /// Mutable view of an ICMP header, returned by [`WinDivertPacket::icmp_mut()`].
+    IcmpHeaderMut => IcmpHeader
+}
+
+icmp_header_mut! {
+    /// Mutable view of an ICMPv6 header, returned by [`WinDivertPacket::icmpv6_mut()`].
+    Icmpv6HeaderMut => Icmpv6Header
+}
+
+/**
+Mutable header views. Each view borrows the packet, copying its data first if it is borrowed, and
+updates the IPv4 header and transport checksums covering the changed fields when dropped, without
+reading the payload. Checksums can be left untouched with [`set_checksum_flags()`], in which case
+the address no longer flags them as valid.
+
+Only fields that don't change the layout of the packet can be set, lengths and protocols are left
+to the code building the packet.
+
+[`set_checksum_flags()`]: TcpHeaderMut::set_checksum_flags
+*/
+impl<L: PacketLayer> WinDivertPacket<'_, L> {
+    /// Mutable view of the IPv4 header, `None` if the packet isn't an IPv4 packet.
+    pub fn ipv4_mut(&mut self) -> Option<Ipv4HeaderMut<'_>> {
+        let tracked = Tracked::new(self, |packet| match packet.ip? {
+            IpHeader::V4(header) => Some(header.as_bytes()),
+            IpHeader::V6(_) => None,
+        })?;
+        Some(Ipv4HeaderMut { tracked })
+    }
+
+    /// Mutable view of the IPv6 fixed header, `None` if the packet isn't an IPv6 packet.
+    pub fn ipv6_mut(&mut self) -> Option<Ipv6HeaderMut<'_>> {
+        let tracked = Tracked::new(self, |packet| match packet.ip? {
+            IpHeader::V6(header) => Some(header.as_bytes()),
+            IpHeader::V4(_) => None,
+        })?;
+        Some(Ipv6HeaderMut { tracked })
+    }
+
+    /// Mutable view of the TCP header, `None` if the packet doesn't have one.
+    pub fn tcp_mut(&mut self) -> Option<TcpHeaderMut<'_>> {
+        let tracked = Tracked::new(self, |packet| match packet.transport? {
+            TransportHeader::Tcp(header) => Some(header.as_bytes()),
+            _ => None,
+        })?;
+        Some(TcpHeaderMut { tracked })
+    }
+
+    /// Mutable view of the UDP header, `None` if the packet doesn't have one.
+    pub fn udp_mut(&mut self) -> Option<UdpHeaderMut<'_>> {
+        let tracked = Tracked::new(self, |packet| match packet.transport? {
+            TransportHeader::Udp(header) => Some(header.as_bytes()),
+            _ => None,
+        })?;
+        Some(UdpHeaderMut { tracked })
+    }
+
+    /// Mutable view of the ICMP header, `None` if the packet doesn't have one.
+    pub fn icmp_mut(&mut self) -> Option<IcmpHeaderMut<'_>> {
+        let tracked = Tracked::new(self, |packet| match packet.transport? {
+            TransportHeader::Icmp(header) => Some(header.as_bytes()),
+            _ => None,
+        })?;
+        Some(IcmpHeaderMut { tracked })
+    }
+
+    /// Mutable view of the ICMPv6 header, `None` if the packet doesn't have one.
+    pub fn icmpv6_mut(&mut self) -> Option<Icmpv6HeaderMut<'_>> {
+        let tracked = Tracked::new(self, |packet| match packet.transport? {
+            TransportHeader::Icmpv6(header) => Some(header.as_bytes()),
+            _ => None,
+        })?;
+        Some(Icmpv6HeaderMut { tracked })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::NetworkLayer;
+
+    // Packets with valid checksums, the same as the ones of the `checksum` tests
+
+    /// 10.0.0.2:49152 -> 93.184.216.34:443, "hello".
+    const IPV4_TCP: &str = "4500002d1c4640004006dea80a0000025db8d822\
+        c00001bb0badcafe123456785018faf03014000068656c6c6f";
+    /// 10.0.0.2:51000 -> 93.184.216.34:53, odd payload length.
+    const IPV4_UDP: &str =
+        "450000251c4640004011dea50a0000025db8d822c7380035001120ea123401000001616263";
+    /// 10.0.0.2 -> 93.184.216.34, echo request.
+    const IPV4_ICMP: &str = "450000201c4640004001deba0a0000025db8d822080006fa1234000170696e67";
+    /// [2001:db8::2]:49152 -> [2001:db8::1]:443, "hello".
+    const IPV6_TCP: &str = "600000000019064020010db800000000000000000000000220010db8\
+        000000000000000000000001c00001bb0badcafe123456785018faf0147c000068656c6c6f";
+    /// [2001:db8::2]:51000 -> [2001:db8::1]:53, odd payload length.
+    const IPV6_UDP: &str = "600000000011114020010db800000000000000000000000220010db8\
+        000000000000000000000001c738003500110552123401000001616263";
+    /// 2001:db8::2 -> 2001:db8::1, echo request.
+    const IPV6_ICMPV6: &str = "60000000000c3a4020010db800000000000000000000000220010db8\
+        0000000000000000000000018000333e1234000170696e67";
+
+    type Edit = fn(&mut WinDivertPacket<'_, NetworkLayer>);
+
+    fn packet(data: Vec<u8>) -> WinDivertPacket<'static, NetworkLayer> {
+        // SAFETY: The zeroed address is a valid inbound network layer address
+        let mut packet = unsafe { WinDivertPacket::<NetworkLayer>::new(data) };
+        packet.address.set_ip_checksum(true);
+        packet.address.set_tcp_checksum(true);
+        packet.address.set_udp_checksum(true);
+        packet
+    }
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Copy of the TCP packet `hex` with a 4 byte option block, and valid checksums.
+    fn with_options(hex: &str) -> Vec<u8> {
+        let mut data = bytes(hex);
+        let transport = match data[0] >> 4 {
+            4 => 20,
+            _ => 40,
+        };
+        data.splice(transport + 20..transport + 20, [1, 1, 1, 0]);
+        data[transport + 12] = 0x60;
+        match transport {
+            20 => data[3] += 4,
+            _ => data[5] += 4,
+        }
+        checksum::calc_checksums(&mut data, ChecksumFlags::new());
+        data
+    }
+
+    /// Applies each edit to its own copy of `data`, and checks it against a full recompute.
+    fn check(data: &[u8], edits: &[(&str, Edit)]) {
+        for (name, edit) in edits {
+            let mut packet = packet(data.to_vec());
+            edit(&mut packet);
+            assert_ne!(
+                packet.data[..],
+                data[..],
+                "{name} left the packet unchanged"
+            );
+
+            let mut expected = packet.data.to_vec();
+            checksum::calc_checksums(&mut expected, ChecksumFlags::new());
+            assert_eq!(packet.data[..], expected[..], "{name}");
+            assert!(packet.address.ip_checksum(), "{name}");
+            assert!(packet.address.tcp_checksum(), "{name}");
+            assert!(packet.address.udp_checksum(), "{name}");
+        }
+    }
+
+    #[test]
+    fn ipv4() {
+        let edits: &[(&str, Edit)] = &[
+            ("tos", |packet| {
+                packet.ipv4_mut().unwrap().set_tos(0xB8);
+            }),
+            ("id", |packet| {
+                packet.ipv4_mut().unwrap().set_id(0xBEEF);
+            }),
+            ("df", |packet| {
+                packet.ipv4_mut().unwrap().set_df(false);
+            }),
+            ("ttl", |packet| {
+                packet.ipv4_mut().unwrap().set_ttl(3);
+            }),
+            ("src_addr", |packet| {
+                let addr = Ipv4Addr::new(192, 168, 1, 100);
+                packet.ipv4_mut().unwrap().set_src_addr(addr);
+            }),
+            ("dst_addr", |packet| {
+                packet.ipv4_mut().unwrap().set_dst_addr(Ipv4Addr::LOCALHOST);
+            }),
+            ("all", |packet| {
+                packet
+                    .ipv4_mut()
+                    .unwrap()
+                    .set_tos(0xB8)
+                    .set_id(0xBEEF)
+                    .set_df(false)
+                    .set_ttl(3)
+                    .set_src_addr(Ipv4Addr::new(192, 168, 1, 100))
+                    .set_dst_addr(Ipv4Addr::LOCALHOST);
+            }),
+        ];
+        for hex in [IPV4_TCP, IPV4_UDP, IPV4_ICMP] {
+            check(&bytes(hex), edits);
+        }
+        assert!(packet(bytes(IPV6_TCP)).ipv4_mut().is_none());
+    }
+
+    #[test]
+    fn ipv6() {
+        let edits: &[(&str, Edit)] = &[
+            ("traffic_class", |packet| {
+                packet.ipv6_mut().unwrap().set_traffic_class(0xB8);
+            }),
+            ("flow_label", |packet| {
+                packet.ipv6_mut().unwrap().set_flow_label(0xABCDE);
+            }),
+            ("hop_limit", |packet| {
+                packet.ipv6_mut().unwrap().set_hop_limit(3);
+            }),
+            ("src_addr", |packet| {
+                let addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 7);
+                packet.ipv6_mut().unwrap().set_src_addr(addr);
+            }),
+            ("dst_addr", |packet| {
+                packet.ipv6_mut().unwrap().set_dst_addr(Ipv6Addr::LOCALHOST);
+            }),
+            ("all", |packet| {
+                packet
+                    .ipv6_mut()
+                    .unwrap()
+                    .set_traffic_class(0xB8)
+                    .set_flow_label(0xABCDE)
+                    .set_hop_limit(3)
+                    .set_src_addr(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 7))
+                    .set_dst_addr(Ipv6Addr::LOCALHOST);
+            }),
+        ];
+        for hex in [IPV6_TCP, IPV6_UDP, IPV6_ICMPV6] {
+            check(&bytes(hex), edits);
+        }
+        assert!(packet(bytes(IPV4_TCP)).ipv6_mut().is_none());
+
+        let mut packet = packet(bytes(IPV6_TCP));
+        packet
+            .ipv6_mut()
+            .unwrap()
+            .set_traffic_class(0xB8)
+            .set_flow_label(0xABCDE);
+        let header = packet.parse().ipv6().unwrap();
+        assert_eq!(header.traffic_class(), 0xB8);
+        assert_eq!(header.flow_label(), 0xABCDE);
+    }
+
+    #[test]
+    fn tcp() {
+        let edits: &[(&str, Edit)] = &[
+            ("src_port", |packet| {
+                packet.tcp_mut().unwrap().set_src_port(50000);
+            }),
+            ("dst_port", |packet| {
+                packet.tcp_mut().unwrap().set_dst_port(8080);
+            }),
+            ("seq_number", |packet| {
+                packet.tcp_mut().unwrap().set_seq_number(0xFFFF_FFF0);
+            }),
+            ("ack_number", |packet| {
+                packet.tcp_mut().unwrap().set_ack_number(7);
+            }),
+            ("urg", |packet| {
+                packet.tcp_mut().unwrap().set_urg(true);
+            }),
+            ("ack", |packet| {
+                packet.tcp_mut().unwrap().set_ack(false);
+            }),
+            ("psh", |packet| {
+                packet.tcp_mut().unwrap().set_psh(false);
+            }),
+            ("rst", |packet| {
+                packet.tcp_mut().unwrap().set_rst(true);
+            }),
+            ("syn", |packet| {
+                packet.tcp_mut().unwrap().set_syn(true);
+            }),
+            ("fin", |packet| {
+                packet.tcp_mut().unwrap().set_fin(true);
+            }),
+            ("window", |packet| {
+                packet.tcp_mut().unwrap().set_window(0x1234);
+            }),
+            ("urg_ptr", |packet| {
+                packet.tcp_mut().unwrap().set_urg_ptr(3);
+            }),
+            ("options", |packet| {
+                let mut tcp = packet.tcp_mut().unwrap();
+                if tcp.options_mut().is_empty() {
+                    tcp.set_window(0x1234);
+                } else {
+                    tcp.options_mut()[1..].copy_from_slice(&[3, 3, 7]);
+                }
+            }),
+            ("ports_and_addresses", |packet| {
+                packet
+                    .tcp_mut()
+                    .unwrap()
+                    .set_src_port(50000)
+                    .set_dst_port(8080);
+                if let Some(mut ipv4) = packet.ipv4_mut() {
+                    ipv4.set_src_addr(Ipv4Addr::LOCALHOST);
+                }
+                if let Some(mut ipv6) = packet.ipv6_mut() {
+                    ipv6.set_src_addr(Ipv6Addr::LOCALHOST);
+                }
+            }),
+        ];
+        for hex in [IPV4_TCP, IPV6_TCP] {
+            check(&bytes(hex), edits);
+            check(&with_options(hex), edits);
+        }
+        assert!(packet(bytes(IPV4_UDP)).tcp_mut().is_none());
+    }
+
+    #[test]
+    fn udp() {
+        let edits: &[(&str, Edit)] = &[
+            ("src_port", |packet| {
+                packet.udp_mut().unwrap().set_src_port(50000);
+            }),
+            ("dst_port", |packet| {
+                packet.udp_mut().unwrap().set_dst_port(5353);
+            }),
+        ];
+        for hex in [IPV4_UDP, IPV6_UDP] {
+            check(&bytes(hex), edits);
+        }
+        assert!(packet(bytes(IPV4_TCP)).udp_mut().is_none());
+    }
+
+    #[test]
+    fn icmp() {
+        let edits: &[(&str, Edit)] = &[
+            ("msg_type", |packet| {
+                packet.icmp_mut().unwrap().set_msg_type(0);
+            }),
+            ("msg_code", |packet| {
+                packet.icmp_mut().unwrap().set_msg_code(3);
+            }),
+            ("body", |packet| {
+                packet.icmp_mut().unwrap().set_body(0xDEAD_BEEF);
+            }),
+        ];
+        check(&bytes(IPV4_ICMP), edits);
+
+        let edits: &[(&str, Edit)] = &[
+            ("msg_type", |packet| {
+                packet.icmpv6_mut().unwrap().set_msg_type(129);
+            }),
+            ("msg_code", |packet| {
+                packet.icmpv6_mut().unwrap().set_msg_code(3);
+            }),
+            ("body", |packet| {
+                packet.icmpv6_mut().unwrap().set_body(0xDEAD_BEEF);
+            }),
+        ];
+        check(&bytes(IPV6_ICMPV6), edits);
+        assert!(packet(bytes(IPV6_ICMPV6)).icmp_mut().is_none());
+        assert!(packet(bytes(IPV4_ICMP)).icmpv6_mut().is_none());
+    }
+
+    #[test]
+    fn checksum_flags() {
+        let original = bytes(IPV4_TCP);
+
+        // The TCP checksum keeps its value, and is no longer flagged as valid
+        let mut packet = packet(original.clone());
+        packet
+            .tcp_mut()
+            .unwrap()
+            .set_checksum_flags(ChecksumFlags::new().set_no_tcp())
+            .set_dst_port(8080);
+        assert_eq!(packet.data[36..38], original[36..38]);
+        assert!(!packet.address.tcp_checksum());
+        assert!(packet.address.ip_checksum());
+
+        // Skipping the IPv4 checksum still updates the TCP one
+        let mut packet = self::packet(original.clone());
+        packet
+            .ipv4_mut()
+            .unwrap()
+            .set_checksum_flags(ChecksumFlags::new().set_no_ip())
+            .set_src_addr(Ipv4Addr::LOCALHOST);
+        let mut expected = packet.data.to_vec();
+        checksum::calc_checksums(&mut expected, ChecksumFlags::new().set_no_ip());
+        assert_eq!(packet.data[..], expected[..]);
+        assert_eq!(packet.data[10..12], original[10..12]);
+        assert!(!packet.address.ip_checksum());
+        assert!(packet.address.tcp_checksum());
+
+        // Unchanged views leave the packet and its flags alone
+        let mut packet = self::packet(original.clone());
+        packet.tcp_mut().unwrap().set_dst_port(443);
+        assert_eq!(packet.data[..], original[..]);
+        assert!(packet.address.tcp_checksum());
+    }
+}
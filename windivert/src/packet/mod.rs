@@ -0,0 +1,106 @@
+use windivert_sys::ChecksumFlags;
+
+use crate::{address::WinDivertAddress, layer};
+
+use std::{borrow::Cow, fmt::Debug};
+
+mod builder;
+pub mod checksum;
+mod header_mut;
+mod parse;
+mod reassembly;
+pub mod reject;
+mod segment;
+
+pub use builder::*;
+pub use header_mut::*;
+pub use parse::*;
+pub use reassembly::*;
+pub use segment::*;
+
+/// Raw captured packet
+#[derive(Debug, Clone)]
+pub struct WinDivertPacket<'a, L: layer::WinDivertLayerTrait> {
+    /// Address data
+    pub address: WinDivertAddress<L>,
+    /// Raw captured data
+    pub data: Cow<'a, [u8]>,
+}
+
+impl<'a> WinDivertPacket<'a, layer::NetworkLayer> {
+    /// Create a new network packet from a raw buffer
+    /// # Safety
+    /// `address` is zeroed, user must fill it with correct data before sending.
+    pub unsafe fn new(data: Vec<u8>) -> Self {
+        Self {
+            address: WinDivertAddress::<layer::NetworkLayer>::new(),
+            data: Cow::from(data),
+        }
+    }
+
+    /// Parses the IP, extension and transport headers of the packet without copying them.
+    pub fn parse(&self) -> ParsedPacket<'_> {
+        ParsedPacket::parse(&self.data)
+    }
+
+    /**
+    Recalculates the checksums of the packet with [`checksum::calc_checksums()`], and marks the
+    recalculated IPv4, TCP and UDP checksums as valid in the address. Borrowed data is copied first.
+    */
+    pub fn recalculate_checksums(&mut self, flags: ChecksumFlags) {
+        let calculated = checksum::calc_checksums(self.data.to_mut(), flags);
+        if calculated.ip {
+            self.address.set_ip_checksum(true);
+        }
+        if calculated.tcp {
+            self.address.set_tcp_checksum(true);
+        }
+        if calculated.udp {
+            self.address.set_udp_checksum(true);
+        }
+    }
+}
+
+impl<'a> WinDivertPacket<'a, layer::ForwardLayer> {
+    /// Create a new network forward packet from a raw buffer
+    /// # Safety
+    /// `address` is zeroed, user must fill it with correct data before sending.
+    pub unsafe fn new(data: Vec<u8>) -> Self {
+        Self {
+            address: WinDivertAddress::<layer::ForwardLayer>::new(),
+            data: Cow::from(data),
+        }
+    }
+
+    /// Parses the IP, extension and transport headers of the packet without copying them.
+    pub fn parse(&self) -> ParsedPacket<'_> {
+        ParsedPacket::parse(&self.data)
+    }
+
+    /**
+    Recalculates the checksums of the packet with [`checksum::calc_checksums()`], and marks the
+    recalculated IPv4, TCP and UDP checksums as valid in the address. Borrowed data is copied first.
+    */
+    pub fn recalculate_checksums(&mut self, flags: ChecksumFlags) {
+        let calculated = checksum::calc_checksums(self.data.to_mut(), flags);
+        if calculated.ip {
+            self.address.set_ip_checksum(true);
+        }
+        if calculated.tcp {
+            self.address.set_tcp_checksum(true);
+        }
+        if calculated.udp {
+            self.address.set_udp_checksum(true);
+        }
+    }
+}
+
+impl<'a, L: layer::WinDivertLayerTrait> WinDivertPacket<'a, L> {
+    /// Create an owned packet from a borrowed packet
+    pub fn into_owned(self) -> WinDivertPacket<'static, L> {
+        WinDivertPacket {
+            address: self.address,
+            data: self.data.into_owned().into(),
+        }
+    }
+}
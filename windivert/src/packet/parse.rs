@@ -0,0 +1,827 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+fn be16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn be32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/**
+Headers and payload of a network packet, borrowed from the packet data.
+
+This is a pure Rust equivalent of
+[`WinDivertHelperParsePacket()`](fn@windivert_sys::WinDivertHelperParsePacket): headers are
+located the same way the filter engine does it, so [`ParsedPacket::tcp()`] is set exactly when the
+`tcp` filter field is true for the packet. Parsing never fails, headers that can't be read are left
+out and [`truncated`](ParsedPacket::truncated) is set when the data ends before the length announced
+by the headers.
+*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ParsedPacket<'a> {
+    /// IPv4 or IPv6 header, `None` if the data doesn't start with a valid IP header.
+    pub ip: Option<IpHeader<'a>>,
+    /// IPv6 extension headers located between the IPv6 header and the transport header.
+    pub extensions: ExtensionHeaders<'a>,
+    /**
+    Protocol of the data following the IP and extension headers, that is, the protocol of the
+    transport header if there is one. `None` if there is no IP header.
+    */
+    pub protocol: Option<u8>,
+    /// ICMP, ICMPv6, TCP or UDP header.
+    pub transport: Option<TransportHeader<'a>>,
+    /**
+    Data following the last header read, up to the length of the IP packet. Trailing bytes past
+    the IP packet length are not part of the payload.
+    */
+    pub payload: &'a [u8],
+    /// Set when the data is shorter than the lengths found in the headers.
+    pub truncated: bool,
+}
+
+impl<'a> ParsedPacket<'a> {
+    /// Parses the headers of an IP packet.
+    pub fn parse(data: &'a [u8]) -> Self {
+        let mut packet = Self {
+            ip: None,
+            extensions: ExtensionHeaders::default(),
+            protocol: None,
+            transport: None,
+            payload: &[],
+            truncated: false,
+        };
+
+        let (protocol, rest) = match data.first().map(|byte| byte >> 4) {
+            Some(4) if data.len() >= 20 => {
+                let header_len = (data[0] & 0x0F) as usize * 4;
+                let total_len = be16(data, 2) as usize;
+                if header_len < 20 || header_len > total_len {
+                    return packet;
+                }
+                packet.truncated = total_len > data.len();
+                if header_len > data.len() {
+                    return packet;
+                }
+                let header = Ipv4Header::new(&data[..header_len]);
+                packet.ip = Some(IpHeader::V4(header));
+                packet.protocol = Some(header.protocol());
+                packet.payload = &data[header_len..total_len.min(data.len())];
+                // Non first fragments don't carry the transport header
+                if header.fragment_offset() != 0 {
+                    return packet;
+                }
+                (header.protocol(), packet.payload)
+            }
+            Some(6) if data.len() >= 40 => {
+                let header = Ipv6Header::new(&data[..40]);
+                let total_len = 40 + header.length() as usize;
+                packet.truncated = total_len > data.len();
+                packet.ip = Some(IpHeader::V6(header));
+                let body = &data[40..total_len.min(data.len())];
+
+                let mut next = header.next_header();
+                let mut rest = body;
+                let mut first_fragment = true;
+                while is_extension(next) {
+                    let Some(len) = extension_length(next, rest) else {
+                        packet.truncated = true;
+                        break;
+                    };
+                    if next == 44 {
+                        first_fragment = be16(rest, 2) & 0xFFF8 == 0;
+                    }
+                    next = rest[0];
+                    rest = &rest[len..];
+                    if !first_fragment {
+                        break;
+                    }
+                }
+                packet.extensions = ExtensionHeaders {
+                    next: header.next_header(),
+                    bytes: &body[..body.len() - rest.len()],
+                };
+                packet.protocol = Some(next);
+                packet.payload = rest;
+                if is_extension(next) || !first_fragment {
+                    return packet;
+                }
+                (next, rest)
+            }
+            Some(4 | 6) => {
+                packet.truncated = true;
+                return packet;
+            }
+            _ => return packet,
+        };
+
+        let ipv4 = matches!(packet.ip, Some(IpHeader::V4(_)));
+        let header_len = match protocol {
+            // Invalid TCP header length
+            6 if rest.len() >= 20 && rest[12] >> 4 < 5 => return packet,
+            6 if rest.len() >= 20 => (rest[12] >> 4) as usize * 4,
+            6 => 20,
+            17 => 8,
+            1 if ipv4 => 8,
+            58 if !ipv4 => 8,
+            _ => return packet,
+        };
+        if header_len > rest.len() {
+            packet.truncated = true;
+            return packet;
+        }
+        let (header, payload) = rest.split_at(header_len);
+        packet.transport = Some(match protocol {
+            6 => TransportHeader::Tcp(TcpHeader { bytes: header }),
+            17 => TransportHeader::Udp(UdpHeader { bytes: header }),
+            1 => TransportHeader::Icmp(IcmpHeader { bytes: header }),
+            _ => TransportHeader::Icmpv6(Icmpv6Header { bytes: header }),
+        });
+        packet.payload = payload;
+        packet
+    }
+
+    /// IPv4 header, if any.
+    pub fn ipv4(&self) -> Option<Ipv4Header<'a>> {
+        match self.ip {
+            Some(IpHeader::V4(header)) => Some(header),
+            _ => None,
+        }
+    }
+
+    /// IPv6 header, if any.
+    pub fn ipv6(&self) -> Option<Ipv6Header<'a>> {
+        match self.ip {
+            Some(IpHeader::V6(header)) => Some(header),
+            _ => None,
+        }
+    }
+
+    /// ICMP header, if any.
+    pub fn icmp(&self) -> Option<IcmpHeader<'a>> {
+        match self.transport {
+            Some(TransportHeader::Icmp(header)) => Some(header),
+            _ => None,
+        }
+    }
+
+    /// ICMPv6 header, if any.
+    pub fn icmpv6(&self) -> Option<Icmpv6Header<'a>> {
+        match self.transport {
+            Some(TransportHeader::Icmpv6(header)) => Some(header),
+            _ => None,
+        }
+    }
+
+    /// TCP header, if any.
+    pub fn tcp(&self) -> Option<TcpHeader<'a>> {
+        match self.transport {
+            Some(TransportHeader::Tcp(header)) => Some(header),
+            _ => None,
+        }
+    }
+
+    /// UDP header, if any.
+    pub fn udp(&self) -> Option<UdpHeader<'a>> {
+        match self.transport {
+            Some(TransportHeader::Udp(header)) => Some(header),
+            _ => None,
+        }
+    }
+
+    /**
+    Returns `true` if the packet is a fragment of a larger datagram: an IPv4 packet with the MF flag
+    or a fragment offset, or an IPv6 packet with a fragment extension header.
+    */
+    pub fn is_fragment(&self) -> bool {
+        match self.ip {
+            Some(IpHeader::V4(header)) => header.mf() || header.fragment_offset() != 0,
+            Some(IpHeader::V6(_)) => {
+                let mut extensions = self.extensions;
+                extensions.any(|header| header.kind() == 44)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Returns `true` for the IPv6 extension headers skipped to reach the transport header.
+fn is_extension(next: u8) -> bool {
+    matches!(next, 0 | 43 | 44 | 51 | 60)
+}
+
+/// Length of the extension header of type `next` at the start of `bytes`, if it fits.
+fn extension_length(next: u8, bytes: &[u8]) -> Option<usize> {
+    let len = match next {
+        _ if bytes.len() < 8 => return None,
+        // Authentication header length is in 32 bit words, not counting the first two
+        51 => (bytes[1] as usize + 2) * 4,
+        44 => 8,
+        _ => (bytes[1] as usize + 1) * 8,
+    };
+    (len <= bytes.len()).then_some(len)
+}
+
+/// IP header of a [`ParsedPacket`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IpHeader<'a> {
+    /// IPv4 header.
+    V4(Ipv4Header<'a>),
+    /// IPv6 header, without extension headers.
+    V6(Ipv6Header<'a>),
+}
+
+impl<'a> IpHeader<'a> {
+    /// Raw header bytes.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        match self {
+            IpHeader::V4(header) => header.as_bytes(),
+            IpHeader::V6(header) => header.as_bytes(),
+        }
+    }
+
+    /// Source address.
+    pub fn src_addr(&self) -> IpAddr {
+        match self {
+            IpHeader::V4(header) => header.src_addr().into(),
+            IpHeader::V6(header) => header.src_addr().into(),
+        }
+    }
+
+    /// Destination address.
+    pub fn dst_addr(&self) -> IpAddr {
+        match self {
+            IpHeader::V4(header) => header.dst_addr().into(),
+            IpHeader::V6(header) => header.dst_addr().into(),
+        }
+    }
+
+    /// Length of the whole IP packet in bytes, as announced by the header.
+    pub fn packet_length(&self) -> usize {
+        match self {
+            IpHeader::V4(header) => header.length() as usize,
+            IpHeader::V6(header) => 40 + header.length() as usize,
+        }
+    }
+}
+
+/// IPv4 header, including its options.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Ipv4Header<'a> {
+    pub(super) bytes: &'a [u8],
+}
+
+impl<'a> Ipv4Header<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    /// Raw header bytes.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// IP version, always `4`.
+    pub fn version(&self) -> u8 {
+        self.bytes[0] >> 4
+    }
+
+    /// Header length in 32 bit words.
+    pub fn header_length(&self) -> u8 {
+        self.bytes[0] & 0x0F
+    }
+
+    /// Type of service.
+    pub fn tos(&self) -> u8 {
+        self.bytes[1]
+    }
+
+    /// Total length of the packet in bytes.
+    pub fn length(&self) -> u16 {
+        be16(self.bytes, 2)
+    }
+
+    /// Identification.
+    pub fn id(&self) -> u16 {
+        be16(self.bytes, 4)
+    }
+
+    /// Don't fragment flag.
+    pub fn df(&self) -> bool {
+        self.bytes[6] & 0x40 != 0
+    }
+
+    /// More fragments flag.
+    pub fn mf(&self) -> bool {
+        self.bytes[6] & 0x20 != 0
+    }
+
+    /// Fragment offset in units of 8 bytes.
+    pub fn fragment_offset(&self) -> u16 {
+        be16(self.bytes, 6) & 0x1FFF
+    }
+
+    /// Time to live.
+    pub fn ttl(&self) -> u8 {
+        self.bytes[8]
+    }
+
+    /// Protocol of the data following the header.
+    pub fn protocol(&self) -> u8 {
+        self.bytes[9]
+    }
+
+    /// Header checksum.
+    pub fn checksum(&self) -> u16 {
+        be16(self.bytes, 10)
+    }
+
+    /// Source address.
+    pub fn src_addr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(be32(self.bytes, 12))
+    }
+
+    /// Destination address.
+    pub fn dst_addr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(be32(self.bytes, 16))
+    }
+
+    /// Header options.
+    pub fn options(&self) -> &'a [u8] {
+        &self.bytes[20..]
+    }
+}
+
+/// IPv6 fixed header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Ipv6Header<'a> {
+    pub(super) bytes: &'a [u8],
+}
+
+impl<'a> Ipv6Header<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    /// Raw header bytes.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// IP version, always `6`.
+    pub fn version(&self) -> u8 {
+        self.bytes[0] >> 4
+    }
+
+    /// Traffic class.
+    pub fn traffic_class(&self) -> u8 {
+        self.bytes[0] << 4 | self.bytes[1] >> 4
+    }
+
+    /// Flow label.
+    pub fn flow_label(&self) -> u32 {
+        be32(self.bytes, 0) & 0x000F_FFFF
+    }
+
+    /// Payload length in bytes, including extension headers.
+    pub fn length(&self) -> u16 {
+        be16(self.bytes, 4)
+    }
+
+    /// Type of the header following the fixed header.
+    pub fn next_header(&self) -> u8 {
+        self.bytes[6]
+    }
+
+    /// Hop limit.
+    pub fn hop_limit(&self) -> u8 {
+        self.bytes[7]
+    }
+
+    /// Source address.
+    pub fn src_addr(&self) -> Ipv6Addr {
+        let mut addr = [0; 16];
+        addr.copy_from_slice(&self.bytes[8..24]);
+        Ipv6Addr::from(addr)
+    }
+
+    /// Destination address.
+    pub fn dst_addr(&self) -> Ipv6Addr {
+        let mut addr = [0; 16];
+        addr.copy_from_slice(&self.bytes[24..40]);
+        Ipv6Addr::from(addr)
+    }
+}
+
+/**
+Iterator over the IPv6 extension headers of a [`ParsedPacket`].
+
+Hop-by-hop options, routing, fragment, authentication and destination options headers are
+recognized, like in the filter engine.
+*/
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ExtensionHeaders<'a> {
+    next: u8,
+    bytes: &'a [u8],
+}
+
+impl<'a> ExtensionHeaders<'a> {
+    /// Raw bytes of the remaining extension headers.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+}
+
+impl<'a> Iterator for ExtensionHeaders<'a> {
+    type Item = ExtensionHeader<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = extension_length(self.next, self.bytes)?;
+        let (bytes, rest) = self.bytes.split_at(len);
+        let header = ExtensionHeader {
+            kind: self.next,
+            bytes,
+        };
+        self.next = bytes[0];
+        self.bytes = rest;
+        Some(header)
+    }
+}
+
+/// IPv6 extension header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ExtensionHeader<'a> {
+    kind: u8,
+    bytes: &'a [u8],
+}
+
+impl<'a> ExtensionHeader<'a> {
+    /// Protocol number identifying the header, e.g. `44` for a fragment header.
+    pub fn kind(&self) -> u8 {
+        self.kind
+    }
+
+    /// Raw header bytes.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Type of the header following this one.
+    pub fn next_header(&self) -> u8 {
+        self.bytes[0]
+    }
+}
+
+/// Transport header of a [`ParsedPacket`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransportHeader<'a> {
+    /// ICMP header, only found in IPv4 packets.
+    Icmp(IcmpHeader<'a>),
+    /// ICMPv6 header, only found in IPv6 packets.
+    Icmpv6(Icmpv6Header<'a>),
+    /// TCP header.
+    Tcp(TcpHeader<'a>),
+    /// UDP header.
+    Udp(UdpHeader<'a>),
+}
+
+impl<'a> TransportHeader<'a> {
+    /// Raw header bytes.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        match self {
+            TransportHeader::Icmp(header) => header.as_bytes(),
+            TransportHeader::Icmpv6(header) => header.as_bytes(),
+            TransportHeader::Tcp(header) => header.as_bytes(),
+            TransportHeader::Udp(header) => header.as_bytes(),
+        }
+    }
+}
+
+macro_rules! icmp_header {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        pub struct $name<'a> {
+            pub(super) bytes: &'a [u8],
+        }
+
+        impl<'a> $name<'a> {
+            /// Raw header bytes.
+            pub fn as_bytes(&self) -> &'a [u8] {
+                self.bytes
+            }
+
+            /// Message type.
+            pub fn msg_type(&self) -> u8 {
+                self.bytes[0]
+            }
+
+            /// Message code.
+            pub fn msg_code(&self) -> u8 {
+                self.bytes[1]
+            }
+
+            /// Checksum.
+            pub fn checksum(&self) -> u16 {
+                be16(self.bytes, 2)
+            }
+
+            /// Rest of the header, whose meaning depends on the message type.
+            pub fn body(&self) -> u32 {
+                be32(self.bytes, 4)
+            }
+        }
+    };
+}
+
+icmp_header! {
+    /// ICMP header.
+    IcmpHeader
+}
+
+icmp_header! {
+    /// ICMPv6 header.
+    Icmpv6Header
+}
+
+/// TCP header, including its options.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TcpHeader<'a> {
+    pub(super) bytes: &'a [u8],
+}
+
+impl<'a> TcpHeader<'a> {
+    /// Raw header bytes.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Source port.
+    pub fn src_port(&self) -> u16 {
+        be16(self.bytes, 0)
+    }
+
+    /// Destination port.
+    pub fn dst_port(&self) -> u16 {
+        be16(self.bytes, 2)
+    }
+
+    /// Sequence number.
+    pub fn seq_number(&self) -> u32 {
+        be32(self.bytes, 4)
+    }
+
+    /// Acknowledgement number.
+    pub fn ack_number(&self) -> u32 {
+        be32(self.bytes, 8)
+    }
+
+    /// Header length in 32 bit words.
+    pub fn header_length(&self) -> u8 {
+        self.bytes[12] >> 4
+    }
+
+    /// URG flag.
+    pub fn urg(&self) -> bool {
+        self.bytes[13] & 0x20 != 0
+    }
+
+    /// ACK flag.
+    pub fn ack(&self) -> bool {
+        self.bytes[13] & 0x10 != 0
+    }
+
+    /// PSH flag.
+    pub fn psh(&self) -> bool {
+        self.bytes[13] & 0x08 != 0
+    }
+
+    /// RST flag.
+    pub fn rst(&self) -> bool {
+        self.bytes[13] & 0x04 != 0
+    }
+
+    /// SYN flag.
+    pub fn syn(&self) -> bool {
+        self.bytes[13] & 0x02 != 0
+    }
+
+    /// FIN flag.
+    pub fn fin(&self) -> bool {
+        self.bytes[13] & 0x01 != 0
+    }
+
+    /// Window size.
+    pub fn window(&self) -> u16 {
+        be16(self.bytes, 14)
+    }
+
+    /// Checksum.
+    pub fn checksum(&self) -> u16 {
+        be16(self.bytes, 16)
+    }
+
+    /// Urgent pointer.
+    pub fn urg_ptr(&self) -> u16 {
+        be16(self.bytes, 18)
+    }
+
+    /// Header options.
+    pub fn options(&self) -> &'a [u8] {
+        &self.bytes[20..]
+    }
+}
+
+/// UDP header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UdpHeader<'a> {
+    pub(super) bytes: &'a [u8],
+}
+
+impl<'a> UdpHeader<'a> {
+    /// Raw header bytes.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Source port.
+    pub fn src_port(&self) -> u16 {
+        be16(self.bytes, 0)
+    }
+
+    /// Destination port.
+    pub fn dst_port(&self) -> u16 {
+        be16(self.bytes, 2)
+    }
+
+    /// Length of the header and payload in bytes.
+    pub fn length(&self) -> u16 {
+        be16(self.bytes, 4)
+    }
+
+    /// Checksum.
+    pub fn checksum(&self) -> u16 {
+        be16(self.bytes, 6)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// TCP header with a 4 byte MSS option, from port 1234 to port 443, followed by `payload`.
+    fn tcp(payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x04, 0xD2, 0x01, 0xBB, 0, 0, 0, 1, 0, 0, 0, 0, 0x60, 0x12];
+        data.extend_from_slice(&[0xFF, 0xFF, 0, 0, 0, 0, 2, 4, 5, 0xB4]);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn ipv4(protocol: u8, fragment: u16, body: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x45, 0, 0, 0, 0, 1];
+        data.extend_from_slice(&fragment.to_be_bytes());
+        data.extend_from_slice(&[64, protocol, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        data.extend_from_slice(body);
+        let length = data.len() as u16;
+        data[2..4].copy_from_slice(&length.to_be_bytes());
+        data
+    }
+
+    fn ipv6(next: u8, body: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x60, 0, 0, 0];
+        data.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        data.extend_from_slice(&[next, 64]);
+        data.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        data.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        data.extend_from_slice(body);
+        data
+    }
+
+    /// Hop-by-hop options, routing and destination options headers in front of `transport`.
+    fn extensions(transport: &[u8]) -> Vec<u8> {
+        let mut data = vec![43, 0, 1, 4, 0, 0, 0, 0];
+        data.extend_from_slice(&[60, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&[6, 0, 1, 4, 0, 0, 0, 0]);
+        data.extend_from_slice(transport);
+        data
+    }
+
+    fn kinds(packet: &ParsedPacket) -> Vec<u8> {
+        packet.extensions.map(|header| header.kind()).collect()
+    }
+
+    #[test]
+    fn ipv6_extensions() {
+        let data = ipv6(0, &extensions(&tcp(b"data")));
+        let packet = ParsedPacket::parse(&data);
+        assert_eq!(kinds(&packet), [0, 43, 60]);
+        assert_eq!(packet.extensions.as_bytes(), &data[40..72]);
+        assert_eq!(packet.protocol, Some(6));
+        let tcp = packet.tcp().unwrap();
+        assert_eq!((tcp.src_port(), tcp.dst_port()), (1234, 443));
+        assert_eq!(tcp.options(), [2, 4, 5, 0xB4]);
+        assert_eq!(packet.payload, b"data");
+        assert!(!packet.truncated && !packet.is_fragment());
+
+        // Authentication header length is counted in 32 bit words
+        let mut body = vec![17, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        body.extend_from_slice(&[0, 53, 0, 53, 0, 8, 0, 0]);
+        let data = ipv6(51, &body);
+        let packet = ParsedPacket::parse(&data);
+        assert_eq!(
+            packet
+                .extensions
+                .map(|header| header.as_bytes().len())
+                .collect::<Vec<_>>(),
+            [16]
+        );
+        assert_eq!(packet.udp().unwrap().dst_port(), 53);
+
+        // No next header
+        let data = ipv6(60, &[59, 0, 1, 4, 0, 0, 0, 0, 1, 2]);
+        let packet = ParsedPacket::parse(&data);
+        assert_eq!(kinds(&packet), [60]);
+        assert_eq!((packet.protocol, packet.transport), (Some(59), None));
+        assert_eq!(packet.payload, [1, 2]);
+
+        // Extension header longer than the packet
+        let data = ipv6(0, &[6, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let packet = ParsedPacket::parse(&data);
+        assert!(packet.truncated);
+        assert_eq!(kinds(&packet), []);
+        assert_eq!((packet.protocol, packet.transport), (Some(0), None));
+    }
+
+    #[test]
+    fn truncated() {
+        for full in [
+            ipv6(0, &extensions(&tcp(b"data"))),
+            ipv4(6, 0, &tcp(b"data")),
+        ] {
+            let tcp_end = full.len() - 4;
+            let ip_len = match full[0] >> 4 {
+                4 => 20,
+                _ => 40,
+            };
+            for len in 0..=full.len() {
+                let packet = ParsedPacket::parse(&full[..len]);
+                assert_eq!(packet.truncated, len > 0 && len < full.len(), "{len}");
+                assert_eq!(packet.ip.is_some(), len >= ip_len, "{len}");
+                assert_eq!(packet.tcp().is_some(), len >= tcp_end, "{len}");
+                if let Some(tcp) = packet.tcp() {
+                    assert_eq!(packet.payload, &full[tcp_end..len]);
+                    assert_eq!(tcp.dst_port(), 443);
+                }
+            }
+
+            // Bytes past the IP packet length aren't payload
+            let mut padded = full.clone();
+            padded.extend_from_slice(&[0; 6]);
+            let packet = ParsedPacket::parse(&padded);
+            assert_eq!(packet.payload, b"data");
+            assert!(!packet.truncated);
+        }
+
+        // The TCP header length can't be less than 5 words
+        let mut data = ipv4(6, 0, &tcp(&[]));
+        data[32] = 0x40;
+        let packet = ParsedPacket::parse(&data);
+        assert_eq!((packet.protocol, packet.transport), (Some(6), None));
+    }
+
+    #[test]
+    fn fragments() {
+        // IPv4 first fragment, with the MF flag, and last fragment at offset 185 * 8
+        let data = ipv4(6, 0x2000, &tcp(b"data"));
+        let packet = ParsedPacket::parse(&data);
+        assert!(packet.is_fragment());
+        assert_eq!(packet.tcp().unwrap().dst_port(), 443);
+        let data = ipv4(6, 185, b"data");
+        let packet = ParsedPacket::parse(&data);
+        assert!(packet.is_fragment());
+        assert_eq!((packet.protocol, packet.transport), (Some(6), None));
+        assert_eq!(packet.payload, b"data");
+        assert!(!ParsedPacket::parse(&ipv4(6, 0x4000, &tcp(&[]))).is_fragment());
+
+        // IPv6 first fragment, the transport header follows the fragment header
+        let mut body = vec![6, 0, 0, 1, 0, 0, 0, 1];
+        body.extend(tcp(b"data"));
+        let data = ipv6(44, &body);
+        let packet = ParsedPacket::parse(&data);
+        assert!(packet.is_fragment());
+        assert_eq!(kinds(&packet), [44]);
+        assert_eq!(packet.tcp().unwrap().dst_port(), 443);
+        assert_eq!(packet.payload, b"data");
+
+        // Later fragments stop at the fragment header, even if more extension headers follow
+        let data = ipv6(
+            0,
+            &[44, 0, 1, 4, 0, 0, 0, 0, 60, 0, 0, 8, 0, 0, 0, 1, 1, 2, 3],
+        );
+        let packet = ParsedPacket::parse(&data);
+        assert!(packet.is_fragment());
+        assert_eq!(kinds(&packet), [0, 44]);
+        assert_eq!((packet.protocol, packet.transport), (Some(60), None));
+        assert_eq!(packet.payload, [1, 2, 3]);
+    }
+}
@@ -0,0 +1,814 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use super::{checksum, IpHeader, ParsedPacket, WinDivertPacket};
+use crate::{address::WinDivertAddress, layer::PacketLayer};
+
+/// Largest IP payload, the IPv4 total length and IPv6 payload length being 16 bit fields.
+const PAYLOAD_MAX: usize = u16::MAX as usize;
+
+/**
+Handling of fragments overlapping data already received for their datagram.
+
+Overlaps carrying the same bytes as the data already received, such as duplicated fragments, are
+always accepted.
+*/
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// Drops the datagram, along with the fragments of it received later, as RFC 5722 requires
+    /// for IPv6.
+    #[default]
+    Drop,
+    /// Keeps the data received first.
+    First,
+    /// Replaces the data received first with the data of the overlapping fragment.
+    Last,
+}
+
+/// Counters of a [`Reassembler`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ReassemblyStats {
+    /// Datagrams reassembled from their fragments.
+    pub reassembled: u64,
+    /// Datagrams dropped because their fragments didn't all arrive in time.
+    pub timed_out: u64,
+    /// Datagrams dropped to stay within the memory and datagram limits.
+    pub evicted: u64,
+    /// Datagrams dropped because of overlapping fragments, with [`OverlapPolicy::Drop`].
+    pub overlapping: u64,
+    /// Fragments dropped because they are truncated or inconsistent with their datagram.
+    pub malformed: u64,
+}
+
+/// Identifies the datagram of a fragment, the protocol being `0` for IPv6.
+type Key = (IpAddr, IpAddr, u8, u32);
+
+/// Fragment of an IPv4 or IPv6 datagram.
+struct Fragment<'a> {
+    key: Key,
+    /// IPv4 header, or IPv6 header and extension headers preceding the fragment header.
+    header: &'a [u8],
+    /// Offset in `header` of the next header field pointing to the IPv6 fragment header.
+    next_header_at: Option<usize>,
+    /// Next header field of the IPv6 fragment header.
+    protocol: u8,
+    offset: usize,
+    more: bool,
+    data: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+    /// Reads a fragment, `None` if it is truncated or not a valid fragment.
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        let packet = ParsedPacket::parse(data);
+        let ip = packet.ip?;
+        if packet.truncated || ip.packet_length() > data.len() {
+            return None;
+        }
+        let fragment = match ip {
+            IpHeader::V4(header) => {
+                let header_len = header.as_bytes().len();
+                Self {
+                    key: (
+                        ip.src_addr(),
+                        ip.dst_addr(),
+                        header.protocol(),
+                        header.id() as u32,
+                    ),
+                    header: header.as_bytes(),
+                    next_header_at: None,
+                    protocol: header.protocol(),
+                    offset: header.fragment_offset() as usize * 8,
+                    more: header.mf(),
+                    data: &data[header_len..ip.packet_length()],
+                }
+            }
+            IpHeader::V6(_) => {
+                let mut next_header_at = 6;
+                let mut extensions = packet.extensions;
+                let fragment = loop {
+                    let extension = extensions.next()?;
+                    let at = extension.as_bytes().as_ptr() as usize - data.as_ptr() as usize;
+                    if extension.kind() == 44 {
+                        break at;
+                    }
+                    next_header_at = at;
+                };
+                let bytes = &data[fragment..fragment + 8];
+                Self {
+                    key: (
+                        ip.src_addr(),
+                        ip.dst_addr(),
+                        0,
+                        u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+                    ),
+                    header: &data[..fragment],
+                    next_header_at: Some(next_header_at),
+                    protocol: bytes[0],
+                    offset: (u16::from_be_bytes([bytes[2], bytes[3]]) & 0xFFF8) as usize,
+                    more: bytes[3] & 1 != 0,
+                    data: &data[fragment + 8..ip.packet_length()],
+                }
+            }
+        };
+        let end = fragment.offset + fragment.data.len();
+        // Every fragment but the last one carries a multiple of 8 bytes
+        let aligned = !fragment.more || fragment.data.len() % 8 == 0;
+        let max = match fragment.next_header_at {
+            Some(_) => PAYLOAD_MAX + 40,
+            None => PAYLOAD_MAX,
+        };
+        match aligned && end + fragment.header.len() <= max {
+            true => Some(fragment),
+            false => None,
+        }
+    }
+}
+
+/// Datagram being reassembled.
+#[derive(Debug)]
+struct Datagram<L: PacketLayer> {
+    created: Instant,
+    /// Address of the first fragment received, replaced by the one of the first fragment.
+    address: WinDivertAddress<L>,
+    /// Header of the first fragment, empty until it is received.
+    header: Vec<u8>,
+    next_header_at: Option<usize>,
+    protocol: u8,
+    data: Vec<u8>,
+    /// Sorted and disjoint ranges of `data` received so far.
+    ranges: Vec<(usize, usize)>,
+    /// Length of the data, known once the last fragment is received.
+    length: Option<usize>,
+    /// Set once the datagram is dropped, until it times out.
+    dropped: bool,
+}
+
+impl<L: PacketLayer> Datagram<L> {
+    fn memory(&self) -> usize {
+        self.header.len() + self.data.len()
+    }
+
+    /// Adds the data of `fragment`, returning `false` if it overlaps data it doesn't match and
+    /// the datagram must be dropped.
+    fn insert(&mut self, fragment: &Fragment, overlap: OverlapPolicy) -> bool {
+        let (start, end) = (fragment.offset, fragment.offset + fragment.data.len());
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+
+        let mut gaps = Vec::new();
+        let mut position = start;
+        for &(first, last) in &self.ranges {
+            if last <= position || first >= end {
+                continue;
+            }
+            if first > position {
+                gaps.push((position, first));
+            }
+            let (first, last) = (first.max(start), last.min(end));
+            if self.data[first..last] != fragment.data[first - start..last - start] {
+                match overlap {
+                    OverlapPolicy::Drop => return false,
+                    OverlapPolicy::First => {}
+                    OverlapPolicy::Last => gaps.push((first, last)),
+                }
+            }
+            position = position.max(last);
+        }
+        if position < end {
+            gaps.push((position, end));
+        }
+        for (first, last) in gaps {
+            self.data[first..last].copy_from_slice(&fragment.data[first - start..last - start]);
+        }
+
+        let index = self.ranges.partition_point(|&(first, _)| first < start);
+        self.ranges.insert(index, (start, end));
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.ranges.len());
+        for &(first, last) in &self.ranges {
+            match merged.last_mut() {
+                Some((_, end)) if first <= *end => *end = (*end).max(last),
+                _ => merged.push((first, last)),
+            }
+        }
+        self.ranges = merged;
+        true
+    }
+
+    /// Whole datagram, once every fragment is received.
+    fn assemble(&self) -> Option<Vec<u8>> {
+        let length = self.length?;
+        if self.header.is_empty() || self.ranges != [(0, length)] {
+            return None;
+        }
+        let mut packet = Vec::with_capacity(self.header.len() + length);
+        packet.extend_from_slice(&self.header);
+        packet.extend_from_slice(&self.data[..length]);
+        match self.next_header_at {
+            None => {
+                let total = u16::try_from(packet.len()).ok()?;
+                packet[2..4].copy_from_slice(&total.to_be_bytes());
+                // Keeps the DF flag, clears the MF flag and the fragment offset
+                packet[6] &= 0x40;
+                packet[7] = 0;
+                let header_len = (packet[0] & 0x0F) as usize * 4;
+                packet[10..12].fill(0);
+                let sum = checksum::checksum(&packet[..header_len]);
+                packet[10..12].copy_from_slice(&sum.to_be_bytes());
+            }
+            Some(at) => {
+                let payload = u16::try_from(packet.len() - 40).ok()?;
+                packet[4..6].copy_from_slice(&payload.to_be_bytes());
+                packet[at] = self.protocol;
+            }
+        }
+        Some(packet)
+    }
+}
+
+/**
+Reassembles the IPv4 and IPv6 fragments received by a handle opened with the
+[`fragments`](windivert_sys::WinDivertFlags::set_fragments) flag.
+
+Fragments are buffered per datagram, identified by its addresses, identification and, for IPv4,
+protocol. Once every fragment of a datagram is received, [`push()`](Reassembler::push) returns the
+whole datagram with the address of its first fragment, keeping the direction and interface needed
+to inject it, or its fragments, back. Reassembled IPv4 datagrams keep the DF flag and the options of
+the first fragment, and IPv6 datagrams lose their fragment header.
+
+Datagrams are dropped when their fragments don't all arrive within the timeout, when a fragment
+overlaps data it doesn't match under [`OverlapPolicy::Drop`], and when buffering a fragment would
+exceed the memory or datagram limits, in which case the oldest datagrams are dropped first.
+*/
+#[derive(Debug)]
+pub struct Reassembler<L: PacketLayer> {
+    timeout: Duration,
+    max_memory: usize,
+    max_datagrams: usize,
+    overlap: OverlapPolicy,
+    memory: usize,
+    datagrams: HashMap<Key, Datagram<L>>,
+    stats: ReassemblyStats,
+}
+
+impl<L: PacketLayer> Default for Reassembler<L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L: PacketLayer> Reassembler<L> {
+    /**
+    Creates a reassembler with a timeout of 30 seconds, a memory limit of 4 MiB, a limit of 1024
+    datagrams and the [`OverlapPolicy::Drop`] policy.
+    */
+    pub fn new() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            max_memory: 4 << 20,
+            max_datagrams: 1024,
+            overlap: OverlapPolicy::Drop,
+            memory: 0,
+            datagrams: HashMap::new(),
+            stats: ReassemblyStats::default(),
+        }
+    }
+
+    /// Time allowed for all the fragments of a datagram to arrive after the first one.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Maximum number of bytes buffered for all datagrams.
+    pub fn max_memory(mut self, max_memory: usize) -> Self {
+        self.max_memory = max_memory;
+        self
+    }
+
+    /// Maximum number of datagrams being reassembled.
+    pub fn max_datagrams(mut self, max_datagrams: usize) -> Self {
+        self.max_datagrams = max_datagrams;
+        self
+    }
+
+    /// Handling of overlapping fragments.
+    pub fn overlap_policy(mut self, overlap: OverlapPolicy) -> Self {
+        self.overlap = overlap;
+        self
+    }
+
+    /// Number of datagrams being reassembled.
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    /// Returns `true` if no datagram is being reassembled.
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// Number of bytes buffered.
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    /// Counters of reassembled and dropped datagrams.
+    pub fn stats(&self) -> ReassemblyStats {
+        self.stats
+    }
+
+    /**
+    Adds a received packet, as [`push_at()`](Reassembler::push_at) does with the current time.
+    */
+    pub fn push(&mut self, packet: WinDivertPacket<'_, L>) -> Option<WinDivertPacket<'static, L>> {
+        self.push_at(packet, Instant::now())
+    }
+
+    /**
+    Adds a packet received at `now`, returning it as is if it isn't a fragment, or the whole
+    datagram if it is its last missing fragment. Datagrams timed out at `now` are dropped first.
+    */
+    pub fn push_at(
+        &mut self,
+        packet: WinDivertPacket<'_, L>,
+        now: Instant,
+    ) -> Option<WinDivertPacket<'static, L>> {
+        self.expire(now);
+        if !ParsedPacket::parse(&packet.data).is_fragment() {
+            return Some(packet.into_owned());
+        }
+        let Some(fragment) = Fragment::parse(&packet.data) else {
+            self.stats.malformed += 1;
+            return None;
+        };
+
+        if !self.datagrams.contains_key(&fragment.key) {
+            if self.datagrams.len() >= self.max_datagrams {
+                self.evict();
+            }
+            if self.max_datagrams == 0 {
+                self.stats.evicted += 1;
+                return None;
+            }
+            let datagram = Datagram {
+                created: now,
+                address: packet.address.clone(),
+                header: Vec::new(),
+                next_header_at: None,
+                protocol: 0,
+                data: Vec::new(),
+                ranges: Vec::new(),
+                length: None,
+                dropped: false,
+            };
+            self.datagrams.insert(fragment.key, datagram);
+        }
+
+        let datagram = self.datagrams.get(&fragment.key)?;
+        if datagram.dropped {
+            return None;
+        }
+        let end = fragment.offset + fragment.data.len();
+        let inconsistent = match (datagram.length, fragment.more) {
+            (Some(length), true) => end > length,
+            (Some(length), false) => end != length,
+            (None, false) => datagram
+                .ranges
+                .last()
+                .map_or(false, |&(_, last)| last > end),
+            (None, true) => false,
+        };
+        if inconsistent {
+            self.stats.malformed += 1;
+            return None;
+        }
+
+        let added = end.saturating_sub(datagram.data.len())
+            + if fragment.offset == 0 && datagram.header.is_empty() {
+                fragment.header.len()
+            } else {
+                0
+            };
+        while self.memory + added > self.max_memory && self.evict() {}
+        // The datagram of the fragment is gone if it had to be evicted too
+        let datagram = self.datagrams.get_mut(&fragment.key)?;
+
+        let memory = datagram.memory();
+        if !datagram.insert(&fragment, self.overlap) {
+            self.memory -= memory;
+            datagram.dropped = true;
+            datagram.header = Vec::new();
+            datagram.data = Vec::new();
+            self.stats.overlapping += 1;
+            return None;
+        }
+        if fragment.offset == 0 && datagram.header.is_empty() {
+            datagram.header = fragment.header.to_vec();
+            datagram.next_header_at = fragment.next_header_at;
+            datagram.protocol = fragment.protocol;
+            datagram.address = packet.address.clone();
+        }
+        if !fragment.more {
+            datagram.length = Some(end);
+        }
+        self.memory += added;
+
+        let data = datagram.assemble()?;
+        let datagram = self.datagrams.remove(&fragment.key)?;
+        self.memory -= datagram.memory();
+        self.stats.reassembled += 1;
+        let mut address = datagram.address;
+        if datagram.next_header_at.is_none() {
+            address.set_ip_checksum(true);
+        }
+        Some(WinDivertPacket {
+            address,
+            data: Cow::Owned(data),
+        })
+    }
+
+    /**
+    Drops the datagrams whose fragments didn't all arrive before `now`, which is also done by
+    every push. Call it periodically to release memory when fragments stop arriving.
+    */
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let (memory, stats) = (&mut self.memory, &mut self.stats);
+        self.datagrams.retain(|_, datagram| {
+            let alive = now.saturating_duration_since(datagram.created) < timeout;
+            if !alive {
+                *memory -= datagram.memory();
+                if !datagram.dropped {
+                    stats.timed_out += 1;
+                }
+            }
+            alive
+        });
+    }
+
+    /// Drops the oldest datagram, returning `false` if there is none.
+    fn evict(&mut self) -> bool {
+        let Some(key) = self
+            .datagrams
+            .iter()
+            .min_by_key(|(_, datagram)| datagram.created)
+            .map(|(key, _)| *key)
+        else {
+            return false;
+        };
+        if let Some(datagram) = self.datagrams.remove(&key) {
+            self.memory -= datagram.memory();
+            if !datagram.dropped {
+                self.stats.evicted += 1;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::{layer::NetworkLayer, packet::PacketBuilder};
+
+    type Packet = WinDivertPacket<'static, NetworkLayer>;
+
+    /// UDP datagram carrying `len` bytes of payload.
+    fn datagram(ipv6: bool, len: usize) -> Packet {
+        let builder = match ipv6 {
+            false => PacketBuilder::ipv4(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1))
+                .id(0x1234),
+            true => PacketBuilder::ipv6(
+                Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2),
+                Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+            ),
+        };
+        let payload: Vec<u8> = (0..len).map(|at| at as u8).collect();
+        builder.udp(51000, 53).payload(payload).build().unwrap()
+    }
+
+    /// IPv6 UDP datagram with a hop-by-hop options header before the UDP header.
+    fn with_hop_by_hop(packet: &Packet) -> Packet {
+        let mut data = packet.data.to_vec();
+        data.splice(40..40, [data[6], 0, 1, 4, 0, 0, 0, 0]);
+        data[6] = 0;
+        let length = u16::from_be_bytes([data[4], data[5]]) + 8;
+        data[4..6].copy_from_slice(&length.to_be_bytes());
+        WinDivertPacket {
+            address: packet.address.clone(),
+            data: Cow::Owned(data),
+        }
+    }
+
+    /// Fragment of `packet` carrying the bytes `start..end` of its IP payload.
+    fn fragment(packet: &Packet, start: usize, end: usize, more: bool) -> Packet {
+        let data = &packet.data;
+        let mut fragment = Vec::new();
+        match data[0] >> 4 {
+            4 => {
+                fragment.extend_from_slice(&data[..20]);
+                fragment.extend_from_slice(&data[20 + start..20 + end]);
+                let total = (20 + end - start) as u16;
+                fragment[2..4].copy_from_slice(&total.to_be_bytes());
+                let flags = (start / 8) as u16 | if more { 0x2000 } else { 0 };
+                fragment[6..8].copy_from_slice(&flags.to_be_bytes());
+                fragment[10..12].fill(0);
+                let sum = checksum::checksum(&fragment[..20]);
+                fragment[10..12].copy_from_slice(&sum.to_be_bytes());
+            }
+            _ => {
+                // The hop-by-hop options header is part of the unfragmentable headers
+                let (header_len, next_at) = match data[6] {
+                    0 => (48, 40),
+                    _ => (40, 6),
+                };
+                fragment.extend_from_slice(&data[..header_len]);
+                let protocol = std::mem::replace(&mut fragment[next_at], 44);
+                let offset = start as u16 | more as u16;
+                fragment.extend_from_slice(&[protocol, 0]);
+                fragment.extend_from_slice(&offset.to_be_bytes());
+                fragment.extend_from_slice(&0xCAFE_u32.to_be_bytes());
+                let start = header_len + start;
+                let end = header_len + end;
+                fragment.extend_from_slice(&data[start..end]);
+                let payload = (fragment.len() - 40) as u16;
+                fragment[4..6].copy_from_slice(&payload.to_be_bytes());
+            }
+        }
+        WinDivertPacket {
+            address: packet.address.clone(),
+            data: Cow::Owned(fragment),
+        }
+    }
+
+    /// Fragments of the IP payload of `packet`, split at `splits`.
+    fn fragments(packet: &Packet, splits: &[usize]) -> Vec<Packet> {
+        let header_len = match (packet.data[0] >> 4, packet.data[6]) {
+            (4, _) => 20,
+            (_, 0) => 48,
+            _ => 40,
+        };
+        let len = packet.data.len() - header_len;
+        let mut bounds = vec![0];
+        bounds.extend_from_slice(splits);
+        bounds.push(len);
+        bounds
+            .windows(2)
+            .map(|bounds| fragment(packet, bounds[0], bounds[1], bounds[1] != len))
+            .collect()
+    }
+
+    /// Pushes `packets` at `now`, returning the output of the last push.
+    fn push_all(
+        reassembler: &mut Reassembler<NetworkLayer>,
+        packets: impl IntoIterator<Item = Packet>,
+        now: Instant,
+    ) -> Option<Packet> {
+        let mut last = None;
+        for packet in packets {
+            assert!(last.is_none(), "reassembled before the last fragment");
+            last = reassembler.push_at(packet, now);
+        }
+        last
+    }
+
+    #[test]
+    fn in_order() {
+        let now = Instant::now();
+        for ipv6 in [false, true] {
+            let packet = datagram(ipv6, 40);
+            let mut reassembler = Reassembler::new();
+            let whole = push_all(&mut reassembler, fragments(&packet, &[16, 32]), now).unwrap();
+            assert_eq!(whole.data, packet.data);
+            assert_eq!(whole.address.ip_checksum(), !ipv6);
+            assert!(reassembler.is_empty());
+            assert_eq!(reassembler.memory(), 0);
+            assert_eq!(reassembler.stats().reassembled, 1);
+        }
+
+        // Packets which aren't fragments are returned as they are
+        let mut reassembler = Reassembler::new();
+        let packet = datagram(false, 40);
+        assert_eq!(reassembler.push(packet.clone()).unwrap().data, packet.data);
+        assert_eq!(reassembler.stats(), ReassemblyStats::default());
+    }
+
+    #[test]
+    fn out_of_order() {
+        let now = Instant::now();
+        let orders = [[2, 1, 0], [1, 2, 0], [0, 2, 1], [2, 0, 1]];
+        for ipv6 in [false, true] {
+            let packet = datagram(ipv6, 40);
+            for order in orders {
+                let mut fragments = fragments(&packet, &[16, 32]);
+                // The address is taken from the first fragment, wherever it arrives
+                fragments[0].address.set_interface_index(1);
+                fragments[1].address.set_interface_index(2);
+                fragments[2].address.set_interface_index(3);
+                let fragments = order.map(|index| fragments[index].clone());
+
+                let mut reassembler = Reassembler::new();
+                let whole = push_all(&mut reassembler, fragments, now).unwrap();
+                assert_eq!(whole.data, packet.data, "{order:?}");
+                assert_eq!(whole.address.interface_index(), 1);
+                assert_eq!(reassembler.memory(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn ipv6_extensions() {
+        let packet = with_hop_by_hop(&datagram(true, 40));
+        let fragments = fragments(&packet, &[24]);
+        let parsed = ParsedPacket::parse(&fragments[0].data);
+        let kinds: Vec<_> = parsed.extensions.map(|header| header.kind()).collect();
+        assert_eq!(kinds, [0, 44]);
+
+        let mut reassembler = Reassembler::new();
+        let whole = push_all(
+            &mut reassembler,
+            fragments.into_iter().rev(),
+            Instant::now(),
+        );
+        assert_eq!(whole.unwrap().data, packet.data);
+    }
+
+    #[test]
+    fn duplicates() {
+        let now = Instant::now();
+        for ipv6 in [false, true] {
+            let packet = datagram(ipv6, 40);
+            let fragments = fragments(&packet, &[16, 32]);
+            // Duplicated fragments, and a fragment overlapping both its neighbours with the
+            // same bytes
+            let overlapping = fragment(&packet, 8, 40, true);
+            let packets = [
+                fragments[1].clone(),
+                fragments[1].clone(),
+                overlapping,
+                fragments[0].clone(),
+                fragments[0].clone(),
+                fragments[2].clone(),
+            ];
+            let mut reassembler = Reassembler::new();
+            let whole = push_all(&mut reassembler, packets, now).unwrap();
+            assert_eq!(whole.data, packet.data);
+            assert_eq!(reassembler.stats().overlapping, 0);
+        }
+    }
+
+    #[test]
+    fn overlaps() {
+        let now = Instant::now();
+        for ipv6 in [false, true] {
+            let packet = datagram(ipv6, 40);
+            let mut altered = packet.clone();
+            for byte in &mut altered.data.to_mut()[packet.data.len() - 24..] {
+                *byte = !*byte;
+            }
+            // The second fragment overlaps the end of the first one with different bytes
+            let len = packet.data.len() - if ipv6 { 40 } else { 20 };
+            let first = fragment(&packet, 0, len - 16, true);
+            let second = fragment(&altered, len - 24, len, false);
+
+            let mut reassembler = Reassembler::new();
+            assert!(reassembler.push_at(first.clone(), now).is_none());
+            assert!(reassembler.push_at(second.clone(), now).is_none());
+            assert_eq!(reassembler.stats().overlapping, 1);
+            assert_eq!((reassembler.len(), reassembler.memory()), (1, 0));
+            // Later fragments of the dropped datagram are dropped too
+            assert!(reassembler.push_at(first.clone(), now).is_none());
+            assert_eq!(reassembler.memory(), 0);
+            reassembler.expire(now + Duration::from_secs(30));
+            assert!(reassembler.is_empty());
+            assert_eq!(reassembler.stats().timed_out, 0);
+
+            let mut reassembler = Reassembler::new().overlap_policy(OverlapPolicy::First);
+            let whole = push_all(&mut reassembler, [first.clone(), second.clone()], now);
+            let whole = whole.unwrap();
+            assert_eq!(
+                whole.data[..packet.data.len() - 16],
+                packet.data[..packet.data.len() - 16]
+            );
+            assert_eq!(
+                whole.data[packet.data.len() - 16..],
+                altered.data[packet.data.len() - 16..]
+            );
+
+            let mut reassembler = Reassembler::new().overlap_policy(OverlapPolicy::Last);
+            let whole = push_all(&mut reassembler, [first, second], now).unwrap();
+            assert_eq!(
+                whole.data[..packet.data.len() - 24],
+                packet.data[..packet.data.len() - 24]
+            );
+            assert_eq!(
+                whole.data[packet.data.len() - 24..],
+                altered.data[packet.data.len() - 24..]
+            );
+            assert_eq!(reassembler.stats().overlapping, 0);
+        }
+    }
+
+    #[test]
+    fn memory_limit() {
+        let now = Instant::now();
+        let (first, second) = (datagram(false, 40), datagram(true, 40));
+        let (first, second) = (fragments(&first, &[32]), fragments(&second, &[16]));
+
+        // The first fragments take 20 + 32 and 40 + 16 bytes
+        let mut reassembler = Reassembler::new().max_memory(100);
+        assert!(reassembler.push_at(first[0].clone(), now).is_none());
+        assert_eq!(reassembler.memory(), 52);
+        let later = now + Duration::from_secs(1);
+        assert!(reassembler.push_at(second[0].clone(), later).is_none());
+        assert_eq!((reassembler.len(), reassembler.memory()), (1, 56));
+        assert_eq!(reassembler.stats().evicted, 1);
+        assert!(reassembler.push_at(second[1].clone(), later).is_some());
+        assert_eq!(reassembler.memory(), 0);
+
+        // A fragment exceeding the limit on its own is rejected
+        let mut reassembler = Reassembler::new().max_memory(30);
+        assert!(reassembler.push_at(first[0].clone(), now).is_none());
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.memory(), 0);
+        assert_eq!(reassembler.stats().evicted, 1);
+
+        // The datagram limit evicts the oldest datagram
+        let mut reassembler = Reassembler::new().max_datagrams(1);
+        assert!(reassembler.push_at(first[0].clone(), now).is_none());
+        assert!(reassembler.push_at(second[0].clone(), later).is_none());
+        assert!(reassembler.push_at(first[1].clone(), later).is_none());
+        assert_eq!(reassembler.stats().evicted, 2);
+
+        let mut reassembler = Reassembler::new().max_datagrams(0);
+        assert!(reassembler.push_at(first[0].clone(), now).is_none());
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.stats().evicted, 1);
+    }
+
+    #[test]
+    fn timeouts() {
+        let now = Instant::now();
+        let packet = datagram(false, 40);
+        let fragments = fragments(&packet, &[16, 32]);
+        let mut reassembler = Reassembler::new().timeout(Duration::from_secs(10));
+
+        assert!(reassembler.push_at(fragments[0].clone(), now).is_none());
+        let within = now + Duration::from_millis(9999);
+        assert!(reassembler.push_at(fragments[1].clone(), within).is_none());
+        assert_eq!(reassembler.memory(), 20 + 32);
+
+        // The last fragment arrives too late, and starts a new datagram buffering its data from
+        // offset 0
+        let late = now + Duration::from_secs(10);
+        assert!(reassembler.push_at(fragments[2].clone(), late).is_none());
+        assert_eq!(reassembler.stats().timed_out, 1);
+        assert_eq!((reassembler.len(), reassembler.memory()), (1, 48));
+
+        reassembler.expire(late + Duration::from_secs(10));
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.memory(), 0);
+        assert_eq!(reassembler.stats().timed_out, 2);
+    }
+
+    #[test]
+    fn malformed() {
+        let now = Instant::now();
+        let packet = datagram(false, 40);
+        let mut reassembler = Reassembler::new();
+
+        // Fragments other than the last one carry a multiple of 8 bytes
+        assert!(reassembler
+            .push_at(fragment(&packet, 0, 12, true), now)
+            .is_none());
+        // A last fragment ending before data already received
+        assert!(reassembler
+            .push_at(fragment(&packet, 16, 32, true), now)
+            .is_none());
+        assert!(reassembler
+            .push_at(fragment(&packet, 8, 24, false), now)
+            .is_none());
+        // A fragment ending after the last one
+        assert!(reassembler
+            .push_at(fragment(&packet, 32, 48, false), now)
+            .is_none());
+        let longer = datagram(false, 64);
+        assert!(reassembler
+            .push_at(fragment(&longer, 40, 56, true), now)
+            .is_none());
+        // A truncated fragment
+        let mut truncated = fragment(&packet, 0, 16, true);
+        truncated.data.to_mut().truncate(30);
+        assert!(reassembler.push_at(truncated, now).is_none());
+        assert_eq!(reassembler.stats().malformed, 4);
+        assert_eq!(reassembler.stats().reassembled, 0);
+    }
+}
@@ -0,0 +1,392 @@
+/*!
+Responses rejecting a diverted packet, as a firewall would instead of silently dropping it.
+
+[`tcp_reset()`] answers a TCP segment with a RST segment aborting the connection, and
+[`icmp_unreachable()`] answers any packet with an ICMP or ICMPv6 destination unreachable message
+quoting it. [`packet_too_big()`] builds the message used by path MTU discovery the same way. The
+responses come from the destination of the rejected packet and go back to its source: their
+address has the opposite direction and the interface of the rejected packet, so they can be
+injected with the handle that diverted it.
+*/
+
+use std::net::IpAddr;
+
+use super::{IpHeader, PacketBuilder, TcpBuilder, TransportHeader, WinDivertPacket};
+use crate::layer::NetworkLayer;
+
+/// Longest ICMP error message, including its IPv4 header, from RFC 1812.
+const ICMP_ERROR_MAX: usize = 576;
+/// Longest ICMPv6 error message, including its IPv6 header, from RFC 4443.
+const ICMPV6_ERROR_MAX: usize = 1280;
+
+/// Builder of a response to `packet`, with swapped addresses and the opposite direction.
+fn response(packet: &WinDivertPacket<'_, NetworkLayer>, ip: IpHeader) -> PacketBuilder {
+    let builder = match ip {
+        IpHeader::V4(header) => PacketBuilder::ipv4(header.dst_addr(), header.src_addr()),
+        IpHeader::V6(header) => PacketBuilder::ipv6(header.dst_addr(), header.src_addr()),
+    };
+    builder
+        .outbound(!packet.address.outbound())
+        .interface_index(packet.address.interface_index())
+        .subinterface_index(packet.address.subinterface_index())
+}
+
+/**
+RST segment resetting the connection of a TCP `packet`, `None` if the packet isn't a TCP segment
+or is itself a reset.
+
+As described by RFC 793, the reset takes its sequence number from the acknowledgement number of
+the segment if it has one. Otherwise it acknowledges the segment, SYN and FIN flags included.
+*/
+pub fn tcp_reset(
+    packet: &WinDivertPacket<'_, NetworkLayer>,
+) -> Option<WinDivertPacket<'static, NetworkLayer>> {
+    let parsed = packet.parse();
+    let (ip, tcp) = (parsed.ip?, parsed.tcp()?);
+    if tcp.rst() {
+        return None;
+    }
+
+    let reset = TcpBuilder::new(tcp.dst_port(), tcp.src_port())
+        .rst(true)
+        .window(0);
+    let reset = match tcp.ack() {
+        true => reset.seq_number(tcp.ack_number()),
+        false => {
+            let length = parsed.payload.len() as u32 + tcp.syn() as u32 + tcp.fin() as u32;
+            reset
+                .ack(true)
+                .ack_number(tcp.seq_number().wrapping_add(length))
+        }
+    };
+    response(packet, ip).tcp(reset).build().ok()
+}
+
+/**
+ICMP destination unreachable message for an IPv4 `packet`, or ICMPv6 one for an IPv6 packet, with
+the given `code`.
+
+Codes differ between the two protocols: port unreachable is `3` for ICMP and `4` for ICMPv6, and
+administratively prohibited is `13` for ICMP and `1` for ICMPv6. The message quotes as much of the
+packet as fits in 576 bytes for ICMP and 1280 bytes for ICMPv6.
+
+Following RFC 1122 and RFC 4443, `None` is returned instead of an error message about an ICMP
+error message, a non first fragment, or a packet to or from a multicast or broadcast address.
+*/
+pub fn icmp_unreachable(
+    packet: &WinDivertPacket<'_, NetworkLayer>,
+    code: u8,
+) -> Option<WinDivertPacket<'static, NetworkLayer>> {
+    icmp_error(packet, (3, code), (1, code), 0, false)
+}
+
+/**
+ICMP fragmentation needed message for an IPv4 `packet`, or ICMPv6 packet too big one for an IPv6
+packet, telling its source to send packets of at most `mtu` bytes.
+
+Messages are built and left out as with [`icmp_unreachable()`], except that an ICMPv6 packet too
+big message is also sent for packets to a multicast address.
+*/
+pub fn packet_too_big(
+    packet: &WinDivertPacket<'_, NetworkLayer>,
+    mtu: u32,
+) -> Option<WinDivertPacket<'static, NetworkLayer>> {
+    let body = match packet.parse().ipv4() {
+        Some(_) => mtu.min(u16::MAX as u32),
+        None => mtu,
+    };
+    icmp_error(packet, (3, 4), (2, 0), body, true)
+}
+
+/// ICMP error message with the type and code of `icmp` or `icmpv6` about `packet`.
+fn icmp_error(
+    packet: &WinDivertPacket<'_, NetworkLayer>,
+    icmp: (u8, u8),
+    icmpv6: (u8, u8),
+    body: u32,
+    multicast_ipv6: bool,
+) -> Option<WinDivertPacket<'static, NetworkLayer>> {
+    let parsed = packet.parse();
+    let ip = parsed.ip?;
+    let ((msg_type, code), limit) = match ip {
+        IpHeader::V4(_) => (icmp, ICMP_ERROR_MAX - 28),
+        IpHeader::V6(_) => (icmpv6, ICMPV6_ERROR_MAX - 48),
+    };
+
+    let error = match parsed.transport {
+        Some(TransportHeader::Icmp(header)) => matches!(header.msg_type(), 3 | 4 | 5 | 11 | 12),
+        Some(TransportHeader::Icmpv6(header)) => header.msg_type() < 128,
+        _ => false,
+    };
+    let first_fragment = match ip {
+        IpHeader::V4(header) => header.fragment_offset() == 0,
+        IpHeader::V6(_) => parsed.extensions.into_iter().all(|header| {
+            let bytes = header.as_bytes();
+            header.kind() != 44 || u16::from_be_bytes([bytes[2], bytes[3]]) & 0xFFF8 == 0
+        }),
+    };
+    let multicast = |addr: IpAddr| match addr {
+        IpAddr::V4(addr) => addr.is_multicast() || addr.is_broadcast() || addr.is_unspecified(),
+        IpAddr::V6(addr) => addr.is_multicast() || addr.is_unspecified(),
+    };
+    let multicast_dst = match ip.dst_addr() {
+        IpAddr::V6(addr) if multicast_ipv6 => addr.is_unspecified(),
+        addr => multicast(addr),
+    };
+    if error || !first_fragment || multicast(ip.src_addr()) || multicast_dst {
+        return None;
+    }
+
+    let quoted = &packet.data[..ip.packet_length().min(packet.data.len())];
+    response(packet, ip)
+        .icmp(msg_type, code, body)
+        .payload(&quoted[..quoted.len().min(limit)])
+        .build()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use windivert_sys::ChecksumFlags;
+
+    use super::*;
+    use crate::packet::checksum;
+
+    const SRC_V4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const DST_V4: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+    const SRC_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+    const DST_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+    /// Inbound packet received on interface `7.3`.
+    fn inbound(builder: PacketBuilder) -> WinDivertPacket<'static, NetworkLayer> {
+        builder
+            .outbound(false)
+            .interface_index(7)
+            .subinterface_index(3)
+            .build()
+            .unwrap()
+    }
+
+    fn builders() -> [PacketBuilder; 2] {
+        [
+            PacketBuilder::ipv4(SRC_V4, DST_V4),
+            PacketBuilder::ipv6(SRC_V6, DST_V6),
+        ]
+    }
+
+    /// Checks that `response` goes back to the source of `packet` with valid checksums.
+    fn assert_response(
+        packet: &WinDivertPacket<'_, NetworkLayer>,
+        response: &WinDivertPacket<'_, NetworkLayer>,
+    ) {
+        let (ip, reply) = (packet.parse().ip.unwrap(), response.parse().ip.unwrap());
+        assert_eq!(
+            (reply.src_addr(), reply.dst_addr()),
+            (ip.dst_addr(), ip.src_addr())
+        );
+        assert!(response.address.outbound());
+        assert_eq!(response.address.interface_index(), 7);
+        assert_eq!(response.address.subinterface_index(), 3);
+
+        let mut expected = response.data.to_vec();
+        checksum::calc_checksums(&mut expected, ChecksumFlags::new());
+        assert_eq!(response.data[..], expected[..]);
+    }
+
+    #[test]
+    fn reset_acknowledged() {
+        for builder in builders() {
+            let tcp = TcpBuilder::new(49152, 443)
+                .seq_number(0x0BAD_CAFE)
+                .ack_number(0x1234_5678)
+                .ack(true)
+                .psh(true);
+            let packet = inbound(builder.tcp(tcp).payload(*b"hello"));
+            let reset = tcp_reset(&packet).unwrap();
+            assert_response(&packet, &reset);
+
+            let parsed = reset.parse();
+            let header = parsed.tcp().unwrap();
+            assert_eq!((header.src_port(), header.dst_port()), (443, 49152));
+            assert_eq!(header.seq_number(), 0x1234_5678);
+            assert!(header.rst() && !header.ack() && !header.psh());
+            assert_eq!((header.ack_number(), header.window()), (0, 0));
+            assert!(parsed.payload.is_empty());
+        }
+    }
+
+    #[test]
+    fn reset_unacknowledged() {
+        // The SYN, FIN and payload lengths are acknowledged, wrapping around
+        let cases = [
+            (
+                TcpBuilder::new(49152, 443).syn(true),
+                &b""[..],
+                0xFFFF_FFFF,
+                0,
+            ),
+            (TcpBuilder::new(49152, 443), &b"hello"[..], 100, 105),
+            (TcpBuilder::new(49152, 443).fin(true), &b"hi"[..], 100, 103),
+        ];
+        for builder in builders() {
+            for (tcp, payload, seq_number, ack_number) in cases.clone() {
+                let tcp = tcp.seq_number(seq_number);
+                let packet = inbound(builder.clone().tcp(tcp).payload(payload));
+                let reset = tcp_reset(&packet).unwrap();
+                assert_response(&packet, &reset);
+
+                let header = reset.parse().tcp().unwrap();
+                assert!(header.rst() && header.ack() && !header.syn() && !header.fin());
+                assert_eq!((header.seq_number(), header.ack_number()), (0, ack_number));
+            }
+        }
+    }
+
+    #[test]
+    fn reset_ignored() {
+        for builder in builders() {
+            let tcp = TcpBuilder::new(49152, 443).rst(true);
+            assert!(tcp_reset(&inbound(builder.clone().tcp(tcp))).is_none());
+            assert!(tcp_reset(&inbound(builder.udp(51000, 53))).is_none());
+        }
+    }
+
+    #[test]
+    fn unreachable() {
+        let packet = inbound(
+            PacketBuilder::ipv4(SRC_V4, DST_V4)
+                .udp(51000, 53)
+                .payload(*b"abc"),
+        );
+        let message = icmp_unreachable(&packet, 3).unwrap();
+        assert_response(&packet, &message);
+        let parsed = message.parse();
+        let header = parsed.icmp().unwrap();
+        assert_eq!(
+            (header.msg_type(), header.msg_code(), header.body()),
+            (3, 3, 0)
+        );
+        assert_eq!(parsed.payload, &packet.data[..]);
+
+        let packet = inbound(
+            PacketBuilder::ipv6(SRC_V6, DST_V6)
+                .udp(51000, 53)
+                .payload(*b"abc"),
+        );
+        let message = icmp_unreachable(&packet, 4).unwrap();
+        assert_response(&packet, &message);
+        let parsed = message.parse();
+        let header = parsed.icmpv6().unwrap();
+        assert_eq!(
+            (header.msg_type(), header.msg_code(), header.body()),
+            (1, 4, 0)
+        );
+        assert_eq!(parsed.payload, &packet.data[..]);
+
+        // Long packets are quoted up to the message size limit
+        for (builder, limit) in builders().into_iter().zip([576, 1280]) {
+            let packet = inbound(builder.udp(51000, 53).payload(vec![0xAB; 2000]));
+            let message = icmp_unreachable(&packet, 1).unwrap();
+            assert_response(&packet, &message);
+            assert_eq!(message.data.len(), limit);
+            let quoted = message.parse().payload;
+            assert_eq!(quoted, &packet.data[..quoted.len()]);
+        }
+    }
+
+    #[test]
+    fn unreachable_ignored() {
+        let udp = |builder: PacketBuilder| inbound(builder.udp(51000, 53));
+        let ignored = [
+            // ICMP error messages
+            inbound(PacketBuilder::ipv4(SRC_V4, DST_V4).icmp(3, 1, 0)),
+            inbound(PacketBuilder::ipv4(SRC_V4, DST_V4).icmp(11, 0, 0)),
+            inbound(PacketBuilder::ipv6(SRC_V6, DST_V6).icmp(1, 4, 0)),
+            inbound(PacketBuilder::ipv6(SRC_V6, DST_V6).icmp(3, 0, 0)),
+            // Multicast, broadcast and unspecified addresses
+            udp(PacketBuilder::ipv4(SRC_V4, Ipv4Addr::new(224, 0, 0, 251))),
+            udp(PacketBuilder::ipv4(SRC_V4, Ipv4Addr::BROADCAST)),
+            udp(PacketBuilder::ipv4(Ipv4Addr::UNSPECIFIED, DST_V4)),
+            udp(PacketBuilder::ipv6(SRC_V6, "ff02::fb".parse().unwrap())),
+            udp(PacketBuilder::ipv6(Ipv6Addr::UNSPECIFIED, DST_V6)),
+            // Non first fragments
+            inbound(
+                PacketBuilder::ipv6(SRC_V6, DST_V6)
+                    .protocol(44)
+                    .payload([17, 0, 0x05, 0x28, 0, 0, 0, 1, 0xAB, 0xAB]),
+            ),
+        ];
+        for packet in ignored {
+            assert!(
+                icmp_unreachable(&packet, 1).is_none(),
+                "{:?}",
+                packet.parse()
+            );
+        }
+
+        let mut fragment = udp(PacketBuilder::ipv4(SRC_V4, DST_V4));
+        fragment.data.to_mut()[6..8].copy_from_slice(&[0, 0x20]);
+        assert!(icmp_unreachable(&fragment, 3).is_none());
+
+        // Informational messages and first fragments are answered
+        let answered = [
+            inbound(PacketBuilder::ipv4(SRC_V4, DST_V4).icmp(8, 0, 0)),
+            inbound(PacketBuilder::ipv6(SRC_V6, DST_V6).icmp(128, 0, 0)),
+            inbound(
+                PacketBuilder::ipv6(SRC_V6, DST_V6)
+                    .protocol(44)
+                    .payload([17, 0, 0x00, 0x01, 0, 0, 0, 1, 0xAB, 0xAB]),
+            ),
+        ];
+        for packet in answered {
+            let message = icmp_unreachable(&packet, 1).unwrap();
+            assert_response(&packet, &message);
+        }
+    }
+
+    #[test]
+    fn too_big() {
+        let packet = inbound(
+            PacketBuilder::ipv4(SRC_V4, DST_V4)
+                .udp(51000, 53)
+                .payload(*b"abc"),
+        );
+        let message = packet_too_big(&packet, 1280).unwrap();
+        assert_response(&packet, &message);
+        let parsed = message.parse();
+        let header = parsed.icmp().unwrap();
+        assert_eq!(
+            (header.msg_type(), header.msg_code(), header.body()),
+            (3, 4, 1280)
+        );
+        assert_eq!(parsed.payload, &packet.data[..]);
+        // The next hop MTU of ICMP is a 16 bit field
+        let message = packet_too_big(&packet, 70000).unwrap();
+        assert_eq!(message.parse().icmp().unwrap().body(), 0xFFFF);
+
+        let packet = inbound(
+            PacketBuilder::ipv6(SRC_V6, DST_V6)
+                .udp(51000, 53)
+                .payload(*b"abc"),
+        );
+        let message = packet_too_big(&packet, 70000).unwrap();
+        assert_response(&packet, &message);
+        let parsed = message.parse();
+        let header = parsed.icmpv6().unwrap();
+        assert_eq!(
+            (header.msg_type(), header.msg_code(), header.body()),
+            (2, 0, 70000)
+        );
+        assert_eq!(parsed.payload, &packet.data[..]);
+
+        // ICMPv6 packet too big messages are also sent for multicast packets
+        let multicast = PacketBuilder::ipv6(SRC_V6, "ff02::fb".parse().unwrap());
+        let packet = inbound(multicast.udp(51000, 53));
+        assert!(icmp_unreachable(&packet, 4).is_none());
+        assert_response(&packet, &packet_too_big(&packet, 1280).unwrap());
+        let multicast = PacketBuilder::ipv4(SRC_V4, Ipv4Addr::new(224, 0, 0, 251));
+        assert!(packet_too_big(&inbound(multicast.udp(51000, 53)), 576).is_none());
+    }
+}
@@ -0,0 +1,482 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use windivert_sys::{ChecksumFlags, WINDIVERT_MTU_MAX};
+
+use super::{checksum, reject, IpHeader, ParsedPacket, WinDivertPacket};
+use crate::layer::NetworkLayer;
+
+/// Smallest MTU of an IPv4 link, from RFC 791.
+const MTU_MIN: u32 = 68;
+
+/// Result of [`Segmenter::segment()`].
+#[derive(Debug, Clone)]
+pub enum Segmented<'a> {
+    /// The packet if it fits in the MTU, or the fragments or segments it was split into.
+    Packets(Vec<WinDivertPacket<'a, NetworkLayer>>),
+    /**
+    The packet is too big and can't be split: it is an IPv4 packet with the DF flag or an IPv6
+    packet, but not a TCP segment. Holds the ICMP fragmentation needed or ICMPv6 packet too big
+    message to inject back to its source, `None` when no message must be sent about the packet.
+    */
+    TooBig(Option<WinDivertPacket<'static, NetworkLayer>>),
+}
+
+/**
+Splits network layer packets larger than the MTU of the interface they are sent on.
+
+TCP segments are split into segments carrying at most one maximum segment size of payload each,
+with their own sequence number and IPv4 identification. Other IPv4 packets are split into fragments
+unless they have the DF flag, in which case they are answered with an ICMP fragmentation needed
+message as a router would, and other IPv6 packets with an ICMPv6 packet too big message. The
+checksums of the packets produced are computed, along with the transport checksum of the datagram
+before it is fragmented.
+
+Each interface has its own MTU, falling back to a default MTU, and MTUs are capped by
+[`WINDIVERT_MTU_MAX`].
+*/
+#[derive(Debug, Clone)]
+pub struct Segmenter {
+    default_mtu: u32,
+    mtus: HashMap<u32, u32>,
+}
+
+impl Default for Segmenter {
+    /// Segmenter with the Ethernet MTU of 1500 bytes.
+    fn default() -> Self {
+        Self::new(1500)
+    }
+}
+
+impl Segmenter {
+    /// Creates a segmenter using `mtu` for every interface.
+    pub fn new(mtu: u32) -> Self {
+        Self {
+            default_mtu: mtu.clamp(MTU_MIN, WINDIVERT_MTU_MAX),
+            mtus: HashMap::new(),
+        }
+    }
+
+    /// Sets the MTU of the interface `interface_index`.
+    pub fn set_mtu(&mut self, interface_index: u32, mtu: u32) -> &mut Self {
+        self.mtus
+            .insert(interface_index, mtu.clamp(MTU_MIN, WINDIVERT_MTU_MAX));
+        self
+    }
+
+    /// MTU of the interface `interface_index`.
+    pub fn mtu(&self, interface_index: u32) -> u32 {
+        self.mtus
+            .get(&interface_index)
+            .copied()
+            .unwrap_or(self.default_mtu)
+    }
+
+    /**
+    Splits `packet` to fit in the MTU of the interface of its address. Packets that fit, and data
+    that isn't an IP packet, are returned as is.
+    */
+    pub fn segment<'a>(&self, packet: WinDivertPacket<'a, NetworkLayer>) -> Segmented<'a> {
+        let mtu = self.mtu(packet.address.interface_index()) as usize;
+        let parsed = ParsedPacket::parse(&packet.data);
+        let Some(ip) = parsed.ip else {
+            return Segmented::Packets(vec![packet]);
+        };
+        let length = ip.packet_length();
+        if length <= mtu || parsed.truncated {
+            return Segmented::Packets(vec![packet]);
+        }
+
+        if let Some(tcp) = parsed.tcp().filter(|_| !parsed.is_fragment()) {
+            let offset = tcp.as_bytes().as_ptr() as usize - packet.data.as_ptr() as usize;
+            if let Some(segments) = segment_tcp(&packet, offset + tcp.as_bytes().len(), mtu) {
+                return Segmented::Packets(segments);
+            }
+        }
+        match ip {
+            IpHeader::V4(header) if !header.df() => {
+                let mut data = packet.data[..length].to_vec();
+                if !parsed.is_fragment() {
+                    checksum::calc_checksums(&mut data, ChecksumFlags::new());
+                }
+                match fragment_ipv4(&data, mtu) {
+                    Some(fragments) => Segmented::Packets(
+                        fragments
+                            .into_iter()
+                            .map(|data| with_address(&packet, data))
+                            .collect(),
+                    ),
+                    None => Segmented::TooBig(reject::packet_too_big(&packet, mtu as u32)),
+                }
+            }
+            _ => Segmented::TooBig(reject::packet_too_big(&packet, mtu as u32)),
+        }
+    }
+}
+
+/// Packet made of `data` with the address of `packet`, its checksums computed.
+fn with_address<'a>(
+    packet: &WinDivertPacket<'_, NetworkLayer>,
+    data: Vec<u8>,
+) -> WinDivertPacket<'a, NetworkLayer> {
+    let mut packet = WinDivertPacket {
+        address: packet.address.clone(),
+        data: Cow::Owned(data),
+    };
+    packet.recalculate_checksums(ChecksumFlags::new());
+    packet
+}
+
+/**
+Splits the payload of a TCP segment into segments fitting in `mtu`, `None` if the headers leave no
+room for the payload. `payload` is the offset of the payload, following the TCP header.
+*/
+fn segment_tcp(
+    packet: &WinDivertPacket<'_, NetworkLayer>,
+    payload: usize,
+    mtu: usize,
+) -> Option<Vec<WinDivertPacket<'static, NetworkLayer>>> {
+    let data = &packet.data[..];
+    let parsed = ParsedPacket::parse(data);
+    let ip = parsed.ip?;
+    let tcp = parsed.tcp()?;
+    let tcp_at = tcp.as_bytes().as_ptr() as usize - data.as_ptr() as usize;
+    let mss = mtu.checked_sub(payload).filter(|&mss| mss > 0)?;
+    let body = &data[payload..ip.packet_length()];
+    if body.is_empty() {
+        return None;
+    }
+
+    let (seq, urg_ptr) = (tcp.seq_number(), tcp.urg_ptr() as usize);
+    let chunks = body.chunks(mss).enumerate();
+    let count = (body.len() + mss - 1) / mss;
+    let segments = chunks.map(|(index, chunk)| {
+        let offset = index * mss;
+        let mut segment = Vec::with_capacity(payload + chunk.len());
+        segment.extend_from_slice(&data[..payload]);
+        segment.extend_from_slice(chunk);
+
+        // The SYN flag takes one sequence number before the payload
+        let first = index == 0;
+        let seq = seq.wrapping_add((offset + (tcp.syn() && !first) as usize) as u32);
+        segment[tcp_at + 4..tcp_at + 8].copy_from_slice(&seq.to_be_bytes());
+        let mut flags = segment[tcp_at + 13];
+        if !first {
+            flags &= !0x02;
+        }
+        if index + 1 < count {
+            // FIN and PSH
+            flags &= !0x09;
+        }
+        // The urgent pointer is relative to the sequence number of the segment
+        let urg_ptr = match tcp.urg() && urg_ptr > offset {
+            true => (urg_ptr - offset) as u16,
+            false => {
+                flags &= !0x20;
+                0
+            }
+        };
+        segment[tcp_at + 13] = flags;
+        segment[tcp_at + 18..tcp_at + 20].copy_from_slice(&urg_ptr.to_be_bytes());
+
+        match ip {
+            IpHeader::V4(header) => {
+                let total = segment.len() as u16;
+                segment[2..4].copy_from_slice(&total.to_be_bytes());
+                let id = header.id().wrapping_add(index as u16);
+                segment[4..6].copy_from_slice(&id.to_be_bytes());
+            }
+            IpHeader::V6(_) => {
+                let length = (segment.len() - 40) as u16;
+                segment[4..6].copy_from_slice(&length.to_be_bytes());
+            }
+        }
+        with_address(packet, segment)
+    });
+    Some(segments.collect())
+}
+
+/**
+Splits an IPv4 packet without the DF flag into fragments fitting in `mtu`, `None` if the header
+leaves no room for the data. Fragments of fragments keep their offset in the original datagram.
+*/
+fn fragment_ipv4(data: &[u8], mtu: usize) -> Option<Vec<Vec<u8>>> {
+    let header_len = (data[0] & 0x0F) as usize * 4;
+    let (header, body) = data.split_at(header_len);
+    let copied = copied_options(&header[20..]);
+    let offset = (u16::from_be_bytes([data[6], data[7]]) & 0x1FFF) as usize * 8;
+    let more = data[6] & 0x20 != 0;
+
+    // Every fragment but the last one carries a multiple of 8 bytes
+    let mut fragments = Vec::new();
+    let mut position = 0;
+    while position < body.len() {
+        let header = match position {
+            0 => header.to_vec(),
+            _ => {
+                let mut header = header[..20].to_vec();
+                header.extend_from_slice(&copied);
+                header[0] = 0x40 | (header.len() / 4) as u8;
+                header
+            }
+        };
+        let room = mtu.checked_sub(header.len())? & !7;
+        if room == 0 {
+            return None;
+        }
+        let end = body.len().min(position + room);
+        let mut fragment = header;
+        fragment.extend_from_slice(&body[position..end]);
+        let total = fragment.len() as u16;
+        fragment[2..4].copy_from_slice(&total.to_be_bytes());
+        let mut flags = ((offset + position) / 8) as u16;
+        if more || end < body.len() {
+            flags |= 0x2000;
+        }
+        fragment[6..8].copy_from_slice(&flags.to_be_bytes());
+        fragments.push(fragment);
+        position = end;
+    }
+    match fragments.is_empty() {
+        true => None,
+        false => Some(fragments),
+    }
+}
+
+/// IPv4 options copied into every fragment, padded to a multiple of 4 bytes.
+fn copied_options(mut options: &[u8]) -> Vec<u8> {
+    let mut copied = Vec::new();
+    while let [kind, rest @ ..] = options {
+        let len = match kind {
+            // End of option list
+            0 => break,
+            // No operation
+            1 => 1,
+            _ => match rest.first() {
+                Some(&len) if len >= 2 && len as usize <= options.len() => len as usize,
+                _ => break,
+            },
+        };
+        if kind & 0x80 != 0 {
+            copied.extend_from_slice(&options[..len]);
+        }
+        options = &options[len..];
+    }
+    copied.resize((copied.len() + 3) / 4 * 4, 0);
+    copied
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::packet::{PacketBuilder, Reassembler, TcpBuilder};
+
+    fn builders() -> [PacketBuilder; 2] {
+        [
+            PacketBuilder::ipv4(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1)).id(0xFFFE),
+            PacketBuilder::ipv6(
+                Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2),
+                Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+            ),
+        ]
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|at| (at % 251) as u8).collect()
+    }
+
+    fn packets(segmented: Segmented<'_>) -> Vec<WinDivertPacket<'_, NetworkLayer>> {
+        match segmented {
+            Segmented::Packets(packets) => packets,
+            Segmented::TooBig(_) => panic!("packet too big"),
+        }
+    }
+
+    fn assert_checksums(packet: &WinDivertPacket<'_, NetworkLayer>) {
+        let mut expected = packet.data.to_vec();
+        checksum::calc_checksums(&mut expected, ChecksumFlags::new());
+        assert_eq!(packet.data[..], expected[..]);
+    }
+
+    #[test]
+    fn mtus() {
+        let mut segmenter = Segmenter::default();
+        segmenter.set_mtu(3, 9000).set_mtu(4, 20);
+        assert_eq!(segmenter.mtu(1), 1500);
+        assert_eq!(segmenter.mtu(3), 9000.min(WINDIVERT_MTU_MAX));
+        assert_eq!(segmenter.mtu(4), MTU_MIN);
+        assert_eq!(Segmenter::new(0).mtu(1), MTU_MIN);
+
+        // Packets fitting in the MTU are returned as they are
+        for builder in builders() {
+            let packet = builder
+                .udp(51000, 53)
+                .payload(payload(1400))
+                .build()
+                .unwrap();
+            let packets = packets(segmenter.segment(packet.clone()));
+            assert_eq!(packets.len(), 1);
+            assert_eq!(packets[0].data, packet.data);
+        }
+    }
+
+    #[test]
+    fn tcp_segments() {
+        let mut segmenter = Segmenter::default();
+        segmenter.set_mtu(5, 1000);
+        let tcp = TcpBuilder::new(49152, 443)
+            .seq_number(0xFFFF_F800)
+            .ack_number(7)
+            .ack(true)
+            .psh(true)
+            .fin(true);
+        for builder in builders() {
+            let body = payload(3000);
+            let packet = builder
+                .interface_index(5)
+                .tcp(tcp.clone())
+                .payload(body.clone())
+                .build()
+                .unwrap();
+            let headers = packet.data.len() - body.len();
+            let mss = 1000 - headers;
+            let segments = packets(segmenter.segment(packet));
+            assert_eq!(segments.len(), (body.len() + mss - 1) / mss);
+
+            let mut data = Vec::new();
+            for (index, segment) in segments.iter().enumerate() {
+                assert!(segment.data.len() <= 1000);
+                assert_eq!(segment.address.interface_index(), 5);
+                assert_checksums(segment);
+                let parsed = segment.parse();
+                assert!(!parsed.truncated);
+                let header = parsed.tcp().unwrap();
+                let seq = 0xFFFF_F800_u32.wrapping_add((index * mss) as u32);
+                assert_eq!(header.seq_number(), seq);
+                assert_eq!(header.ack_number(), 7);
+                assert!(header.ack());
+                // FIN and PSH only on the last segment
+                let last = index + 1 == segments.len();
+                assert_eq!((header.fin(), header.psh()), (last, last));
+                if let Some(ipv4) = parsed.ipv4() {
+                    assert_eq!(ipv4.id(), 0xFFFE_u16.wrapping_add(index as u16));
+                }
+                data.extend_from_slice(parsed.payload);
+            }
+            assert_eq!(data, body);
+        }
+    }
+
+    #[test]
+    fn tcp_syn_and_urgent() {
+        let segmenter = Segmenter::new(600);
+        let tcp = TcpBuilder::new(49152, 443)
+            .seq_number(100)
+            .syn(true)
+            .urg(true)
+            .urg_ptr(700);
+        for builder in builders() {
+            let packet = builder
+                .tcp(tcp.clone())
+                .payload(payload(1200))
+                .build()
+                .unwrap();
+            let mss = 600 - (packet.data.len() - 1200);
+            let segments = packets(segmenter.segment(packet));
+            assert_eq!(segments.len(), 3);
+            let headers: Vec<_> = segments
+                .iter()
+                .map(|segment| segment.parse().tcp().unwrap())
+                .collect();
+
+            // The SYN flag takes one sequence number, on the first segment only
+            assert_eq!(headers[0].seq_number(), 100);
+            assert_eq!(headers[1].seq_number(), 100 + 1 + mss as u32);
+            assert!(headers[0].syn() && !headers[1].syn() && !headers[2].syn());
+            // The urgent pointer follows the segments until it is passed
+            assert!(headers[0].urg() && headers[1].urg() && !headers[2].urg());
+            assert_eq!(headers[0].urg_ptr(), 700);
+            assert_eq!(headers[1].urg_ptr(), (700 - mss) as u16);
+            assert_eq!(headers[2].urg_ptr(), 0);
+            segments.iter().for_each(assert_checksums);
+        }
+    }
+
+    #[test]
+    fn ipv4_fragments() {
+        let segmenter = Segmenter::new(576);
+        let [builder, _] = builders();
+        let packet = builder
+            .udp(51000, 53)
+            .payload(payload(3000))
+            .build()
+            .unwrap();
+        let fragments = packets(segmenter.segment(packet.clone()));
+        assert_eq!(fragments.len(), 6);
+
+        let mut reassembler = Reassembler::new();
+        let mut whole = None;
+        for (index, fragment) in fragments.into_iter().enumerate().rev() {
+            assert!(fragment.data.len() <= 576);
+            assert_checksums(&fragment);
+            let header = fragment.parse().ipv4().unwrap();
+            assert_eq!(header.fragment_offset() as usize, index * 552 / 8);
+            assert_eq!(header.mf(), index != 5);
+            assert_eq!(whole, None::<Vec<u8>>);
+            whole = reassembler
+                .push(fragment)
+                .map(|packet| packet.data.into_owned());
+        }
+        assert_eq!(whole.unwrap(), packet.data[..]);
+
+        // Wrong transport checksums are computed before the datagram is fragmented
+        let mut broken = packet.clone();
+        broken.data.to_mut()[26..28].copy_from_slice(&[0xBE, 0xEF]);
+        let fragments = packets(segmenter.segment(broken));
+        let whole = fragments
+            .into_iter()
+            .find_map(|fragment| reassembler.push(fragment));
+        assert_eq!(whole.unwrap().data, packet.data);
+    }
+
+    #[test]
+    fn copied_options() {
+        // Router alert is copied, record route and no operation are not
+        let options = [1, 7, 7, 4, 0, 0, 0, 0, 0x94, 4, 0, 0, 0];
+        assert_eq!(super::copied_options(&options), [0x94, 4, 0, 0]);
+        assert_eq!(super::copied_options(&[0x94, 3, 0, 1]), [0x94, 3, 0, 0]);
+        assert!(super::copied_options(&[0x94, 9, 0]).is_empty());
+    }
+
+    #[test]
+    fn too_big() {
+        let segmenter = Segmenter::new(1280);
+        for builder in builders() {
+            let packet = builder
+                .df(true)
+                .outbound(false)
+                .udp(51000, 53)
+                .payload(payload(2000))
+                .build()
+                .unwrap();
+            let Segmented::TooBig(Some(message)) = segmenter.segment(packet.clone()) else {
+                panic!("packet split");
+            };
+            assert_eq!(
+                message.data,
+                reject::packet_too_big(&packet, 1280).unwrap().data
+            );
+            let parsed = message.parse();
+            let (msg_type, body) = match parsed.icmp() {
+                Some(icmp) => ((icmp.msg_type(), icmp.msg_code()), icmp.body()),
+                None => {
+                    let icmpv6 = parsed.icmpv6().unwrap();
+                    ((icmpv6.msg_type(), icmpv6.msg_code()), icmpv6.body())
+                }
+            };
+            assert!(msg_type == (3, 4) || msg_type == (2, 0));
+            assert_eq!(body, 1280);
+        }
+    }
+}
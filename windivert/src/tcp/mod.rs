@@ -0,0 +1,64 @@
+/*!
+TCP connections followed across diverted packets.
+
+[`StreamTracker`] rebuilds the ordered byte streams of the connections from their segments, for
+protocol inspection, identifying connections by the [`FlowKey`] of their client, the endpoint that
+sent the first segment seen, or the SYN segment. [`TcpRewriter`] replaces the payloads of segments
+with ones of other lengths, moving the sequence and acknowledgement numbers of the connections to
+match.
+*/
+
+use std::net::IpAddr;
+
+use crate::packet::ParsedPacket;
+
+mod rewrite;
+mod stream;
+
+pub use rewrite::*;
+pub use stream::*;
+
+/// Addresses and ports of the segments sent from one endpoint of a TCP connection to the other.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    /// Address of the sender.
+    pub src_addr: IpAddr,
+    /// Port of the sender.
+    pub src_port: u16,
+    /// Address of the receiver.
+    pub dst_addr: IpAddr,
+    /// Port of the receiver.
+    pub dst_port: u16,
+}
+
+impl FlowKey {
+    /// Key of the TCP segment `packet`, `None` if it isn't one.
+    pub fn of(packet: &ParsedPacket) -> Option<Self> {
+        let (ip, tcp) = (packet.ip?, packet.tcp()?);
+        Some(Self {
+            src_addr: ip.src_addr(),
+            src_port: tcp.src_port(),
+            dst_addr: ip.dst_addr(),
+            dst_port: tcp.dst_port(),
+        })
+    }
+
+    /// Key of the segments sent the other way.
+    pub fn reverse(&self) -> Self {
+        Self {
+            src_addr: self.dst_addr,
+            src_port: self.dst_port,
+            dst_addr: self.src_addr,
+            dst_port: self.src_port,
+        }
+    }
+}
+
+/// Endpoint of a TCP connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// Endpoint that opened the connection.
+    Client,
+    /// Endpoint the connection was opened to.
+    Server,
+}
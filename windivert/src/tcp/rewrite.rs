@@ -0,0 +1,621 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use windivert_sys::ChecksumFlags;
+
+use super::FlowKey;
+use crate::{
+    layer::NetworkLayer,
+    packet::{IpHeader, WinDivertPacket},
+};
+
+/// Segment whose payload was replaced by one of a different length.
+#[derive(Debug, Clone, Copy)]
+struct Edit {
+    /// Position of the first byte of the original payload.
+    start: i64,
+    /// Length of the original payload.
+    length: i64,
+    /// Length of the new payload minus the length of the original one.
+    delta: i64,
+    /// Sum of the deltas of this edit and the ones before it.
+    shift: i64,
+}
+
+/**
+Edits made to the segments sent in one direction of a connection.
+
+Sequence numbers are turned into positions relative to the first edited segment, so they can wrap
+around. Positions of the original stream are mapped to the stream sent after the edits, and back.
+*/
+#[derive(Debug)]
+struct Offsets {
+    /// Sequence number of position 0.
+    base: u32,
+    /// Farthest position sent, the reference used to unwrap sequence numbers.
+    high: i64,
+    /// Shift of the edits acknowledged by the receiver and forgotten.
+    shift: i64,
+    /// End of the edits forgotten, before which segments can't be edited anymore.
+    floor: i64,
+    /// Edits not acknowledged yet, by position.
+    edits: Vec<Edit>,
+    last_used: u64,
+}
+
+impl Offsets {
+    fn new(base: u32) -> Self {
+        Self {
+            base,
+            high: 0,
+            shift: 0,
+            floor: i64::MIN,
+            edits: Vec::new(),
+            last_used: 0,
+        }
+    }
+
+    /// Position of the original sequence number `seq`.
+    fn position(&self, seq: u32) -> i64 {
+        let high = self.base.wrapping_add(self.high as u32);
+        self.high + seq.wrapping_sub(high) as i32 as i64
+    }
+
+    /// Position of the sequence number `seq` of the stream sent after the edits.
+    fn edited_position(&self, seq: u32) -> i64 {
+        let high = self.translate(self.high);
+        high + seq.wrapping_sub(self.base.wrapping_add(high as u32)) as i32 as i64
+    }
+
+    fn seq(&self, position: i64) -> u32 {
+        self.base.wrapping_add(position as u32)
+    }
+
+    /// Maps an original position to the stream sent after the edits.
+    fn translate(&self, position: i64) -> i64 {
+        let index = self.edits.partition_point(|edit| edit.start < position);
+        match index.checked_sub(1).map(|index| &self.edits[index]) {
+            // Within an edited segment, as when it is sent again in smaller segments
+            Some(edit) if position < edit.start + edit.length => position + edit.shift - edit.delta,
+            Some(edit) => position + edit.shift,
+            None => position + self.shift,
+        }
+    }
+
+    /**
+    Maps a position of the stream sent after the edits to the original stream. Positions within an
+    edited payload have no original position, and are rounded to its start or end.
+    */
+    fn restore(&self, position: i64, round_up: bool) -> i64 {
+        let index = self
+            .edits
+            .partition_point(|edit| edit.start + edit.shift - edit.delta < position);
+        match index.checked_sub(1).map(|index| &self.edits[index]) {
+            Some(edit) if position >= edit.start + edit.length + edit.shift => {
+                position - edit.shift
+            }
+            Some(edit) if round_up => edit.start + edit.length,
+            Some(edit) => edit.start,
+            None => position - self.shift,
+        }
+    }
+
+    /**
+    Records that the payload of `length` bytes at `start` is replaced by one of `length + delta`
+    bytes. Returns `false` if the payload overlaps an edited payload without being the same, or
+    was already acknowledged.
+    */
+    fn edit(&mut self, start: i64, length: i64, delta: i64) -> bool {
+        let index = self.edits.partition_point(|edit| edit.start < start);
+        let after_previous = index.checked_sub(1).map_or(start >= self.floor, |index| {
+            let previous = &self.edits[index];
+            start >= previous.start + previous.length
+        });
+        let next = self.edits.get(index);
+        let same = next.map_or(false, |edit| edit.start == start && edit.length == length);
+        let overlaps_next = next.map_or(false, |edit| edit.start < start + length);
+        if !after_previous || (!same && overlaps_next) {
+            return false;
+        }
+
+        let previous_delta = match same {
+            true => std::mem::replace(&mut self.edits[index].delta, delta),
+            false if delta == 0 => return true,
+            false => {
+                let shift = index
+                    .checked_sub(1)
+                    .map_or(self.shift, |index| self.edits[index].shift);
+                let edit = Edit {
+                    start,
+                    length,
+                    delta,
+                    shift,
+                };
+                self.edits.insert(index, edit);
+                0
+            }
+        };
+        for edit in &mut self.edits[index..] {
+            edit.shift += delta - previous_delta;
+        }
+        true
+    }
+
+    /// Forgets the edits before `position`, acknowledged by the receiver.
+    fn forget(&mut self, position: i64) {
+        if position > self.high {
+            return;
+        }
+        let count = self
+            .edits
+            .partition_point(|edit| edit.start + edit.length <= position);
+        if let Some(edit) = count.checked_sub(1).map(|index| self.edits[index]) {
+            self.shift = edit.shift;
+            self.floor = edit.start + edit.length;
+            self.edits.drain(..count);
+        }
+    }
+}
+
+/**
+Rewrites the payloads of TCP segments, changing their length, and keeps the connections working.
+
+Replacing a payload by one of a different length moves the sequence numbers of every byte sent
+after it. [`rewrite()`](TcpRewriter::rewrite) records these changes for each direction of a
+connection, and [`adjust()`](TcpRewriter::adjust) applies them to the other segments: sequence
+numbers are moved in the direction of the rewritten segments, and acknowledgement numbers and SACK
+blocks are moved back in the other direction, so both endpoints see consistent streams. Every
+segment of a connection must go through the rewriter between being diverted and sent, with the
+checksums updated.
+
+Retransmitted segments must be rewritten the same way as the first time, or forwarded unchanged
+when they were left alone. The changes of a connection are forgotten once they are acknowledged,
+when it is reset or opened again with a SYN segment, or to make room for another connection when
+too many are tracked.
+*/
+#[derive(Debug)]
+pub struct TcpRewriter {
+    max_flows: usize,
+    flows: HashMap<FlowKey, Offsets>,
+    uses: u64,
+}
+
+impl Default for TcpRewriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TcpRewriter {
+    /// Creates a rewriter tracking up to 4096 directions of connections.
+    pub fn new() -> Self {
+        Self {
+            max_flows: 4096,
+            flows: HashMap::new(),
+            uses: 0,
+        }
+    }
+
+    /// Maximum number of directions of connections with rewritten segments tracked.
+    pub fn max_flows(mut self, max_flows: usize) -> Self {
+        self.max_flows = max_flows;
+        self
+    }
+
+    /// Number of directions of connections with rewritten segments tracked.
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// Returns `true` if no rewritten segment is tracked.
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    /// Forgets the rewritten segments of both directions of the connection of `flow`.
+    pub fn remove(&mut self, flow: &FlowKey) {
+        self.flows.remove(flow);
+        self.flows.remove(&flow.reverse());
+    }
+
+    /**
+    Replaces the payload of the TCP segment `packet` by `payload`, then adjusts it as
+    [`adjust()`](TcpRewriter::adjust) does and computes its checksums.
+
+    Returns `false`, only adjusting the segment, if it has no payload, the SYN or RST flag, is a
+    fragment or truncated, would be larger than an IP packet can be, or if its payload overlaps a
+    rewritten one without being a retransmission of it, or was already acknowledged. Packets that
+    aren't TCP segments are left untouched.
+    */
+    pub fn rewrite(
+        &mut self,
+        packet: &mut WinDivertPacket<'_, NetworkLayer>,
+        payload: &[u8],
+    ) -> bool {
+        let parsed = packet.parse();
+        let (Some(flow), Some(ip), Some(tcp)) = (FlowKey::of(&parsed), parsed.ip, parsed.tcp())
+        else {
+            return false;
+        };
+        let header_len = parsed.payload.as_ptr() as usize - packet.data.as_ptr() as usize;
+        let length = header_len + payload.len();
+        // Offset and value of the total length of IPv4 or payload length of IPv6
+        let (length_at, ip_length) = match ip {
+            IpHeader::V4(_) => (2, length),
+            IpHeader::V6(_) => (4, length - 40),
+        };
+        if parsed.payload.is_empty()
+            || tcp.syn()
+            || tcp.rst()
+            || parsed.is_fragment()
+            || parsed.truncated
+            || ip_length > u16::MAX as usize
+        {
+            self.adjust(packet);
+            return false;
+        }
+
+        let seq = tcp.seq_number();
+        let original = parsed.payload.len() as i64;
+        if !self.flows.contains_key(&flow) {
+            self.make_room();
+        }
+        let offsets = self.flows.entry(flow).or_insert_with(|| Offsets::new(seq));
+        let start = offsets.position(seq);
+        if !offsets.edit(start, original, payload.len() as i64 - original) {
+            self.adjust(packet);
+            return false;
+        }
+
+        // The sequence number of the segment only moves with the payloads rewritten before it
+        self.adjust(packet);
+        let mut data = Vec::with_capacity(length);
+        data.extend_from_slice(&packet.data[..header_len]);
+        data.extend_from_slice(payload);
+        data[length_at..length_at + 2].copy_from_slice(&(ip_length as u16).to_be_bytes());
+        packet.data = Cow::Owned(data);
+        packet.recalculate_checksums(ChecksumFlags::new());
+        true
+    }
+
+    /**
+    Moves the sequence number of the TCP segment `packet` past the changes made to the segments
+    rewritten before it in the same direction, and its acknowledgement number and SACK blocks back
+    past the changes made in the other direction, updating its checksums.
+
+    Packets that aren't TCP segments or are fragments are left untouched.
+    */
+    pub fn adjust(&mut self, packet: &mut WinDivertPacket<'_, NetworkLayer>) {
+        let parsed = packet.parse();
+        let (Some(flow), Some(tcp)) = (FlowKey::of(&parsed), parsed.tcp()) else {
+            return;
+        };
+        if parsed.is_fragment() {
+            return;
+        }
+        if tcp.syn() {
+            // A new connection, or a retransmission of the SYN segments before any payload
+            self.flows.remove(&flow);
+            if !tcp.ack() {
+                self.flows.remove(&flow.reverse());
+            }
+            return;
+        }
+
+        self.uses += 1;
+        let uses = self.uses;
+        let seq = self.flows.get_mut(&flow).map(|offsets| {
+            offsets.last_used = uses;
+            let position = offsets.position(tcp.seq_number());
+            let length = parsed.payload.len() as i64 + tcp.fin() as i64;
+            offsets.high = offsets.high.max(position + length);
+            offsets.seq(offsets.translate(position))
+        });
+        let reverse = self.flows.get_mut(&flow.reverse()).filter(|_| tcp.ack());
+        let (ack, options) = match reverse {
+            Some(offsets) => {
+                let ack = offsets.restore(offsets.edited_position(tcp.ack_number()), false);
+                let mut options = tcp.options().to_vec();
+                restore_sack(offsets, &mut options);
+                offsets.forget(ack);
+                (Some(offsets.seq(ack)), Some(options))
+            }
+            None => (None, None),
+        };
+        let rst = tcp.rst();
+
+        if let Some(mut header) = packet.tcp_mut() {
+            if let Some(seq) = seq {
+                header.set_seq_number(seq);
+            }
+            if let Some(ack) = ack {
+                header.set_ack_number(ack);
+            }
+            if let Some(options) = options {
+                header.options_mut().copy_from_slice(&options);
+            }
+        }
+        if rst {
+            self.remove(&flow);
+        }
+    }
+
+    /// Forgets the least recently used direction of a connection if too many are tracked.
+    fn make_room(&mut self) {
+        if self.flows.len() < self.max_flows.max(1) {
+            return;
+        }
+        let oldest = self
+            .flows
+            .iter()
+            .min_by_key(|(_, offsets)| offsets.last_used)
+            .map(|(flow, _)| *flow);
+        if let Some(flow) = oldest {
+            self.flows.remove(&flow);
+        }
+    }
+}
+
+/// Moves the SACK blocks in the TCP `options` back to the original stream of `offsets`.
+fn restore_sack(offsets: &Offsets, mut options: &mut [u8]) {
+    while let Some(&kind) = options.first() {
+        let len = match kind {
+            // End of option list
+            0 => break,
+            // No operation
+            1 => 1,
+            _ => match options.get(1) {
+                Some(&len) if len >= 2 && len as usize <= options.len() => len as usize,
+                _ => break,
+            },
+        };
+        let (option, rest) = std::mem::take(&mut options).split_at_mut(len);
+        // Selective acknowledgement, from RFC 2018
+        if kind == 5 {
+            for block in option[2..].chunks_exact_mut(8) {
+                let left = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+                let right = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+                // Blocks shrink to the bytes received for sure
+                let left = offsets.restore(offsets.edited_position(left), true);
+                let right = offsets.restore(offsets.edited_position(right), false);
+                block[..4].copy_from_slice(&offsets.seq(left).to_be_bytes());
+                block[4..].copy_from_slice(&offsets.seq(right).to_be_bytes());
+            }
+        }
+        options = rest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::packet::{checksum, PacketBuilder, TcpBuilder};
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    /// Initial sequence number of the server.
+    const SERVER_SEQ: u32 = 5000;
+
+    /// Client segment with the sequence number `seq` and the ACK flag.
+    fn client(seq: u32, payload: &[u8]) -> WinDivertPacket<'static, NetworkLayer> {
+        let tcp = TcpBuilder::new(49152, 443)
+            .seq_number(seq)
+            .ack_number(SERVER_SEQ)
+            .ack(true);
+        PacketBuilder::ipv4(CLIENT, SERVER)
+            .tcp(tcp)
+            .payload(payload)
+            .build()
+            .unwrap()
+    }
+
+    /// Server segment acknowledging `ack`, with the SACK blocks `sack`.
+    fn server(ack: u32, sack: &[(u32, u32)]) -> WinDivertPacket<'static, NetworkLayer> {
+        let mut options = Vec::new();
+        if !sack.is_empty() {
+            options.extend_from_slice(&[1, 1, 5, 2 + 8 * sack.len() as u8]);
+            for (left, right) in sack {
+                options.extend_from_slice(&left.to_be_bytes());
+                options.extend_from_slice(&right.to_be_bytes());
+            }
+        }
+        let tcp = TcpBuilder::new(443, 49152)
+            .seq_number(SERVER_SEQ)
+            .ack_number(ack)
+            .ack(true)
+            .options(options);
+        PacketBuilder::ipv4(SERVER, CLIENT)
+            .tcp(tcp)
+            .build()
+            .unwrap()
+    }
+
+    /// Sequence number, payload and SACK blocks of `packet`, checking its checksums.
+    fn read(packet: &WinDivertPacket<'_, NetworkLayer>) -> (u32, u32, Vec<u8>, Vec<(u32, u32)>) {
+        let mut expected = packet.data.to_vec();
+        checksum::calc_checksums(&mut expected, ChecksumFlags::new());
+        assert_eq!(packet.data[..], expected[..]);
+
+        let parsed = packet.parse();
+        let tcp = parsed.tcp().unwrap();
+        let sack = match tcp.options() {
+            [1, 1, 5, _, blocks @ ..] => blocks
+                .chunks_exact(8)
+                .map(|block| {
+                    let left = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+                    let right = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+                    (left, right)
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let seq = tcp.seq_number();
+        (seq, tcp.ack_number(), parsed.payload.to_vec(), sack)
+    }
+
+    fn rewrite(rewriter: &mut TcpRewriter, seq: u32, old: &[u8], new: &[u8]) -> (u32, Vec<u8>) {
+        let mut packet = client(seq, old);
+        assert!(rewriter.rewrite(&mut packet, new));
+        let (seq, _, payload, _) = read(&packet);
+        (seq, payload)
+    }
+
+    fn adjust(
+        rewriter: &mut TcpRewriter,
+        mut packet: WinDivertPacket<'static, NetworkLayer>,
+    ) -> (u32, u32, Vec<u8>, Vec<(u32, u32)>) {
+        rewriter.adjust(&mut packet);
+        read(&packet)
+    }
+
+    #[test]
+    fn edits() {
+        // The second base wraps around in the middle of the first payload
+        for base in [1000, u32::MAX - 2] {
+            let seq = |offset: u32| base.wrapping_add(offset);
+            let mut rewriter = TcpRewriter::new();
+
+            // Original stream: hello abc xyz end, sent as: hello world abc x end
+            let rewritten = rewrite(&mut rewriter, seq(0), b"hello", b"hello world");
+            assert_eq!(rewritten, (seq(0), b"hello world".to_vec()));
+            let (abc, _, payload, _) = adjust(&mut rewriter, client(seq(5), b"abc"));
+            assert_eq!((abc, payload), (seq(11), b"abc".to_vec()));
+            let rewritten = rewrite(&mut rewriter, seq(8), b"xyz", b"x");
+            assert_eq!(rewritten, (seq(14), b"x".to_vec()));
+            let (end, _, _, _) = adjust(&mut rewriter, client(seq(11), b"end"));
+            assert_eq!(end, seq(15));
+            assert_eq!(rewriter.len(), 1);
+
+            // Acknowledgements within a rewritten payload are rounded down to its start
+            let acks = [(6, 0), (11, 5), (12, 6), (14, 8), (15, 11), (18, 14)];
+            for (sent, original) in acks {
+                let (_, ack, _, _) = adjust(&mut rewriter, server(seq(sent), &[]));
+                assert_eq!(ack, seq(original), "{sent}");
+            }
+        }
+    }
+
+    #[test]
+    fn retransmissions() {
+        let mut rewriter = TcpRewriter::new();
+        rewrite(&mut rewriter, 1000, b"hello", b"hello world");
+        rewrite(&mut rewriter, 1008, b"xyz", b"x");
+
+        // Retransmissions are rewritten again, possibly with another length
+        assert_eq!(
+            rewrite(&mut rewriter, 1008, b"xyz", b"x"),
+            (1014, b"x".to_vec())
+        );
+        assert_eq!(
+            rewrite(&mut rewriter, 1000, b"hello", b"hi"),
+            (1000, b"hi".to_vec())
+        );
+        let (seq, _, _, _) = adjust(&mut rewriter, client(1011, b"end"));
+        assert_eq!(seq, 1000 + 2 + 3 + 1);
+        // Segments overlapping a rewritten payload without being the same can't be rewritten
+        let mut packet = client(1009, b"yz");
+        assert!(!rewriter.rewrite(&mut packet, b"YZ"));
+        // It keeps its offset from the start of the rewritten payload
+        assert_eq!(read(&packet).0, 1000 + 2 + 3 + 1);
+
+        // Neither can acknowledged segments
+        adjust(&mut rewriter, server(1006, &[]));
+        let mut packet = client(1000, b"hello");
+        assert!(!rewriter.rewrite(&mut packet, b"hi"));
+        assert_eq!(read(&packet).2, b"hello");
+    }
+
+    #[test]
+    fn sack() {
+        let base = u32::MAX - 4;
+        let seq = |offset: u32| base.wrapping_add(offset);
+        let mut rewriter = TcpRewriter::new();
+        rewrite(&mut rewriter, seq(0), b"hello", b"hello world");
+        rewrite(&mut rewriter, seq(8), b"xyz", b"x");
+
+        // Blocks shrink to the original bytes they cover for sure
+        let blocks = [(seq(15), seq(18)), (seq(14), seq(15)), (seq(2), seq(13))];
+        let (_, ack, _, sack) = adjust(&mut rewriter, server(seq(0), &blocks));
+        assert_eq!(ack, seq(0));
+        assert_eq!(
+            sack,
+            [(seq(11), seq(14)), (seq(8), seq(11)), (seq(5), seq(7))]
+        );
+
+        // Options other than SACK are left alone
+        let tcp = TcpBuilder::new(443, 49152)
+            .ack_number(seq(11))
+            .ack(true)
+            .options([2, 4, 0x05, 0xB4]);
+        let mut packet = PacketBuilder::ipv4(SERVER, CLIENT)
+            .tcp(tcp)
+            .build()
+            .unwrap();
+        rewriter.adjust(&mut packet);
+        assert_eq!(packet.parse().tcp().unwrap().options(), [2, 4, 0x05, 0xB4]);
+        assert_eq!(read(&packet).1, seq(5));
+    }
+
+    #[test]
+    fn ignored() {
+        let mut rewriter = TcpRewriter::new();
+        let mut empty = client(1000, b"");
+        assert!(!rewriter.rewrite(&mut empty, b"data"));
+        let tcp = TcpBuilder::new(49152, 443).syn(true);
+        let mut syn = PacketBuilder::ipv4(CLIENT, SERVER)
+            .tcp(tcp)
+            .payload(*b"data")
+            .build()
+            .unwrap();
+        assert!(!rewriter.rewrite(&mut syn, b"other"));
+        let mut udp = PacketBuilder::ipv4(CLIENT, SERVER)
+            .udp(51000, 53)
+            .payload(*b"data")
+            .build()
+            .unwrap();
+        assert!(!rewriter.rewrite(&mut udp, b"other"));
+        assert_eq!(udp.parse().payload, b"data");
+        assert!(rewriter.is_empty());
+    }
+
+    #[test]
+    fn resets() {
+        let mut rewriter = TcpRewriter::new();
+        rewrite(&mut rewriter, 1000, b"hello", b"hello world");
+        assert_eq!(rewriter.len(), 1);
+        let tcp = TcpBuilder::new(443, 49152).rst(true);
+        let mut rst = PacketBuilder::ipv4(SERVER, CLIENT)
+            .tcp(tcp)
+            .build()
+            .unwrap();
+        rewriter.adjust(&mut rst);
+        assert!(rewriter.is_empty());
+
+        // A SYN segment forgets the previous connection
+        rewrite(&mut rewriter, 1000, b"hello", b"hello world");
+        let tcp = TcpBuilder::new(49152, 443).seq_number(90000).syn(true);
+        let mut syn = PacketBuilder::ipv4(CLIENT, SERVER)
+            .tcp(tcp)
+            .build()
+            .unwrap();
+        rewriter.adjust(&mut syn);
+        assert!(rewriter.is_empty());
+        let (seq, _, _, _) = adjust(&mut rewriter, client(1005, b"abc"));
+        assert_eq!(seq, 1005);
+
+        // The least recently used direction makes room for a new one
+        let mut rewriter = TcpRewriter::new().max_flows(1);
+        rewrite(&mut rewriter, 1000, b"hello", b"hello world");
+        let tcp = TcpBuilder::new(443, 49152).seq_number(SERVER_SEQ).ack(true);
+        let mut packet = PacketBuilder::ipv4(SERVER, CLIENT)
+            .tcp(tcp)
+            .payload(*b"reply")
+            .build()
+            .unwrap();
+        assert!(rewriter.rewrite(&mut packet, b"longer reply"));
+        assert_eq!(rewriter.len(), 1);
+        let (seq, _, _, _) = adjust(&mut rewriter, client(1005, b"abc"));
+        assert_eq!(seq, 1005);
+    }
+}
@@ -0,0 +1,693 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use super::{Endpoint, FlowKey};
+use crate::{layer::NetworkLayer, packet::WinDivertPacket};
+
+/// Farthest a segment can start ahead of the delivered data, the largest window of RFC 7323.
+const SEQ_WINDOW: i64 = 1 << 30;
+
+/**
+Event of a connection followed by a [`StreamTracker`].
+
+`flow` is always the key of the segments sent by the client. Connections stop being tracked after
+a [`Reset`](StreamEvent::Reset) or [`Expired`](StreamEvent::Expired) event. Once both endpoints are
+[`Closed`](StreamEvent::Closed), a connection is kept without further events until it times out, so
+late retransmissions don't open it again, or until a new SYN segment reuses its ports.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    /// A connection is tracked from now on.
+    Opened {
+        /// Key of the client segments.
+        flow: FlowKey,
+    },
+    /// Bytes sent by `sender`, following the bytes delivered before.
+    Data {
+        /// Key of the client segments.
+        flow: FlowKey,
+        /// Endpoint that sent the bytes.
+        sender: Endpoint,
+        /// Position of the bytes in the stream of the sender.
+        offset: u64,
+        /// Contiguous bytes of the stream.
+        data: Vec<u8>,
+    },
+    /// Bytes sent by `sender` that were skipped to stay within the memory limit of the connection.
+    Gap {
+        /// Key of the client segments.
+        flow: FlowKey,
+        /// Endpoint that sent the bytes.
+        sender: Endpoint,
+        /// Position of the first skipped byte in the stream of the sender.
+        offset: u64,
+        /// Number of bytes skipped.
+        length: u64,
+    },
+    /// `sender` closed its stream with a FIN segment, and all its bytes were delivered.
+    Closed {
+        /// Key of the client segments.
+        flow: FlowKey,
+        /// Endpoint that closed its stream.
+        sender: Endpoint,
+    },
+    /// The connection was reset by either endpoint.
+    Reset {
+        /// Key of the client segments.
+        flow: FlowKey,
+    },
+    /**
+    The connection was idle longer than the timeout, made room for a new connection, or was
+    replaced by a new connection reusing its ports after a FIN segment.
+    */
+    Expired {
+        /// Key of the client segments.
+        flow: FlowKey,
+    },
+}
+
+/// Bytes sent by one endpoint of a connection.
+#[derive(Debug, Default)]
+struct Stream {
+    /// Sequence number of the first byte of the stream, set by the first segment seen.
+    base: Option<u32>,
+    /// Number of bytes delivered.
+    offset: u64,
+    /// Segments received ahead of the delivered bytes, by offset.
+    pending: BTreeMap<u64, Vec<u8>>,
+    /// Offset of the FIN flag, once received.
+    fin: Option<u64>,
+    closed: bool,
+}
+
+impl Stream {
+    fn buffered(&self) -> usize {
+        self.pending.values().map(Vec::len).sum()
+    }
+
+    /// Buffers the bytes of `data` at `start` that are neither delivered nor buffered already.
+    fn insert(&mut self, start: u64, data: &[u8]) {
+        let end = start + data.len() as u64;
+        let mut gaps = Vec::new();
+        let mut position = start.max(self.offset);
+        for (&first, buffered) in self.pending.range(..end) {
+            let last = first + buffered.len() as u64;
+            if last <= position {
+                continue;
+            }
+            if first > position {
+                gaps.push((position, first));
+            }
+            position = last;
+        }
+        if position < end {
+            gaps.push((position, end));
+        }
+        for (first, last) in gaps {
+            let bytes = &data[(first - start) as usize..(last - start) as usize];
+            self.pending.insert(first, bytes.to_vec());
+        }
+    }
+
+    /// Delivers the pending segments following the delivered bytes.
+    fn drain(&mut self, mut deliver: impl FnMut(u64, Vec<u8>)) {
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() > self.offset {
+                break;
+            }
+            let (start, mut data) = entry.remove_entry();
+            let end = start + data.len() as u64;
+            if end > self.offset {
+                data.drain(..(self.offset - start) as usize);
+                let offset = self.offset;
+                self.offset = end;
+                deliver(offset, data);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    client: Stream,
+    server: Stream,
+    last_seen: Instant,
+}
+
+impl Connection {
+    fn finished(&self) -> bool {
+        self.client.closed && self.server.closed
+    }
+
+    /**
+    Returns `true` if a SYN segment from `sender` with the sequence number `seq` opens a new
+    connection on the same ports, once either endpoint sent a FIN segment. Retransmissions of the
+    first SYN segment of the connection take the sequence number before its base.
+    */
+    fn reused(&self, sender: Endpoint, seq: u32) -> bool {
+        let stream = match sender {
+            Endpoint::Client => &self.client,
+            Endpoint::Server => &self.server,
+        };
+        let fin = self.client.fin.is_some() || self.server.fin.is_some();
+        fin && stream.base != Some(seq.wrapping_add(1))
+    }
+
+    fn stream(&mut self, sender: Endpoint) -> &mut Stream {
+        match sender {
+            Endpoint::Client => &mut self.client,
+            Endpoint::Server => &mut self.server,
+        }
+    }
+}
+
+/**
+Rebuilds the byte streams of the TCP connections seen in network layer packets.
+
+Each segment given to [`push()`](StreamTracker::push) is placed in the stream of its sender, using
+sequence numbers relative to the first segment seen so they can wrap around. Bytes are delivered in
+order as [`StreamEvent::Data`] events as soon as they follow the bytes delivered before:
+retransmitted bytes are ignored, bytes overlapping delivered ones are trimmed, and segments received
+out of order are buffered until the missing bytes arrive. Among segments overlapping each other,
+the bytes received first are kept. Segments starting more than 1 GiB ahead of the delivered bytes,
+farther than the largest TCP window, are ignored.
+
+A SYN segment with a new sequence number reuses the ports of a connection once either endpoint
+sent a FIN segment, replacing the connection with a new one.
+
+The bytes buffered for a connection are limited in size. When the limit is exceeded, the
+missing bytes of the stream are given up on with a [`StreamEvent::Gap`] event so the buffered ones
+can be delivered. Connections idle for longer than the timeout are dropped, as are finished or else
+the least recently active ones when too many connections are tracked.
+*/
+#[derive(Debug)]
+pub struct StreamTracker {
+    timeout: Duration,
+    max_buffer: usize,
+    max_connections: usize,
+    connections: HashMap<FlowKey, Connection>,
+}
+
+impl Default for StreamTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamTracker {
+    /**
+    Creates a tracker with an idle timeout of 5 minutes, a limit of 1 MiB of buffered segments per
+    connection and a limit of 4096 connections.
+    */
+    pub fn new() -> Self {
+        Self {
+            timeout: Duration::from_secs(300),
+            max_buffer: 1 << 20,
+            max_connections: 4096,
+            connections: HashMap::new(),
+        }
+    }
+
+    /// Time after which connections without segments are dropped.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Maximum number of bytes buffered per connection for segments received out of order.
+    pub fn max_buffer(mut self, max_buffer: usize) -> Self {
+        self.max_buffer = max_buffer;
+        self
+    }
+
+    /// Maximum number of connections tracked.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Number of connections tracked.
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Returns `true` if no connection is tracked.
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// Adds a segment, as [`push_at()`](StreamTracker::push_at) does with the current time.
+    pub fn push(&mut self, packet: &WinDivertPacket<'_, NetworkLayer>) -> Vec<StreamEvent> {
+        self.push_at(packet, Instant::now())
+    }
+
+    /**
+    Adds a segment received at `now` and returns the events it caused, after the ones of the
+    connections expired at `now`. Packets that aren't TCP segments are ignored, and so are segments
+    of untracked connections unless they have the SYN flag or a payload.
+    */
+    pub fn push_at(
+        &mut self,
+        packet: &WinDivertPacket<'_, NetworkLayer>,
+        now: Instant,
+    ) -> Vec<StreamEvent> {
+        let mut events = self.expire(now);
+        let parsed = packet.parse();
+        let (Some(key), Some(tcp)) = (FlowKey::of(&parsed), parsed.tcp()) else {
+            return events;
+        };
+
+        let tracked = if self.connections.contains_key(&key) {
+            Some((key, Endpoint::Client))
+        } else if self.connections.contains_key(&key.reverse()) {
+            Some((key.reverse(), Endpoint::Server))
+        } else {
+            None
+        };
+        let tracked = match tracked {
+            Some((flow, sender))
+                if tcp.syn()
+                    && !tcp.ack()
+                    && self.connections[&flow].reused(sender, tcp.seq_number()) =>
+            {
+                if self
+                    .connections
+                    .remove(&flow)
+                    .map_or(false, |c| !c.finished())
+                {
+                    events.push(StreamEvent::Expired { flow });
+                }
+                None
+            }
+            tracked => tracked,
+        };
+
+        let (flow, sender) = if let Some(tracked) = tracked {
+            tracked
+        } else if tcp.rst() || !tcp.syn() && parsed.payload.is_empty() {
+            // Such as the last ACK segment of a closed connection
+            return events;
+        } else {
+            if self.connections.len() >= self.max_connections {
+                self.evict(&mut events);
+            }
+            if self.max_connections == 0 {
+                return events;
+            }
+            // The server answers the SYN segment with a SYN ACK segment
+            let (flow, sender) = match tcp.syn() && tcp.ack() {
+                true => (key.reverse(), Endpoint::Server),
+                false => (key, Endpoint::Client),
+            };
+            let connection = Connection {
+                client: Stream::default(),
+                server: Stream::default(),
+                last_seen: now,
+            };
+            self.connections.insert(flow, connection);
+            events.push(StreamEvent::Opened { flow });
+            (flow, sender)
+        };
+
+        if tcp.rst() {
+            self.connections.remove(&flow);
+            events.push(StreamEvent::Reset { flow });
+            return events;
+        }
+        let Some(connection) = self.connections.get_mut(&flow) else {
+            return events;
+        };
+        connection.last_seen = now;
+        let other = match sender {
+            Endpoint::Client => connection.server.buffered(),
+            Endpoint::Server => connection.client.buffered(),
+        };
+        let stream = connection.stream(sender);
+        if stream.closed {
+            return events;
+        }
+
+        // The SYN flag takes the sequence number before the first byte
+        let seq = tcp.seq_number().wrapping_add(tcp.syn() as u32);
+        let base = *stream.base.get_or_insert(seq);
+        let expected = base.wrapping_add(stream.offset as u32);
+        let delta = seq.wrapping_sub(expected) as i32 as i64;
+        let start = stream.offset as i64 + delta;
+        let data = parsed.payload;
+        let end = start + data.len() as i64;
+        if start < 0 || delta >= SEQ_WINDOW {
+            return events;
+        }
+        let (start, end) = (start as u64, end as u64);
+        if tcp.fin() && stream.fin.is_none() {
+            stream.fin = Some(end);
+        }
+
+        let data_event = |offset, data| StreamEvent::Data {
+            flow,
+            sender,
+            offset,
+            data,
+        };
+        if end > stream.offset && !data.is_empty() {
+            stream.insert(start, data);
+            stream.drain(|offset, data| events.push(data_event(offset, data)));
+        }
+        let max_buffer = self.max_buffer.saturating_sub(other);
+        while stream.buffered() > max_buffer {
+            let Some((&next, _)) = stream.pending.first_key_value() else {
+                break;
+            };
+            events.push(StreamEvent::Gap {
+                flow,
+                sender,
+                offset: stream.offset,
+                length: next - stream.offset,
+            });
+            stream.offset = next;
+            stream.drain(|offset, data| events.push(data_event(offset, data)));
+        }
+
+        if stream.fin.map_or(false, |fin| fin <= stream.offset) && !stream.closed {
+            stream.closed = true;
+            events.push(StreamEvent::Closed { flow, sender });
+        }
+        events
+    }
+
+    /// Drops the connections idle since before `now` minus the timeout, returning their events.
+    pub fn expire(&mut self, now: Instant) -> Vec<StreamEvent> {
+        let mut events = Vec::new();
+        let timeout = self.timeout;
+        self.connections.retain(|&flow, connection| {
+            let alive = now.saturating_duration_since(connection.last_seen) < timeout;
+            if !alive && !connection.finished() {
+                events.push(StreamEvent::Expired { flow });
+            }
+            alive
+        });
+        events
+    }
+
+    /// Drops a finished connection, or else the least recently active one.
+    fn evict(&mut self, events: &mut Vec<StreamEvent>) {
+        let oldest = self
+            .connections
+            .iter()
+            .min_by_key(|(_, connection)| (!connection.finished(), connection.last_seen))
+            .map(|(flow, _)| *flow);
+        let Some(flow) = oldest else {
+            return;
+        };
+        if self
+            .connections
+            .remove(&flow)
+            .map_or(false, |c| !c.finished())
+        {
+            events.push(StreamEvent::Expired { flow });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::packet::{PacketBuilder, TcpBuilder};
+    use Endpoint::{Client, Server};
+
+    const CLIENT: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 2), 49152);
+    const SERVER: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 1), 443);
+    const FLOW: FlowKey = FlowKey {
+        src_addr: IpAddr::V4(CLIENT.0),
+        src_port: CLIENT.1,
+        dst_addr: IpAddr::V4(SERVER.0),
+        dst_port: SERVER.1,
+    };
+
+    /// Segment sent by `sender` with the flags in `flags`, among `SAFR`.
+    fn segment(
+        sender: Endpoint,
+        seq: u32,
+        flags: &str,
+        payload: &[u8],
+    ) -> WinDivertPacket<'static, NetworkLayer> {
+        let ((src_addr, src_port), (dst_addr, dst_port)) = match sender {
+            Client => (CLIENT, SERVER),
+            Server => (SERVER, CLIENT),
+        };
+        let tcp = TcpBuilder::new(src_port, dst_port)
+            .seq_number(seq)
+            .syn(flags.contains('S'))
+            .ack(flags.contains('A'))
+            .fin(flags.contains('F'))
+            .rst(flags.contains('R'));
+        PacketBuilder::ipv4(src_addr, dst_addr)
+            .tcp(tcp)
+            .payload(payload)
+            .build()
+            .unwrap()
+    }
+
+    fn data(sender: Endpoint, offset: u64, data: &[u8]) -> StreamEvent {
+        StreamEvent::Data {
+            flow: FLOW,
+            sender,
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
+    /// Tracker following a connection opened by the client with the sequence number `seq`, the
+    /// server answering with `seq + 1000`.
+    fn opened(tracker: StreamTracker, seq: u32) -> StreamTracker {
+        let mut tracker = tracker;
+        let events = tracker.push(&segment(Client, seq, "S", b""));
+        assert_eq!(events, [StreamEvent::Opened { flow: FLOW }]);
+        let syn_ack = segment(Server, seq.wrapping_add(1000), "SA", b"");
+        assert_eq!(tracker.push(&syn_ack), []);
+        tracker
+    }
+
+    #[test]
+    fn reordering() {
+        let mut tracker = opened(StreamTracker::new(), 1000);
+        assert_eq!(
+            tracker.push(&segment(Client, 1001, "A", b"abc")),
+            [data(Client, 0, b"abc")]
+        );
+        assert_eq!(tracker.push(&segment(Client, 1010, "A", b"jkl")), []);
+        assert_eq!(tracker.push(&segment(Client, 1007, "A", b"ghi")), []);
+        assert_eq!(
+            tracker.push(&segment(Client, 1004, "A", b"def")),
+            [
+                data(Client, 3, b"def"),
+                data(Client, 6, b"ghi"),
+                data(Client, 9, b"jkl")
+            ]
+        );
+        assert_eq!(
+            tracker.push(&segment(Server, 2001, "A", b"xyz")),
+            [data(Server, 0, b"xyz")]
+        );
+        assert_eq!(tracker.len(), 1);
+    }
+
+    #[test]
+    fn overlap_and_retransmit() {
+        let mut tracker = opened(StreamTracker::new(), 1000);
+        let abcdef = segment(Client, 1001, "A", b"abcdef");
+        assert_eq!(tracker.push(&abcdef), [data(Client, 0, b"abcdef")]);
+        // Retransmitted bytes are ignored, and overlapping delivered ones are trimmed
+        assert_eq!(tracker.push(&abcdef), []);
+        assert_eq!(tracker.push(&segment(Client, 1001, "A", b"abc")), []);
+        assert_eq!(
+            tracker.push(&segment(Client, 1004, "A", b"DEFghi")),
+            [data(Client, 6, b"ghi")]
+        );
+
+        // Buffered bytes received first are kept
+        assert_eq!(tracker.push(&segment(Client, 1020, "A", b"0123")), []);
+        assert_eq!(tracker.push(&segment(Client, 1018, "A", b"xy01")), []);
+        assert_eq!(tracker.push(&segment(Client, 1020, "A", b"456789")), []);
+        assert_eq!(
+            tracker.push(&segment(Client, 1010, "A", b"jklmnopqXY")),
+            [
+                data(Client, 9, b"jklmnopq"),
+                data(Client, 17, b"xy"),
+                data(Client, 19, b"0123"),
+                data(Client, 23, b"89"),
+            ]
+        );
+    }
+
+    #[test]
+    fn max_buffer() {
+        let mut tracker = opened(StreamTracker::new().max_buffer(8), 1000);
+        assert_eq!(tracker.push(&segment(Client, 1011, "A", b"klmnop")), []);
+        // Bytes already buffered don't count twice
+        assert_eq!(tracker.push(&segment(Client, 1011, "A", b"klmnop")), []);
+        assert_eq!(tracker.push(&segment(Client, 1013, "A", b"mnop")), []);
+        assert_eq!(tracker.push(&segment(Client, 1015, "A", b"op")), []);
+
+        // Exceeding the limit gives up on the missing bytes
+        assert_eq!(
+            tracker.push(&segment(Client, 1021, "A", b"uvw")),
+            [
+                StreamEvent::Gap {
+                    flow: FLOW,
+                    sender: Client,
+                    offset: 0,
+                    length: 10,
+                },
+                data(Client, 10, b"klmnop"),
+            ]
+        );
+        assert_eq!(
+            tracker.push(&segment(Client, 1017, "A", b"qrst")),
+            [data(Client, 16, b"qrst"), data(Client, 20, b"uvw")]
+        );
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut tracker = opened(StreamTracker::new(), u32::MAX - 1);
+        assert_eq!(
+            tracker.push(&segment(Client, u32::MAX, "A", b"ab")),
+            [data(Client, 0, b"ab")]
+        );
+        assert_eq!(tracker.push(&segment(Client, 3, "A", b"ef")), []);
+        assert_eq!(
+            tracker.push(&segment(Client, 1, "A", b"cd")),
+            [data(Client, 2, b"cd"), data(Client, 4, b"ef")]
+        );
+        // The server stream starts past the wraparound
+        assert_eq!(
+            tracker.push(&segment(Server, 999, "A", b"xyz")),
+            [data(Server, 0, b"xyz")]
+        );
+    }
+
+    #[test]
+    fn window() {
+        let mut tracker = opened(StreamTracker::new().max_buffer(0), 1000);
+        // Segments farther than the window, or before the stream, are ignored
+        let far = 1001 + (1 << 30);
+        assert_eq!(tracker.push(&segment(Client, far, "A", b"far")), []);
+        assert_eq!(tracker.push(&segment(Client, 990, "A", b"early")), []);
+        assert_eq!(
+            tracker.push(&segment(Client, far - 1, "A", b"near")),
+            [
+                StreamEvent::Gap {
+                    flow: FLOW,
+                    sender: Client,
+                    offset: 0,
+                    length: (1 << 30) - 1,
+                },
+                data(Client, (1 << 30) - 1, b"near"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reuse_after_fin() {
+        let mut tracker = opened(StreamTracker::new(), 1000);
+        assert_eq!(
+            tracker.push(&segment(Client, 1001, "AF", b"abc")),
+            [
+                data(Client, 0, b"abc"),
+                StreamEvent::Closed {
+                    flow: FLOW,
+                    sender: Client
+                }
+            ]
+        );
+        // A retransmitted SYN doesn't open the connection again
+        assert_eq!(tracker.push(&segment(Client, 1000, "S", b"")), []);
+        assert_eq!(
+            tracker.push(&segment(Server, 2001, "AF", b"")),
+            [StreamEvent::Closed {
+                flow: FLOW,
+                sender: Server
+            }]
+        );
+        assert_eq!(tracker.push(&segment(Client, 1005, "A", b"")), []);
+
+        // A new SYN replaces the finished connection
+        assert_eq!(
+            tracker.push(&segment(Client, 90000, "S", b"")),
+            [StreamEvent::Opened { flow: FLOW }]
+        );
+        assert_eq!(
+            tracker.push(&segment(Client, 90001, "A", b"new")),
+            [data(Client, 0, b"new")]
+        );
+        assert_eq!(tracker.len(), 1);
+
+        // Connections closed by one endpoint only expire when replaced
+        assert_eq!(
+            tracker.push(&segment(Client, 90004, "AF", b"")),
+            [StreamEvent::Closed {
+                flow: FLOW,
+                sender: Client
+            }]
+        );
+        assert_eq!(
+            tracker.push(&segment(Client, 5, "S", b"")),
+            [
+                StreamEvent::Expired { flow: FLOW },
+                StreamEvent::Opened { flow: FLOW }
+            ]
+        );
+        assert_eq!(
+            tracker.push(&segment(Client, 6, "A", b"again")),
+            [data(Client, 0, b"again")]
+        );
+    }
+
+    #[test]
+    fn reuse_after_reset() {
+        let mut tracker = opened(StreamTracker::new(), 1000);
+        assert_eq!(
+            tracker.push(&segment(Server, 2001, "R", b"")),
+            [StreamEvent::Reset { flow: FLOW }]
+        );
+        assert!(tracker.is_empty());
+        // Segments of the reset connection are ignored, a new SYN opens it again
+        assert_eq!(tracker.push(&segment(Client, 1001, "A", b"")), []);
+        let mut tracker = opened(tracker, 50000);
+        assert_eq!(
+            tracker.push(&segment(Client, 50001, "A", b"hi")),
+            [data(Client, 0, b"hi")]
+        );
+    }
+
+    #[test]
+    fn expiry() {
+        let now = Instant::now();
+        let mut tracker = StreamTracker::new()
+            .timeout(Duration::from_secs(10))
+            .max_connections(1);
+        let syn = segment(Client, 1000, "S", b"");
+        assert_eq!(
+            tracker.push_at(&syn, now),
+            [StreamEvent::Opened { flow: FLOW }]
+        );
+        let later = now + Duration::from_secs(10);
+        assert_eq!(tracker.expire(later), [StreamEvent::Expired { flow: FLOW }]);
+        assert!(tracker.is_empty());
+
+        // The connection limit evicts the least recently active connection
+        tracker.push_at(&syn, now);
+        let other = PacketBuilder::ipv4(CLIENT.0, SERVER.0)
+            .tcp(TcpBuilder::new(49153, 443).syn(true))
+            .build()
+            .unwrap();
+        let events = tracker.push_at(&other, now);
+        assert_eq!(events[0], StreamEvent::Expired { flow: FLOW });
+        assert_eq!(tracker.len(), 1);
+    }
+}